@@ -0,0 +1,183 @@
+use std::{cell::RefCell, collections::BTreeMap, fs, process::Command, rc::Rc};
+
+use anyhow::bail;
+use log::{debug, warn};
+use nix::unistd::Group;
+use rootasrole_core::database::structs::{SActor, SActorType, SConfig, SGroups};
+use serde::Deserialize;
+use serde_json::Map;
+
+/// Users and groups allowed to assume a role
+#[derive(Deserialize)]
+struct Members {
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl Members {
+    fn to_actors(&self) -> Vec<SActor> {
+        let users = self.users.iter().map(|user| SActor::User {
+            id: Some(SActorType::Name(user.clone())),
+            _extra_fields: Map::new(),
+        });
+        let groups = self.groups.iter().map(|group| SActor::Group {
+            groups: Some(SGroups::Single(SActorType::Name(group.clone()))),
+            _extra_fields: Map::new(),
+        });
+        users.chain(groups).collect()
+    }
+}
+
+/// Where the role memberships are read from
+pub(crate) enum Source {
+    /// YAML mapping of role names to their users and groups
+    File(String),
+    /// posixGroup entries named after the roles, under the base DN of the URL
+    Ldap(String),
+    /// Groups named after the roles, as resolved by NSS (SSSD, files...)
+    Nss,
+}
+
+impl Source {
+    pub(crate) fn parse(source: &str) -> Self {
+        if source.starts_with("ldap://") || source.starts_with("ldaps://") {
+            Source::Ldap(source.to_string())
+        } else if source == "nss" || source == "sssd" {
+            Source::Nss
+        } else {
+            Source::File(source.to_string())
+        }
+    }
+
+    fn members(&self, roles: &[String]) -> anyhow::Result<BTreeMap<String, Members>> {
+        match self {
+            Source::File(path) => {
+                let mapping: BTreeMap<String, Members> =
+                    serde_yaml::from_str(&fs::read_to_string(path)?)?;
+                Ok(mapping)
+            }
+            Source::Ldap(url) => {
+                let mut mapping = BTreeMap::new();
+                for role in roles {
+                    if let Some(users) = ldap_members(url, role)? {
+                        mapping.insert(
+                            role.clone(),
+                            Members {
+                                users,
+                                groups: Vec::new(),
+                            },
+                        );
+                    }
+                }
+                Ok(mapping)
+            }
+            Source::Nss => Ok(roles
+                .iter()
+                .filter_map(|role| match Group::from_name(role) {
+                    Ok(Some(group)) => Some((
+                        role.clone(),
+                        Members {
+                            users: group.mem,
+                            groups: Vec::new(),
+                        },
+                    )),
+                    _ => None,
+                })
+                .collect()),
+        }
+    }
+}
+
+/// Escape a value of an LDAP search filter (RFC 4515), a role name cannot alter the filter
+fn escape_filter(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '*' | '(' | ')' | '\\' | '\0' => format!("\\{:02x}", c as u32),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+/// memberUid of the posixGroup named after the role, None when there is no such group
+fn ldap_members(url: &str, role: &str) -> anyhow::Result<Option<Vec<String>>> {
+    let (server, base) = match url.find("://").map(|i| i + 3) {
+        Some(start) => match url[start..].find('/') {
+            Some(slash) => (&url[..start + slash], &url[start + slash + 1..]),
+            None => (url, ""),
+        },
+        None => (url, ""),
+    };
+    if base.is_empty() {
+        bail!(
+            "LDAP source {} has no base DN, e.g. ldap://host/dc=example,dc=com",
+            url
+        );
+    }
+    debug!("Searching group {} in {}", role, url);
+    let output = Command::new("ldapsearch")
+        .args(["-x", "-LLL", "-H", server, "-b", base])
+        .arg(format!(
+            "(&(objectClass=posixGroup)(cn={}))",
+            escape_filter(role)
+        ))
+        .arg("memberUid")
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "ldapsearch failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !stdout.lines().any(|line| line.starts_with("dn:")) {
+        return Ok(None);
+    }
+    Ok(Some(
+        stdout
+            .lines()
+            .filter_map(|line| line.strip_prefix("memberUid:"))
+            .map(|uid| uid.trim().to_string())
+            .collect(),
+    ))
+}
+
+/// Replace the actors of the roles found in the source, returning the updated roles
+pub(crate) fn sync(config: &Rc<RefCell<SConfig>>, source: &Source) -> anyhow::Result<Vec<String>> {
+    let roles: Vec<String> = config
+        .as_ref()
+        .borrow()
+        .roles
+        .iter()
+        .map(|role| role.as_ref().borrow().name.clone())
+        .collect();
+    let mapping = source.members(&roles)?;
+    let mut updated = Vec::new();
+    for (name, members) in mapping {
+        let Some(role) = config.as_ref().borrow().role(&name).cloned() else {
+            warn!("Role {} of the source is not in the configuration", name);
+            continue;
+        };
+        let actors = members.to_actors();
+        if role.as_ref().borrow().actors != actors {
+            role.as_ref().borrow_mut().actors = actors;
+            updated.push(name);
+        }
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_filter_special_characters() {
+        assert_eq!(escape_filter("web-admins"), "web-admins");
+        assert_eq!(escape_filter("*)(uid=*"), "\\2a\\29\\28uid=\\2a");
+        assert_eq!(escape_filter("a\\b\0"), "a\\5cb\\00");
+        assert_eq!(escape_filter("équipe"), "équipe");
+    }
+}
@@ -0,0 +1,36 @@
+use log::warn;
+use rootasrole_core::database::structs::{SCredentials, STask};
+use serde_json::Value;
+
+use crate::policy::{normalize_path, NormalizeOptions, Policy};
+
+/// Mark granted paths of the task as alert on use, warning about those it is not granted
+pub(crate) fn mark(task: &mut STask, policy: &Policy, paths: &[String]) {
+    let options = NormalizeOptions::default();
+    let mut alerted = Vec::new();
+    for path in paths {
+        let path = normalize_path(path, &options);
+        if policy.files.contains_key(&path) {
+            alerted.push(Value::from(path));
+        } else {
+            warn!(
+                "{} is not granted to the task, it cannot alert on its use",
+                path
+            );
+        }
+    }
+    if !alerted.is_empty() {
+        task.cred
+            ._extra_fields
+            .insert("alert_on_use".to_string(), Value::Array(alerted));
+    }
+}
+
+/// Granted paths marked alert on use
+pub(crate) fn alerted(cred: &SCredentials) -> Vec<&str> {
+    cred._extra_fields
+        .get("alert_on_use")
+        .and_then(Value::as_array)
+        .map(|paths| paths.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
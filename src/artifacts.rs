@@ -0,0 +1,120 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{capable::TraceStats, policy::Policy, secrets};
+
+/// What a trace of one command saw, saved to compare runs
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Artifacts {
+    pub(crate) command: Vec<String>,
+    /// Executables run by the traced process tree
+    pub(crate) processes: BTreeSet<String>,
+    pub(crate) stats: TraceStats,
+    pub(crate) policy: Policy,
+}
+
+/// Save the trace of the index-th command, along with the raw output of capable. Secrets
+/// of the command line, environment and raw output are masked
+pub(crate) fn save(
+    dir: &Path,
+    index: usize,
+    artifacts: &Artifacts,
+    raw: Option<&Path>,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut policy = artifacts.policy.clone();
+    policy.env_vars = secrets::redact_env(&policy.env_vars);
+    let saved = Artifacts {
+        command: secrets::redact_args(&artifacts.command),
+        processes: artifacts.processes.clone(),
+        stats: artifacts.stats.clone(),
+        policy,
+    };
+    fs::write(
+        dir.join(format!("trace-{}.json", index)),
+        serde_json::to_string_pretty(&saved)?,
+    )?;
+    if let Some(raw) = raw.filter(|raw| raw.exists()) {
+        fs::write(
+            dir.join(format!("capable-{}.json", index)),
+            secrets::redact(&fs::read_to_string(raw)?),
+        )?;
+    }
+    Ok(())
+}
+
+/// Saved traces of an artifact set, by command line
+fn load(dir: &Path) -> anyhow::Result<BTreeMap<String, Artifacts>> {
+    let mut traces = BTreeMap::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("Cannot read {}", dir.display()))? {
+        let path = entry?.path();
+        let is_trace = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("trace-") && name.ends_with(".json"));
+        if !is_trace {
+            continue;
+        }
+        let artifacts: Artifacts = serde_json::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Invalid trace artifact {}", path.display()))?;
+        traces.insert(artifacts.command.join(" "), artifacts);
+    }
+    Ok(traces)
+}
+
+fn set_diff(a: &BTreeSet<String>, b: &BTreeSet<String>, what: &str) -> Vec<String> {
+    a.difference(b)
+        .map(|item| format!("-{} {}", what, item))
+        .chain(b.difference(a).map(|item| format!("+{} {}", what, item)))
+        .collect()
+}
+
+/// Differences from the trace a to the trace b: `-` only seen in a, `+` only seen in b
+fn diff(a: &Artifacts, b: &Artifacts) -> Vec<String> {
+    let mut lines = set_diff(&a.processes, &b.processes, "process");
+    lines.extend((a.policy.clone() - b.policy.clone()).describe('-'));
+    lines.extend((b.policy.clone() - a.policy.clone()).describe('+'));
+    let env = |artifacts: &Artifacts| -> BTreeSet<String> {
+        artifacts.policy.env_vars.keys().cloned().collect()
+    };
+    lines.extend(set_diff(&env(a), &env(b), "env"));
+    if a.stats.exit_code != b.stats.exit_code {
+        lines.push(format!(
+            "~exit code {:?} -> {:?}",
+            a.stats.exit_code, b.stats.exit_code
+        ));
+    }
+    lines
+}
+
+/// Print what differs between the traces of the same commands in two artifact sets
+pub(crate) fn trace_diff(a: &Path, b: &Path) -> anyhow::Result<()> {
+    let (a, b) = (load(a)?, load(b)?);
+    let mut identical = true;
+    for command in a.keys().chain(b.keys()).collect::<BTreeSet<_>>() {
+        let lines = match (a.get(command), b.get(command)) {
+            (Some(a), Some(b)) => diff(a, b),
+            (Some(_), None) => vec!["-traced only in the first set".to_string()],
+            (None, Some(_)) => vec!["+traced only in the second set".to_string()],
+            (None, None) => continue,
+        };
+        if lines.is_empty() {
+            continue;
+        }
+        identical = false;
+        println!("{}:", command);
+        for line in lines {
+            println!("    {}", line);
+        }
+    }
+    if identical {
+        println!("The traces are identical");
+    }
+    Ok(())
+}
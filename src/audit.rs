@@ -0,0 +1,597 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    rc::Rc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use nix::unistd::{Uid, User};
+use rootasrole_core::database::structs::SConfig;
+use serde::Serialize;
+
+use crate::{
+    auditlog, deploy,
+    doc::Doc,
+    notify,
+    params::glob_match,
+    policy::{normalize_path, NormalizeOptions},
+    state,
+};
+
+const RULES_PREFIX: &str = "gensr-";
+/// Key of the rules watching the paths marked alert on use
+const ALERT_PREFIX: &str = "gensr-alert-";
+/// Key of the rules logging the operations denied to the role users of a shadow deploy
+const SHADOW_PREFIX: &str = "gensr-shadow-";
+/// Key of the rules logging the task binaries run as root through the legacy path, such as
+/// sudo, while their task is shadow deployed
+const LEGACY_PREFIX: &str = "gensr-legacy-";
+
+/// Writes auditd rules watching the privileges granted to generated role users
+pub(crate) struct AuditRulesWriter {
+    rules_folder: PathBuf,
+}
+
+impl AuditRulesWriter {
+    pub(crate) fn new() -> Self {
+        let rules_folder = env::var("AUDIT_RULES_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("/etc/audit/rules.d"));
+        AuditRulesWriter { rules_folder }
+    }
+
+    fn rules_file_path(&self, username: &str) -> PathBuf {
+        self.rules_folder
+            .join(format!("{}{}.rules", RULES_PREFIX, username))
+    }
+
+    pub(crate) fn key(role: &str) -> String {
+        format!("{}{}", RULES_PREFIX, role)
+    }
+
+    fn alert_key(role: &str) -> String {
+        format!("{}{}", ALERT_PREFIX, role)
+    }
+
+    fn shadow_key(role: &str) -> String {
+        format!("{}{}", SHADOW_PREFIX, role)
+    }
+
+    fn legacy_key(role: &str) -> String {
+        format!("{}{}", LEGACY_PREFIX, role)
+    }
+
+    /// Watch the grants of the role user. In shadow mode, also log what it is denied, and
+    /// the executions of the task binaries as root with the files they open, which is
+    /// how the task still runs until enforced
+    pub(crate) fn add_rules(
+        &self,
+        role: &str,
+        username: &str,
+        uid: &Uid,
+        files: &[(&str, &str)],
+        alerts: &[&str],
+        shadow: Option<&[PathBuf]>,
+    ) -> io::Result<()> {
+        let key = Self::key(role);
+        let path = self.rules_file_path(username);
+        debug!("Writing audit rules for user {} at {:?}", username, path);
+        fs::create_dir_all(&self.rules_folder)?;
+        let mut writer = File::create(path)?;
+        writeln!(writer, "# Generated by gensr for role {}", role)?;
+        for (file, permission) in files {
+            writeln!(
+                writer,
+                "-w {} -p {} -k {}",
+                file,
+                access_to_audit_perms(permission),
+                key
+            )?;
+        }
+        for arch in ["b64", "b32"] {
+            writeln!(
+                writer,
+                "-a always,exit -F arch={} -S execve -F euid={} -k {}",
+                arch,
+                uid.as_raw(),
+                key
+            )?;
+        }
+        // Only the role user exercising an alerted grant is reported, not its owner
+        for (file, permission) in files.iter().filter(|(file, _)| alerts.contains(file)) {
+            let field = if Path::new(file).is_dir() {
+                "dir"
+            } else {
+                "path"
+            };
+            for arch in ["b64", "b32"] {
+                writeln!(
+                    writer,
+                    "-a always,exit -F arch={} -F {}={} -F perm={} -F euid={} -k {}",
+                    arch,
+                    field,
+                    file,
+                    access_to_audit_perms(permission),
+                    uid.as_raw(),
+                    Self::alert_key(role)
+                )?;
+            }
+        }
+        // What the policy does not grant fails with EACCES or EPERM, every syscall is watched
+        for exit in ["-EACCES", "-EPERM"].iter().filter(|_| shadow.is_some()) {
+            for arch in ["b64", "b32"] {
+                writeln!(
+                    writer,
+                    "-a always,exit -F arch={} -F exit={} -F euid={} -k {}",
+                    arch,
+                    exit,
+                    uid.as_raw(),
+                    Self::shadow_key(role)
+                )?;
+            }
+        }
+        for binary in shadow.unwrap_or_default() {
+            for arch in ["b64", "b32"] {
+                writeln!(
+                    writer,
+                    "-a always,exit -F arch={} -S execve -F path={} -F euid=0 -k {}",
+                    arch,
+                    binary.display(),
+                    Self::legacy_key(role)
+                )?;
+                writeln!(
+                    writer,
+                    "-a always,exit -F arch={} -S openat -F exe={} -F euid=0 -k {}",
+                    arch,
+                    binary.display(),
+                    Self::legacy_key(role)
+                )?;
+            }
+        }
+        writer.flush()?;
+        auditlog::record(
+            "audit_rules_write",
+            &self.rules_file_path(username).to_string_lossy(),
+            &format!("{} watched path(s) for role {}", files.len(), role),
+        );
+        Ok(())
+    }
+
+    pub(crate) fn remove_rules(&self, username: &str) -> io::Result<bool> {
+        let path = self.rules_file_path(username);
+        if path.exists() {
+            fs::remove_file(&path)?;
+            auditlog::record(
+                "audit_rules_remove",
+                &path.to_string_lossy(),
+                &format!("removed audit rules of {}", username),
+            );
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// List the audit keys installed by gensr, the alert and shadow ones apart
+    pub(crate) fn keys(&self) -> io::Result<Vec<String>> {
+        Ok(self
+            .all_keys()?
+            .into_iter()
+            .filter(|key| {
+                !key.starts_with(ALERT_PREFIX)
+                    && !key.starts_with(SHADOW_PREFIX)
+                    && !key.starts_with(LEGACY_PREFIX)
+            })
+            .collect())
+    }
+
+    fn alert_keys(&self) -> io::Result<Vec<String>> {
+        Ok(self
+            .all_keys()?
+            .into_iter()
+            .filter(|key| key.starts_with(ALERT_PREFIX))
+            .collect())
+    }
+
+    fn all_keys(&self) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        if !self.rules_folder.is_dir() {
+            return Ok(keys);
+        }
+        for entry in fs::read_dir(&self.rules_folder)? {
+            let path = entry?.path();
+            let is_gensr = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(RULES_PREFIX));
+            if !is_gensr {
+                continue;
+            }
+            for line in fs::read_to_string(&path)?.lines() {
+                if let Some((_, key)) = line.rsplit_once("-k ") {
+                    let key = key.trim().to_string();
+                    if !keys.contains(&key) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn reload() -> io::Result<()> {
+        match Command::new("augenrules").arg("--load").status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("augenrules exited with {}", status),
+            )),
+            Err(e) => {
+                warn!("Unable to reload audit rules: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn access_to_audit_perms(permission: &str) -> String {
+    let mut perms = String::new();
+    for c in permission.chars() {
+        let watched = match c {
+            'r' | 'R' => "r",
+            'w' | 'W' | 'c' | 'C' | 'd' | 'D' | 'n' | 'N' => "wa",
+            'x' | 'X' => "x",
+            'a' | 'A' => "a",
+            _ => "",
+        };
+        for perm in watched.chars() {
+            if !perms.contains(perm) {
+                perms.push(perm);
+            }
+        }
+    }
+    if perms.is_empty() {
+        perms.push('a');
+    }
+    perms
+}
+
+#[derive(Default)]
+struct RoleActivity {
+    events: usize,
+    executions: BTreeMap<String, usize>,
+    paths: BTreeMap<String, usize>,
+    /// Epoch seconds of the latest record
+    latest: u64,
+}
+
+impl RoleActivity {
+    fn collect(key: &str, since: Option<&str>, after: Option<u64>) -> io::Result<Self> {
+        let raw = search_key(key, since)?;
+        let mut activity = RoleActivity::default();
+        for record in raw.lines() {
+            activity.add(record, after);
+        }
+        Ok(activity)
+    }
+
+    /// The activity of each user matching the key, by euid. The PATH records of an event
+    /// carry no uid, they are attributed through the SYSCALL record of the same event
+    fn collect_by_euid(key: &str, after: Option<u64>) -> io::Result<BTreeMap<u32, Self>> {
+        let raw = search_key(key, None)?;
+        let euids: BTreeMap<&str, u32> = raw
+            .lines()
+            .filter(|record| field(record, "type") == Some("SYSCALL"))
+            .filter_map(|record| {
+                Some((field(record, "msg")?, field(record, "euid")?.parse().ok()?))
+            })
+            .collect();
+        let mut activities: BTreeMap<u32, Self> = BTreeMap::new();
+        for record in raw.lines() {
+            if let Some(euid) = field(record, "msg").and_then(|msg| euids.get(msg)) {
+                activities.entry(*euid).or_default().add(record, after);
+            }
+        }
+        Ok(activities)
+    }
+
+    fn add(&mut self, record: &str, after: Option<u64>) {
+        if let Some(timestamp) = timestamp(record) {
+            if after.is_some_and(|after| timestamp < after) {
+                return;
+            }
+            self.latest = self.latest.max(timestamp);
+        }
+        match field(record, "type") {
+            Some("SYSCALL") => {
+                self.events += 1;
+                if let Some(exe) = field(record, "exe") {
+                    *self.executions.entry(exe.to_string()).or_default() += 1;
+                }
+            }
+            Some("PATH") => {
+                if let Some(name) = field(record, "name") {
+                    *self.paths.entry(name.to_string()).or_default() += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn used_path(&self, granted: &str) -> bool {
+        let options = NormalizeOptions::default();
+        let granted = normalize_path(granted, &options);
+        self.paths
+            .keys()
+            .chain(self.executions.keys())
+            .map(|path| normalize_path(path, &options))
+            .any(|path| {
+                Path::new(&path).starts_with(&granted)
+                    || glob_match(granted.as_bytes(), path.as_bytes())
+            })
+    }
+}
+
+fn field<'a>(record: &'a str, name: &str) -> Option<&'a str> {
+    record
+        .split_whitespace()
+        .find_map(|token| token.strip_prefix(name)?.strip_prefix('='))
+        .map(|value| value.trim_matches('"'))
+}
+
+/// Extract the epoch seconds of a raw record, e.g. `msg=audit(1700000000.123:42):`
+fn timestamp(record: &str) -> Option<u64> {
+    field(record, "msg")?
+        .strip_prefix("audit(")?
+        .split('.')
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn search_key(key: &str, since: Option<&str>) -> io::Result<String> {
+    let mut command = Command::new("ausearch");
+    command.arg("--raw").arg("-k").arg(key);
+    if let Some(since) = since {
+        command.arg("--start").arg(since);
+    }
+    let output = command.output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Summarize the activity recorded by auditd for every gensr role
+pub(crate) fn audit_report(role: Option<&str>, since: Option<&str>) -> anyhow::Result<()> {
+    let writer = AuditRulesWriter::new();
+    let keys: Vec<String> = match role {
+        Some(role) => vec![AuditRulesWriter::key(role)],
+        None => writer.keys()?,
+    };
+    if keys.is_empty() {
+        println!("No gensr audit rules are installed");
+        return Ok(());
+    }
+    let shadowed = writer.all_keys()?;
+    for key in &keys {
+        let role = key.trim_start_matches(RULES_PREFIX);
+        let activity = RoleActivity::collect(key, since, None)?;
+        println!("Role {}: {} event(s)", role, activity.events);
+        for (exe, count) in &activity.executions {
+            println!("    exec  {:>6}  {}", count, exe);
+        }
+        for (path, count) in &activity.paths {
+            println!("    path  {:>6}  {}", count, path);
+        }
+        let shadow_key = AuditRulesWriter::shadow_key(role);
+        if !shadowed.contains(&shadow_key) {
+            continue;
+        }
+        // Operations a shadow deployed role was denied, that enforcing it would break
+        let violations = RoleActivity::collect(&shadow_key, since, None)?;
+        println!(
+            "    {} violation(s) of the shadow policy",
+            violations.events
+        );
+        for (exe, count) in &violations.executions {
+            println!("    denied exec  {:>6}  {}", count, exe);
+        }
+        for (path, count) in &violations.paths {
+            println!("    denied path  {:>6}  {}", count, path);
+        }
+        // The task binaries still run as root until the role is enforced
+        let legacy = RoleActivity::collect(&AuditRulesWriter::legacy_key(role), since, None)?;
+        println!("    {} event(s) of the legacy path as root", legacy.events);
+        for (exe, count) in &legacy.executions {
+            println!("    legacy exec  {:>6}  {}", count, exe);
+        }
+        for (path, count) in &legacy.paths {
+            println!("    legacy path  {:>6}  {}", count, path);
+        }
+    }
+    Ok(())
+}
+
+fn alerts_checked_path() -> PathBuf {
+    state::state_dir().join("alerts-checked")
+}
+
+/// Report the uses of the grants marked alert on use since the last check, notifying the
+/// webhooks of a `grant_used` event for each role
+pub(crate) fn alert_check(since: Option<&str>) -> anyhow::Result<()> {
+    let keys = AuditRulesWriter::new().alert_keys()?;
+    if keys.is_empty() {
+        println!("No grant is marked alert on use");
+        return Ok(());
+    }
+    let checked: Option<u64> = fs::read_to_string(alerts_checked_path())
+        .ok()
+        .and_then(|checked| checked.trim().parse().ok());
+    let after = since.is_none().then_some(checked).flatten().map(|t| t + 1);
+    let mut latest = checked.unwrap_or_default();
+    for key in &keys {
+        let activity = RoleActivity::collect(key, since, after)?;
+        latest = latest.max(activity.latest);
+        if activity.events == 0 {
+            continue;
+        }
+        let role = key.trim_start_matches(ALERT_PREFIX);
+        let summary = format!(
+            "alerted grant(s) used {} time(s): {}",
+            activity.events,
+            activity
+                .paths
+                .keys()
+                .chain(activity.executions.keys())
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        println!("Role {}: {}", role, summary);
+        auditlog::record("grant_used", role, &summary);
+        notify::notify("grant_used", role, &summary, 100);
+    }
+    fs::create_dir_all(state::state_dir())?;
+    fs::write(alerts_checked_path(), latest.to_string())?;
+    Ok(())
+}
+
+/// Parse a duration such as `30d`, `12h`, `45m` or `2w`
+pub(crate) fn parse_duration(duration: &str) -> anyhow::Result<Duration> {
+    let duration = duration.trim();
+    let (value, unit) = duration.split_at(
+        duration
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(duration.len()),
+    );
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid duration: {}", duration))?;
+    let seconds = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 604800,
+        _ => return Err(anyhow::anyhow!("Invalid duration unit: {}", unit)),
+    };
+    value
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow::anyhow!("Duration too large: {}", duration))
+}
+
+/// Privileges granted to a task that were never exercised during the period
+#[derive(Serialize, Default)]
+pub(crate) struct UnusedGrants {
+    /// Justification of the task, to weigh the unused grants against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) doc: Option<Doc>,
+    pub(crate) files: Vec<String>,
+    /// auditd does not record capability checks, these are only listed for review
+    pub(crate) capabilities: Vec<String>,
+}
+
+/// Correlate the recorded activity of each role with its granted privileges
+pub(crate) fn usage_report(
+    config: &Rc<RefCell<SConfig>>,
+    role: Option<&str>,
+    since: &str,
+    json: bool,
+) -> anyhow::Result<()> {
+    let after = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .saturating_sub(parse_duration(since)?)
+        .as_secs();
+    let mut report: BTreeMap<String, BTreeMap<String, UnusedGrants>> = BTreeMap::new();
+    for r in &config.as_ref().borrow().roles {
+        let r = r.as_ref().borrow();
+        if role.is_some_and(|role| role != r.name) {
+            continue;
+        }
+        let activities =
+            RoleActivity::collect_by_euid(&AuditRulesWriter::key(&r.name), Some(after))?;
+        let unexercised = RoleActivity::default();
+        let tasks = report.entry(r.name.clone()).or_default();
+        for task in &r.tasks {
+            let task = task.as_ref().borrow();
+            // Each task runs as its own user, the grants of a task are only used by it
+            let activity = User::from_name(&deploy::task_username(&r.name, &task))?
+                .and_then(|user| activities.get(&user.uid.as_raw()))
+                .unwrap_or(&unexercised);
+            let mut unused = UnusedGrants {
+                doc: Doc::of(&task),
+                ..Default::default()
+            };
+            if let Some(files) = task
+                .cred
+                ._extra_fields
+                .get("files")
+                .and_then(|value| value.as_object())
+            {
+                unused.files = files
+                    .keys()
+                    .filter(|path| !activity.used_path(path))
+                    .cloned()
+                    .collect();
+            }
+            if let Some(caps) = &task.cred.capabilities {
+                unused.capabilities = caps.add.iter().map(|c| c.to_string()).collect();
+            }
+            tasks.insert(task.name.to_string(), unused);
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    for (role, tasks) in &report {
+        for (task, unused) in tasks {
+            match &unused.doc {
+                Some(doc) => println!("Role {} task {} ({}):", role, task, doc),
+                None => println!("Role {} task {}:", role, task),
+            }
+            for file in &unused.files {
+                println!("    unused file        {}", file);
+            }
+            for cap in &unused.capabilities {
+                println!("    unverified cap     {}", cap);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert_eq!(
+            parse_duration("12h").unwrap(),
+            Duration::from_secs(12 * 3600)
+        );
+        assert_eq!(
+            parse_duration(" 30d ").unwrap(),
+            Duration::from_secs(30 * 86400)
+        );
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(2 * 604800)
+        );
+    }
+
+    #[test]
+    fn parse_duration_invalid() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration(&format!("{}w", u64::MAX)).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use nix::unistd::{getuid, Uid, User};
+use serde_json::{json, Value};
+
+use crate::secrets;
+
+fn log_file_path() -> PathBuf {
+    env::var("GENSR_AUDIT_LOG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/var/log/gensr/audit.log"))
+}
+
+/// Uid of the human behind the change: the login uid survives sudo and sr, unlike the
+/// real uid
+pub(crate) fn operator_uid() -> Uid {
+    fs::read_to_string("/proc/self/loginuid")
+        .ok()
+        .and_then(|uid| uid.trim().parse::<u32>().ok())
+        .filter(|uid| *uid != u32::MAX)
+        .map(Uid::from_raw)
+        .unwrap_or_else(getuid)
+}
+
+/// Name of the human behind the change
+pub(crate) fn operator() -> String {
+    let uid = operator_uid();
+    match User::from_uid(uid) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    }
+}
+
+fn append(action: &str, target: &str, summary: &str, details: Option<Value>) -> io::Result<()> {
+    let path = log_file_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut entry = json!({
+        "timestamp": timestamp,
+        "operator": operator(),
+        "action": action,
+        "target": target,
+        "summary": secrets::redact(summary),
+    });
+    if let Some(details) = details {
+        entry["details"] = secrets::redact_value(&details);
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", entry)?;
+    Ok(())
+}
+
+/// Append a system change made by gensr to the audit log
+pub(crate) fn record(action: &str, target: &str, summary: &str) {
+    if let Err(e) = append(action, target, summary, None) {
+        warn!(
+            "Failed to write audit log entry for {} {}: {}",
+            action, target, e
+        );
+    }
+}
+
+/// Append an entry carrying structured details, read back by the reports
+pub(crate) fn record_details(action: &str, target: &str, summary: &str, details: Value) {
+    if let Err(e) = append(action, target, summary, Some(details)) {
+        warn!(
+            "Failed to write audit log entry for {} {}: {}",
+            action, target, e
+        );
+    }
+}
+
+/// Entries of the audit log, skipping the unreadable lines
+pub(crate) fn entries() -> io::Result<Vec<Value>> {
+    let content = match fs::read_to_string(log_file_path()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
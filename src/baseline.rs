@@ -0,0 +1,254 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    rc::Rc,
+};
+
+use anyhow::{bail, Context};
+use rootasrole_core::database::structs::{SConfig, STask};
+use serde_json::Value;
+
+use crate::{auditlog, library::LIBRARY_ROLE};
+
+/// Approved tasks, keyed by `role/task`
+pub(crate) type Baseline = BTreeMap<String, Value>;
+
+/// File holding the baseline, at the root of the git repository or served by the endpoint
+const BUNDLE: &str = "baseline.json";
+
+/// Where approved baselines are shared
+pub(crate) enum Remote {
+    Git(String),
+    /// Endpoint answering GET and PUT with the whole baseline
+    Http(String),
+}
+
+impl Remote {
+    pub(crate) fn parse(remote: &str) -> Self {
+        if (remote.starts_with("http://") || remote.starts_with("https://"))
+            && !remote.ends_with(".git")
+        {
+            Remote::Http(remote.to_string())
+        } else {
+            Remote::Git(remote.to_string())
+        }
+    }
+}
+
+/// Local copy of the last pulled baseline
+fn cache_dir() -> PathBuf {
+    env::var("GENSR_BASELINE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/var/lib/gensr/baseline"))
+}
+
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Cannot run {}", program))?;
+    if !status.success() {
+        bail!("{} {} exited with {}", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+    let dir = dir.to_string_lossy();
+    run("git", &[&["-C", &dir], args].concat())
+}
+
+pub(crate) fn key(role: &str, task: &STask) -> String {
+    format!("{}/{}", role, task.name)
+}
+
+/// The tasks of the configuration, as they would be compared with the baseline
+pub(crate) fn snapshot(config: &Rc<RefCell<SConfig>>) -> anyhow::Result<Baseline> {
+    let mut baseline = Baseline::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        if role.name == LIBRARY_ROLE {
+            continue;
+        }
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            baseline.insert(key(&role.name, &task), serde_json::to_value(&*task)?);
+        }
+    }
+    Ok(baseline)
+}
+
+/// Fetch the approved baseline into the local copy, returning its number of tasks
+pub(crate) fn pull(remote: &Remote) -> anyhow::Result<usize> {
+    let dir = cache_dir();
+    match remote {
+        Remote::Git(url) if dir.join(".git").exists() => {
+            git(&dir, &["remote", "set-url", "origin", url])?;
+            git(&dir, &["pull", "--ff-only", "--quiet"])?;
+        }
+        Remote::Git(url) => {
+            fs::create_dir_all(dir.parent().unwrap_or(Path::new("/")))?;
+            run("git", &["clone", "--quiet", url, &dir.to_string_lossy()])?;
+        }
+        Remote::Http(url) => {
+            fs::create_dir_all(&dir)?;
+            let bundle = dir.join(BUNDLE);
+            run(
+                "curl",
+                &["-sSf", "-m", "30", "-o", &bundle.to_string_lossy(), url],
+            )?;
+        }
+    }
+    Ok(load()?.len())
+}
+
+/// Publish the tasks of the configuration as the approved baseline
+pub(crate) fn push(config: &Rc<RefCell<SConfig>>, remote: &Remote) -> anyhow::Result<usize> {
+    let baseline = snapshot(config)?;
+    let dir = cache_dir();
+    if let Remote::Git(_) = remote {
+        pull(remote)?;
+    }
+    fs::create_dir_all(&dir)?;
+    let bundle = dir.join(BUNDLE);
+    fs::write(&bundle, serde_json::to_string_pretty(&baseline)?)?;
+    match remote {
+        Remote::Git(_) => {
+            git(&dir, &["add", BUNDLE])?;
+            // Nothing to commit when the baseline is unchanged
+            if git(&dir, &["diff", "--cached", "--quiet"]).is_err() {
+                let message = format!("Update baseline by {}", auditlog::operator());
+                git(&dir, &["commit", "--quiet", "-m", &message])?;
+                git(&dir, &["push", "--quiet"])?;
+            }
+        }
+        Remote::Http(url) => run(
+            "curl",
+            &[
+                "-sSf",
+                "-m",
+                "30",
+                "-X",
+                "PUT",
+                "-H",
+                "Content-Type: application/json",
+                "--data-binary",
+                &format!("@{}", bundle.display()),
+                url,
+            ],
+        )?,
+    }
+    auditlog::record(
+        "baseline_push",
+        &bundle.to_string_lossy(),
+        &format!("pushed {} task(s)", baseline.len()),
+    );
+    Ok(baseline.len())
+}
+
+/// The last pulled baseline
+pub(crate) fn load() -> anyhow::Result<Baseline> {
+    let bundle = cache_dir().join(BUNDLE);
+    let content = fs::read_to_string(&bundle).with_context(|| {
+        format!(
+            "No baseline in {}, run `gensr baseline pull` first",
+            bundle.display()
+        )
+    })?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Files, capabilities and D-Bus grants of a task
+fn grants(task: &Value) -> BTreeSet<String> {
+    let mut grants = BTreeSet::new();
+    if let Some(files) = task.pointer("/cred/files").and_then(Value::as_object) {
+        grants.extend(
+            files
+                .iter()
+                .map(|(path, access)| format!("file:{}:{}", path, access)),
+        );
+    }
+    let capabilities = match task.pointer("/cred/capabilities") {
+        Some(Value::Array(capabilities)) => Some(capabilities),
+        Some(capabilities) => capabilities.get("add").and_then(Value::as_array),
+        None => None,
+    };
+    grants.extend(
+        capabilities
+            .into_iter()
+            .flatten()
+            .map(|cap| format!("cap:{}", cap)),
+    );
+    if let Some(dbus) = task.pointer("/cred/dbus").and_then(Value::as_array) {
+        grants.extend(dbus.iter().map(|rule| format!("dbus:{}", rule)));
+    }
+    grants
+}
+
+/// A task differing from its approved baseline
+pub(crate) struct Divergence {
+    pub(crate) task: String,
+    /// Share of the grants present on one side only, from 0 to 1
+    pub(crate) score: f64,
+    pub(crate) approved: bool,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.approved {
+            write!(
+                f,
+                "{}: {:.0}% of the grants differ from the baseline",
+                self.task,
+                self.score * 100.0
+            )
+        } else {
+            write!(f, "{}: not in the baseline", self.task)
+        }
+    }
+}
+
+/// Compare local tasks with their approved baseline, ignoring the identical ones
+pub(crate) fn diff(baseline: &Baseline, local: &Baseline) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    for (task, value) in local {
+        let Some(approved) = baseline.get(task) else {
+            divergences.push(Divergence {
+                task: task.clone(),
+                score: 1.0,
+                approved: false,
+            });
+            continue;
+        };
+        let (local, approved) = (grants(value), grants(approved));
+        let differing = local.symmetric_difference(&approved).count();
+        if differing > 0 {
+            divergences.push(Divergence {
+                task: task.clone(),
+                score: differing as f64 / local.union(&approved).count() as f64,
+                approved: true,
+            });
+        }
+    }
+    divergences
+}
+
+/// Fail when a local task diverges from the pulled baseline beyond the threshold
+pub(crate) fn check(local: &Baseline, threshold: f64) -> anyhow::Result<()> {
+    let beyond: Vec<String> = diff(&load()?, local)
+        .into_iter()
+        .filter(|divergence| divergence.score > threshold)
+        .map(|divergence| divergence.to_string())
+        .collect();
+    if !beyond.is_empty() {
+        bail!(
+            "Diverging from the approved baseline beyond {:.0}%:\n{}",
+            threshold * 100.0,
+            beyond.join("\n")
+        );
+    }
+    Ok(())
+}
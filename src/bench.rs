@@ -0,0 +1,133 @@
+use std::{
+    cell::RefCell,
+    process::{Command, Stdio},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use log::{debug, warn};
+use nix::sys::resource::{getrusage, UsageWho};
+use rootasrole_core::database::structs::SConfig;
+
+/// Wall-clock and CPU time spent by a set of runs
+#[derive(Default)]
+struct Measure {
+    wall: Duration,
+    cpu: Duration,
+    failures: usize,
+}
+
+impl Measure {
+    fn per_run(&self, runs: usize) -> (f64, f64) {
+        (
+            self.wall.as_secs_f64() / runs as f64,
+            self.cpu.as_secs_f64() / runs as f64,
+        )
+    }
+}
+
+fn children_cpu_time() -> anyhow::Result<Duration> {
+    let usage = getrusage(UsageWho::RUSAGE_CHILDREN)?;
+    let user = usage.user_time();
+    let system = usage.system_time();
+    Ok(
+        Duration::from_secs((user.tv_sec() + system.tv_sec()) as u64)
+            + Duration::from_micros((user.tv_usec() + system.tv_usec()) as u64),
+    )
+}
+
+fn measure(argv: &[String], runs: usize) -> anyhow::Result<Measure> {
+    let mut measure = Measure::default();
+    for _ in 0..runs {
+        let cpu_before = children_cpu_time()?;
+        let start = Instant::now();
+        let status = Command::new(&argv[0])
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        measure.wall += start.elapsed();
+        measure.cpu += children_cpu_time()?.saturating_sub(cpu_before);
+        if !status.success() {
+            debug!("{:?} exited with {}", argv, status);
+            measure.failures += 1;
+        }
+    }
+    Ok(measure)
+}
+
+/// Name of the role holding the given task
+fn find_role(config: &Rc<RefCell<SConfig>>, task: &str) -> anyhow::Result<String> {
+    config
+        .as_ref()
+        .borrow()
+        .roles
+        .iter()
+        .find(|role| {
+            role.as_ref()
+                .borrow()
+                .tasks
+                .iter()
+                .any(|t| t.as_ref().borrow().name.to_string() == task)
+        })
+        .map(|role| role.as_ref().borrow().name.clone())
+        .ok_or_else(|| anyhow!("Task {} not found in the configuration", task))
+}
+
+/// Compare running the command through its deployed role with running it directly as root
+pub(crate) fn bench(
+    config: &Rc<RefCell<SConfig>>,
+    task: &str,
+    command: &[String],
+    runs: usize,
+) -> anyhow::Result<()> {
+    if runs == 0 {
+        bail!("At least one run is required");
+    }
+    let role = find_role(config, task)?;
+    let sr = which::which("sr").map_err(|_| anyhow!("sr is not installed"))?;
+    let mut with_role = vec![
+        sr.to_string_lossy().to_string(),
+        "-r".to_string(),
+        role.clone(),
+        "-t".to_string(),
+        task.to_string(),
+    ];
+    with_role.extend_from_slice(command);
+    // Warm the caches so the first measured set does not pay for them
+    measure(command, 1)?;
+    let direct = measure(command, runs)?;
+    let confined = measure(&with_role, runs)?;
+    if confined.failures > 0 {
+        warn!(
+            "{} of {} runs through role {} failed, is the policy deployed?",
+            confined.failures, runs, role
+        );
+    }
+    if direct.failures > 0 {
+        warn!("{} of {} direct runs failed", direct.failures, runs);
+    }
+    let (direct_wall, direct_cpu) = direct.per_run(runs);
+    let (role_wall, role_cpu) = confined.per_run(runs);
+    println!("Task {} of role {}, {} runs:", task, role, runs);
+    println!("                 wall (s)    cpu (s)");
+    println!(
+        "    as root      {:>10.4} {:>10.4}",
+        direct_wall, direct_cpu
+    );
+    println!("    with role    {:>10.4} {:>10.4}", role_wall, role_cpu);
+    println!(
+        "    overhead     {:>10.4} {:>10.4}",
+        role_wall - direct_wall,
+        role_cpu - direct_cpu
+    );
+    if direct_wall > 0.0 {
+        println!(
+            "    relative     {:>9.1}%",
+            (role_wall - direct_wall) / direct_wall * 100.0
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use log::warn;
+use nix::unistd::{Group, User};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{auditlog, deploy, state::DeployState};
+
+/// Version of the bundle format, bumped on incompatible changes
+const FORMAT: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct BundleUser {
+    name: String,
+    uid: u32,
+    group: String,
+    gid: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundleGroup {
+    name: String,
+    gid: u32,
+    members: Vec<String>,
+}
+
+/// Everything a deploy set up on a host, to rebuild it on a replacement host
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    format: u32,
+    config_path: String,
+    config: Value,
+    state: DeployState,
+    users: Vec<BundleUser>,
+    groups: Vec<BundleGroup>,
+    /// Bits of the ACL entries of the task users and role groups, by path and entry
+    acl_entries: BTreeMap<String, BTreeMap<String, u32>>,
+    /// Contents of the dbus and polkit policy files, by path
+    files: BTreeMap<String, String>,
+}
+
+/// Write the deployed state of this host into a bundle
+pub(crate) fn export(config_path: &str, output: &Path) -> anyhow::Result<()> {
+    let state = DeployState::load()?;
+    let config = serde_json::from_str(
+        &fs::read_to_string(config_path)
+            .with_context(|| format!("Cannot read the configuration {}", config_path))?,
+    )?;
+    let mut users = Vec::new();
+    let usernames: BTreeSet<&String> = state.tasks.keys().chain(state.ids.keys()).collect();
+    for name in usernames {
+        let Some(user) = User::from_name(name)? else {
+            warn!("Task user {} no longer exists, not exported", name);
+            continue;
+        };
+        let group = Group::from_gid(user.gid)?
+            .map(|group| group.name)
+            .unwrap_or_else(|| name.clone());
+        users.push(BundleUser {
+            name: name.clone(),
+            uid: user.uid.as_raw(),
+            group,
+            gid: user.gid.as_raw(),
+        });
+    }
+    let mut groups = Vec::new();
+    for name in &state.groups {
+        if let Some(group) = Group::from_name(name)? {
+            groups.push(BundleGroup {
+                name: name.clone(),
+                gid: group.gid.as_raw(),
+                members: group.mem,
+            });
+        }
+    }
+    let mut acl_entries: BTreeMap<String, BTreeMap<String, u32>> = BTreeMap::new();
+    for (path, entries) in &state.acl_holders {
+        for entry in entries.keys() {
+            match deploy::acl_entry(path, entry) {
+                Ok(Some(bits)) => {
+                    acl_entries
+                        .entry(path.clone())
+                        .or_default()
+                        .insert(entry.clone(), bits);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Cannot read the ACL of {}: {}", path, e),
+            }
+        }
+    }
+    let mut files = BTreeMap::new();
+    for path in deploy::policy_files()? {
+        files.insert(
+            path.to_string_lossy().to_string(),
+            fs::read_to_string(&path)?,
+        );
+    }
+    let bundle = Bundle {
+        format: FORMAT,
+        config_path: config_path.to_string(),
+        config,
+        state,
+        users,
+        groups,
+        acl_entries,
+        files,
+    };
+    fs::write(output, serde_json::to_string_pretty(&bundle)?)?;
+    auditlog::record(
+        "state_export",
+        &output.to_string_lossy(),
+        &format!(
+            "{} user(s), {} group(s), {} path(s)",
+            bundle.users.len(),
+            bundle.groups.len(),
+            bundle.acl_entries.len()
+        ),
+    );
+    Ok(())
+}
+
+/// The ACL entry (`user:<uid>` or `group:<gid>`) of the source host as it is on this
+/// host, None when it belongs to no user or group of the bundle
+fn remap_entry(
+    entry: &str,
+    uids: &BTreeMap<u32, u32>,
+    gids: &BTreeMap<u32, u32>,
+) -> Option<String> {
+    let (kind, id) = entry.split_once(':')?;
+    let id: u32 = id.parse().ok()?;
+    let ids = match kind {
+        "user" => uids,
+        "group" => gids,
+        _ => return None,
+    };
+    ids.get(&id).map(|id| format!("{}:{}", kind, id))
+}
+
+/// ACL entries keyed by their source host entry, keyed by the entry of this host instead.
+/// Entries of no user or group of the bundle are dropped, they were not restored
+fn remap_entries<T>(
+    entries: BTreeMap<String, BTreeMap<String, T>>,
+    uids: &BTreeMap<u32, u32>,
+    gids: &BTreeMap<u32, u32>,
+) -> BTreeMap<String, BTreeMap<String, T>> {
+    entries
+        .into_iter()
+        .map(|(path, entries)| {
+            let entries: BTreeMap<String, T> = entries
+                .into_iter()
+                .filter_map(|(entry, value)| Some((remap_entry(&entry, uids, gids)?, value)))
+                .collect();
+            (path, entries)
+        })
+        .filter(|(_, entries)| !entries.is_empty())
+        .collect()
+}
+
+/// Rebuild the deployed state recorded in a bundle on this host, writing its
+/// configuration to `config_path`
+pub(crate) fn import(input: &Path, config_path: &str, force: bool) -> anyhow::Result<()> {
+    let bundle: Bundle = serde_json::from_str(
+        &fs::read_to_string(input)
+            .with_context(|| format!("Cannot read the bundle {}", input.display()))?,
+    )?;
+    if bundle.format != FORMAT {
+        bail!(
+            "Bundle format {} is not supported, expected {}",
+            bundle.format,
+            FORMAT
+        );
+    }
+    if !force && !DeployState::load()?.tasks.is_empty() {
+        bail!("Tasks are already deployed on this host, use --force to replace its state");
+    }
+    // The bundle only carries policy files, anything else would let it write anywhere
+    if let Some(path) = bundle
+        .files
+        .keys()
+        .find(|path| !deploy::is_policy_file(Path::new(path)))
+    {
+        bail!(
+            "The bundle holds {}, which is not a dbus or polkit policy file of this host",
+            path
+        );
+    }
+    if bundle.config_path != config_path {
+        warn!(
+            "The bundle configuration was at {}, it is written to {}",
+            bundle.config_path, config_path
+        );
+    }
+    // Ids of the source host, by the ids the same users and groups have on this host
+    let mut uids = BTreeMap::new();
+    let mut gids = BTreeMap::new();
+    let mut state = bundle.state;
+    for user in &bundle.users {
+        let restored = deploy::restore_user(&user.name, user.uid, &user.group, user.gid)?;
+        uids.insert(user.uid, restored.uid.as_raw());
+        gids.insert(user.gid, restored.gid.as_raw());
+        if let Some(id) = state.ids.get_mut(&user.name) {
+            *id = restored.uid.as_raw();
+        }
+    }
+    for group in &bundle.groups {
+        let restored = deploy::restore_group(&group.name, group.gid, &group.members)?;
+        gids.insert(group.gid, restored.gid.as_raw());
+    }
+    for (path, contents) in &bundle.files {
+        if let Some(parent) = Path::new(path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+    }
+    fs::write(config_path, serde_json::to_string_pretty(&bundle.config)?)?;
+    for (path, entries) in &bundle.acl_entries {
+        for (entry, bits) in entries {
+            let Some(local) = remap_entry(entry, &uids, &gids) else {
+                warn!(
+                    "The ACL entry {} of {} belongs to no user or group of the bundle, not restored",
+                    entry, path
+                );
+                continue;
+            };
+            if let Err(e) = deploy::restore_acl(path, &local, *bits) {
+                warn!("Cannot restore the ACL of {}: {}", path, e);
+            }
+        }
+    }
+    // The state refers to the users and groups by their ids, which may differ on this host
+    state.acls = remap_entries(state.acls, &uids, &gids);
+    state.acl_holders = remap_entries(state.acl_holders, &uids, &gids);
+    state.save()?;
+    deploy::reload_dbus_policies()?;
+    auditlog::record(
+        "state_import",
+        &input.to_string_lossy(),
+        &format!(
+            "{} user(s), {} group(s), {} path(s)",
+            bundle.users.len(),
+            bundle.groups.len(),
+            bundle.acl_entries.len()
+        ),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remap_entry_to_local_ids() {
+        let uids = BTreeMap::from([(1001, 2001)]);
+        let gids = BTreeMap::from([(1001, 3001)]);
+        assert_eq!(
+            remap_entry("user:1001", &uids, &gids).as_deref(),
+            Some("user:2001")
+        );
+        assert_eq!(
+            remap_entry("group:1001", &uids, &gids).as_deref(),
+            Some("group:3001")
+        );
+        assert_eq!(remap_entry("user:1002", &uids, &gids), None);
+        assert_eq!(remap_entry("mask:1001", &uids, &gids), None);
+        assert_eq!(remap_entry("user:root", &uids, &gids), None);
+        assert_eq!(remap_entry("user", &uids, &gids), None);
+    }
+
+    #[test]
+    fn remap_entries_of_the_state() {
+        let uids = BTreeMap::from([(1001, 2001)]);
+        let gids = BTreeMap::from([(1001, 3001)]);
+        let acls = BTreeMap::from([
+            (
+                "/srv/a".to_string(),
+                BTreeMap::from([("user:1001".to_string(), 4), ("group:1001".to_string(), 6)]),
+            ),
+            (
+                "/srv/b".to_string(),
+                BTreeMap::from([("user:1002".to_string(), 4)]),
+            ),
+        ]);
+        assert_eq!(
+            remap_entries(acls, &uids, &gids),
+            BTreeMap::from([(
+                "/srv/a".to_string(),
+                BTreeMap::from([("user:2001".to_string(), 4), ("group:3001".to_string(), 6),]),
+            )])
+        );
+    }
+}
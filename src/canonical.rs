@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, fs};
+
+use log::warn;
+use serde_json::{Map, Value};
+
+use crate::policy::{normalize_path, Access, NormalizeOptions};
+
+/// Roles of the raw configuration, either at the top level or under `config`
+pub(crate) fn roles_mut(config: &mut Value) -> Vec<&mut Value> {
+    let roles = match config.get("roles") {
+        Some(_) => config.get_mut("roles"),
+        None => config.get_mut("config").and_then(|c| c.get_mut("roles")),
+    };
+    roles
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Merge the file grants whose paths normalize to the same path, with their access bits
+/// in canonical order
+fn merge_files(files: &mut Map<String, Value>) -> usize {
+    let options = NormalizeOptions::default();
+    let mut merged: BTreeMap<String, Value> = BTreeMap::new();
+    let before = files.len();
+    for (path, access) in std::mem::take(files) {
+        let path = normalize_path(&path, &options);
+        let parsed = access.as_str().and_then(|a| a.parse::<Access>().ok());
+        let value = match (merged.remove(&path), parsed) {
+            (None, Some(access)) => Value::from(access.to_string()),
+            (Some(existing), Some(access)) => {
+                match existing.as_str().and_then(|a| a.parse::<Access>().ok()) {
+                    Some(existing) => Value::from((existing | access).to_string()),
+                    None => existing,
+                }
+            }
+            // Unknown access values are kept as written
+            (_, None) => access,
+        };
+        merged.insert(path, value);
+    }
+    files.extend(merged);
+    before - files.len()
+}
+
+/// Sort and deduplicate an array of scalars or rules
+fn sort_array(value: &mut Value) {
+    if let Some(array) = value.as_array_mut() {
+        array.sort_by_key(|item| item.to_string());
+        array.dedup();
+    }
+}
+
+/// Rewrite a task with sorted capabilities and D-Bus rules and merged file grants,
+/// returning the number of merged file entries
+fn format_task(task: &mut Value) -> usize {
+    let mut merged = 0;
+    let Some(cred) = task.get_mut("cred") else {
+        return merged;
+    };
+    match cred.get_mut("capabilities") {
+        Some(Value::Object(capabilities)) => {
+            for set in ["add", "sub", "ambient"] {
+                if let Some(set) = capabilities.get_mut(set) {
+                    sort_array(set);
+                }
+            }
+        }
+        Some(capabilities) => sort_array(capabilities),
+        None => {}
+    }
+    if let Some(Value::Object(files)) = cred.get_mut("files") {
+        merged += merge_files(files);
+    }
+    if let Some(dbus) = cred.get_mut("dbus") {
+        sort_array(dbus);
+    }
+    merged
+}
+
+/// Rewrite the configuration in canonical form, warning about the tasks of a role that
+/// grant exactly the same as another one
+pub(crate) fn format(config: &mut Value) {
+    for role in roles_mut(config) {
+        let name = role
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let Some(tasks) = role.get_mut("tasks").and_then(Value::as_array_mut) else {
+            continue;
+        };
+        let mut grants: BTreeMap<String, String> = BTreeMap::new();
+        for task in tasks.iter_mut() {
+            let merged = format_task(task);
+            let task_name = task
+                .get("name")
+                .map(|name| name.to_string())
+                .unwrap_or_default();
+            if merged > 0 {
+                warn!(
+                    "Merged {} duplicate file grant(s) of {}/{}",
+                    merged, name, task_name
+                );
+            }
+            // Keys of serde_json maps are sorted, so equal grants serialize equally
+            let key = format!(
+                "{}{}",
+                task.get("cred").map(Value::to_string).unwrap_or_default(),
+                task.get("commands")
+                    .map(Value::to_string)
+                    .unwrap_or_default()
+            );
+            if let Some(other) = grants.insert(key, task_name.clone()) {
+                warn!(
+                    "Tasks {} and {} of role {} grant the same privileges and commands",
+                    other, task_name, name
+                );
+            }
+        }
+    }
+}
+
+/// Rewrite the configuration file in canonical form, or only check that it already is.
+/// Returns whether the file was, or would be, changed
+pub(crate) fn run(path: &str, check: bool) -> anyhow::Result<bool> {
+    let contents = fs::read_to_string(path)?;
+    let mut config: Value = serde_json::from_str(&contents)?;
+    format(&mut config);
+    let formatted = serde_json::to_string_pretty(&config)? + "\n";
+    let changed = formatted != contents;
+    if changed && !check {
+        fs::write(path, formatted)?;
+    }
+    Ok(changed)
+}
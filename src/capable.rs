@@ -1,11 +1,161 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{ChildStderr, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
 use bon::bon;
 use capctl::{bounding, CapSet};
-use log::debug;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::{SIGINT, SIGTERM};
 use tempfile::{Builder, NamedTempFile};
 
-use crate::policy::Policy;
+use crate::{
+    cgroup::TraceScope,
+    fanotify::{Backend, FileWatcher},
+    fileops, kernel,
+    metrics::Metrics,
+    policy::{Access, Policy},
+    procscan::ProcCollector,
+    sandbox::{Supervisor, WriteStrategy},
+    setuid, umask,
+};
+
+/// Statistics of the traces run to generate a policy, a shallow trace hints that the
+/// command exited before reaching its real code path
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub(crate) struct TraceStats {
+    pub(crate) paths: usize,
+    pub(crate) capabilities: usize,
+    pub(crate) dbus: usize,
+    pub(crate) processes: usize,
+    pub(crate) duration_secs: f64,
+    pub(crate) iterations: usize,
+    pub(crate) exit_code: Option<i32>,
+}
+
+/// Error output of commands failing because another process holds a shared resource,
+/// which a later run gets once released
+const TRANSIENT_FAILURES: [(&str, &str); 7] = [
+    (
+        "Could not get lock /var/lib/dpkg/lock",
+        "another apt instance holds the dpkg lock",
+    ),
+    (
+        "Could not get lock /var/lib/apt/lists/lock",
+        "another apt instance holds the lists lock",
+    ),
+    (
+        "Unable to acquire the dpkg frontend lock",
+        "another apt instance holds the dpkg lock",
+    ),
+    (
+        "Waiting for process with pid",
+        "another dnf instance holds its lock",
+    ),
+    (
+        "is currently holding the yum lock",
+        "another yum instance holds its lock",
+    ),
+    (
+        "System management is locked",
+        "another zypper instance holds its lock",
+    ),
+    ("is destructive", "a conflicting systemd job is queued"),
+];
+
+/// Delay between two runs of a command failing on a transient resource
+const TRANSIENT_DELAY: Duration = Duration::from_secs(5);
+
+/// Runs of a command failing on a transient resource, before the failure is kept
+const TRANSIENT_ATTEMPTS: u32 = 60;
+
+/// Last bytes of the error output kept to recognize the failure
+const STDERR_TAIL: usize = 64 * 1024;
+
+/// Exit codes conventionally reporting a bad invocation rather than a failed operation
+const USAGE_EXIT_CODES: [(i32, &str); 4] = [
+    (2, "invalid usage"),
+    (64, "EX_USAGE"),
+    (126, "command not executable"),
+    (127, "command not found"),
+];
+
+impl TraceStats {
+    /// Reasons to suspect the trace did not exercise the real code path of the command
+    pub(crate) fn shallow_reasons(
+        &self,
+        min_duration: Duration,
+        success_exit_codes: &[i32],
+    ) -> Vec<String> {
+        let mut reasons = Vec::new();
+        if self.duration_secs < min_duration.as_secs_f64() {
+            reasons.push(format!(
+                "the command exited after {:.3}s, under the minimum of {:.3}s",
+                self.duration_secs,
+                min_duration.as_secs_f64()
+            ));
+        }
+        if let Some((code, meaning)) = USAGE_EXIT_CODES
+            .iter()
+            .filter(|(code, _)| !success_exit_codes.contains(code))
+            .find(|(code, _)| self.exit_code == Some(*code))
+        {
+            reasons.push(format!("the command exited with {} ({})", code, meaning));
+        }
+        if self.is_empty()
+            && self
+                .exit_code
+                .is_some_and(|code| success_exit_codes.contains(&code))
+        {
+            reasons.push("the command succeeded without using any privilege".to_string());
+        }
+        reasons
+    }
+
+    /// Accumulate the statistics of another command traced into the same task
+    pub(crate) fn merge(&mut self, other: &TraceStats) {
+        self.paths += other.paths;
+        self.capabilities += other.capabilities;
+        self.dbus += other.dbus;
+        self.processes += other.processes;
+        self.duration_secs += other.duration_secs;
+        // Keep the exit code of the first failing command
+        if self.iterations == 0 || self.exit_code == Some(0) {
+            self.exit_code = other.exit_code;
+        }
+        self.iterations += other.iterations;
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.paths == 0 && self.capabilities == 0 && self.dbus == 0
+    }
+}
+
+impl std::fmt::Display for TraceStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Trace statistics:")?;
+        writeln!(f, "    iterations       {}", self.iterations)?;
+        writeln!(f, "    duration         {:.3}s", self.duration_secs)?;
+        writeln!(f, "    processes        {}", self.processes)?;
+        writeln!(f, "    paths            {}", self.paths)?;
+        writeln!(f, "    capabilities     {}", self.capabilities)?;
+        writeln!(f, "    dbus             {}", self.dbus)?;
+        match self.exit_code {
+            Some(code) => writeln!(f, "    exit code        {}", code),
+            None => writeln!(f, "    exit code        killed by signal"),
+        }
+    }
+}
 
 pub(crate) struct Capable {
     path: Option<PathBuf>,
@@ -14,6 +164,29 @@ pub(crate) struct Capable {
     caps: CapSet,
     ran: bool,
     failed: bool,
+    rootless: bool,
+    cwd: Option<PathBuf>,
+    envs: Vec<(String, String)>,
+    clear_env: bool,
+    limit_cpu: Option<u64>,
+    limit_mem: Option<u64>,
+    nice: Option<i32>,
+    timeout: Option<Duration>,
+    netns: Option<String>,
+    /// Service whose namespaces the traced command enters
+    join_unit: Option<String>,
+    no_side_effects: Option<WriteStrategy>,
+    backend: Backend,
+    /// Exit codes of a successful run of the traced command
+    success_exit_codes: Vec<i32>,
+    retries: u32,
+    retry_delay: Duration,
+    /// The traced command alone, without the arguments of capable
+    traced: Vec<String>,
+    watcher: Option<FileWatcher>,
+    seen_pids: HashSet<u32>,
+    collector: ProcCollector,
+    stats: TraceStats,
     tmp_file: NamedTempFile,
     pub last_stdout: String,
     pub last_stderr: String,
@@ -38,6 +211,26 @@ impl Default for Capable {
             ],
             ran: false,
             failed: false,
+            rootless: false,
+            cwd: None,
+            envs: Vec::new(),
+            clear_env: false,
+            limit_cpu: None,
+            limit_mem: None,
+            nice: None,
+            timeout: None,
+            netns: None,
+            join_unit: None,
+            no_side_effects: None,
+            backend: Backend::Capable,
+            success_exit_codes: vec![0],
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            traced: Vec::new(),
+            watcher: None,
+            seen_pids: HashSet::new(),
+            collector: ProcCollector::default(),
+            stats: TraceStats::default(),
             tmp_file,
             last_stdout: String::new(),
             last_stderr: String::new(),
@@ -52,18 +245,49 @@ impl Capable {
         path: Option<PathBuf>,
         command: Vec<String>,
         fail_then_add: bool,
+        #[builder(default)] rootless: bool,
+        cwd: Option<PathBuf>,
+        #[builder(default)] envs: Vec<(String, String)>,
+        #[builder(default)] clear_env: bool,
+        limit_cpu: Option<u64>,
+        limit_mem: Option<u64>,
+        nice: Option<i32>,
+        timeout: Option<Duration>,
+        netns: Option<String>,
+        join_unit: Option<String>,
+        no_side_effects: Option<WriteStrategy>,
+        #[builder(default)] backend: Backend,
+        #[builder(default = vec![0])] success_exit_codes: Vec<i32>,
+        #[builder(default)] retries: u32,
+        #[builder(default)] retry_delay: Duration,
     ) -> anyhow::Result<Self> {
         let mut default = Self::default();
         if let Some(path) = path {
             default.path = Some(path);
-        } else if default.path.is_none() {
+        } else if default.path.is_none() && backend == Backend::Capable {
             return Err(anyhow::anyhow!("capable not found in PATH"));
         }
+        default.backend = backend;
+        default.success_exit_codes = success_exit_codes;
+        default.retries = retries;
+        default.retry_delay = retry_delay;
+        default.traced = command.clone();
         default.command.extend(command);
         debug!("Command: {:?}", default.command);
         if fail_then_add {
             default.caps.clear();
         }
+        default.rootless = rootless;
+        default.cwd = cwd;
+        default.envs = envs;
+        default.clear_env = clear_env;
+        default.limit_cpu = limit_cpu;
+        default.limit_mem = limit_mem;
+        default.nice = nice;
+        default.timeout = timeout;
+        default.netns = netns;
+        default.join_unit = join_unit;
+        default.no_side_effects = no_side_effects;
         Ok(default)
     }
     pub(crate) fn add_caps(&mut self, caps: &CapSet) {
@@ -82,28 +306,395 @@ impl Capable {
     pub(crate) fn is_failed(&self) -> bool {
         self.failed
     }
+    /// Whether the traced command exited with one of its success exit codes
+    fn succeeded(&self, status: &ExitStatus) -> bool {
+        status
+            .code()
+            .is_some_and(|code| self.success_exit_codes.contains(&code))
+    }
+    /// Statistics of the last trace, with the number of iterations run so far
+    /// Executables run by the last trace
+    pub(crate) fn processes(&self) -> &BTreeSet<String> {
+        self.collector.executables()
+    }
+
+    /// Raw output of capable for the last trace
+    pub(crate) fn raw_output(&self) -> &Path {
+        self.tmp_file.path()
+    }
+
+    pub(crate) fn stats(&self) -> &TraceStats {
+        &self.stats
+    }
+    /// Trace the command, running it again up to `retries` times while it fails, so that
+    /// a transient failure is not taken for a missing privilege
     pub(crate) fn run(&mut self) -> Result<Policy, anyhow::Error> {
-        let mut command = self.command.clone();
-        // prepend -c CAPS to the
-        command.insert(0, capset_to_string(&self.caps));
-        command.insert(0, "-c".to_string());
+        let mut attempt = 0;
+        let mut waited = 0;
+        loop {
+            let result = self.run_once();
+            if result.is_ok() && self.failed && waited < TRANSIENT_ATTEMPTS {
+                if let Some(reason) = transient_failure(&self.last_stderr) {
+                    waited += 1;
+                    warn!(
+                        "The traced command failed because {}, waiting {:?} before running it again",
+                        reason, TRANSIENT_DELAY
+                    );
+                    thread::sleep(TRANSIENT_DELAY);
+                    continue;
+                }
+            }
+            if attempt >= self.retries || result.as_ref().is_ok_and(|_| !self.failed) {
+                return result;
+            }
+            attempt += 1;
+            match &result {
+                Ok(_) => warn!(
+                    "The traced command failed, retrying in {:?} ({}/{})",
+                    self.retry_delay, attempt, self.retries
+                ),
+                Err(e) => warn!(
+                    "The trace failed: {}, retrying in {:?} ({}/{})",
+                    e, self.retry_delay, attempt, self.retries
+                ),
+            }
+            thread::sleep(self.retry_delay);
+        }
+    }
+
+    fn run_once(&mut self) -> Result<Policy, anyhow::Error> {
+        let mut command = match self.backend {
+            Backend::Capable => {
+                let mut command = self.command.clone();
+                // prepend -c CAPS to the
+                command.insert(0, capset_to_string(&self.caps));
+                command.insert(0, "-c".to_string());
+                command.insert(0, self.path.as_ref().unwrap().to_string_lossy().to_string());
+                command
+            }
+            Backend::Fanotify => {
+                self.watcher = Some(FileWatcher::new()?);
+                self.traced.clone()
+            }
+        };
+        if let Some(unit) = &self.join_unit {
+            // Only the traced command enters the service, the tracer and its output stay here
+            let at = command.len() - self.traced.len();
+            command.splice(at..at, unit_wrapper(unit)?);
+        }
+        let supervisor = match self.no_side_effects {
+            Some(strategy) => {
+                let supervisor = Supervisor::start(strategy)?;
+                // Only the writes of the traced command are intercepted, not the tracer's
+                let at = command.len() - self.traced.len();
+                command.splice(at..at, supervisor.wrapper()?);
+                Some(supervisor)
+            }
+            None => None,
+        };
+        if let Some(watcher) = &self.watcher {
+            let at = command.len() - self.traced.len();
+            command.splice(at..at, watcher.wrapper()?);
+        }
+        // The limits only constrain the traced command, the tracer is never starved or killed
+        if let Some(nice) = self.nice {
+            let at = command.len() - self.traced.len();
+            command.splice(at..at, wrapper("nice", &["-n", &nice.to_string(), "--"])?);
+        }
+        let mut limits = Vec::new();
+        if let Some(cpu) = self.limit_cpu {
+            limits.push(format!("--cpu={}", cpu));
+        }
+        if let Some(mem) = self.limit_mem {
+            limits.push(format!("--as={}", mem));
+        }
+        if !limits.is_empty() {
+            // rlimits are inherited by the traced process tree
+            limits.push("--".to_string());
+            let limits: Vec<&str> = limits.iter().map(|l| l.as_str()).collect();
+            let at = command.len() - self.traced.len();
+            command.splice(at..at, wrapper("prlimit", &limits)?);
+        }
+        if self.rootless {
+            // Map the operator to root inside a new user namespace
+            command.splice(
+                0..0,
+                wrapper("unshare", &["--user", "--map-root-user", "--"])?,
+            );
+        }
+        match self.netns.as_deref() {
+            // A fresh namespace only has a loopback interface, where mock services can listen
+            Some("new") => {
+                command.splice(
+                    0..0,
+                    wrapper(
+                        "sh",
+                        &["-c", "ip link set lo up && exec \"$@\"", "gensr-netns"],
+                    )?,
+                );
+                command.splice(0..0, wrapper("unshare", &["--net", "--"])?);
+            }
+            Some(name) => {
+                command.splice(0..0, wrapper("ip", &["netns", "exec", name])?);
+            }
+            None => {}
+        }
+        let scope = match TraceScope::new() {
+            Ok(scope) => {
+                command.splice(0..0, scope.wrapper());
+                Some(scope)
+            }
+            Err(e) => {
+                warn!("Cannot create a cgroup for the traced command: {}", e);
+                None
+            }
+        };
+        let program = command.remove(0);
         debug!("Running command: {:?}", command);
-        let cmd = std::process::Command::new(self.path.as_ref().unwrap().as_os_str())
+        let mut cmd = std::process::Command::new(program);
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        let started = SystemTime::now();
+        let mut child = cmd
+            .envs(self.envs.iter().map(|(k, v)| (k, v)))
             .args(command)
             .stdin(std::process::Stdio::inherit())
             .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
-            .output()?;
-        self.failed = !cmd.status.success();
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        if let Some(watcher) = &self.watcher {
+            watcher.attach(&mut child)?;
+        }
+        let stderr = child.stderr.take().map(tee_stderr);
+        let start = Instant::now();
+        self.seen_pids.clear();
+        self.collector = ProcCollector::default();
+        let status = self.wait(child, scope.as_ref());
+        self.last_stderr = stderr
+            .and_then(|stderr| stderr.join().ok())
+            .unwrap_or_default();
+        let failed = !status.as_ref().is_ok_and(|s| self.succeeded(s));
+        let duration = start.elapsed().as_secs_f64();
+        Metrics::record(|m| {
+            m.traces_total += 1;
+            m.trace_failures_total += failed as u64;
+            m.trace_duration_seconds_sum += duration;
+        });
+        let status = status?;
+        self.failed = !self.succeeded(&status);
         // open the file and parse the policy
-        let mut policy: Policy = serde_json::de::from_reader(self.tmp_file.as_file())?;
+        let mut policy: Policy = match self.watcher.take() {
+            Some(watcher) => Policy {
+                files: watcher.files().clone(),
+                ..Default::default()
+            },
+            None => serde_json::de::from_reader(self.tmp_file.as_file())?,
+        };
+        let output = self.tmp_file.path().to_string_lossy();
+        for (path, access) in self.collector.files() {
+            if *path != output {
+                *policy.files.entry(path.clone()).or_insert(Access::empty()) |= *access;
+            }
+        }
+        // The intercepted writes never reached the filesystem for the tracer to see them
+        for (path, access) in supervisor.map(Supervisor::finish).unwrap_or_default() {
+            *policy.files.entry(path).or_insert(Access::empty()) |= access;
+        }
+        self.stats = TraceStats {
+            paths: policy.files.len(),
+            capabilities: policy.capabilities.len(),
+            dbus: policy.dbus.len(),
+            processes: self.seen_pids.len(),
+            duration_secs: duration,
+            iterations: self.stats.iterations + 1,
+            exit_code: status.code(),
+        };
+        policy.setuid_helpers = setuid::detect(&policy, self.collector.executables());
+        if !policy.setuid_helpers.is_empty() {
+            debug!("Setuid helpers executed: {:?}", policy.setuid_helpers);
+        }
+        policy.kernel_requirements =
+            kernel::requirements(&policy, self.collector.kernel_features());
+        policy.umasks = self.collector.umasks().clone();
+        policy.created_modes = umask::created(&policy, started);
+        fileops::mark(&mut policy, started, self.collector.inodes());
+        if self.rootless {
+            // The namespace is gensr's own, the task will not create it
+            policy
+                .kernel_requirements
+                .remove(&kernel::Requirement::UnprivilegedUserns);
+        }
         policy.current_user_creds();
+        policy.userns_derived = self.rootless;
         self.ran = true;
         Ok(policy)
     }
 }
 
-fn capset_to_string(capset: &CapSet) -> String {
+/// Copy the error output of the traced command to ours, keeping its last bytes
+fn tee_stderr(mut pipe: ChildStderr) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut tail = Vec::new();
+        let mut buffer = [0; 4096];
+        while let Ok(read) = pipe.read(&mut buffer) {
+            if read == 0 {
+                break;
+            }
+            let _ = io::stderr().write_all(&buffer[..read]);
+            tail.extend_from_slice(&buffer[..read]);
+            if tail.len() > STDERR_TAIL {
+                tail.drain(..tail.len() - STDERR_TAIL);
+            }
+        }
+        String::from_utf8_lossy(&tail).to_string()
+    })
+}
+
+/// Why the error output shows the command failed on a resource held by another process
+fn transient_failure(stderr: &str) -> Option<&'static str> {
+    TRANSIENT_FAILURES
+        .iter()
+        .find(|(signature, _)| stderr.contains(signature))
+        .map(|(_, reason)| *reason)
+}
+
+impl Capable {
+    /// Wait for the traced process tree, killing it on timeout or interruption
+    fn wait(
+        &mut self,
+        mut child: std::process::Child,
+        scope: Option<&TraceScope>,
+    ) -> anyhow::Result<ExitStatus> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let handlers = [
+            signal_hook::flag::register(SIGINT, interrupted.clone())?,
+            signal_hook::flag::register(SIGTERM, interrupted.clone())?,
+        ];
+        let start = Instant::now();
+        self.seen_pids.insert(child.id());
+        let result = loop {
+            if let Some(status) = child.try_wait()? {
+                if let Some(watcher) = self.watcher.as_mut() {
+                    watcher.poll();
+                }
+                break Ok(status);
+            }
+            if let Some(scope) = scope {
+                let pids = scope.pids();
+                self.collector
+                    .sample(&pids, self.path.as_deref().unwrap_or(Path::new("")));
+                self.seen_pids.extend(pids);
+            }
+            if let Some(watcher) = self.watcher.as_mut() {
+                watcher.poll();
+            }
+            let timed_out = self.timeout.is_some_and(|t| start.elapsed() > t);
+            if timed_out || interrupted.load(Ordering::Relaxed) {
+                match scope {
+                    Some(scope) => scope.kill()?,
+                    None => child.kill()?,
+                }
+                child.wait()?;
+                break Err(anyhow::anyhow!(
+                    "The traced command was {}",
+                    if timed_out {
+                        "killed after timeout"
+                    } else {
+                        "interrupted"
+                    }
+                ));
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        for handler in handlers {
+            signal_hook::low_level::unregister(handler);
+        }
+        debug!(
+            "{} process(es) observed in the trace scope",
+            self.seen_pids.len()
+        );
+        result
+    }
+}
+
+/// Resolve a wrapper program before the traced environment may drop PATH
+pub(crate) fn wrapper(program: &str, args: &[&str]) -> anyhow::Result<Vec<String>> {
+    let path =
+        which::which(program).map_err(|_| anyhow::anyhow!("{} not found in PATH", program))?;
+    let mut wrapper = vec![path.to_string_lossy().to_string()];
+    wrapper.extend(args.iter().map(|a| a.to_string()));
+    Ok(wrapper)
+}
+
+/// Wrapper entering the mount, network, IPC, UTS and cgroup namespaces of the main
+/// process of a running service
+fn unit_wrapper(unit: &str) -> anyhow::Result<Vec<String>> {
+    let output = std::process::Command::new("systemctl")
+        .args(["show", "--property", "MainPID", "--value", unit])
+        .output()?;
+    let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || pid.is_empty() || pid == "0" {
+        return Err(anyhow::anyhow!("{} is not running, cannot join it", unit));
+    }
+    wrapper(
+        "nsenter",
+        &[
+            "--target", &pid, "--mount", "--net", "--ipc", "--uts", "--cgroup", "--",
+        ],
+    )
+}
+
+/// Parse a size such as `512M` or `2G` into bytes
+pub(crate) fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let size = size.trim();
+    let (value, unit) = size.split_at(
+        size.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len()),
+    );
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid size: {}", size))?;
+    let multiplier = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(anyhow::anyhow!("Invalid size unit: {}", unit)),
+    };
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow::anyhow!("Size too large: {}", size))
+}
+
+/// Parse a `KEY=VALUE` assignment
+pub(crate) fn parse_env_var(var: &str) -> anyhow::Result<(String, String)> {
+    let var = var.trim();
+    let var = var.strip_prefix("export ").unwrap_or(var);
+    let (key, value) = var.split_once('=').ok_or_else(|| {
+        anyhow::anyhow!("Invalid environment variable '{}', expected KEY=VALUE", var)
+    })?;
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+        .unwrap_or(value);
+    Ok((key.trim().to_string(), value.to_string()))
+}
+
+/// Read an environment file as used by systemd's EnvironmentFile or cron
+pub(crate) fn read_env_file(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(parse_env_var)
+        .collect()
+}
+
+pub(crate) fn capset_to_string(capset: &CapSet) -> String {
     capset
         .iter()
         .map(|c| c.to_string())
@@ -115,3 +706,25 @@ fn capset_to_string(capset: &CapSet) -> String {
             }
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("1b").unwrap(), 1);
+        assert_eq!(parse_size("4K").unwrap(), 4096);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 2G ").unwrap(), 2 << 30);
+    }
+
+    #[test]
+    fn parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size(&format!("{}G", u64::MAX >> 20)).is_err());
+    }
+}
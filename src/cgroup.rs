@@ -0,0 +1,105 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+static SCOPE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A transient cgroup holding the whole traced process tree,
+/// so every descendant can be enumerated and killed
+pub(crate) struct TraceScope {
+    path: PathBuf,
+}
+
+impl TraceScope {
+    pub(crate) fn new() -> io::Result<Self> {
+        let root = env::var("GENSR_CGROUP_ROOT")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("/sys/fs/cgroup/gensr"));
+        if !root
+            .parent()
+            .is_some_and(|p| p.join("cgroup.controllers").exists())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "cgroup v2 is not mounted",
+            ));
+        }
+        let path = root.join(format!(
+            "trace-{}-{}",
+            process::id(),
+            SCOPE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+        debug!("Created trace scope {:?}", path);
+        Ok(TraceScope { path })
+    }
+
+    /// Command prefix moving itself into the scope before executing the rest of the command line
+    pub(crate) fn wrapper(&self) -> Vec<String> {
+        vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "echo $$ > \"$0\" && exec \"$@\"".to_string(),
+            self.path.join("cgroup.procs").to_string_lossy().to_string(),
+        ]
+    }
+
+    pub(crate) fn pids(&self) -> Vec<u32> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .map(|procs| procs.lines().filter_map(|p| p.parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn kill(&self) -> io::Result<()> {
+        let kill_file = self.path.join("cgroup.kill");
+        if kill_file.exists() {
+            return fs::write(kill_file, "1");
+        }
+        // cgroup.kill is only available since Linux 5.14
+        for pid in self.pids() {
+            if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+                debug!("Failed to kill {}: {}", pid, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(path: &Path) -> io::Result<()> {
+        // rmdir fails until the killed processes are reaped
+        for _ in 0..20 {
+            match fs::remove_dir(path) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(nix::libc::EBUSY) => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        fs::remove_dir(path)
+    }
+}
+
+impl Drop for TraceScope {
+    fn drop(&mut self) {
+        if !self.pids().is_empty() {
+            warn!("Killing processes left behind by the traced command");
+            if let Err(e) = self.kill() {
+                warn!("Failed to kill the trace scope: {}", e);
+            }
+        }
+        if let Err(e) = Self::remove(&self.path) {
+            warn!("Failed to remove trace scope {:?}: {}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashMap, fs::File, path::Path};
+
+use rootasrole_core::database::structs::STask;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::policy::{Access, Policy};
+
+/// A rule forbidding a kind of grant, e.g. write access under /etc or CAP_SYS_MODULE
+#[derive(Deserialize)]
+struct Rule {
+    id: String,
+    #[serde(default)]
+    description: Option<String>,
+    /// Forbid grants on this path and everything under it
+    #[serde(default)]
+    path: Option<String>,
+    /// Only forbid these access bits on `path`, any access when unset
+    #[serde(default)]
+    access: Option<Access>,
+    #[serde(default)]
+    capability: Option<String>,
+}
+
+/// Organization-provided rules evaluated during generate and deploy
+#[derive(Deserialize)]
+pub(crate) struct ComplianceRules {
+    rules: Vec<Rule>,
+}
+
+pub(crate) struct Violation {
+    pub(crate) rule: String,
+    pub(crate) detail: String,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.rule, self.detail)
+    }
+}
+
+impl ComplianceRules {
+    pub(crate) fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(serde_yaml::from_reader(File::open(path)?)?)
+    }
+
+    fn check<'a>(
+        &self,
+        files: impl Iterator<Item = (&'a str, Access)> + Clone,
+        capabilities: impl Iterator<Item = String> + Clone,
+    ) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for rule in &self.rules {
+            let description = rule.description.as_deref().unwrap_or(&rule.id);
+            if let Some(forbidden) = &rule.path {
+                for (path, access) in files.clone() {
+                    let matched = rule.access.is_none_or(|a| access.intersects(a));
+                    if Path::new(path).starts_with(forbidden) && matched {
+                        violations.push(Violation {
+                            rule: rule.id.clone(),
+                            detail: format!("{} access on {}: {}", access, path, description),
+                        });
+                    }
+                }
+            }
+            if let Some(forbidden) = &rule.capability {
+                for cap in capabilities.clone() {
+                    if cap.eq_ignore_ascii_case(forbidden) {
+                        violations.push(Violation {
+                            rule: rule.id.clone(),
+                            detail: format!("{}: {}", cap, description),
+                        });
+                    }
+                }
+            }
+        }
+        violations
+    }
+
+    pub(crate) fn check_policy(&self, policy: &Policy) -> Vec<Violation> {
+        self.check(
+            policy.files.iter().map(|(p, a)| (p.as_str(), *a)),
+            policy.capabilities.iter().cloned(),
+        )
+    }
+
+    pub(crate) fn check_task(&self, task: &STask) -> Vec<Violation> {
+        let empty = Map::new();
+        let files = task
+            .cred
+            ._extra_fields
+            .get("files")
+            .and_then(|value| value.as_object())
+            .unwrap_or(&empty);
+        let capabilities: Vec<String> = task
+            .cred
+            .capabilities
+            .as_ref()
+            .map(|caps| caps.add.iter().map(|c| c.to_string()).collect())
+            .unwrap_or_default();
+        self.check(
+            files
+                .iter()
+                .filter_map(|(path, access)| Some((path.as_str(), access.as_str()?.parse().ok()?))),
+            capabilities.into_iter(),
+        )
+    }
+}
+
+/// Parse `--waive RULE=JUSTIFICATION` arguments
+pub(crate) fn parse_waivers(waivers: &[String]) -> anyhow::Result<HashMap<String, String>> {
+    waivers
+        .iter()
+        .map(|w| match w.split_once('=') {
+            Some((rule, justification)) if !justification.trim().is_empty() => {
+                Ok((rule.to_string(), justification.to_string()))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Waiver '{}' must be written RULE=JUSTIFICATION",
+                w
+            )),
+        })
+        .collect()
+}
+
+/// Waivers recorded in a task, as written by generate
+pub(crate) fn task_waivers(task: &STask) -> HashMap<String, String> {
+    task._extra_fields
+        .get("waivers")
+        .and_then(|value| value.as_object())
+        .map(|waivers| {
+            waivers
+                .iter()
+                .filter_map(|(rule, j)| Some((rule.clone(), j.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub(crate) fn waivers_to_value(waivers: &HashMap<String, String>) -> Value {
+    Value::Object(
+        waivers
+            .iter()
+            .map(|(rule, j)| (rule.clone(), Value::String(j.clone())))
+            .collect(),
+    )
+}
+
+/// Fail on every violation that was not waived
+pub(crate) fn enforce(
+    violations: &[Violation],
+    waivers: &HashMap<String, String>,
+    subject: &str,
+) -> anyhow::Result<()> {
+    let unwaived: Vec<String> = violations
+        .iter()
+        .filter(|v| !waivers.contains_key(&v.rule))
+        .map(|v| v.to_string())
+        .collect();
+    if unwaived.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "{} violates the compliance rules:\n    {}",
+        subject,
+        unwaived.join("\n    ")
+    ))
+}
@@ -1,31 +1,123 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     env,
     fs::{self, File},
-    io::{self, BufWriter, Error, Read, Write},
+    io::{self, BufRead, BufWriter, Error, Read, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
-use log::debug;
-use nix::unistd::{Uid, User};
-use posix_acl::{PosixACL, ACL_EXECUTE, ACL_READ, ACL_WRITE};
-use rootasrole_core::database::structs::{SActorType, SConfig, SCredentials};
+use log::{debug, info, warn};
+use nix::unistd::{Gid, Group, Uid, User};
+use posix_acl::{PosixACL, Qualifier, ACL_EXECUTE, ACL_READ, ACL_WRITE};
+use rootasrole_core::database::structs::{SActor, SActorType, SConfig, SCredentials, STask};
+use serde_json::{Map, Value};
+use sha2::Digest;
 use sxd_document::writer::format_document;
 
-use crate::policy::Policy;
+use crate::{
+    alerts, audit::AuditRulesWriter, auditlog, compliance, compliance::ComplianceRules, ephemeral,
+    fapolicyd, fapolicyd::FapolicydTrustWriter, fileops, metrics::Metrics, mounts,
+    pam::PamAccessWriter, params, policy::Policy, polkitcache, polkitcache::PolkitCache, rules,
+    rules::DbusRule, secrets, secrets::SecretSources, selinux, state::AclRelease,
+    state::DeployState, state::IdRange, subid, subid::SubIdFile, telemetry,
+};
 
 struct DBusPolicyBuilder {
     system_config: PathBuf,
     rootasrole_folder: PathBuf,
+    layout: DbusLayout,
+    /// Policy blocks of the consolidated layout by user, written at once by `build`
+    policies: BTreeMap<String, String>,
+}
+
+/// How the dbus policies of the task users are laid out
+#[derive(Clone, Copy, Default, PartialEq, Debug, clap::ValueEnum)]
+pub(crate) enum DbusLayout {
+    /// One file per task user
+    #[default]
+    PerUser,
+    /// A single rootasrole.conf holding every policy, rewritten atomically
+    Consolidated,
 }
 
+/// File of the consolidated layout, in the rootasrole folder
+const CONSOLIDATED_FILE: &str = "rootasrole.conf";
+
 fn mkdirs<P: AsRef<Path>>(path: P) -> io::Result<()> {
     fs::create_dir_all(path)
 }
 
+/// Whether the name is a user name dbus-daemon can resolve: portable characters, not
+/// starting with a dash, possibly ending with `$` as machine accounts do
+fn is_valid_username(user: &str) -> bool {
+    let name = user.strip_suffix('$').unwrap_or(user);
+    !name.is_empty()
+        && user.len() <= 256
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Load the policy file in a throwaway dbus-daemon, which refuses to start when the file is
+/// malformed, instead of letting the system bus silently ignore it
+fn check_dbus_syntax(path: &Path) -> io::Result<()> {
+    let Ok(daemon) = which::which("dbus-daemon") else {
+        debug!(
+            "dbus-daemon not found, skipping the syntax check of {:?}",
+            path
+        );
+        return Ok(());
+    };
+    let dir = tempfile::tempdir()?;
+    let wrapper = dir.path().join("check.conf");
+    fs::write(
+        &wrapper,
+        format!(
+            "<busconfig>\n<type>session</type>\n<listen>unix:dir={}</listen>\n\
+             <policy context=\"default\"><allow send_destination=\"*\"/></policy>\n\
+             <include>{}</include>\n</busconfig>\n",
+            rules::xml_escape(&dir.path().to_string_lossy()),
+            rules::xml_escape(&path.to_string_lossy())
+        ),
+    )?;
+    let mut child = Command::new(daemon)
+        .arg(format!("--config-file={}", wrapper.display()))
+        .args(["--print-address", "--nofork", "--nopidfile"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // The address is printed once the configuration is loaded, a rejected one closes stdout
+    let mut address = String::new();
+    if let Some(stdout) = child.stdout.take() {
+        io::BufReader::new(stdout).read_line(&mut address)?;
+    }
+    if !address.trim().is_empty() {
+        child.kill()?;
+        child.wait()?;
+        return Ok(());
+    }
+    let mut error = String::new();
+    if let Some(mut stderr) = child.stderr.take() {
+        stderr.read_to_string(&mut error)?;
+    }
+    child.wait()?;
+    Err(Error::new(
+        io::ErrorKind::InvalidData,
+        format!("dbus-daemon rejects {:?}: {}", path, error.trim()),
+    ))
+}
+
 impl DBusPolicyBuilder {
     pub(crate) fn new() -> Self {
         let datadir = Self::find_datadir().unwrap();
@@ -34,6 +126,15 @@ impl DBusPolicyBuilder {
         DBusPolicyBuilder {
             system_config: datadir.join("system.conf"),
             rootasrole_folder,
+            layout: DbusLayout::PerUser,
+            policies: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn with_layout(layout: DbusLayout) -> Self {
+        DBusPolicyBuilder {
+            layout,
+            ..Self::new()
         }
     }
 
@@ -107,6 +208,11 @@ impl DBusPolicyBuilder {
             writer.write_all(header.as_bytes())?;
             writer.write_all(contents.as_bytes())?;
             writer.flush()?;
+            auditlog::record(
+                "dbus_system_conf_modify",
+                &self.system_config.to_string_lossy(),
+                "added rootasrole includedir",
+            );
         }
         Ok(())
     }
@@ -115,29 +221,138 @@ impl DBusPolicyBuilder {
         "    ".repeat(level)
     }
 
-    pub fn add_policy(&mut self, user: &str, dbus_permissions: &[&str]) -> io::Result<()> {
-        debug!(
-            "Adding dbus policy for user {} at {:?}",
-            user,
-            self.rootasrole_folder.join(format!("{}.conf", user))
-        );
-        let mut writer = File::create(self.rootasrole_folder.join(format!("{}.conf", user)))?;
-        writer.write_all(DBusPolicyBuilder::header().as_bytes())?;
-        writer.write_all(b"<busconfig>\n")?;
+    /// The `<policy>` element of the user
+    fn policy_block(user: &str, dbus_permissions: &[DbusRule]) -> String {
         let mut policy = format!("{}<policy user=\"{}\">", Self::indent(1), user);
         for permission in dbus_permissions {
             policy.push_str(&format!(
-                "{}<allow send_destination=\"{}\"/>",
+                "{}<allow {}/>",
                 Self::indent(2),
-                permission
+                permission.to_allow_attributes()
             ));
         }
-        policy.push_str(&format!("{}</policy>\n</busconfig>", Self::indent(1)));
-        writer.write_all(policy.as_bytes())?;
-        writer.flush()?;
+        policy.push_str(&format!("{}</policy>", Self::indent(1)));
+        policy
+    }
+
+    fn consolidated_path(&self) -> PathBuf {
+        self.rootasrole_folder.join(CONSOLIDATED_FILE)
+    }
+
+    /// Policy blocks of the consolidated file by user
+    fn consolidated_policies(&self) -> BTreeMap<String, String> {
+        let mut policies = BTreeMap::new();
+        let contents = fs::read_to_string(self.consolidated_path()).unwrap_or_default();
+        let mut rest = contents.as_str();
+        while let Some(start) = rest.find("<policy user=\"") {
+            let block = &rest[start..];
+            let Some(end) = block.find("</policy>").map(|end| end + "</policy>".len()) else {
+                break;
+            };
+            if let Some(user) = block["<policy user=\"".len()..].split('"').next() {
+                policies.insert(
+                    user.to_string(),
+                    format!("{}{}", Self::indent(1), &block[..end]),
+                );
+            }
+            rest = &block[end..];
+        }
+        policies
+    }
+
+    /// Replace the consolidated file through a rename, so dbus never reads it half written
+    fn write_consolidated(&self, policies: &BTreeMap<String, String>) -> io::Result<()> {
+        let path = self.consolidated_path();
+        if policies.is_empty() {
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+        // dbus only includes the .conf files of the folder
+        let tmp = self
+            .rootasrole_folder
+            .join(format!(".{}.tmp", CONSOLIDATED_FILE));
+        let mut writer = File::create(&tmp)?;
+        writer.write_all(DBusPolicyBuilder::header().as_bytes())?;
+        writer.write_all(format!("{}\n<busconfig>\n", DBUS_MARKER).as_bytes())?;
+        for policy in policies.values() {
+            writer.write_all(format!("{}\n", policy).as_bytes())?;
+        }
+        writer.write_all(b"</busconfig>")?;
+        writer.sync_all()?;
+        if let Err(e) = check_dbus_syntax(&tmp) {
+            fs::remove_file(&tmp)?;
+            return Err(e);
+        }
+        fs::rename(&tmp, &path)?;
+        auditlog::record(
+            "dbus_policy_write",
+            &path.to_string_lossy(),
+            &format!("consolidated policies of {} user(s)", policies.len()),
+        );
         Ok(())
     }
 
+    /// Drop the policy of the user from the consolidated file
+    fn remove_consolidated(&self, user: &str) -> io::Result<()> {
+        let mut policies = self.consolidated_policies();
+        if policies.remove(user).is_some() {
+            self.write_consolidated(&policies)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_policy(&mut self, user: &str, dbus_permissions: &[DbusRule]) -> io::Result<()> {
+        if !is_valid_username(user) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid user name {:?} for a dbus policy", user),
+            ));
+        }
+        // dbus-daemon ignores the whole file on a single invalid name
+        let dbus_permissions: Vec<DbusRule> = dbus_permissions
+            .iter()
+            .filter(|rule| match rule.validate() {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Skipping the dbus rule of {}: {}", user, e);
+                    false
+                }
+            })
+            .cloned()
+            .collect();
+        let dbus_permissions = dbus_permissions.as_slice();
+        if self.layout == DbusLayout::Consolidated {
+            self.policies
+                .insert(user.to_string(), Self::policy_block(user, dbus_permissions));
+            // A policy is held by a single layout
+            return remove_dbus_file(&self.rootasrole_folder.join(format!("{}.conf", user)), user);
+        }
+        let path = self.rootasrole_folder.join(format!("{}.conf", user));
+        debug!("Adding dbus policy for user {} at {:?}", user, path);
+        // Checked before the rename, dbus never reads it half written nor invalid, and a
+        // rejected policy leaves the previous one in place
+        let tmp = self.rootasrole_folder.join(format!(".{}.conf.tmp", user));
+        let mut writer = File::create(&tmp)?;
+        writer.write_all(DBusPolicyBuilder::header().as_bytes())?;
+        writer.write_all(format!("{}\n<busconfig>\n", DBUS_MARKER).as_bytes())?;
+        let policy = Self::policy_block(user, dbus_permissions);
+        writer.write_all(format!("{}\n</busconfig>", policy).as_bytes())?;
+        writer.sync_all()?;
+        if let Err(e) = check_dbus_syntax(&tmp) {
+            fs::remove_file(&tmp)?;
+            return Err(e);
+        }
+        fs::rename(&tmp, &path)?;
+        auditlog::record(
+            "dbus_policy_write",
+            &path.to_string_lossy(),
+            &format!("{} rule(s) allowed for {}", dbus_permissions.len(), user),
+        );
+        self.remove_consolidated(user)
+    }
+
     fn header() -> &'static str {
         r#"<?xml version="1.0" encoding="UTF-8"?>
 <!DOCTYPE busconfig PUBLIC
@@ -147,6 +362,12 @@ impl DBusPolicyBuilder {
     }
 
     pub(crate) fn build(&self) -> io::Result<()> {
+        if !self.policies.is_empty() {
+            // Policies of the users not redeployed this time are kept
+            let mut policies = self.consolidated_policies();
+            policies.extend(self.policies.clone());
+            self.write_consolidated(&policies)?;
+        }
         self.insert_new_dbus_config_folder()?;
         Ok(())
     }
@@ -168,7 +389,7 @@ impl DBusPolicyBuilder {
     }
 }
 
-fn resolve_config_dir(
+pub(crate) fn resolve_config_dir(
     env_key: &str,
     first_dir: PathBuf,
     second_dir: PathBuf,
@@ -201,12 +422,46 @@ type PolkitPolicy = HashMap<String, PolkitActionSet>;
 
 type PolkitActionSet = HashSet<String>;
 
+/// Configuration deployed when none is given, whose polkit policy is not namespaced
+const DEFAULT_CONFIG: &str = "/etc/security/rootasrole.json";
+
+/// Namespace of the polkit policy of a configuration, so that configurations deployed on the
+/// same host (staging, production) keep their own action grants: the given one, or one
+/// derived from the configuration path unless it is the default configuration
+pub(crate) fn polkit_namespace(
+    config_path: &str,
+    namespace: Option<String>,
+) -> anyhow::Result<Option<String>> {
+    if let Some(namespace) = namespace {
+        if namespace.is_empty()
+            || !namespace
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!(
+                "Invalid polkit namespace {}, use letters, digits, - and _",
+                namespace
+            );
+        }
+        return Ok(Some(namespace));
+    }
+    let path = fs::canonicalize(config_path)?;
+    if path == Path::new(DEFAULT_CONFIG) {
+        return Ok(None);
+    }
+    let digest = sha2::Sha256::digest(path.to_string_lossy().as_bytes());
+    Ok(Some(hex::encode(&digest[..4])))
+}
+
 struct PolkitPolicyWorker {
     rules_folder: PathBuf,
+    namespace: Option<String>,
+    /// Seconds the rule asks the check to cache its decisions
+    cache_ttl: Option<u64>,
 }
 
 impl PolkitPolicyWorker {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(namespace: Option<&str>) -> Self {
         let datadir = resolve_config_dir(
             "POLKIT_DATA_DIR",
             "/usr/share/polkit-1".into(),
@@ -215,6 +470,20 @@ impl PolkitPolicyWorker {
         .unwrap();
         PolkitPolicyWorker {
             rules_folder: datadir.join("rules.d"),
+            namespace: namespace.map(str::to_string),
+            cache_ttl: None,
+        }
+    }
+
+    pub(crate) fn with_cache_ttl(self, cache_ttl: Option<u64>) -> Self {
+        PolkitPolicyWorker { cache_ttl, ..self }
+    }
+
+    /// `rootasrole`, or `rootasrole-<namespace>`
+    fn file_stem(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("rootasrole-{}", namespace),
+            None => "rootasrole".to_string(),
         }
     }
 
@@ -225,18 +494,26 @@ impl PolkitPolicyWorker {
         } else {
             PolkitPolicy::new()
         };
-        let permissions: HashSet<String> = dbus_permissions.iter().map(|s| s.to_string()).collect();
         policy
-            .get_mut(user)
-            .get_or_insert(&mut HashSet::new())
-            .extend(permissions);
+            .entry(user.to_string())
+            .or_default()
+            .extend(dbus_permissions.iter().map(|s| s.to_string()));
         let writer = File::create(self.get_policy_file_path())?;
         serde_json::to_writer(writer, &policy)?;
+        auditlog::record(
+            "polkit_policy_write",
+            &self.get_policy_file_path().to_string_lossy(),
+            &format!("{} action(s) granted to {}", dbus_permissions.len(), user),
+        );
         Ok(())
     }
 
     pub(crate) fn get_policy_file_path(&self) -> PathBuf {
-        self.rules_folder.join("rootasrole.json")
+        self.rules_folder.join(format!("{}.json", self.file_stem()))
+    }
+
+    fn rule_file_path(&self) -> PathBuf {
+        self.rules_folder.join(format!("{}.js", self.file_stem()))
     }
 
     pub(crate) fn check_policy(&self, user: &str, action: &str) -> anyhow::Result<bool> {
@@ -254,19 +531,80 @@ impl PolkitPolicyWorker {
     }
 
     pub(crate) fn build(&self) -> anyhow::Result<()> {
-        let mut rule_file = File::create(self.rules_folder.join("rootasrole.js"))?;
+        let mut rule_file = File::create(self.rule_file_path())?;
         let template = include_str!("./rootasrole_polkit.js");
         //format the template with the current binary path
-        let formatted = template.replace("{{BINARY_PATH}}", env::current_exe()?.to_str().unwrap());
+        let namespace_args = match &self.namespace {
+            Some(namespace) => format!(", \"--namespace\", \"{}\"", namespace),
+            None => String::new(),
+        };
+        let cache_args = match self.cache_ttl {
+            Some(ttl) => format!(", \"--cache-ttl\", \"{}\"", ttl),
+            None => String::new(),
+        };
+        let formatted = template
+            .replace("{{BINARY_PATH}}", env::current_exe()?.to_str().unwrap())
+            .replace("{{NAMESPACE_ARGS}}", &namespace_args)
+            .replace("{{CACHE_ARGS}}", &cache_args);
         rule_file.write_all(formatted.as_bytes())?;
+        auditlog::record(
+            "polkit_rule_write",
+            &self.rule_file_path().to_string_lossy(),
+            "installed rootasrole polkit rule",
+        );
         Ok(())
     }
 
     fn del_policy(&self, username: &str) -> anyhow::Result<()> {
+        if !self.get_policy_file_path().exists() {
+            return Ok(());
+        }
         let mut policy: PolkitPolicy = self.polkit_policy()?;
-        policy.remove(username);
+        if policy.remove(username).is_none() {
+            return Ok(());
+        }
         let writer = File::create(self.get_policy_file_path())?;
         serde_json::to_writer(writer, &policy)?;
+        auditlog::record(
+            "polkit_policy_remove",
+            &self.get_policy_file_path().to_string_lossy(),
+            &format!("removed actions of {}", username),
+        );
+        Ok(())
+    }
+
+    /// Before namespacing, every configuration granted its actions in the global policy,
+    /// which the default rule still consults: drop the legacy grants of a user now held
+    /// by the namespace
+    fn migrate_legacy(&self, username: &str) -> anyhow::Result<()> {
+        if self.namespace.is_none() {
+            return Ok(());
+        }
+        let legacy = PolkitPolicyWorker {
+            namespace: None,
+            cache_ttl: None,
+            rules_folder: self.rules_folder.clone(),
+        };
+        legacy.del_policy(username)
+    }
+
+    /// Remove the policy and rule of the namespace once it grants nothing. The global ones
+    /// are left to RootAsRole
+    fn clean(&self) -> anyhow::Result<()> {
+        if self.namespace.is_none() || !self.polkit_policy().is_ok_and(|p| p.is_empty()) {
+            return Ok(());
+        }
+        for path in [self.get_policy_file_path(), self.rule_file_path()] {
+            match fs::remove_file(&path) {
+                Ok(()) => auditlog::record(
+                    "polkit_policy_remove",
+                    &path.to_string_lossy(),
+                    "namespace emptied",
+                ),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
         Ok(())
     }
 }
@@ -284,95 +622,1102 @@ fn str_to_permission(perm: &str) -> anyhow::Result<u32> {
     return Ok(perms);
 }
 
-fn set_acl<P: AsRef<Path>>(user: &Uid, path: P, permissions: &str) -> anyhow::Result<()> {
+/// Key of an ACL entry in the deploy state
+fn qualifier_key(qualifier: Qualifier) -> String {
+    match qualifier {
+        Qualifier::User(uid) => format!("user:{}", uid),
+        Qualifier::Group(gid) => format!("group:{}", gid),
+        qualifier => format!("{:?}", qualifier),
+    }
+}
+
+fn parse_qualifier_key(entry: &str) -> anyhow::Result<Qualifier> {
+    match entry.split_once(':') {
+        Some(("user", uid)) => Ok(Qualifier::User(uid.parse()?)),
+        Some(("group", gid)) => Ok(Qualifier::Group(gid.parse()?)),
+        _ => Err(anyhow::anyhow!("Unknown ACL entry {}", entry)),
+    }
+}
+
+/// Bits of an ACL entry (`user:<uid>` or `group:<gid>`) of the path
+pub(crate) fn acl_entry(path: &str, entry: &str) -> anyhow::Result<Option<u32>> {
+    Ok(PosixACL::read_acl(path)?.get(parse_qualifier_key(entry)?))
+}
+
+/// Give an ACL entry the bits it had on another host
+pub(crate) fn restore_acl(path: &str, entry: &str, bits: u32) -> anyhow::Result<()> {
+    let qualifier = parse_qualifier_key(entry)?;
+    let mut acl = PosixACL::read_acl(path)?;
+    let current = acl.get(qualifier).unwrap_or(0);
+    acl.set(qualifier, current | bits);
+    acl.write_acl(path)?;
+    auditlog::record(
+        "acl_set",
+        path,
+        &format!(
+            "{}: {:o} -> {:o}, restored",
+            describe_qualifier(qualifier),
+            current,
+            current | bits
+        ),
+    );
+    Ok(())
+}
+
+fn dbus_policy_dir() -> Option<PathBuf> {
+    DBusPolicyBuilder::find_datadir()
+        .ok()
+        .map(|datadir| datadir.join("system.d/rootasrole"))
+}
+
+fn polkit_rules_dir() -> Option<PathBuf> {
+    resolve_config_dir(
+        "POLKIT_DATA_DIR",
+        "/usr/share/polkit-1".into(),
+        "/etc/polkit-1".into(),
+    )
+    .ok()
+    .map(|datadir| datadir.join("rules.d"))
+}
+
+fn is_polkit_file(name: &str) -> bool {
+    name.starts_with("rootasrole") && (name.ends_with(".json") || name.ends_with(".js"))
+}
+
+/// Files holding the deployed dbus and polkit policies
+pub(crate) fn policy_files() -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    if let Some(Ok(entries)) = dbus_policy_dir().map(fs::read_dir) {
+        for entry in entries {
+            files.push(entry?.path());
+        }
+    }
+    // Every namespace has its own policy and rule
+    if let Some(Ok(entries)) = polkit_rules_dir().map(fs::read_dir) {
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(is_polkit_file)
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether the path is one `policy_files` would list, directly in the dbus policy
+/// directory or a rootasrole rule of the polkit rules directory
+pub(crate) fn is_policy_file(path: &Path) -> bool {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str()))
+    else {
+        return false;
+    };
+    if !path.is_absolute() || name.starts_with('.') {
+        return false;
+    }
+    dbus_policy_dir().is_some_and(|dir| dir == parent)
+        || (is_polkit_file(name) && polkit_rules_dir().is_some_and(|dir| dir == parent))
+}
+
+/// Include the restored dbus policies in the system bus configuration and reload it
+pub(crate) fn reload_dbus_policies() -> io::Result<()> {
+    let builder = DBusPolicyBuilder::new();
+    builder.build()?;
+    builder.enforce()
+}
+
+fn describe_qualifier(qualifier: Qualifier) -> String {
+    match qualifier {
+        Qualifier::User(uid) => format!("user {}", uid),
+        Qualifier::Group(gid) => format!("group {}", gid),
+        qualifier => format!("{:?}", qualifier),
+    }
+}
+
+/// Add the permissions to the ACL entry, returning the bits that were not already granted
+fn set_acl<P: AsRef<Path>>(
+    qualifier: Qualifier,
+    path: P,
+    permissions: &str,
+) -> anyhow::Result<u32> {
     debug!(
-        "Setting {} ACL for user {} on path {}",
+        "Setting {} ACL for {} on path {}",
         permissions,
-        user,
+        describe_qualifier(qualifier),
         path.as_ref().display()
     );
     let mut acl = PosixACL::read_acl(&path)?;
-    let current = acl
-        .get(posix_acl::Qualifier::User(user.as_raw()))
-        .unwrap_or(0);
-    acl.set(
-        posix_acl::Qualifier::User(user.as_raw()),
-        current | str_to_permission(permissions)?,
-    );
+    let current = acl.get(qualifier).unwrap_or(0);
+    let new = current | str_to_permission(permissions)?;
+    acl.set(qualifier, new);
     acl.write_acl(&path)?;
-    Ok(())
+    auditlog::record(
+        "acl_set",
+        &path.as_ref().to_string_lossy(),
+        &format!(
+            "{}: {:o} -> {:o}",
+            describe_qualifier(qualifier),
+            current,
+            new
+        ),
+    );
+    Ok(new & !current)
 }
 
-fn del_acl<P: AsRef<Path>>(user: &Uid, path: P) -> anyhow::Result<()> {
+/// Strip the bits gensr added to the ACL entry, keeping the ones that predate it.
+/// The whole entry is removed when the added bits were not recorded
+fn del_acl<P: AsRef<Path>>(
+    qualifier: Qualifier,
+    path: P,
+    added: Option<u32>,
+) -> anyhow::Result<()> {
     let mut acl = PosixACL::read_acl(&path)?;
-    acl.remove(posix_acl::Qualifier::User(user.as_raw()));
+    let Some(previous) = acl.get(qualifier) else {
+        return Ok(());
+    };
+    let kept = added.map_or(0, |added| previous & !added);
+    if kept == 0 {
+        acl.remove(qualifier);
+    } else {
+        acl.set(qualifier, kept);
+    }
     acl.write_acl(&path)?;
+    auditlog::record(
+        "acl_remove",
+        &path.as_ref().to_string_lossy(),
+        &format!(
+            "{}: {:o} -> {}",
+            describe_qualifier(qualifier),
+            previous,
+            if kept == 0 {
+                "none".to_string()
+            } else {
+                format!("{:o}", kept)
+            }
+        ),
+    );
     Ok(())
 }
 
-pub(crate) fn setup_role_based_access(config: &Rc<RefCell<SConfig>>) -> io::Result<()> {
-    let mut builder = DBusPolicyBuilder::new();
+/// Where the role users come from
+#[derive(Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub(crate) enum UserBackend {
+    /// Create the missing role users locally
+    #[default]
+    Local,
+    /// Role users are managed centrally (SSSD, LDAP), only check they exist
+    NssOnly,
+}
+
+#[derive(Default)]
+pub(crate) struct DeployOptions {
+    /// Install auditd rules for every deployed role user
+    pub(crate) audit: bool,
+    /// Install fapolicyd trust entries for the binaries of each task
+    pub(crate) fapolicyd: bool,
+    /// SELinux user the role users are mapped to, when SELinux is enforcing
+    pub(crate) selinux_user: Option<String>,
+    /// Deny interactive logins (ssh, console) of the role users
+    pub(crate) deny_login: bool,
+    /// Do not create the role users, they are allocated at each invocation by `gensr ephemeral`
+    pub(crate) ephemeral_users: bool,
+    /// Compliance rules checked against every task before any change is made
+    pub(crate) compliance: Option<ComplianceRules>,
+    /// Whether missing role users are created locally
+    pub(crate) user_backend: UserBackend,
+    /// Number of tasks deployed concurrently
+    pub(crate) jobs: usize,
+    /// Redeploy every task, even the ones unchanged since the last deploy
+    pub(crate) full: bool,
+    /// Grant the files to a `rar-<role>` group holding the task users of the role
+    pub(crate) role_groups: bool,
+    /// Also add the users granted the role to its group
+    pub(crate) role_group_actors: bool,
+    /// Install the PAM hook recording each execution of a role
+    pub(crate) usage_hook: bool,
+    /// Ids the task users are allocated from, recorded in the state for later deploys
+    pub(crate) id_range: Option<IdRange>,
+    /// Namespace of the polkit policy, None for the default configuration
+    pub(crate) polkit_namespace: Option<String>,
+    /// Seconds the polkit check caches its decisions, not cached when None
+    pub(crate) polkit_cache_ttl: Option<u64>,
+    pub(crate) dbus_layout: DbusLayout,
+    /// Monitor-only deploy: the grants are set up and their violations audited, nothing
+    /// restricting the existing access paths is installed
+    pub(crate) shadow: bool,
+    /// Grant write on the directory of the files the tasks create, delete or rename
+    pub(crate) parent_write: bool,
+    /// How the secrets stored in the tasks are decrypted or resolved
+    pub(crate) secret_sources: SecretSources,
+}
+
+/// Locks of the system resources shared by the deploy workers
+#[derive(Default)]
+struct DeployLocks {
+    /// passwd, shadow and the subordinate id files
+    users: Mutex<()>,
+    /// PAM access list, polkit policy and SELinux login mappings, rewritten as a whole
+    shared_files: Mutex<()>,
+    /// The ACL of a path is read, modified and written back
+    paths: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl DeployLocks {
+    fn path(&self, path: &str) -> Arc<Mutex<()>> {
+        self.paths
+            .lock()
+            .unwrap()
+            .entry(path.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+/// What a deploy worker needs from a task, detached from the shared configuration
+struct TaskDeployment {
+    role: String,
+    username: String,
+    cred: SCredentials,
+    binaries: Vec<PathBuf>,
+    /// `encrypted_set` and `secret_refs` blocks of the task
+    secrets: Map<String, Value>,
+    /// Hash of everything deploying the task depends on
+    fingerprint: String,
+}
+
+impl TaskDeployment {
+    fn new(role: &str, task: &STask, options: &DeployOptions) -> io::Result<Self> {
+        let username = task_username(role, task);
+        let cred = params::expand_files(task)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let binaries = if options.fapolicyd || options.shadow {
+            fapolicyd::task_binaries(task)
+        } else {
+            Vec::new()
+        };
+        let secrets = secrets::task_secrets(task);
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(
+            serde_json::json!({
+                "username": username,
+                "cred": cred,
+                "binaries": binaries,
+                "secrets": secrets,
+                "audit": options.audit,
+                "fapolicyd": options.fapolicyd,
+                "selinux_user": options.selinux_user,
+                "deny_login": options.deny_login,
+                "ephemeral_users": options.ephemeral_users,
+                "nss_only": options.user_backend == UserBackend::NssOnly,
+                "role_groups": options.role_groups,
+                "role_group_actors": options.role_group_actors,
+                "shadow": options.shadow,
+                "parent_write": options.parent_write,
+                "usage_hook": options.usage_hook,
+            })
+            .to_string(),
+        );
+        Ok(TaskDeployment {
+            role: role.to_string(),
+            username,
+            cred,
+            binaries,
+            secrets,
+            fingerprint: hex::encode(hasher.finalize()),
+        })
+    }
+
+    /// Whether the last deploy already set the task up as it is now, and its user still exists
+    fn is_unchanged(&self, state: &DeployState, options: &DeployOptions) -> bool {
+        !options.full
+            && state.tasks.get(&self.username) == Some(&self.fingerprint)
+            && (options.ephemeral_users
+                || User::from_name(&self.username).is_ok_and(|u| u.is_some()))
+    }
+}
+
+/// Every task of the configuration, as deploy sets them up
+fn task_deployments(
+    config: &Rc<RefCell<SConfig>>,
+    options: &DeployOptions,
+) -> io::Result<Vec<TaskDeployment>> {
+    let mut deployments = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            deployments.push(TaskDeployment::new(
+                &role.name,
+                &task.as_ref().borrow(),
+                options,
+            )?);
+        }
+    }
+    Ok(deployments)
+}
+
+/// Writers of the deployed integrations, shared by the deploy workers
+struct Writers {
+    audit: AuditRulesWriter,
+    trust: FapolicydTrustWriter,
+    pam: PamAccessWriter,
+}
+
+/// User a task is deployed for, under which deploy records its grants and undeploy finds them
+pub(crate) fn task_username(role: &str, task: &STask) -> String {
+    format!("{}-{}", role, &task.name)
+}
+
+/// Credentials of the task as deploy granted them, the task itself when its parameters
+/// could not be expanded, in which case nothing was granted
+fn deployed_cred(task: &STask) -> SCredentials {
+    params::expand_files(task).unwrap_or_else(|_| task.cred.clone())
+}
+
+fn task_files(cred: &SCredentials) -> Vec<(String, String)> {
+    cred._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+        .map(|files| {
+            files
+                .iter()
+                .map(|(path, access)| (path.clone(), access.as_str().unwrap_or("").to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Every system change a deploy or undeploy is about to make
+pub(crate) struct ChangeSummary {
+    action: &'static str,
+    /// Users the action applies to, only those not existing yet for a deploy
+    users: Vec<String>,
+    /// Existing users whose grants are deployed again
+    updated: Vec<String>,
+    acl_entries: Vec<(String, String)>,
+    files: Vec<PathBuf>,
+    notes: Vec<String>,
+}
+
+impl std::fmt::Display for ChangeSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{} user(s) will be {}:", self.users.len(), self.action)?;
+        for user in &self.users {
+            writeln!(f, "    {}", user)?;
+        }
+        if !self.updated.is_empty() {
+            writeln!(
+                f,
+                "{} existing user(s) will be updated:",
+                self.updated.len()
+            )?;
+            for user in &self.updated {
+                writeln!(f, "    {}", user)?;
+            }
+        }
+        writeln!(f, "{} ACL entries will be changed:", self.acl_entries.len())?;
+        for (path, access) in &self.acl_entries {
+            writeln!(f, "    {} {}", access, path)?;
+        }
+        writeln!(
+            f,
+            "{} file(s) will be written or removed:",
+            self.files.len()
+        )?;
+        for file in &self.files {
+            writeln!(f, "    {}", file.display())?;
+        }
+        for note in &self.notes {
+            writeln!(f, "{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl ChangeSummary {
+    /// Number of users the changes apply to, created or updated
+    pub(crate) fn affected(&self) -> usize {
+        self.users.len() + self.updated.len()
+    }
+
+    /// One-line account of the changes, once applied
+    pub(crate) fn headline(&self) -> String {
+        format!(
+            "{} user(s) {}, {} updated, {} ACL entries and {} file(s) changed",
+            self.users.len(),
+            self.action,
+            self.updated.len(),
+            self.acl_entries.len(),
+            self.files.len()
+        )
+    }
+}
+
+pub(crate) fn deploy_summary(
+    config: &Rc<RefCell<SConfig>>,
+    options: &DeployOptions,
+) -> io::Result<ChangeSummary> {
+    let mut summary = ChangeSummary {
+        action: "created",
+        users: Vec::new(),
+        updated: Vec::new(),
+        acl_entries: Vec::new(),
+        files: Vec::new(),
+        notes: Vec::new(),
+    };
+    let dbus_datadir = DBusPolicyBuilder::find_datadir().ok();
+    let state = DeployState::load()?;
+    let mut unchanged = 0;
+    for deployment in task_deployments(config, options)? {
+        if deployment.is_unchanged(&state, options) {
+            unchanged += 1;
+            continue;
+        }
+        if let Some(datadir) = dbus_datadir
+            .as_ref()
+            .filter(|_| options.dbus_layout == DbusLayout::PerUser)
+        {
+            summary
+                .files
+                .push(datadir.join(format!("system.d/rootasrole/{}.conf", deployment.username)));
+        }
+        summary.acl_entries.extend(task_files(&deployment.cred));
+        // Only the users useradd creates are counted as created
+        if !options.ephemeral_users && User::from_name(&deployment.username)?.is_some() {
+            summary.updated.push(deployment.username);
+        } else {
+            summary.users.push(deployment.username);
+        }
+    }
+    if unchanged > 0 {
+        summary.notes.push(format!(
+            "{} task(s) unchanged since the last deploy will be left untouched",
+            unchanged
+        ));
+    }
+    if options.shadow {
+        summary.notes.push(
+            "Shadow mode: the tasks are monitor-only, their violations are audited".to_string(),
+        );
+    }
+    if let Some(datadir) = dbus_datadir
+        .as_ref()
+        .filter(|_| options.dbus_layout == DbusLayout::Consolidated)
+    {
+        summary
+            .files
+            .push(datadir.join("system.d/rootasrole").join(CONSOLIDATED_FILE));
+    }
+    summary
+        .files
+        .push(PolkitPolicyWorker::new(options.polkit_namespace.as_deref()).get_policy_file_path());
+    if let Some(datadir) = &dbus_datadir {
+        summary.notes.push(format!(
+            "{} will include the rootasrole dbus policy folder",
+            datadir.join("system.conf").display()
+        ));
+    }
+    if options.ephemeral_users {
+        summary.action = "registered as ephemeral";
+    }
+    if options.role_groups {
+        summary.notes.push(
+            "rar-<role> groups will hold the task users and be granted their files".to_string(),
+        );
+    }
+    if options.audit {
+        summary
+            .notes
+            .push("auditd rules will be installed and reloaded".to_string());
+    }
+    if options.fapolicyd {
+        summary
+            .notes
+            .push("fapolicyd trust entries will be installed".to_string());
+    }
+    if options.deny_login {
+        summary
+            .notes
+            .push("PAM login services will deny the role users".to_string());
+    }
+    if let Some(seuser) = &options.selinux_user {
+        summary.notes.push(format!(
+            "Role users will be mapped to SELinux user {}",
+            seuser
+        ));
+    }
+    Ok(summary)
+}
+
+pub(crate) fn undeploy_summary(config: &Rc<RefCell<SConfig>>) -> io::Result<ChangeSummary> {
+    let mut summary = ChangeSummary {
+        action: "deleted with their home directory",
+        users: Vec::new(),
+        updated: Vec::new(),
+        acl_entries: Vec::new(),
+        files: Vec::new(),
+        notes: Vec::new(),
+    };
+    if let Ok(datadir) = DBusPolicyBuilder::find_datadir() {
+        summary.files.push(datadir.join("system.d/rootasrole"));
+    }
+    let state = DeployState::load()?;
     for role in &config.as_ref().borrow().roles {
         let role = role.as_ref().borrow();
-        let r_name = &role.name;
         for task in &role.tasks {
             let task = task.as_ref().borrow();
-            let username = format!("{}-{}", r_name, &task.name);
-            let user = useradd(&username)?;
-            let cred = &task.cred;
-            deploy_acl(cred, user)?;
-            deploy_dbus(cred, &mut builder, &username)?;
-            deploy_polkit(cred, &username)?;
+            let username = task_username(&role.name, &task);
+            let user = User::from_name(&username).ok().flatten();
+            if !state.tasks.contains_key(&username) && !is_owned(user.as_ref()) {
+                continue;
+            }
+            summary
+                .acl_entries
+                .extend(task_files(&deployed_cred(&task)));
+            if !is_owned(user.as_ref()) {
+                summary.notes.push(format!(
+                    "{} is not managed by gensr and will be kept",
+                    username
+                ));
+                continue;
+            }
+            summary.users.push(username.clone());
+            // userdel -r removes the home directory along with the user
+            if let Some(user) = user {
+                if is_local_user(&username) && user.dir.exists() {
+                    summary.files.push(user.dir);
+                }
+            }
         }
     }
+    Ok(summary)
+}
+
+/// Ask the operator to confirm a single change
+fn confirm(prompt: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(matches!(input.trim(), "y" | "Y" | "yes"))
+}
+
+fn check_compliance(config: &Rc<RefCell<SConfig>>, rules: &ComplianceRules) -> io::Result<()> {
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            compliance::enforce(
+                &rules.check_task(&task),
+                &compliance::task_waivers(&task),
+                &format!("Task {} of role {}", task.name, role.name),
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn setup_role_based_access(
+    config: &Rc<RefCell<SConfig>>,
+    options: &DeployOptions,
+) -> io::Result<()> {
+    if let Some(rules) = &options.compliance {
+        check_compliance(config, rules)?;
+    }
+    let mut builder = DBusPolicyBuilder::with_layout(options.dbus_layout);
+    let audit = AuditRulesWriter::new();
+    let trust = FapolicydTrustWriter::new();
+    let pam = PamAccessWriter::new();
+    if options.fapolicyd && !trust.is_available() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "fapolicyd does not seem to be installed on this system",
+        ));
+    }
+    if options.usage_hook {
+        telemetry::install_hook()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    let mut state = DeployState::load()?;
+    if let Some(range) = options.id_range {
+        state.id_range = Some(range);
+    }
+    let all = task_deployments(config, options)?;
+    // Users of the state still hold the ACLs of their uid until undeployed
+    if options.ephemeral_users {
+        ephemeral::check_collisions(
+            all.iter()
+                .map(|deployment| deployment.username.as_str())
+                .chain(state.tasks.keys().map(String::as_str)),
+        )?;
+    }
+    for username in state.tasks.keys() {
+        if !all
+            .iter()
+            .any(|deployment| &deployment.username == username)
+        {
+            warn!(
+                "{} was deployed but is no longer in the configuration, undeploy it to remove it",
+                username
+            );
+        }
+    }
+    let (unchanged, deployments): (Vec<_>, Vec<_>) = all
+        .into_iter()
+        .partition(|deployment| deployment.is_unchanged(&state, options));
+    let promoted = !options.shadow
+        && deployments
+            .iter()
+            .any(|deployment| state.shadow.contains(&deployment.username));
+    if !unchanged.is_empty() {
+        info!(
+            "{} task(s) unchanged since the last deploy, use --full to redeploy them",
+            unchanged.len()
+        );
+    }
+    if deployments.is_empty() {
+        return Ok(());
+    }
+    let role_groups = if options.role_groups {
+        deploy_role_groups(config, options, &mut state)?
+    } else {
+        HashMap::new()
+    };
+    let writers = Writers { audit, trust, pam };
+    let locks = DeployLocks::default();
+    let queue = Mutex::new(deployments.iter().enumerate());
+    let dbus_users = Mutex::new(vec![None; deployments.len()]);
+    let failed = AtomicBool::new(false);
+    let shared_state = Mutex::new(state);
+    let outcome = thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.jobs.clamp(1, deployments.len().max(1)))
+            .map(|_| {
+                scope.spawn(|| -> io::Result<()> {
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((i, deployment)) = queue.lock().unwrap().next() else {
+                            break;
+                        };
+                        match deploy_task(
+                            deployment,
+                            options,
+                            &writers,
+                            &locks,
+                            &role_groups,
+                            &shared_state,
+                        ) {
+                            Ok(dbus_user) => dbus_users.lock().unwrap()[i] = Some(dbus_user),
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("deploy worker panicked"))
+    });
+    // Record what was set up even when a task failed, so it can be undeployed
+    let mut state = shared_state.into_inner().unwrap();
+    let dbus_users = dbus_users.into_inner().unwrap();
+    let hooked = !state.usage_hook.is_empty();
+    for (deployment, dbus_user) in deployments.iter().zip(&dbus_users) {
+        if dbus_user.is_some() {
+            state
+                .tasks
+                .insert(deployment.username.clone(), deployment.fingerprint.clone());
+            if options.usage_hook {
+                state.usage_hook.insert(deployment.username.clone());
+            } else {
+                state.usage_hook.remove(&deployment.username);
+            }
+        }
+    }
+    if hooked && state.usage_hook.is_empty() {
+        telemetry::remove_hook()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    state.save()?;
+    outcome?;
+    // The D-Bus policy is assembled from every task before being written at once
+    for (deployment, dbus_user) in deployments.iter().zip(dbus_users.iter().flatten()) {
+        deploy_dbus(&deployment.cred, &mut builder, dbus_user)?;
+    }
+    Metrics::record(|m| m.policies_deployed_total += deployments.len() as u64);
     builder.build()?;
     builder.enforce()?;
+    if options.polkit_namespace.is_some() || options.polkit_cache_ttl.is_some() {
+        // The rule of a namespace asks gensr to check its own policy
+        PolkitPolicyWorker::new(options.polkit_namespace.as_deref())
+            .with_cache_ttl(options.polkit_cache_ttl)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    if options.polkit_cache_ttl.is_some_and(|ttl| ttl > 0) {
+        polkitcache::prepare_dir()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    if options.audit || promoted {
+        AuditRulesWriter::reload()?;
+    }
+    if options.fapolicyd {
+        FapolicydTrustWriter::reload()?;
+    }
+    if options.deny_login {
+        writers.pam.build()?;
+    }
     Ok(())
 }
 
-pub(crate) fn remove_role_based_access(config: &Rc<RefCell<SConfig>>) -> io::Result<()> {
-    let dbus_policy_file = DBusPolicyBuilder::new().rootasrole_folder();
-    fs::remove_dir_all(dbus_policy_file)?;
-    let polkit_policy = PolkitPolicyWorker::new();
+/// Create the user of a task and apply its grants, returning the user name D-Bus refers to
+fn deploy_task(
+    deployment: &TaskDeployment,
+    options: &DeployOptions,
+    writers: &Writers,
+    locks: &DeployLocks,
+    role_groups: &HashMap<String, Gid>,
+    state: &Mutex<DeployState>,
+) -> io::Result<String> {
+    let TaskDeployment {
+        role,
+        username,
+        cred,
+        binaries,
+        secrets,
+        ..
+    } = deployment;
+    // dbus resolves user names when loading its policies, ephemeral users are referenced by uid
+    let (uid, dbus_user) = if options.ephemeral_users {
+        let uid = ephemeral::uid(username);
+        if let Some(user) = User::from_uid(uid)?.filter(|user| &user.name != username) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "UID {} of ephemeral user {} is already used by {}",
+                    uid, username, user.name
+                ),
+            ));
+        }
+        (uid, uid.to_string())
+    } else {
+        let _users = locks.users.lock().unwrap();
+        (
+            useradd(username, options.user_backend, &mut state.lock().unwrap())?.uid,
+            username.clone(),
+        )
+    };
+    // Changed since the last deploy, what it granted then is diffed against the new grants
+    let redeployed = state.lock().unwrap().tasks.contains_key(username);
+    // A shadow deployed task deployed without --shadow is enforced from now on
+    let promoted = !options.shadow && state.lock().unwrap().shadow.remove(username);
+    if options.audit {
+        // The legacy path of a shadow task runs its binaries as root
+        let shadow = options.shadow.then_some(binaries.as_slice());
+        deploy_audit(cred, &writers.audit, role, username, &uid, shadow)?;
+    } else if promoted {
+        writers.audit.remove_rules(username)?;
+    }
+    if options.shadow {
+        state.lock().unwrap().shadow.insert(username.clone());
+    }
+    if options.fapolicyd {
+        writers.trust.add_trust(username, binaries)?;
+    }
+    if !secrets.is_empty() {
+        let values = options
+            .secret_sources
+            .resolve(secrets, username)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        secrets::write_env_file(uid, &values)?;
+    } else if redeployed {
+        secrets::remove_env_file(uid)?;
+    }
+    if let Some(seuser) = &options.selinux_user {
+        if options.ephemeral_users {
+            warn!(
+                "Ephemeral user {} cannot be mapped to an SELinux user",
+                username
+            );
+        } else {
+            let _shared_files = locks.shared_files.lock().unwrap();
+            deploy_selinux(cred, seuser, username, &uid)?;
+        }
+    }
+    if options.deny_login {
+        let _shared_files = locks.shared_files.lock().unwrap();
+        writers.pam.deny_login(username)?;
+    }
+    if subid::needs_subids(cred) {
+        let _users = locks.users.lock().unwrap();
+        SubIdFile::subuid().allocate(username)?;
+        SubIdFile::subgid().allocate(username)?;
+    }
+    let qualifier = match role_groups.get(role) {
+        Some(gid) if !options.ephemeral_users => {
+            let _users = locks.users.lock().unwrap();
+            add_to_group(username, &role_group_name(role))?;
+            Qualifier::Group(gid.as_raw())
+        }
+        _ => Qualifier::User(uid.as_raw()),
+    };
+    if redeployed {
+        revoke_stale_acls(
+            cred,
+            options.parent_write,
+            qualifier,
+            username,
+            locks,
+            state,
+        )?;
+    }
+    deploy_acl(
+        cred,
+        options.parent_write,
+        qualifier,
+        username,
+        locks,
+        state,
+    )?;
+    {
+        let _shared_files = locks.shared_files.lock().unwrap();
+        if redeployed {
+            // The grants of the previous deploy are replaced, not added to
+            PolkitPolicyWorker::new(options.polkit_namespace.as_deref())
+                .del_policy(username)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            if !cred._extra_fields.contains_key("dbus") {
+                remove_dbus_policy(&dbus_user)?;
+            }
+        }
+        deploy_polkit(cred, username, options.polkit_namespace.as_deref())?;
+    }
+    Ok(dbus_user)
+}
+
+/// Release the ACL entries a previous deploy of the task set up that its grants no longer
+/// need, and the bits they no longer need of the others
+fn revoke_stale_acls(
+    cred: &SCredentials,
+    parent_write: bool,
+    qualifier: Qualifier,
+    username: &str,
+    locks: &DeployLocks,
+    state: &Mutex<DeployState>,
+) -> Result<(), Error> {
+    let key = qualifier_key(qualifier);
+    let mut grants = HashMap::new();
+    for (path, permission) in fileops::task_grants(cred, parent_write) {
+        let bits = str_to_permission(&permission.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        grants.insert(mounts::canonical(&path), bits);
+    }
+    let held = state.lock().unwrap().held_acls(username);
+    for (path, entry) in held {
+        let entry_qualifier = parse_qualifier_key(&entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let lock = locks.path(&path);
+        let _path = lock.lock().unwrap();
+        let mut state = state.lock().unwrap();
+        if let Some(bits) = grants.get(&path).filter(|_| entry == key) {
+            let excess = state.narrow_acl(&path, &entry, username, *bits);
+            if excess != 0 {
+                del_acl(entry_qualifier, &path, Some(excess))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            }
+            continue;
+        }
+        match state.release_acl(&path, &entry, username) {
+            AclRelease::Shared => debug!(
+                "Keeping the {} ACL entry of {}, still used by other tasks",
+                describe_qualifier(entry_qualifier),
+                path
+            ),
+            AclRelease::Last(added) => del_acl(entry_qualifier, &path, added)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+        }
+    }
+    Ok(())
+}
+
+/// Remove what deploy set up, confirming each role user first when interactive
+pub(crate) fn remove_role_based_access(
+    config: &Rc<RefCell<SConfig>>,
+    interactive: bool,
+    polkit_namespace: Option<&str>,
+) -> io::Result<()> {
+    let polkit_policy = PolkitPolicyWorker::new(polkit_namespace);
+    let audit = AuditRulesWriter::new();
+    let mut audit_changed = false;
+    let trust = FapolicydTrustWriter::new();
+    let mut trust_changed = false;
+    let pam = PamAccessWriter::new();
+    let mut state = DeployState::load()?;
+    let mut hook_released = false;
+    // Roles with a task kept by the operator, whose group its task user still relies on
+    let mut kept_roles = HashSet::new();
     for role in &config.as_ref().borrow().roles {
         let role = role.as_ref().borrow();
         for task in &role.tasks {
             let task = task.as_ref().borrow();
-            let creds = &task.cred;
-            match creds.setuid.as_ref() {
-                Some(SActorType::Name(username)) => {
-                    if username.starts_with("rar_") || username.starts_with("gsr_") {
-                        let user = User::from_name(username).unwrap().unwrap();
-                        polkit_policy
-                            .del_policy(username)
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                        remove_acl(creds, user)?;
-                        userdel(username)?;
-                    }
+            let creds = &deployed_cred(&task);
+            // The same name deploy recorded the grants under
+            let username = task_username(&role.name, &task);
+            let username = username.as_str();
+            let user = User::from_name(username)?;
+            let owned = is_owned(user.as_ref());
+            if !state.tasks.contains_key(username) && !owned {
+                continue;
+            }
+            if interactive
+                && !confirm(&format!(
+                    "Remove role user {} of task {}, its {} ACL entries{}?",
+                    username,
+                    task.name,
+                    task_files(creds).len(),
+                    user.as_ref()
+                        .filter(|user| is_local_user(username) && user.dir.exists())
+                        .map(|user| format!(" and its home {}", user.dir.display()))
+                        .unwrap_or_default()
+                ))?
+            {
+                kept_roles.insert(role.name.clone());
+                continue;
+            }
+            let uid = user
+                .as_ref()
+                .map_or_else(|| ephemeral::uid(username), |user| user.uid);
+            polkit_policy
+                .del_policy(username)
+                .and_then(|_| polkit_policy.migrate_legacy(username))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            remove_acl(creds, Qualifier::User(uid.as_raw()), username, &mut state)?;
+            // Deploy refers to ephemeral users by uid in their dbus policy
+            if user.is_none() || uid == ephemeral::uid(username) {
+                remove_dbus_policy(&uid.to_string())?;
+            } else {
+                remove_dbus_policy(username)?;
+            }
+            secrets::remove_env_file(uid)?;
+            if !owned {
+                warn!(
+                    "Not deleting {}: the user does not carry the gensr ownership marker",
+                    username
+                );
+            } else if user.is_some() {
+                userdel(username)?;
+            }
+            audit_changed |= audit.remove_rules(username)?;
+            trust_changed |= trust.remove_trust(username)?;
+            if selinux::is_enforcing() {
+                selinux::unmap_login(username)?;
+            }
+            pam.allow_login(username)?;
+            SubIdFile::subuid().release(username)?;
+            SubIdFile::subgid().release(username)?;
+            state.tasks.remove(username);
+            state.shadow.remove(username);
+            state.ids.remove(username);
+            hook_released |= state.usage_hook.remove(username);
+        }
+    }
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        let name = role_group_name(&role.name);
+        if !state.groups.contains(&name) {
+            continue;
+        }
+        if kept_roles.contains(&role.name) {
+            info!(
+                "Keeping role group {}, a task user of the role was kept",
+                name
+            );
+            continue;
+        }
+        let group = Group::from_name(&name)?;
+        if interactive
+            && !confirm(&format!(
+                "Remove role group {}{}?",
+                name,
+                if group.is_some() {
+                    " and its ACL entries"
+                } else {
+                    ""
                 }
-                _ => {}
+            ))?
+        {
+            continue;
+        }
+        if let Some(group) = group {
+            for task in &role.tasks {
+                let task = task.as_ref().borrow();
+                remove_acl(
+                    &deployed_cred(&task),
+                    Qualifier::Group(group.gid.as_raw()),
+                    &task_username(&role.name, &task),
+                    &mut state,
+                )?;
             }
+            Command::new("groupdel").arg(&name).status()?;
+            auditlog::record("group_delete", &name, "role group created by gensr");
         }
+        state.groups.remove(&name);
+    }
+    polkit_policy
+        .clean()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let dbus_policy_folder = DBusPolicyBuilder::new().rootasrole_folder();
+    if fs::remove_dir(&dbus_policy_folder).is_err() {
+        warn!(
+            "{} still holds policies not created by gensr, keeping it",
+            dbus_policy_folder.display()
+        );
+    }
+    if audit_changed {
+        AuditRulesWriter::reload()?;
     }
+    if trust_changed {
+        FapolicydTrustWriter::reload()?;
+    }
+    pam.clean()?;
+    // The hook stays as long as a deployed task relies on it, of this configuration or not
+    if state.usage_hook.is_empty() && (hook_released || state.tasks.is_empty()) {
+        telemetry::remove_hook()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    state.save()?;
     Ok(())
 }
 //
 pub(crate) fn enforce_policy(username: &str, policy: &Policy) -> anyhow::Result<()> {
-    let user = useradd(username)?;
+    let mut state = DeployState::load()?;
+    let user = useradd(username, UserBackend::Local, &mut state)?;
+    let qualifier = Qualifier::User(user.uid.as_raw());
     for (path, permission) in &policy.files {
-        set_acl(&user.uid, path, &permission.to_string())?;
+        for path in fileops::expand(path) {
+            let path = mounts::canonical(&path);
+            let added = set_acl(qualifier, &path, &permission.to_string())?;
+            state.record_acl(&path, &qualifier_key(qualifier), username, added);
+        }
     }
-    let dbus_vec = policy
-        .dbus
-        .iter()
-        .map(|s| s.as_str())
-        .collect::<Vec<&str>>();
+    state.save()?;
+    let dbus_vec = policy.dbus.iter().cloned().collect::<Vec<DbusRule>>();
     let mut builder = DBusPolicyBuilder::new();
     builder.add_policy(username, &dbus_vec)?;
     //polkit for loop
     builder.build()?;
-    let worker = PolkitPolicyWorker::new();
-    worker.add_policy(username, &dbus_vec)?;
+    let worker = PolkitPolicyWorker::new(None);
+    let destinations = dbus_vec
+        .iter()
+        .map(|rule| rule.destination.as_str())
+        .collect::<Vec<&str>>();
+    worker.add_policy(username, &destinations)?;
     worker.build()?;
     Ok(())
 }
@@ -380,53 +1725,316 @@ pub(crate) fn enforce_policy(username: &str, policy: &Policy) -> anyhow::Result<
 pub(crate) fn remove_policy(username: &str, policy: &Policy) -> anyhow::Result<()> {
     let user = User::from_name(username)?
         .expect(format!("User {} wasn't created correctly", username).as_str());
-    for (path, _) in &policy.files {
-        del_acl(&user.uid, path)?;
+    let qualifier = Qualifier::User(user.uid.as_raw());
+    let mut state = DeployState::load()?;
+    for path in policy.files.keys().flat_map(|path| fileops::expand(path)) {
+        let path = mounts::canonical(&path);
+        if let AclRelease::Last(added) =
+            state.release_acl(&path, &qualifier_key(qualifier), username)
+        {
+            del_acl(qualifier, &path, added)?;
+        }
     }
-    userdel(username)?;
-    let dbus_policy_file = DBusPolicyBuilder::new().rootasrole_folder();
-    if dbus_policy_file.join(format!("{}.conf", username)).exists() {
-        fs::remove_file(dbus_policy_file.join(format!("{}.conf", username)))?;
+    state.save()?;
+    if has_owner_marker(&user) {
+        userdel(username)?;
     }
-    let worker = PolkitPolicyWorker::new();
+    remove_dbus_policy(username)?;
+    let worker = PolkitPolicyWorker::new(None);
     worker.del_policy(username)?;
     Ok(())
 }
 
+/// GECOS comment of the users created by gensr
+const USER_MARKER: &str = "gensr role user";
+
+/// First line of the dbus policies written by gensr
+const DBUS_MARKER: &str = "<!-- Managed by gensr -->";
+
+fn has_owner_marker(user: &User) -> bool {
+    user.gecos.to_str().is_ok_and(|gecos| gecos == USER_MARKER)
+}
+
+/// Whether gensr created the role user. A generated task does not make its user gensr's:
+/// an account of the same name may predate it
+fn is_owned(user: Option<&User>) -> bool {
+    user.is_some_and(has_owner_marker)
+}
+
+/// Remove the dbus policy of a user, if gensr wrote it
+fn remove_dbus_policy(username: &str) -> io::Result<()> {
+    let builder = DBusPolicyBuilder::new();
+    builder.remove_consolidated(username)?;
+    remove_dbus_file(
+        &builder
+            .rootasrole_folder()
+            .join(format!("{}.conf", username)),
+        username,
+    )
+}
+
+/// Remove the per-user dbus policy file, when gensr wrote it
+fn remove_dbus_file(path: &Path, username: &str) -> io::Result<()> {
+    match fs::read_to_string(path) {
+        Ok(contents) if contents.contains(DBUS_MARKER) => {
+            fs::remove_file(path)?;
+            auditlog::record(
+                "dbus_policy_remove",
+                &path.to_string_lossy(),
+                &format!("removed dbus policy of {}", username),
+            );
+        }
+        Ok(_) => warn!(
+            "Not removing {}, it was not written by gensr",
+            path.display()
+        ),
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+/// Whether the user is a local account, rather than provided by SSSD or LDAP through NSS
+fn is_local_user(username: &str) -> bool {
+    fs::read_to_string("/etc/passwd").is_ok_and(|passwd| {
+        passwd
+            .lines()
+            .any(|line| line.split(':').next() == Some(username))
+    })
+}
+
+fn role_group_name(role: &str) -> String {
+    format!("rar-{}", role)
+}
+
+fn groupadd(name: &str, backend: UserBackend, gid: Option<u32>) -> io::Result<Group> {
+    if let Some(group) = Group::from_name(name)? {
+        return Ok(group);
+    }
+    if backend == UserBackend::NssOnly {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Group {} is not provided by the directory, create it there first",
+                name
+            ),
+        ));
+    }
+    let mut command = Command::new("/usr/sbin/groupadd");
+    if let Some(gid) = gid {
+        command.arg("-g").arg(gid.to_string());
+    }
+    let status = command.arg("-r").arg(name).status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Failed to create group {}: groupadd exited with {}",
+                name, status
+            ),
+        ));
+    }
+    auditlog::record("group_create", name, "system group of the role users");
+    Group::from_name(name)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Group {} was not created", name),
+        )
+    })
+}
+
+fn add_to_group(username: &str, group: &str) -> io::Result<()> {
+    if Group::from_name(group)?.is_some_and(|g| g.mem.iter().any(|member| member == username)) {
+        return Ok(());
+    }
+    let status = Command::new("usermod")
+        .args(["-a", "-G", group, username])
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Failed to add {} to group {}: usermod exited with {}",
+                username, group, status
+            ),
+        ));
+    }
+    auditlog::record("group_member_add", group, &format!("added {}", username));
+    Ok(())
+}
+
+/// Create the group of each role, adding the users granted the role when asked,
+/// and remember the groups gensr created
+fn deploy_role_groups(
+    config: &Rc<RefCell<SConfig>>,
+    options: &DeployOptions,
+    state: &mut DeployState,
+) -> io::Result<HashMap<String, Gid>> {
+    let mut groups = HashMap::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        let name = role_group_name(&role.name);
+        let existed = Group::from_name(&name)?.is_some();
+        let group = groupadd(&name, options.user_backend, None)?;
+        if !existed {
+            state.groups.insert(name.clone());
+        }
+        if options.role_group_actors {
+            for actor in &role.actors {
+                let user = match actor {
+                    SActor::User {
+                        id: Some(SActorType::Name(name)),
+                        ..
+                    } => User::from_name(name)?,
+                    SActor::User {
+                        id: Some(SActorType::Id(uid)),
+                        ..
+                    } => User::from_uid(Uid::from_raw(*uid))?,
+                    _ => None,
+                };
+                if let Some(user) = user {
+                    add_to_group(&user.name, &name)?;
+                }
+            }
+        }
+        groups.insert(role.name.clone(), group.gid);
+    }
+    Ok(groups)
+}
+
 fn userdel(username: &str) -> Result<(), Error> {
+    if !is_local_user(username) {
+        debug!("User {} is not a local account, not deleting it", username);
+        return Ok(());
+    }
     Command::new("userdel").arg("-r").arg(username).status()?;
+    auditlog::record("user_delete", username, "deleted with its home directory");
     Ok(())
 }
 
-fn useradd(username: &str) -> Result<User, Error> {
+/// Whether a user or a group already holds the id
+fn id_taken(id: u32) -> bool {
+    User::from_uid(Uid::from_raw(id)).is_ok_and(|user| user.is_some())
+        || Group::from_gid(Gid::from_raw(id)).is_ok_and(|group| group.is_some())
+}
+
+fn useradd(username: &str, backend: UserBackend, state: &mut DeployState) -> Result<User, Error> {
     if let Some(user) = User::from_name(username)? {
         debug!("User {} already exists", username);
         Ok(user)
+    } else if backend == UserBackend::NssOnly {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "User {} is not provided by the directory, create it there first",
+                username
+            ),
+        ))
     } else {
-        let mut binding = Command::new("/usr/bin/useradd");
-        let c = binding
-            .arg("-r")
-            .arg("-M")
-            .arg("-s")
-            .arg("/bin/sh")
-            .arg(username)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
-        let output = c.output()?;
-        if !output.status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Failed to create user {}: {}",
-                    username,
-                    String::from_utf8_lossy(&output.stderr)
-                ),
-            ));
+        let id = state
+            .allocate_id(username, id_taken)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        if let Some(id) = id {
+            // The group of the user gets the same id, useradd would pick its own
+            groupadd(username, backend, Some(id))?;
+        }
+        create_user(username, id, id)
+    }
+}
+
+fn create_user(username: &str, uid: Option<u32>, gid: Option<u32>) -> Result<User, Error> {
+    let mut binding = Command::new("/usr/bin/useradd");
+    if let Some(uid) = uid {
+        binding.arg("-u").arg(uid.to_string());
+    }
+    if let Some(gid) = gid {
+        binding.arg("-g").arg(gid.to_string());
+    }
+    let c = binding
+        .arg("-r")
+        .arg("-M")
+        .arg("-c")
+        .arg(USER_MARKER)
+        .arg("-s")
+        .arg("/bin/sh")
+        .arg(username)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let output = c.output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Failed to create user {}: {}",
+                username,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    debug!("Creating user5 {}", username);
+    auditlog::record(
+        "user_create",
+        username,
+        "system user without home directory",
+    );
+    Ok(User::from_name(username)?
+        .expect(format!("User {} wasn't created correctly", username).as_str()))
+}
+
+/// Recreate a task user with the ids it had on another host
+///
+/// The user keeps the ids it already has on this host, and gets fresh ones when the
+/// recorded ids belong to someone else. Returns the user as it is on this host, so that
+/// the recorded ids can be mapped to it
+pub(crate) fn restore_user(username: &str, uid: u32, group: &str, gid: u32) -> io::Result<User> {
+    if let Some(user) = User::from_name(username)? {
+        if user.uid.as_raw() != uid || user.gid.as_raw() != gid {
+            warn!(
+                "User {} already exists as {}:{} instead of {}:{}, its ACL entries are remapped",
+                username, user.uid, user.gid, uid, gid
+            );
         }
-        debug!("Creating user5 {}", username);
-        Ok(User::from_name(username)?
-            .expect(format!("User {} wasn't created correctly", username).as_str()))
+        return Ok(user);
     }
+    let gid = if Group::from_gid(Gid::from_raw(gid))?.is_some_and(|g| g.name != group) {
+        warn!(
+            "Gid {} is taken on this host, group {} gets a new one",
+            gid, group
+        );
+        None
+    } else {
+        Some(gid)
+    };
+    let group = groupadd(group, UserBackend::Local, gid)?;
+    let uid = match User::from_uid(Uid::from_raw(uid))? {
+        Some(other) => {
+            warn!(
+                "Uid {} belongs to {} on this host, user {} gets a new one",
+                uid, other.name, username
+            );
+            None
+        }
+        None => Some(uid),
+    };
+    create_user(username, uid, Some(group.gid.as_raw()))
+}
+
+/// Recreate a role group with the gid and the members it had on another host, or a fresh
+/// gid when the recorded one belongs to another group. Returns the group on this host
+pub(crate) fn restore_group(name: &str, gid: u32, members: &[String]) -> io::Result<Group> {
+    let gid = if Group::from_gid(Gid::from_raw(gid))?.is_some_and(|g| g.name != name) {
+        warn!(
+            "Gid {} is taken on this host, group {} gets a new one",
+            gid, name
+        );
+        None
+    } else {
+        Some(gid)
+    };
+    let group = groupadd(name, UserBackend::Local, gid)?;
+    for member in members {
+        add_to_group(member, name)?;
+    }
+    Ok(group)
 }
 
 fn deploy_dbus(
@@ -434,82 +2042,141 @@ fn deploy_dbus(
     builder: &mut DBusPolicyBuilder,
     username: &str,
 ) -> io::Result<()> {
-    if let Some(dbus) = cred
-        ._extra_fields
-        .get("dbus")
-        .map(|value| value.as_array())
-        .flatten()
-    {
-        for object in dbus {
-            let permissions: Vec<&str> = object
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|v| v.as_str().unwrap())
-                .collect();
-            builder.add_policy(&username, &permissions)?;
-        }
+    if let Some(dbus) = cred._extra_fields.get("dbus") {
+        builder.add_policy(username, &rules::dbus_rules(dbus))?;
     }
     Ok(())
 }
 
-fn deploy_polkit(cred: &SCredentials, username: &str) -> io::Result<()> {
-    let worker = PolkitPolicyWorker::new();
-    if let Some(dbus) = cred
-        ._extra_fields
-        .get("dbus")
-        .map(|value| value.as_array())
-        .flatten()
-    {
-        for object in dbus {
-            let permissions: Vec<&str> = object
-                .as_array()
-                .unwrap()
-                .iter()
-                .map(|v| v.as_str().unwrap())
-                .collect();
-            worker.add_policy(&username, &permissions)?;
+fn deploy_polkit(cred: &SCredentials, username: &str, namespace: Option<&str>) -> io::Result<()> {
+    let worker = PolkitPolicyWorker::new(namespace);
+    worker
+        .migrate_legacy(username)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    if let Some(dbus) = cred._extra_fields.get("dbus") {
+        let rules = rules::dbus_rules(dbus);
+        let destinations: Vec<&str> = rules.iter().map(|r| r.destination.as_str()).collect();
+        worker.add_policy(username, &destinations)?;
+    }
+    Ok(())
+}
+
+fn deploy_acl(
+    cred: &SCredentials,
+    parent_write: bool,
+    qualifier: Qualifier,
+    username: &str,
+    locks: &DeployLocks,
+    state: &Mutex<DeployState>,
+) -> Result<(), Error> {
+    fileops::warn_unenforceable(cred);
+    for (path, permission) in fileops::task_grants(cred, parent_write) {
+        let file_path = mounts::canonical(&path);
+        if file_path != path {
+            debug!("Granting {} at its canonical location {}", path, file_path);
         }
+        let lock = locks.path(&file_path);
+        let _path = lock.lock().unwrap();
+        let added = set_acl(qualifier, &file_path, &permission.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        state
+            .lock()
+            .unwrap()
+            .record_acl(&file_path, &qualifier_key(qualifier), username, added);
     }
     Ok(())
 }
 
-fn deploy_acl(cred: &SCredentials, user: User) -> Result<(), Error> {
+fn deploy_audit(
+    cred: &SCredentials,
+    audit: &AuditRulesWriter,
+    role: &str,
+    username: &str,
+    uid: &Uid,
+    shadow: Option<&[PathBuf]>,
+) -> io::Result<()> {
+    let files: Vec<(&str, &str)> = cred
+        ._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+        .map(|files| {
+            files
+                .iter()
+                .map(|(path, permission)| (path.as_str(), permission.as_str().unwrap_or("")))
+                .collect()
+        })
+        .unwrap_or_default();
+    audit.add_rules(role, username, uid, &files, &alerts::alerted(cred), shadow)
+}
+
+fn deploy_selinux(cred: &SCredentials, seuser: &str, username: &str, uid: &Uid) -> io::Result<()> {
+    selinux::map_login(username, seuser)?;
     if let Some(files) = cred
         ._extra_fields
         .get("files")
-        .map(|value| value.as_object())
-        .flatten()
+        .and_then(|value| value.as_object())
     {
-        for (path, permission) in files {
-            let file_path = path.as_str();
-            let permission = permission.as_str().unwrap();
-            set_acl(&user.uid, file_path, permission)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        for path in files.keys() {
+            match selinux::file_context(path) {
+                Some(context) => debug!("{} has SELinux context {}", path, context),
+                None => warn!("Cannot read SELinux context of {}", path),
+            }
         }
     }
+    let denials = selinux::report_denials(username, uid)?;
+    if denials > 0 {
+        warn!(
+            "{} recent SELinux denial(s) for {}, the role may fail at runtime",
+            denials, username
+        );
+    }
     Ok(())
 }
 
-fn remove_acl(cred: &SCredentials, user: User) -> Result<(), Error> {
-    if let Some(files) = cred
-        ._extra_fields
-        .get("files")
-        .map(|value| value.as_object())
-        .flatten()
-    {
-        for (path, _) in files {
-            let file_path = path.as_str();
-            del_acl(&user.uid, file_path)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+fn remove_acl(
+    cred: &SCredentials,
+    qualifier: Qualifier,
+    username: &str,
+    state: &mut DeployState,
+) -> Result<(), Error> {
+    for path in fileops::granted_paths(cred) {
+        let file_path = mounts::canonical(&path);
+        match state.release_acl(&file_path, &qualifier_key(qualifier), username) {
+            AclRelease::Shared => debug!(
+                "Keeping the {} ACL entry of {}, still used by other tasks",
+                describe_qualifier(qualifier),
+                file_path
+            ),
+            AclRelease::Last(added) => del_acl(qualifier, &file_path, added)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
         }
     }
     Ok(())
 }
 
-pub(crate) fn check_polkit(user: &str, action: &str) -> io::Result<()> {
-    let worker = PolkitPolicyWorker::new();
-    match worker.check_policy(user, action) {
+pub(crate) fn check_polkit(
+    user: &str,
+    action: &str,
+    namespace: Option<&str>,
+    cache_ttl: Option<u64>,
+) -> io::Result<()> {
+    let worker = PolkitPolicyWorker::new(namespace);
+    let mut cache = cache_ttl
+        .filter(|ttl| *ttl > 0)
+        .map(|ttl| PolkitCache::load(&worker.get_policy_file_path(), Duration::from_secs(ttl)));
+    let decision = match cache.as_ref().and_then(|cache| cache.get(user, action)) {
+        Some(allowed) => Ok(allowed),
+        None => {
+            // Cache hits are meant to be cheap, only the checks reading the policy are counted
+            Metrics::record(|m| m.polkit_checks_total += 1);
+            worker.check_policy(user, action).inspect(|allowed| {
+                if let Some(cache) = cache.as_mut() {
+                    cache.insert(user, action, *allowed);
+                }
+            })
+        }
+    };
+    match decision {
         Ok(true) => Ok(()),
         Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
         Ok(false) => Err(io::Error::new(
@@ -518,3 +2185,89 @@ pub(crate) fn check_polkit(user: &str, action: &str) -> io::Result<()> {
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_policy_of_a_new_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let worker = PolkitPolicyWorker {
+            rules_folder: dir.path().to_path_buf(),
+            namespace: None,
+            cache_ttl: None,
+        };
+        worker
+            .add_policy("rar-backup", &["org.freedesktop.login1.reboot"])
+            .unwrap();
+        worker
+            .add_policy("rar-web", &["org.freedesktop.systemd1.manage-units"])
+            .unwrap();
+        worker
+            .add_policy("rar-backup", &["org.freedesktop.udisks2.filesystem-mount"])
+            .unwrap();
+        assert!(worker
+            .check_policy("rar-backup", "org.freedesktop.login1.reboot")
+            .unwrap());
+        assert!(worker
+            .check_policy("rar-backup", "org.freedesktop.udisks2.filesystem-mount")
+            .unwrap());
+        assert!(worker
+            .check_policy("rar-web", "org.freedesktop.systemd1.manage-units")
+            .unwrap());
+        assert!(!worker
+            .check_policy("rar-web", "org.freedesktop.login1.reboot")
+            .unwrap());
+    }
+
+    #[test]
+    fn polkit_namespace_given() {
+        assert_eq!(
+            polkit_namespace("/nonexistent", Some("staging_2-a".to_string())).unwrap(),
+            Some("staging_2-a".to_string())
+        );
+        for namespace in ["", "../etc", "a b", "prod.json"] {
+            assert!(polkit_namespace("/nonexistent", Some(namespace.to_string())).is_err());
+        }
+    }
+
+    #[test]
+    fn polkit_namespace_derived_from_the_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = dir.path().join("staging.json");
+        let production = dir.path().join("production.json");
+        fs::write(&staging, "{}").unwrap();
+        fs::write(&production, "{}").unwrap();
+        let namespace = polkit_namespace(staging.to_str().unwrap(), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(namespace.len(), 8);
+        assert!(namespace.chars().all(|c| c.is_ascii_hexdigit()));
+        let relative = dir.path().join(".").join("staging.json");
+        assert_eq!(
+            polkit_namespace(relative.to_str().unwrap(), None).unwrap(),
+            Some(namespace.clone())
+        );
+        assert_ne!(
+            polkit_namespace(production.to_str().unwrap(), None).unwrap(),
+            Some(namespace)
+        );
+    }
+
+    #[test]
+    fn rule_checks_the_subject_and_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let worker = PolkitPolicyWorker {
+            rules_folder: dir.path().to_path_buf(),
+            namespace: Some("staging".to_string()),
+            cache_ttl: Some(30),
+        };
+        worker.build().unwrap();
+        let rule = fs::read_to_string(dir.path().join("rootasrole-staging.js")).unwrap();
+        assert!(rule.contains(
+            "\"polkit\", \"--namespace\", \"staging\", \"--cache-ttl\", \"30\", \"--user\", subject.user, \"--action\", action.id]"
+        ));
+        assert!(rule.contains("polkit.Result.YES") && rule.contains("polkit.Result.NOT_HANDLED"));
+    }
+}
@@ -0,0 +1,43 @@
+use rootasrole_core::database::structs::STask;
+use serde::{Deserialize, Serialize};
+
+/// Business justification of a task, recorded in its `doc` block
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct Doc {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    /// Change or incident ticket the task was requested in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ticket: Option<String>,
+}
+
+impl Doc {
+    pub(crate) fn new(description: Option<String>, ticket: Option<String>) -> Option<Doc> {
+        (description.is_some() || ticket.is_some()).then_some(Doc {
+            description,
+            ticket,
+        })
+    }
+
+    /// The `doc` block of the task, if any
+    pub(crate) fn of(task: &STask) -> Option<Doc> {
+        serde_json::from_value(task._extra_fields.get("doc")?.clone()).ok()
+    }
+
+    pub(crate) fn apply(&self, task: &mut STask) -> anyhow::Result<()> {
+        task._extra_fields
+            .insert("doc".to_string(), serde_json::to_value(self)?);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Doc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.description, &self.ticket) {
+            (Some(description), Some(ticket)) => write!(f, "{} [{}]", description, ticket),
+            (Some(description), None) => write!(f, "{}", description),
+            (None, Some(ticket)) => write!(f, "[{}]", ticket),
+            (None, None) => Ok(()),
+        }
+    }
+}
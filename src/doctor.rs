@@ -0,0 +1,188 @@
+use std::{fs, io, path::Path, process::Command};
+
+use posix_acl::PosixACL;
+
+use crate::deploy::resolve_config_dir;
+
+/// Outcome of a single check, with a fix-it hint when it failed
+struct Check {
+    name: &'static str,
+    result: Result<String, (String, &'static str)>,
+}
+
+fn check_capable() -> Check {
+    let result = match which::which("capable") {
+        Ok(path) => {
+            let version = Command::new(&path)
+                .arg("--version")
+                .output()
+                .ok()
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+                .filter(|version| !version.is_empty())
+                .unwrap_or_else(|| "unknown version".to_string());
+            Ok(format!("{} ({})", path.display(), version))
+        }
+        Err(_) => Err((
+            "not found in PATH".to_string(),
+            "install capable from RootAsRole, or pass its location with --capable",
+        )),
+    };
+    Check {
+        name: "capable",
+        result,
+    }
+}
+
+fn check_bpf() -> Check {
+    let result = if !Path::new("/sys/kernel/btf/vmlinux").exists() {
+        Err((
+            "the kernel exposes no BTF type information".to_string(),
+            "use a kernel built with CONFIG_DEBUG_INFO_BTF=y",
+        ))
+    } else if !fs::read_to_string("/proc/self/mounts")
+        .is_ok_and(|mounts| mounts.lines().any(|line| line.contains(" bpf ")))
+    {
+        Err((
+            "no bpf filesystem is mounted".to_string(),
+            "mount it with: mount -t bpf bpf /sys/fs/bpf",
+        ))
+    } else {
+        Ok("BTF available, bpffs mounted".to_string())
+    };
+    Check {
+        name: "bpf",
+        result,
+    }
+}
+
+fn systemd_active(unit: &str) -> bool {
+    Command::new("systemctl")
+        .args(["is-active", "--quiet", unit])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+fn check_dbus() -> Check {
+    let result = match resolve_config_dir(
+        "DBUS_CONF_DIR",
+        "/usr/share/dbus-1".into(),
+        "/etc/dbus-1".into(),
+    ) {
+        Err(e) => Err((e.to_string(), "install dbus, or set DBUS_CONF_DIR")),
+        Ok(dir) if !Path::new("/run/dbus/system_bus_socket").exists() => Err((
+            format!("{} found, but the system bus is not running", dir.display()),
+            "start it with: systemctl start dbus",
+        )),
+        Ok(dir) => Ok(format!("{}, system bus running", dir.display())),
+    };
+    Check {
+        name: "dbus",
+        result,
+    }
+}
+
+fn check_polkit() -> Check {
+    let result = match resolve_config_dir(
+        "POLKIT_DATA_DIR",
+        "/usr/share/polkit-1".into(),
+        "/etc/polkit-1".into(),
+    ) {
+        Err(e) => Err((e.to_string(), "install polkit, or set POLKIT_DATA_DIR")),
+        Ok(dir) if !dir.join("rules.d").is_dir() => Err((
+            format!("{} has no rules.d directory", dir.display()),
+            "reinstall polkit, or create the rules.d directory",
+        )),
+        Ok(dir) if !systemd_active("polkit") => Err((
+            format!("{} found, but polkitd is not running", dir.display()),
+            "start it with: systemctl start polkit",
+        )),
+        Ok(dir) => Ok(format!("{}, polkitd running", dir.display())),
+    };
+    Check {
+        name: "polkit",
+        result,
+    }
+}
+
+/// Mount point and options of the filesystem holding a path
+fn mount_of(path: &str) -> Option<(String, String)> {
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.len() > 3).then(|| (fields[1].to_string(), fields[3].to_string()))
+        })
+        .filter(|(mount, _)| {
+            path == mount || mount == "/" || path.starts_with(&format!("{}/", mount))
+        })
+        .max_by_key(|(mount, _)| mount.len())
+}
+
+fn check_acl() -> Vec<Check> {
+    ["/", "/etc", "/var", "/home", "/opt"]
+        .iter()
+        .filter(|path| Path::new(path).exists())
+        .map(|path| {
+            let (mount, options) = mount_of(path).unwrap_or_default();
+            let result = match PosixACL::read_acl(path) {
+                Err(e) => Err((
+                    format!("{} does not support ACLs: {}", path, e),
+                    "use a filesystem supporting POSIX ACLs",
+                )),
+                Ok(_) if options.split(',').any(|o| o == "noacl") => Err((
+                    format!("{} is mounted on {} with noacl", path, mount),
+                    "remount it without noacl",
+                )),
+                Ok(_) => Ok(format!("{} (mounted on {})", path, mount)),
+            };
+            Check {
+                name: "acl",
+                result,
+            }
+        })
+        .collect()
+}
+
+fn check_rootasrole() -> Check {
+    let result = match which::which("sr") {
+        Err(_) => Err((
+            "sr not found in PATH".to_string(),
+            "install RootAsRole: https://github.com/LeChatP/RootAsRole",
+        )),
+        Ok(_) if !Path::new("/etc/security/rootasrole.json").exists() => Err((
+            "/etc/security/rootasrole.json is missing".to_string(),
+            "reinstall RootAsRole, or run its configure script",
+        )),
+        Ok(sr) => Ok(format!("{}", sr.display())),
+    };
+    Check {
+        name: "rootasrole",
+        result,
+    }
+}
+
+/// Verify the system integrations gensr relies on, failing when any check failed
+pub(crate) fn doctor() -> io::Result<()> {
+    let mut checks = vec![check_capable(), check_bpf(), check_dbus(), check_polkit()];
+    checks.extend(check_acl());
+    checks.push(check_rootasrole());
+    let mut failed = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(detail) => println!("[ OK ] {}: {}", check.name, detail),
+            Err((problem, hint)) => {
+                failed += 1;
+                println!("[FAIL] {}: {}", check.name, problem);
+                println!("       hint: {}", hint);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} check(s) failed", failed),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,194 @@
+use std::{cell::RefCell, rc::Rc, str::FromStr};
+
+use anyhow::bail;
+use rootasrole_core::database::structs::SConfig;
+
+use crate::{
+    policy::{capability_name, Access, Policy},
+    rules::DbusRule,
+};
+
+/// A grant selected across tasks: `capability:CAP_NET_RAW`, `file:/etc/shadow[:R]`
+/// or `dbus:org.freedesktop.systemd1`
+#[derive(Clone)]
+pub(crate) enum Privilege {
+    Capability(String),
+    /// A file, with the access bits selected, all of them when none is given
+    File(String, Access),
+    Dbus(String),
+}
+
+impl FromStr for Privilege {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((kind, value)) = s.split_once(':') else {
+            bail!(
+                "Invalid privilege {}, expected <capability|file|dbus>:<value>",
+                s
+            );
+        };
+        match kind {
+            "capability" | "cap" => Ok(Privilege::Capability(capability_name(value))),
+            "file" => {
+                let (path, access) = match value.rsplit_once(':') {
+                    Some((path, access)) if !access.contains('/') => (
+                        path,
+                        access
+                            .parse::<Access>()
+                            .map_err(|e| anyhow::anyhow!("{} in {}", e, s))?,
+                    ),
+                    _ => (value, Access::all()),
+                };
+                Ok(Privilege::File(path.to_string(), access))
+            }
+            "dbus" => Ok(Privilege::Dbus(value.to_string())),
+            _ => bail!(
+                "Unknown privilege kind {}, expected capability, file or dbus",
+                kind
+            ),
+        }
+    }
+}
+
+impl Privilege {
+    /// The part of the policy the privilege selects
+    fn within(&self, policy: &Policy) -> Policy {
+        let mut selected = Policy::default();
+        match self {
+            Privilege::Capability(capability) => {
+                if policy.capabilities.contains(capability) {
+                    selected.capabilities.insert(capability.clone());
+                }
+            }
+            Privilege::File(path, access) => {
+                if let Some(granted) = policy.files.get(path) {
+                    let access = *granted & *access;
+                    if !access.is_empty() {
+                        selected.files.insert(path.clone(), access);
+                    }
+                }
+            }
+            Privilege::Dbus(destination) => {
+                selected.dbus = policy
+                    .dbus
+                    .iter()
+                    .filter(|rule| rule.destination == *destination)
+                    .cloned()
+                    .collect();
+            }
+        }
+        selected
+    }
+
+    /// A policy granting the privilege alone
+    fn grant(&self) -> Policy {
+        let mut policy = Policy::default();
+        match self {
+            Privilege::Capability(capability) => {
+                policy.capabilities.insert(capability.clone());
+            }
+            Privilege::File(path, access) => {
+                let access = if *access == Access::all() {
+                    Access::R
+                } else {
+                    *access
+                };
+                policy.files.insert(path.clone(), access);
+            }
+            Privilege::Dbus(destination) => {
+                policy.dbus.insert(DbusRule::destination(destination));
+            }
+        }
+        policy
+    }
+}
+
+/// What to do with the tasks holding the matched privilege
+pub(crate) enum Action {
+    Remove,
+    Add(Privilege),
+}
+
+/// Change planned on one task
+pub(crate) struct Edit {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    removed: Policy,
+    added: Policy,
+}
+
+impl std::fmt::Display for Edit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}/{}:", self.role, self.task)?;
+        for line in self
+            .removed
+            .describe('-')
+            .into_iter()
+            .chain(self.added.describe('+'))
+        {
+            writeln!(f, "    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes of the action on every task holding the matched privilege, in the role if given
+pub(crate) fn plan(
+    config: &Rc<RefCell<SConfig>>,
+    matched: &Privilege,
+    role: Option<&str>,
+    action: &Action,
+) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for r in &config.as_ref().borrow().roles {
+        let r = r.as_ref().borrow();
+        if role.is_some_and(|role| role != r.name) {
+            continue;
+        }
+        for task in &r.tasks {
+            let task = task.as_ref().borrow();
+            let current = Policy::from_stask(&task);
+            let selected = matched.within(&current);
+            if selected.is_empty() {
+                continue;
+            }
+            let (removed, added) = match action {
+                Action::Remove => (selected, Policy::default()),
+                Action::Add(privilege) => (Policy::default(), privilege.grant() - current),
+            };
+            if removed.is_empty() && added.is_empty() {
+                continue;
+            }
+            edits.push(Edit {
+                role: r.name.clone(),
+                task: task.name.to_string(),
+                removed,
+                added,
+            });
+        }
+    }
+    edits
+}
+
+/// Apply the planned changes to the configuration in memory
+pub(crate) fn apply(config: &Rc<RefCell<SConfig>>, edits: &[Edit]) {
+    let config = config.as_ref().borrow();
+    for edit in edits {
+        let Some(role) = config.role(&edit.role) else {
+            continue;
+        };
+        let role = role.as_ref().borrow();
+        let Some(task) = role
+            .tasks
+            .iter()
+            .find(|task| task.as_ref().borrow().name.to_string() == edit.task)
+        else {
+            continue;
+        };
+        let mut task = task.as_ref().borrow_mut();
+        let current = Policy::from_stask(&task);
+        ((current - edit.removed.clone()) | edit.added.clone())
+            .replace_grants(&mut task, &role.name);
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+use log::debug;
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+    unistd::{Uid, User},
+};
+use sha2::Digest;
+
+use crate::{auditlog, state::state_dir, telemetry::Execution};
+
+/// UID range reserved by systemd for dynamic users
+const DYNAMIC_UID_MIN: u32 = 61184;
+const DYNAMIC_UID_MAX: u32 = 65519;
+
+/// Deterministic UID of an ephemeral role user, so ACLs written at deploy time
+/// match the account allocated at each invocation
+pub(crate) fn uid(username: &str) -> Uid {
+    let mut hasher = sha2::Sha224::new();
+    hasher.update(username.as_bytes());
+    let hash = hasher.finalize();
+    let value = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+    Uid::from_raw(DYNAMIC_UID_MIN + value % (DYNAMIC_UID_MAX - DYNAMIC_UID_MIN + 1))
+}
+
+/// Fail when two ephemeral users hash to the same uid, each would be granted the ACLs of
+/// the other
+pub(crate) fn check_collisions<'a>(usernames: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+    let mut owners: BTreeMap<u32, &str> = BTreeMap::new();
+    for username in usernames {
+        let uid = uid(username);
+        match owners.insert(uid.as_raw(), username) {
+            Some(other) if other != username => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                    "Ephemeral users {} and {} share the uid {}, rename the task of one of them",
+                    other, username, uid
+                ),
+                ))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn allocate(username: &str) -> io::Result<Uid> {
+    let uid = uid(username);
+    match User::from_uid(uid)? {
+        Some(user) if user.name == username => return Ok(uid),
+        Some(user) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "UID {} of ephemeral user {} is already used by {}",
+                    uid, username, user.name
+                ),
+            ))
+        }
+        None => {}
+    }
+    debug!("Allocating ephemeral user {} with uid {}", username, uid);
+    let output = Command::new("/usr/bin/useradd")
+        .arg("-r")
+        .arg("-M")
+        .arg("-U")
+        .arg("-u")
+        .arg(uid.to_string())
+        .arg("-s")
+        .arg("/usr/sbin/nologin")
+        .arg(username)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Failed to allocate ephemeral user {}: {}",
+                username,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    auditlog::record(
+        "user_create",
+        username,
+        &format!("ephemeral user with uid {}", uid),
+    );
+    Ok(uid)
+}
+
+/// Lock of the ephemeral user, `.lock` serializing its allocation and removal, `.users`
+/// held shared by each run using it
+fn lock(username: &str, extension: &str, arg: FlockArg) -> io::Result<Flock<File>> {
+    let dir = state_dir().join("ephemeral");
+    fs::create_dir_all(&dir)?;
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(format!("{}.{}", username, extension)))?;
+    Flock::lock(file, arg).map_err(|(_, errno)| io::Error::from(errno))
+}
+
+/// Remove the account unless another run of the task still uses it. The shared locks of
+/// the runs are released by the kernel when they exit, a crashed run never pins the user
+fn release(username: &str, using: Flock<File>) -> io::Result<()> {
+    let _allocation = lock(username, "lock", FlockArg::LockExclusive)?;
+    drop(using);
+    match lock(username, "users", FlockArg::LockExclusiveNonblock) {
+        Ok(_) => {}
+        Err(e) if e.raw_os_error() == Some(Errno::EWOULDBLOCK as i32) => {
+            debug!("Ephemeral user {} is still used, keeping it", username);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    debug!("Releasing ephemeral user {}", username);
+    Command::new("userdel").arg(username).status()?;
+    auditlog::record("user_delete", username, "ephemeral user released");
+    Ok(())
+}
+
+/// Allocate the role user, run the command under its identity, then remove the account
+pub(crate) fn run(username: &str, command: &[String]) -> io::Result<i32> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No command to execute"))?;
+    let (uid, using) = {
+        let _allocation = lock(username, "lock", FlockArg::LockExclusive)?;
+        let uid = allocate(username)?;
+        (uid, lock(username, "users", FlockArg::LockShared)?)
+    };
+    let gid = User::from_uid(uid)?
+        .map(|user| user.gid.as_raw())
+        .unwrap_or(uid.as_raw());
+    let start = Instant::now();
+    let status = Command::new(program)
+        .args(args)
+        .uid(uid.as_raw())
+        .gid(gid)
+        .status();
+    release(username, using)?;
+    let code = status?.code().unwrap_or(1);
+    Execution {
+        command: command.join(" "),
+        duration_secs: start.elapsed().as_secs_f64(),
+        exit_status: Some(code),
+        ..Default::default()
+    }
+    .record();
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uid_in_the_dynamic_range() {
+        let raw = uid("rar-backup-nightly").as_raw();
+        assert!((DYNAMIC_UID_MIN..=DYNAMIC_UID_MAX).contains(&raw));
+        assert_eq!(uid("rar-backup-nightly").as_raw(), raw);
+    }
+
+    #[test]
+    fn check_collisions_of_distinct_users() {
+        assert!(check_collisions(["rar-a", "rar-b", "rar-a"]).is_ok());
+        let names: Vec<String> = (0..).map(|i| format!("rar-task{}", i)).take(1000).collect();
+        let (first, second) = names
+            .iter()
+            .enumerate()
+            .find_map(|(i, name)| {
+                names[..i]
+                    .iter()
+                    .find(|other| uid(other) == uid(name))
+                    .map(|other| (other, name))
+            })
+            .expect("1000 names over 4336 uids always collide");
+        assert!(check_collisions([first.as_str(), second.as_str()]).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{cell::RefCell, env, os::unix::process::CommandExt, process::Command, rc::Rc};
+
+use anyhow::{anyhow, bail, Context};
+use capctl::{ambient, bounding, prctl, Cap, CapSet, CapState};
+use nix::unistd::{getgid, getgroups, getuid, setgid, setgroups, setuid, Gid, Group, Uid, User};
+use rootasrole_core::database::structs::{SActorType, SConfig, SGroups, STask, SetBehavior};
+
+use crate::capable::capset_to_string;
+
+/// Credentials sr gives the task
+struct Credentials {
+    user: User,
+    groups: Vec<Gid>,
+    caps: CapSet,
+}
+
+fn user(actor: &SActorType) -> anyhow::Result<User> {
+    match actor {
+        SActorType::Name(name) => User::from_name(name)?,
+        SActorType::Id(uid) => User::from_uid(Uid::from_raw(*uid))?,
+    }
+    .ok_or_else(|| anyhow!("The user {:?} of the task does not exist", actor))
+}
+
+fn group(actor: &SActorType) -> anyhow::Result<Gid> {
+    match actor {
+        SActorType::Name(name) => Group::from_name(name)?
+            .map(|group| group.gid)
+            .ok_or_else(|| anyhow!("The group {} of the task does not exist", name)),
+        SActorType::Id(gid) => Ok(Gid::from_raw(*gid)),
+    }
+}
+
+/// The user gensr exec was invoked by, through sudo or directly
+fn invoking_user() -> anyhow::Result<User> {
+    let uid = env::var("SUDO_UID")
+        .ok()
+        .and_then(|uid| uid.parse().ok())
+        .map(Uid::from_raw)
+        .unwrap_or_else(getuid);
+    User::from_uid(uid)?.ok_or_else(|| anyhow!("The invoking user {} does not exist", uid))
+}
+
+fn credentials(task: &STask) -> anyhow::Result<Credentials> {
+    // A task without a user keeps the one of its caller, never root's identity
+    let user = match &task.cred.setuid {
+        Some(actor) => user(actor)?,
+        None => {
+            let user = invoking_user()?;
+            if user.uid.is_root() {
+                bail!(
+                    "Task {} sets no user and gensr exec was invoked by root, run it through sudo from the user to test as",
+                    task.name
+                );
+            }
+            user
+        }
+    };
+    let mut groups = match &task.cred.setgid {
+        Some(SGroups::Single(actor)) => vec![group(actor)?],
+        Some(SGroups::Multiple(actors)) => actors.iter().map(group).collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    // sr switches to the primary group of the user when the task sets none, root's
+    // supplementary groups are never kept
+    if groups.is_empty() {
+        groups.push(user.gid);
+    }
+    let mut caps = CapSet::empty();
+    if let Some(capabilities) = &task.cred.capabilities {
+        if capabilities.default_behavior == SetBehavior::All {
+            caps = Cap::iter().filter(|cap| cap.is_supported()).collect();
+        }
+        caps |= capabilities.add;
+        caps -= capabilities.sub;
+    }
+    Ok(Credentials { user, groups, caps })
+}
+
+fn find_task(
+    config: &Rc<RefCell<SConfig>>,
+    role: Option<&str>,
+    task: &str,
+) -> anyhow::Result<Credentials> {
+    for r in &config.as_ref().borrow().roles {
+        let r = r.as_ref().borrow();
+        if role.is_some_and(|role| role != r.name) {
+            continue;
+        }
+        if let Some(t) = r
+            .tasks
+            .iter()
+            .find(|t| t.as_ref().borrow().name.to_string() == task)
+        {
+            return credentials(&t.as_ref().borrow());
+        }
+    }
+    bail!("Task {} not found", task)
+}
+
+/// Restrict the bounding set, switch to the user and groups of the task keeping its
+/// capabilities, and raise them in the ambient set so that the command inherits them
+fn apply(credentials: &Credentials) -> anyhow::Result<()> {
+    for cap in Cap::iter().filter(|cap| cap.is_supported() && !credentials.caps.has(*cap)) {
+        bounding::drop(cap)
+            .with_context(|| format!("Cannot drop {} from the bounding set", cap))?;
+    }
+    prctl::set_keepcaps(true)?;
+    setgroups(&credentials.groups)?;
+    setgid(credentials.groups[0])?;
+    setuid(credentials.user.uid)?;
+    prctl::set_keepcaps(false)?;
+    CapState {
+        effective: credentials.caps,
+        permitted: credentials.caps,
+        inheritable: credentials.caps,
+    }
+    .set_current()
+    .context("Cannot set the capabilities of the task")?;
+    ambient::clear()?;
+    for cap in credentials.caps.iter() {
+        ambient::raise(cap).with_context(|| format!("Cannot raise {} as ambient", cap))?;
+    }
+    Ok(())
+}
+
+fn ids<T: std::fmt::Display>(ids: impl IntoIterator<Item = T>) -> String {
+    ids.into_iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The credentials the process holds, read back from the kernel
+fn report() -> anyhow::Result<String> {
+    let state = CapState::get_current()?;
+    let caps = |set: &CapSet| match capset_to_string(set) {
+        names if names.is_empty() => "none".to_string(),
+        names => names,
+    };
+    Ok(format!(
+        "uid={} gid={} groups={}\n\
+         bounding:    {}\n\
+         permitted:   {}\n\
+         effective:   {}\n\
+         inheritable: {}\n\
+         ambient:     {}",
+        getuid(),
+        getgid(),
+        ids(getgroups()?),
+        caps(&bounding::probe()),
+        caps(&state.permitted),
+        caps(&state.effective),
+        caps(&state.inheritable),
+        ambient::probe().map_or("unsupported".to_string(), |set| caps(&set)),
+    ))
+}
+
+/// Execute the command with the credentials sr would give the task, reporting them first.
+/// Only returns on failure
+pub(crate) fn exec(
+    config: &Rc<RefCell<SConfig>>,
+    role: Option<&str>,
+    task: &str,
+    command: &[String],
+) -> anyhow::Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("No command to execute"))?;
+    let credentials = find_task(config, role, task)?;
+    if !Uid::effective().is_root() {
+        bail!("gensr exec must run as root to switch to the credentials of the task");
+    }
+    apply(&credentials)?;
+    eprintln!("{}", report()?);
+    Err(Command::new(program).args(args).exec())
+        .with_context(|| format!("Cannot execute {}", program))
+}
@@ -0,0 +1,394 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::bail;
+#[cfg(any(feature = "nftables", feature = "firewalld"))]
+use log::warn;
+use rootasrole_core::database::structs::STask;
+#[cfg(feature = "chsr")]
+use rootasrole_core::database::{
+    options::SAuthentication,
+    structs::{SCommand, SetBehavior},
+};
+
+use crate::policy::Policy;
+#[cfg(any(feature = "nftables", feature = "firewalld"))]
+use crate::rules::NetworkDirection;
+#[cfg(any(feature = "nftables", feature = "firewalld", feature = "chsr"))]
+use crate::rules::NetworkRule;
+
+/// The RootAsRole task, written into the configuration rather than by an exporter
+pub(crate) const JSON: &str = "json";
+
+/// Renders a generated policy for another tool
+pub(crate) trait Exporter {
+    /// Value of `--format` selecting this exporter
+    fn name(&self) -> &'static str;
+
+    /// The task is the one gensr would write for the policy
+    fn render(&self, policy: &Policy, task: &STask, username: &str) -> anyhow::Result<String>;
+}
+
+/// Options shared by every exporter
+#[derive(Default)]
+pub(crate) struct ExportOptions {
+    /// File to write instead of the standard output
+    pub(crate) output: Option<PathBuf>,
+    /// Template whose `{{rules}}` and `{{user}}` placeholders are replaced
+    pub(crate) template: Option<PathBuf>,
+}
+
+/// Available exporters, each behind the cargo feature of its name
+fn registry() -> Vec<Box<dyn Exporter>> {
+    vec![
+        #[cfg(feature = "nftables")]
+        Box::new(Nftables),
+        #[cfg(feature = "firewalld")]
+        Box::new(Firewalld),
+        #[cfg(feature = "chsr")]
+        Box::new(Chsr),
+    ]
+}
+
+/// Values accepted by `--format`
+pub(crate) fn names() -> Vec<&'static str> {
+    std::iter::once(JSON)
+        .chain(registry().iter().map(|exporter| exporter.name()))
+        .collect()
+}
+
+/// Render the policy with the exporter of the format and write it,
+/// false for the RootAsRole format
+pub(crate) fn export(
+    format: &str,
+    policy: &Policy,
+    task: &STask,
+    username: &str,
+    options: &ExportOptions,
+) -> anyhow::Result<bool> {
+    if format == JSON {
+        return Ok(false);
+    }
+    let Some(exporter) = registry().into_iter().find(|e| e.name() == format) else {
+        bail!(
+            "Unknown format {}, expected one of {}",
+            format,
+            names().join(", ")
+        );
+    };
+    let mut rendered = exporter.render(policy, task, username)?;
+    if rendered.is_empty() {
+        return Ok(true);
+    }
+    if let Some(template) = &options.template {
+        rendered = fs::read_to_string(template)?
+            .replace("{{rules}}", &rendered)
+            .replace("{{user}}", username);
+    }
+    match &options.output {
+        Some(path) => fs::write(path, rendered)?,
+        None => print!("{}", rendered),
+    }
+    Ok(true)
+}
+
+/// Without any observed flow the ruleset would only reject the role user's traffic, while
+/// the trace may simply not have recorded it: nothing is exported then
+#[cfg(any(feature = "nftables", feature = "firewalld"))]
+fn no_flows(policy: &Policy, format: &str) -> bool {
+    if policy.network.is_empty() {
+        warn!(
+            "No network flow was observed, no {} rules are exported rather than denying every flow of the role user",
+            format
+        );
+    }
+    policy.network.is_empty()
+}
+
+/// nftables ruleset restricting the role user to the observed flows
+#[cfg(feature = "nftables")]
+struct Nftables;
+
+#[cfg(feature = "nftables")]
+impl Exporter for Nftables {
+    fn name(&self) -> &'static str {
+        "nftables"
+    }
+
+    fn render(&self, policy: &Policy, _task: &STask, username: &str) -> anyhow::Result<String> {
+        if no_flows(policy, self.name()) {
+            return Ok(String::new());
+        }
+        Ok(nftables(policy, username))
+    }
+}
+
+/// firewalld direct rules restricting the role user to the observed flows
+#[cfg(feature = "firewalld")]
+struct Firewalld;
+
+#[cfg(feature = "firewalld")]
+impl Exporter for Firewalld {
+    fn name(&self) -> &'static str {
+        "firewalld"
+    }
+
+    fn render(&self, policy: &Policy, _task: &STask, username: &str) -> anyhow::Result<String> {
+        if no_flows(policy, self.name()) {
+            return Ok(String::new());
+        }
+        Ok(firewalld(policy, username))
+    }
+}
+
+/// `chsr` commands creating the task, for admins changing the configuration through the
+/// official tool
+#[cfg(feature = "chsr")]
+struct Chsr;
+
+#[cfg(feature = "chsr")]
+impl Exporter for Chsr {
+    fn name(&self) -> &'static str {
+        "chsr"
+    }
+
+    fn render(&self, policy: &Policy, task: &STask, username: &str) -> anyhow::Result<String> {
+        Ok(chsr(policy, task, username))
+    }
+}
+
+#[cfg(any(feature = "nftables", feature = "firewalld"))]
+fn is_ipv6(address: &str) -> bool {
+    address.contains(':')
+}
+
+#[cfg(feature = "nftables")]
+fn nft_match(rule: &NetworkRule) -> String {
+    let mut matches = Vec::new();
+    if let Some(address) = &rule.address {
+        let family = if is_ipv6(address) { "ip6" } else { "ip" };
+        // The remote address of outbound flows, the local one of inbound flows
+        matches.push(format!("{} daddr {}", family, address));
+    }
+    match rule.port {
+        Some(port) => matches.push(format!("{} dport {}", rule.protocol, port)),
+        None => matches.push(format!("meta l4proto {}", rule.protocol)),
+    }
+    matches.join(" ")
+}
+
+/// Outbound packets are matched on the owner of their socket, which only exists
+/// for locally generated traffic, so inbound flows are only accepted by port
+#[cfg(feature = "nftables")]
+fn nftables(policy: &Policy, username: &str) -> String {
+    let mut ruleset = format!("table inet gensr_{} {{\n", username);
+    ruleset.push_str("    chain output {\n");
+    ruleset.push_str("        type filter hook output priority filter; policy accept;\n");
+    for rule in policy
+        .network
+        .iter()
+        .filter(|r| r.direction == NetworkDirection::Outbound)
+    {
+        ruleset.push_str(&format!(
+            "        meta skuid \"{}\" {} accept\n",
+            username,
+            nft_match(rule)
+        ));
+    }
+    ruleset.push_str(&format!(
+        "        meta skuid \"{}\" reject\n    }}\n",
+        username
+    ));
+    ruleset.push_str("    chain input {\n");
+    ruleset.push_str("        type filter hook input priority filter; policy accept;\n");
+    for rule in policy
+        .network
+        .iter()
+        .filter(|r| r.direction == NetworkDirection::Inbound)
+    {
+        ruleset.push_str(&format!("        {} accept\n", nft_match(rule)));
+    }
+    ruleset.push_str("    }\n}\n");
+    ruleset
+}
+
+#[cfg(any(feature = "firewalld", feature = "chsr"))]
+fn iptables_args(rule: &NetworkRule) -> String {
+    let mut args = format!("-p {}", rule.protocol);
+    if let Some(address) = &rule.address {
+        args.push_str(&format!(" -d {}", address));
+    }
+    if let Some(port) = rule.port {
+        args.push_str(&format!(" --dport {}", port));
+    }
+    args
+}
+
+/// Address families a rule applies to, both when it has no address
+#[cfg(feature = "firewalld")]
+fn families(rule: &NetworkRule) -> Vec<&'static str> {
+    match &rule.address {
+        Some(address) if is_ipv6(address) => vec!["ipv6"],
+        Some(_) => vec!["ipv4"],
+        None => vec!["ipv4", "ipv6"],
+    }
+}
+
+#[cfg(feature = "firewalld")]
+fn firewalld(policy: &Policy, username: &str) -> String {
+    let mut direct = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<direct>\n");
+    for rule in &policy.network {
+        let (chain, owner) = match rule.direction {
+            NetworkDirection::Outbound => ("OUTPUT", format!("-m owner --uid-owner {} ", username)),
+            NetworkDirection::Inbound => ("INPUT", String::new()),
+        };
+        for ipv in families(rule) {
+            direct.push_str(&format!(
+                "  <rule ipv=\"{}\" table=\"filter\" chain=\"{}\" priority=\"0\">{}{} -j ACCEPT</rule>\n",
+                ipv,
+                chain,
+                owner,
+                iptables_args(rule)
+            ));
+        }
+    }
+    for ipv in ["ipv4", "ipv6"] {
+        direct.push_str(&format!(
+            "  <rule ipv=\"{}\" table=\"filter\" chain=\"OUTPUT\" priority=\"1\">-m owner --uid-owner {} -j REJECT</rule>\n",
+            ipv, username
+        ));
+    }
+    direct.push_str("</direct>\n");
+    direct
+}
+
+/// Quote the argument for the shell
+#[cfg(feature = "chsr")]
+fn quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./,:=@".contains(c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Grants written by gensr outside of what chsr manages, left as comments
+#[cfg(feature = "chsr")]
+fn gensr_grants(policy: &Policy) -> Vec<String> {
+    let mut grants = Vec::new();
+    for (path, access) in &policy.files {
+        grants.push(format!("file {} {}", path, access));
+    }
+    for rule in &policy.dbus {
+        grants.push(format!("dbus <allow {}/>", rule.to_allow_attributes()));
+    }
+    for rule in &policy.network {
+        grants.push(format!("network {}", iptables_args(rule)));
+    }
+    grants
+}
+
+#[cfg(feature = "chsr")]
+fn chsr(policy: &Policy, task: &STask, username: &str) -> String {
+    let prefix = format!(
+        "chsr role {} task {}",
+        quote(username),
+        quote(&task.name.to_string())
+    );
+    let mut script = String::from("#!/bin/sh\n");
+    script.push_str("# Skip the first command when the role already exists\n");
+    script.push_str(&format!("chsr role {} add\n", quote(username)));
+    script.push_str(&format!("{} add\n", prefix));
+    let mut cred = format!(
+        "{} cred set --setuid {} --setgid {}",
+        prefix,
+        quote(username),
+        quote(username)
+    );
+    if !policy.capabilities.is_empty() {
+        let capabilities = policy.capabilities.iter().cloned().collect::<Vec<_>>();
+        cred.push_str(&format!(" --caps {}", quote(&capabilities.join(","))));
+    }
+    script.push_str(&cred);
+    script.push('\n');
+    if task.commands.default_behavior == Some(SetBehavior::All) {
+        script.push_str(&format!("{} cmd setpolicy allow-all\n", prefix));
+    } else {
+        script.push_str(&format!("{} cmd setpolicy deny-all\n", prefix));
+        for command in &task.commands.add {
+            if let SCommand::Simple(command) = command {
+                script.push_str(&format!(
+                    "{} cmd whitelist add {}\n",
+                    prefix,
+                    quote(command)
+                ));
+            }
+        }
+    }
+    let authentication = task
+        .options
+        .as_ref()
+        .and_then(|options| options.as_ref().borrow().authentication);
+    if let Some(authentication) = authentication {
+        let auth = if authentication == SAuthentication::Skip {
+            "skip"
+        } else {
+            "perform"
+        };
+        script.push_str(&format!("{} options auth {}\n", prefix, auth));
+    }
+    script.push_str(&format!("{} options env setpolicy delete-all\n", prefix));
+    if !policy.env_vars.is_empty() {
+        let keep = policy.env_vars.keys().cloned().collect::<Vec<_>>();
+        script.push_str(&format!(
+            "{} options env whitelist set {}\n",
+            prefix,
+            quote(&keep.join(","))
+        ));
+    }
+    let grants = gensr_grants(policy);
+    if !grants.is_empty() {
+        script.push_str("# chsr cannot express the following grants, deploy them with gensr:\n");
+        for grant in grants {
+            script.push_str(&format!("#   {}\n", grant));
+        }
+    }
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "chsr", feature = "nftables"))]
+    use super::*;
+
+    #[cfg(feature = "chsr")]
+    #[test]
+    fn quote_shell_arguments() {
+        assert_eq!(quote("CAP_NET_RAW,CAP_CHOWN"), "CAP_NET_RAW,CAP_CHOWN");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("web admins"), "'web admins'");
+        assert_eq!(quote("it's; rm -rf /"), "'it'\\''s; rm -rf /'");
+    }
+
+    #[cfg(feature = "nftables")]
+    #[test]
+    fn nft_match_by_family() {
+        let rule = |address: Option<&str>, port| NetworkRule {
+            direction: NetworkDirection::Outbound,
+            protocol: "tcp".to_string(),
+            address: address.map(str::to_string),
+            port,
+        };
+        assert_eq!(
+            nft_match(&rule(Some("10.0.0.1"), Some(443))),
+            "ip daddr 10.0.0.1 tcp dport 443"
+        );
+        assert_eq!(
+            nft_match(&rule(Some("fd00::1"), None)),
+            "ip6 daddr fd00::1 meta l4proto tcp"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    process::Child,
+    thread,
+    time::Duration,
+};
+
+use anyhow::bail;
+use clap::ValueEnum;
+use log::{debug, warn};
+use nix::{
+    errno::Errno,
+    libc,
+    sys::{
+        fanotify::{EventFFlags, Fanotify, InitFlags, MarkFlags, MaskFlags},
+        stat::Mode,
+    },
+    unistd::mkfifo,
+};
+use tempfile::TempDir;
+
+use crate::{capable, policy::Access};
+
+/// How the privileges of the traced command are collected
+#[derive(Clone, Copy, PartialEq, Default, Debug, ValueEnum)]
+pub(crate) enum Backend {
+    /// eBPF tracing with capable: capabilities, files and dbus
+    #[default]
+    Capable,
+    /// fanotify mount marks, for systems where eBPF is locked down: files only
+    Fanotify,
+}
+
+/// Filesystems without regular files worth granting
+const PSEUDO_FILESYSTEMS: [&str; 12] = [
+    "proc",
+    "sysfs",
+    "devtmpfs",
+    "devpts",
+    "cgroup",
+    "cgroup2",
+    "bpf",
+    "tracefs",
+    "debugfs",
+    "securityfs",
+    "mqueue",
+    "autofs",
+];
+
+/// Collects the files accessed by the traced processes from fanotify events on every mount
+/// of their private mount namespace: only the traced process tree, short-lived children
+/// included, accesses files through these mounts
+pub(crate) struct FileWatcher {
+    fanotify: Fanotify,
+    files: BTreeMap<String, Access>,
+    /// Holds the fifo the traced command waits on until its mounts are watched
+    dir: TempDir,
+}
+
+impl FileWatcher {
+    pub(crate) fn new() -> anyhow::Result<Self> {
+        let fanotify = Fanotify::init(
+            InitFlags::FAN_CLASS_NOTIF
+                | InitFlags::FAN_CLOEXEC
+                | InitFlags::FAN_NONBLOCK
+                | InitFlags::FAN_UNLIMITED_QUEUE,
+            EventFFlags::O_RDONLY | EventFFlags::O_LARGEFILE | EventFFlags::O_CLOEXEC,
+        )?;
+        let dir = TempDir::new()?;
+        mkfifo(&dir.path().join("ready"), Mode::S_IRUSR | Mode::S_IWUSR)?;
+        Ok(FileWatcher {
+            fanotify,
+            files: BTreeMap::new(),
+            dir,
+        })
+    }
+
+    /// Wrapper moving the traced command into a private mount namespace, where it waits
+    /// for its mounts to be watched
+    pub(crate) fn wrapper(&self) -> anyhow::Result<Vec<String>> {
+        let mut wrapper =
+            capable::wrapper("unshare", &["--mount", "--propagation", "private", "--"])?;
+        wrapper.extend(capable::wrapper(
+            "sh",
+            &["-c", "read -r _ < \"$0\" && exec \"$@\""],
+        )?);
+        wrapper.push(self.dir.path().join("ready").to_string_lossy().to_string());
+        Ok(wrapper)
+    }
+
+    /// Watch the mounts of the namespace of the spawned command, then let it run. The
+    /// command is killed when its mounts cannot be watched
+    pub(crate) fn attach(&self, child: &mut Child) -> anyhow::Result<()> {
+        let result = self.watch(child);
+        if result.is_err() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        result
+    }
+
+    fn watch(&self, child: &mut Child) -> anyhow::Result<()> {
+        let fifo = self.dir.path().join("ready");
+        // The fifo is opened once the wrapper is in its namespace and reads it
+        let mut ready = loop {
+            match fs::File::options()
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&fifo)
+            {
+                Ok(ready) => break ready,
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                    if let Some(status) = child.try_wait()? {
+                        bail!(
+                            "The traced command exited with {} before it was watched",
+                            status
+                        );
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let root = format!("/proc/{}/root", child.id());
+        let mounts = fs::read_to_string(format!("/proc/{}/mounts", child.id()))?;
+        for line in mounts.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(mount), Some(fstype)) = (fields.get(1), fields.get(2)) else {
+                continue;
+            };
+            if PSEUDO_FILESYSTEMS.contains(fstype) {
+                continue;
+            }
+            // The namespace's own mount, reached through the root of the command
+            let mount = format!("{}{}", root, mount);
+            if let Err(e) = self.fanotify.mark(
+                MarkFlags::FAN_MARK_ADD | MarkFlags::FAN_MARK_MOUNT,
+                MaskFlags::FAN_ACCESS
+                    | MaskFlags::FAN_MODIFY
+                    | MaskFlags::FAN_CLOSE
+                    | MaskFlags::FAN_OPEN_EXEC,
+                None,
+                Some(mount.as_str()),
+            ) {
+                debug!("Cannot watch mount {}: {}", mount, e);
+            }
+        }
+        ready.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Record the pending events caused by the traced processes
+    pub(crate) fn poll(&mut self) {
+        loop {
+            let events = match self.fanotify.read_events() {
+                Ok(events) if !events.is_empty() => events,
+                Ok(_) | Err(Errno::EAGAIN) => return,
+                Err(e) => {
+                    warn!("Failed to read fanotify events: {}", e);
+                    return;
+                }
+            };
+            for event in events {
+                let Some(fd) = event.fd() else {
+                    warn!("fanotify queue overflowed, some file accesses were lost");
+                    continue;
+                };
+                let Ok(path) = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())) else {
+                    continue;
+                };
+                let mask = event.mask();
+                let mut access = Access::empty();
+                if mask.intersects(MaskFlags::FAN_ACCESS | MaskFlags::FAN_CLOSE_NOWRITE) {
+                    access |= Access::R;
+                }
+                if mask.intersects(MaskFlags::FAN_MODIFY | MaskFlags::FAN_CLOSE_WRITE) {
+                    access |= Access::W;
+                }
+                if mask.contains(MaskFlags::FAN_OPEN_EXEC) {
+                    access |= Access::X;
+                }
+                *self
+                    .files
+                    .entry(path.to_string_lossy().to_string())
+                    .or_insert(Access::empty()) |= access;
+            }
+        }
+    }
+
+    pub(crate) fn files(&self) -> &BTreeMap<String, Access> {
+        &self.files
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use log::{debug, warn};
+use rootasrole_core::database::structs::{SCommand, STask};
+use sha2::Digest;
+
+use crate::auditlog;
+
+const TRUST_PREFIX: &str = "gensr-";
+
+/// Writes fapolicyd trust entries for the binaries a role is allowed to execute
+pub(crate) struct FapolicydTrustWriter {
+    trust_folder: PathBuf,
+}
+
+impl FapolicydTrustWriter {
+    pub(crate) fn new() -> Self {
+        let trust_folder = env::var("FAPOLICYD_TRUST_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("/etc/fapolicyd/trust.d"));
+        FapolicydTrustWriter { trust_folder }
+    }
+
+    pub(crate) fn is_available(&self) -> bool {
+        self.trust_folder.parent().is_some_and(|p| p.is_dir())
+    }
+
+    fn trust_file_path(&self, username: &str) -> PathBuf {
+        self.trust_folder
+            .join(format!("{}{}", TRUST_PREFIX, username))
+    }
+
+    pub(crate) fn add_trust(&self, username: &str, binaries: &[PathBuf]) -> io::Result<()> {
+        if binaries.is_empty() {
+            return Ok(());
+        }
+        let path = self.trust_file_path(username);
+        debug!(
+            "Writing fapolicyd trust for user {} at {:?}",
+            username, path
+        );
+        fs::create_dir_all(&self.trust_folder)?;
+        let mut writer = File::create(path)?;
+        for binary in binaries {
+            match trust_entry(binary) {
+                Ok(entry) => writeln!(writer, "{}", entry)?,
+                Err(e) => warn!("Cannot trust {}: {}", binary.display(), e),
+            }
+        }
+        writer.flush()?;
+        auditlog::record(
+            "fapolicyd_trust_write",
+            &self.trust_file_path(username).to_string_lossy(),
+            &format!("{} trusted binaries for {}", binaries.len(), username),
+        );
+        Ok(())
+    }
+
+    pub(crate) fn remove_trust(&self, username: &str) -> io::Result<bool> {
+        let path = self.trust_file_path(username);
+        if path.exists() {
+            fs::remove_file(&path)?;
+            auditlog::record(
+                "fapolicyd_trust_remove",
+                &path.to_string_lossy(),
+                &format!("removed trusted binaries of {}", username),
+            );
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    pub(crate) fn reload() -> io::Result<()> {
+        match Command::new("fapolicyd-cli").arg("--update").status() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("fapolicyd-cli exited with {}", status),
+            )),
+            Err(e) => {
+                warn!("Unable to update fapolicyd trust database: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Format a trust.d line: `<path> <size> <sha256>`
+fn trust_entry(binary: &Path) -> io::Result<String> {
+    let mut file = File::open(binary)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = [0u8; 8192];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        size += read;
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!(
+        "{} {} {}",
+        binary.display(),
+        size,
+        hex::encode(hasher.finalize())
+    ))
+}
+
+/// Binaries executed by a task: its explicit commands and its executable file grants
+pub(crate) fn task_binaries(task: &STask) -> Vec<PathBuf> {
+    let mut binaries: Vec<PathBuf> = Vec::new();
+    for command in &task.commands.add {
+        if let SCommand::Simple(command) = command {
+            if let Some(program) = command.split_whitespace().next() {
+                if let Ok(path) = which::which(program) {
+                    binaries.push(path);
+                }
+            }
+        }
+    }
+    if let Some(files) = task
+        .cred
+        ._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+    {
+        for (path, permission) in files {
+            let executable = permission.as_str().is_some_and(|p| p.contains(['X', 'x']));
+            if executable && Path::new(path).is_file() {
+                binaries.push(PathBuf::from(path));
+            }
+        }
+    }
+    binaries.sort();
+    binaries.dedup();
+    binaries
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use capctl::Cap;
+use log::warn;
+use rootasrole_core::database::structs::SCredentials;
+
+use crate::{
+    params::glob_match,
+    policy::{Access, Policy},
+};
+
+fn since(secs: i64) -> Option<SystemTime> {
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs.try_into().ok()?))
+}
+
+/// Mark the files the traced command created, deleted, renamed, or changed the mode or
+/// owner of, from what the trace left on the filesystem. `inodes` are the inodes of the
+/// files seen open, a missing file whose inode is now at another path was renamed there
+pub(crate) fn mark(policy: &mut Policy, started: SystemTime, inodes: &BTreeMap<String, u64>) {
+    let mut moved: BTreeMap<u64, String> = BTreeMap::new();
+    let mut marks: Vec<(String, Access)> = Vec::new();
+    for path in policy.files.keys() {
+        if policy.created_modes.contains_key(path) {
+            marks.push((path.clone(), Access::C));
+            continue;
+        }
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                moved.insert(metadata.ino(), path.clone());
+                // A status change without a content change is a chmod or a chown
+                if since(metadata.ctime()).is_some_and(|ctime| ctime >= started)
+                    && since(metadata.mtime()).is_some_and(|mtime| mtime < started)
+                {
+                    marks.push((path.clone(), Access::A));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                marks.push((path.clone(), Access::D));
+            }
+            Err(_) => {}
+        }
+    }
+    for (path, access) in marks.iter_mut() {
+        if *access != Access::D {
+            continue;
+        }
+        if let Some(target) = inodes.get(path).and_then(|inode| moved.get(inode)) {
+            *access = Access::N;
+            *policy
+                .files
+                .entry(target.clone())
+                .or_insert(Access::empty()) |= Access::N;
+        }
+    }
+    for (path, access) in marks {
+        *policy.files.entry(path).or_insert(Access::empty()) |= access;
+    }
+}
+
+/// Directories, along with everything below them, whose write bit lets a user replace
+/// system files, such as /etc/passwd
+const SYSTEM_TREES: &[&str] = &[
+    "/bin", "/boot", "/dev", "/etc", "/lib", "/lib32", "/lib64", "/proc", "/root", "/sbin", "/sys",
+    "/usr",
+];
+
+/// Directories holding the files of other users and services, but not their subdirectories
+const SYSTEM_DIRS: &[&str] = &[
+    "/", "/home", "/mnt", "/opt", "/run", "/srv", "/tmp", "/var", "/var/lib", "/var/log",
+];
+
+fn is_system_dir(dir: &Path) -> bool {
+    SYSTEM_DIRS.iter().any(|system| dir == Path::new(system))
+        || SYSTEM_TREES.iter().any(|system| dir.starts_with(system))
+}
+
+/// ACL grants enforcing an access: the read, write and execute bits on the path itself,
+/// and write and search on its directory to create, delete or rename it, flagged as such
+fn acl_grants(path: &str, access: Access) -> Vec<(String, Access, bool)> {
+    let mut grants = Vec::new();
+    if access.intersects(Access::RWX) {
+        grants.push((path.to_string(), access & Access::RWX, false));
+    }
+    if access.intersects(Access::C | Access::D | Access::N) {
+        if let Some(parent) = Path::new(path).parent().filter(|p| p != &Path::new("")) {
+            grants.push((parent.to_string_lossy().to_string(), Access::WX, true));
+        }
+    }
+    grants
+}
+
+/// Paths a grant applies to. No ACL can be set on a glob: a glob in the last component,
+/// such as the `dir/*` of `--collapse-threshold`, is expanded to the matching entries of
+/// its directory, the files created after the deploy are not granted
+pub(crate) fn expand(path: &str) -> Vec<String> {
+    let is_glob = |s: &str| s.contains(['*', '?', '[']);
+    if !is_glob(path) {
+        return vec![path.to_string()];
+    }
+    let (dir, pattern) = path.rsplit_once('/').unwrap_or((".", path));
+    if is_glob(dir) || pattern.contains('[') {
+        warn!(
+            "Cannot grant {}, only `*` and `?` in the last component are expanded",
+            path
+        );
+        return Vec::new();
+    }
+    let dir = if dir.is_empty() { "/" } else { dir };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Cannot expand {}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| glob_match(pattern.as_bytes(), entry.file_name().as_bytes()))
+        .map(|entry| Path::new(dir).join(entry.file_name()))
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn task_accesses(cred: &SCredentials) -> Vec<(String, Access)> {
+    let Some(files) = cred
+        ._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+    else {
+        return Vec::new();
+    };
+    files
+        .iter()
+        .filter_map(|(path, access)| {
+            let parsed = access.as_str().and_then(|a| a.parse::<Access>().ok());
+            if parsed.is_none() {
+                warn!("Invalid access {} of {}, not granted", access, path);
+            }
+            Some((path, parsed?))
+        })
+        .flat_map(|(path, access)| expand(path).into_iter().map(move |path| (path, access)))
+        .collect()
+}
+
+/// ACL grants enforcing every file access of the task, by path. Write on the directory of
+/// a created, deleted or renamed file lets the user replace any file in it: it is only
+/// granted with `parent_write`, and never on a system directory
+pub(crate) fn task_grants(cred: &SCredentials, parent_write: bool) -> BTreeMap<String, Access> {
+    let mut grants: BTreeMap<String, Access> = BTreeMap::new();
+    for (path, access) in task_accesses(cred) {
+        for (granted, access, parent) in acl_grants(&path, access) {
+            if parent && is_system_dir(Path::new(&granted)) {
+                warn!(
+                    "The task creates, deletes or renames {}, write on the system directory {} is never granted",
+                    path,
+                    granted
+                );
+                continue;
+            }
+            if parent && !parent_write {
+                warn!(
+                    "The task creates, deletes or renames {}, which needs write on {}: not granted without --allow-parent-write",
+                    path,
+                    granted
+                );
+                continue;
+            }
+            if parent {
+                warn!(
+                    "Granting write on {} to create, delete or rename {}, any file in it can be replaced",
+                    granted, path
+                );
+            }
+            *grants.entry(granted).or_insert(Access::empty()) |= access;
+        }
+    }
+    grants
+}
+
+/// Every path a deploy may have granted to the task, to release them
+pub(crate) fn granted_paths(cred: &SCredentials) -> Vec<String> {
+    let mut paths: Vec<String> = task_accesses(cred)
+        .into_iter()
+        .flat_map(|(path, access)| acl_grants(&path, access))
+        .map(|(path, _, _)| path)
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Warn about the accesses of the task no ACL can grant: only the owner of a file, or a
+/// process with CAP_FOWNER and CAP_CHOWN, changes its mode or owner
+pub(crate) fn warn_unenforceable(cred: &SCredentials) {
+    let has = |cap| {
+        cred.capabilities
+            .as_ref()
+            .is_some_and(|caps| caps.add.has(cap))
+    };
+    if has(Cap::FOWNER) && has(Cap::CHOWN) {
+        return;
+    }
+    for (path, access) in task_accesses(cred) {
+        if access.contains(Access::A) {
+            warn!(
+                "The task changes the mode or owner of {}, which no ACL grants, it needs CAP_FOWNER and CAP_CHOWN on every file",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{bail, Context};
+use log::debug;
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::policy::Policy;
+
+/// Pipe the policy as JSON through each filter command in turn, reading back the
+/// transformed policy from its standard output
+pub(crate) fn apply(policy: &Policy, filters: &[String], username: &str) -> anyhow::Result<Policy> {
+    let mut policy = policy.clone();
+    for filter in filters {
+        debug!("Filtering the policy through {}", filter);
+        let mut child = Command::new("sh")
+            .args(["-c", filter])
+            .env("GENSR_ROLE_USER", username)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Cannot run filter {}", filter))?;
+        let input = serde_json::to_vec(&policy)?;
+        let mut stdin = child.stdin.take().unwrap();
+        // Write from another thread, the filter may start answering before reading everything.
+        // SIGPIPE is blocked there so that a filter exiting early fails the write, not gensr
+        let writer = std::thread::spawn(move || {
+            let mut sigpipe = SigSet::empty();
+            sigpipe.add(Signal::SIGPIPE);
+            sigpipe.thread_block().map_err(io::Error::from)?;
+            stdin.write_all(&input)
+        });
+        let output = child.wait_with_output()?;
+        // A filter may answer without reading all of its input, its output decides
+        match writer.join().expect("filter writer panicked") {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+        if !output.status.success() {
+            bail!("Filter {} exited with {}", filter, output.status);
+        }
+        policy = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("Filter {} did not return a valid policy", filter))?;
+    }
+    Ok(policy)
+}
@@ -0,0 +1,165 @@
+use std::{cell::RefCell, collections::BTreeMap, fs, rc::Rc};
+
+use rootasrole_core::database::structs::SConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::packages;
+
+/// The system a policy was generated on
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct Fingerprint {
+    /// `ID` of os-release, such as debian or fedora
+    pub(crate) distro: String,
+    /// `VERSION_ID` of os-release
+    pub(crate) version: String,
+    pub(crate) kernel: String,
+    /// Versions of the packages owning the traced binaries
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) packages: BTreeMap<String, String>,
+}
+
+fn os_release(key: &str) -> String {
+    fs::read_to_string("/etc/os-release")
+        .unwrap_or_default()
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim_matches('"').to_string())
+        .unwrap_or_default()
+}
+
+/// The first `parts` dot-separated parts of a version, what materially differs
+fn prefix(version: &str, parts: usize) -> String {
+    version.split('.').take(parts).collect::<Vec<_>>().join(".")
+}
+
+impl Fingerprint {
+    /// This host, without packages
+    fn host() -> Fingerprint {
+        Fingerprint {
+            distro: os_release("ID"),
+            version: os_release("VERSION_ID"),
+            kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+                .unwrap_or_default()
+                .trim()
+                .to_string(),
+            packages: BTreeMap::new(),
+        }
+    }
+
+    /// This host, with the packages owning the given binaries
+    pub(crate) fn current<'a>(binaries: impl IntoIterator<Item = &'a String>) -> Fingerprint {
+        Fingerprint {
+            packages: binaries
+                .into_iter()
+                .filter_map(|binary| packages::owner(binary))
+                .collect(),
+            ..Fingerprint::host()
+        }
+    }
+
+    /// Material differences of this host from the fingerprint: another distribution, major
+    /// release or kernel series, or another version of a package the task runs
+    pub(crate) fn differences(&self, host: &Fingerprint) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.distro != host.distro || prefix(&self.version, 1) != prefix(&host.version, 1) {
+            differences.push(format!(
+                "generated on {} {}, this host runs {} {}",
+                self.distro, self.version, host.distro, host.version
+            ));
+        }
+        if prefix(&self.kernel, 2) != prefix(&host.kernel, 2) {
+            differences.push(format!(
+                "traced on kernel {}, this host runs {}",
+                self.kernel, host.kernel
+            ));
+        }
+        for (package, version) in &self.packages {
+            match host.packages.get(package) {
+                Some(current) if current != version => differences.push(format!(
+                    "traced with {} {}, this host has {}",
+                    package, version, current
+                )),
+                None => differences.push(format!("{} is not installed on this host", package)),
+                _ => {}
+            }
+        }
+        differences
+    }
+}
+
+/// Tasks generated on a system materially different from this host
+pub(crate) fn verify(config: &Rc<RefCell<SConfig>>) -> Vec<String> {
+    let host = Fingerprint::host();
+    let mut mismatches = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            let Some(fingerprint) = task
+                ._extra_fields
+                .get("provenance")
+                .and_then(|provenance| provenance.get("fingerprint"))
+                .and_then(|value| serde_json::from_value::<Fingerprint>(value.clone()).ok())
+            else {
+                continue;
+            };
+            let host = Fingerprint {
+                packages: fingerprint
+                    .packages
+                    .keys()
+                    .filter_map(|package| {
+                        packages::version(package).map(|version| (package.clone(), version))
+                    })
+                    .collect(),
+                ..host.clone()
+            };
+            for difference in fingerprint.differences(&host) {
+                mismatches.push(format!("{}/{}: {}", role.name, task.name, difference));
+            }
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(version: &str, kernel: &str, packages: &[(&str, &str)]) -> Fingerprint {
+        Fingerprint {
+            distro: "debian".to_string(),
+            version: version.to_string(),
+            kernel: kernel.to_string(),
+            packages: packages
+                .iter()
+                .map(|(package, version)| (package.to_string(), version.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn differences_ignore_minor_changes() {
+        let traced = fingerprint("12.4", "6.1.0-18-amd64", &[("coreutils", "9.1-1")]);
+        let host = fingerprint("12.7", "6.1.0-25-amd64", &[("coreutils", "9.1-1")]);
+        assert!(traced.differences(&host).is_empty());
+    }
+
+    #[test]
+    fn differences_report_material_changes() {
+        let traced = fingerprint(
+            "12",
+            "6.1.0",
+            &[("coreutils", "9.1-1"), ("iproute2", "6.1")],
+        );
+        let host = fingerprint("13", "6.12.0", &[("coreutils", "9.5-1")]);
+        assert_eq!(
+            traced.differences(&host),
+            [
+                "generated on debian 12, this host runs debian 13",
+                "traced on kernel 6.1.0, this host runs 6.12.0",
+                "traced with coreutils 9.1-1, this host has 9.5-1",
+                "iproute2 is not installed on this host",
+            ]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{bail, Context};
+use log::{debug, warn};
+use nix::{
+    mount::{mount, MsFlags},
+    sched::{unshare, CloneFlags},
+};
+
+/// Input data laid out as in the root filesystem, e.g. `<dir>/etc/ssl/certs/ca.pem`, put in
+/// place for the trace and removed when dropped. Missing files are copied, existing ones are
+/// hidden by a bind mount so that they are never modified. The bind mounts are made in a
+/// private mount namespace of gensr, only seen by the traced command
+pub(crate) struct Fixture {
+    copied: Vec<PathBuf>,
+    /// Directories created for the copied files, deepest last
+    created: Vec<PathBuf>,
+    mounted: Vec<PathBuf>,
+}
+
+fn files(dir: &Path, found: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && !path.is_symlink() {
+            files(&path, found)?;
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Move gensr into a mount namespace of its own whose mounts never propagate to the host,
+/// so that the bind mounts neither hide the files of other processes nor outlive gensr
+fn private_mount_namespace() -> anyhow::Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS).context("Cannot create a private mount namespace")?;
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .context("Cannot make the mounts of the namespace private")?;
+    Ok(())
+}
+
+fn bind_mount(source: &Path, target: &Path) -> anyhow::Result<()> {
+    let status = Command::new("mount")
+        .arg("--bind")
+        .arg(source)
+        .arg(target)
+        .status()
+        .context("Cannot run mount")?;
+    if !status.success() {
+        bail!(
+            "Cannot bind mount {} on {}",
+            source.display(),
+            target.display()
+        );
+    }
+    Ok(())
+}
+
+impl Fixture {
+    pub(crate) fn install(dir: &Path) -> anyhow::Result<Fixture> {
+        let mut sources = Vec::new();
+        files(dir, &mut sources).with_context(|| format!("Cannot read {}", dir.display()))?;
+        let mut fixture = Fixture {
+            copied: Vec::new(),
+            created: Vec::new(),
+            mounted: Vec::new(),
+        };
+        if sources.iter().any(|source| {
+            source
+                .strip_prefix(dir)
+                .is_ok_and(|target| Path::new("/").join(target).exists())
+        }) {
+            private_mount_namespace()?;
+        }
+        for source in sources {
+            let target = Path::new("/").join(source.strip_prefix(dir)?);
+            if target.exists() {
+                debug!("Bind mounting fixture {}", target.display());
+                bind_mount(&source, &target)?;
+                fixture.mounted.push(target);
+                continue;
+            }
+            let mut missing: Vec<PathBuf> = target
+                .ancestors()
+                .skip(1)
+                .take_while(|parent| !parent.exists())
+                .map(Path::to_path_buf)
+                .collect();
+            missing.reverse();
+            for parent in missing {
+                fs::create_dir(&parent)?;
+                fixture.created.push(parent);
+            }
+            debug!("Copying fixture {}", target.display());
+            fs::copy(&source, &target)
+                .with_context(|| format!("Cannot copy the fixture {}", target.display()))?;
+            fixture.copied.push(target);
+        }
+        Ok(fixture)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        for target in self.mounted.iter().rev() {
+            let unmounted = Command::new("umount")
+                .arg(target)
+                .status()
+                .is_ok_and(|status| status.success());
+            if !unmounted {
+                warn!("Failed to unmount the fixture {}", target.display());
+            }
+        }
+        for target in &self.copied {
+            if let Err(e) = fs::remove_file(target) {
+                warn!("Failed to remove the fixture {}: {}", target.display(), e);
+            }
+        }
+        for dir in self.created.iter().rev() {
+            if let Err(e) = fs::remove_dir(dir) {
+                warn!(
+                    "Failed to remove the fixture directory {}: {}",
+                    dir.display(),
+                    e
+                );
+            }
+        }
+    }
+}
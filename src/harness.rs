@@ -0,0 +1,462 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    rc::Rc,
+    thread,
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use log::{debug, warn};
+use rootasrole_core::database::structs::{SActor, SActorType, SConfig};
+use serde_json::Value;
+use tempfile::NamedTempFile;
+
+use crate::{canonical::roles_mut, policy::Policy, regenerate::task_commands};
+
+const CONTAINER_CONFIG: &str = "/etc/security/rootasrole.json";
+
+/// Capabilities sr needs on top of the ones of the task, to switch to its credentials
+const SR_CAPABILITIES: [&str; 5] = [
+    "CAP_SETUID",
+    "CAP_SETGID",
+    "CAP_SETPCAP",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_AUDIT_WRITE",
+];
+
+/// Program header type of the dynamic loader a binary asks for
+const PT_INTERP: u64 = 3;
+
+/// Whether the ELF binary needs no dynamic loader, and so no library of the image
+fn is_static(elf: &[u8]) -> anyhow::Result<bool> {
+    ensure!(
+        elf.starts_with(b"\x7fELF") && elf.get(4) == Some(&2) && elf.get(5) == Some(&1),
+        "not a 64-bit little-endian ELF binary"
+    );
+    let read = |at: u64, len: u64| -> anyhow::Result<u64> {
+        elf.get(at as usize..(at + len) as usize)
+            .map(|bytes| {
+                bytes
+                    .iter()
+                    .rev()
+                    .fold(0, |value, b| value << 8 | *b as u64)
+            })
+            .context("truncated ELF binary")
+    };
+    let (offset, size, count) = (read(0x20, 8)?, read(0x36, 2)?, read(0x38, 2)?);
+    for header in 0..count {
+        if read(offset + header * size, 4)? == PT_INTERP {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// A throwaway podman container, removed when dropped unless kept for inspection
+struct Container {
+    name: String,
+    keep: bool,
+}
+
+impl Container {
+    /// Only the given capabilities are added to the default ones of podman, the container
+    /// is never privileged
+    fn start(
+        image: &str,
+        index: usize,
+        keep: bool,
+        capabilities: &BTreeSet<&str>,
+    ) -> anyhow::Result<Container> {
+        let name = format!("gensr-test-{}-{}", std::process::id(), index);
+        let mut args = vec!["run", "-d", "--name", &name];
+        for capability in capabilities {
+            args.extend(["--cap-add", capability]);
+        }
+        args.extend([image, "sleep", "infinity"]);
+        podman(&args).with_context(|| format!("Cannot start a container from {}", image))?;
+        Ok(Container { name, keep })
+    }
+
+    fn copy(&self, source: &Path, target: &str) -> anyhow::Result<()> {
+        podman(&[
+            "cp",
+            &source.to_string_lossy(),
+            &format!("{}:{}", self.name, target),
+        ])
+        .map(|_| ())
+    }
+
+    fn exec(&self, user: Option<&str>, command: &[&str]) -> anyhow::Result<String> {
+        let mut args = vec!["exec"];
+        if let Some(user) = user {
+            args.extend(["--user", user]);
+        }
+        args.push(&self.name);
+        args.extend(command);
+        podman(&args)
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if self.keep {
+            println!("Container {} kept for inspection", self.name);
+        } else if let Err(e) = podman(&["rm", "-f", &self.name]) {
+            warn!("Failed to remove the container {}: {}", self.name, e);
+        }
+    }
+}
+
+/// Run podman, returning its output, or its error output on failure
+fn podman(args: &[&str]) -> anyhow::Result<String> {
+    debug!("podman {:?}", args);
+    let output = Command::new("podman")
+        .args(args)
+        .output()
+        .context("Cannot run podman")?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// What is tested of the task, shared by every image
+struct Target {
+    role: String,
+    /// The first command the task was traced from
+    command: Vec<String>,
+    /// Actor of the role running the command
+    actor: String,
+    policy: Policy,
+}
+
+fn target(
+    config: &Rc<RefCell<SConfig>>,
+    role: Option<&str>,
+    task: &str,
+    user: Option<&str>,
+) -> anyhow::Result<Target> {
+    for r in &config.as_ref().borrow().roles {
+        let r = r.as_ref().borrow();
+        if role.is_some_and(|role| role != r.name) {
+            continue;
+        }
+        let Some(t) = r
+            .tasks
+            .iter()
+            .find(|t| t.as_ref().borrow().name.to_string() == task)
+        else {
+            continue;
+        };
+        let command = task_commands(&t.as_ref().borrow())
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Task {} has no command to run", task))?;
+        if command.iter().any(|arg| arg.contains("{{")) {
+            bail!("Task {} takes parameters, it cannot be run as is", task);
+        }
+        let actor = match user {
+            Some(user) => user.to_string(),
+            None => r
+                .actors
+                .iter()
+                .find_map(|actor| match actor {
+                    SActor::User {
+                        id: Some(SActorType::Name(name)),
+                        ..
+                    } => Some(name.clone()),
+                    _ => None,
+                })
+                .ok_or_else(|| anyhow!("Role {} has no user actor, use --user", r.name))?,
+        };
+        return Ok(Target {
+            role: r.name.clone(),
+            command,
+            actor,
+            policy: Policy::from_stask(&t.as_ref().borrow()),
+        });
+    }
+    bail!("Task {} not found", task)
+}
+
+/// A copy of the configuration where the task skips authentication, nobody can type a
+/// password in the container
+fn prepared_config(config_path: &str, role: &str, task: &str) -> anyhow::Result<NamedTempFile> {
+    let mut raw: Value = serde_json::from_str(&fs::read_to_string(config_path)?)?;
+    let task = roles_mut(&mut raw)
+        .into_iter()
+        .filter(|r| r.get("name").and_then(Value::as_str) == Some(role))
+        .filter_map(|r| r.get_mut("tasks").and_then(Value::as_array_mut))
+        .flatten()
+        .find(|t| t.get("name").and_then(Value::as_str) == Some(task))
+        .ok_or_else(|| anyhow!("Task {} not found in {}", task, config_path))?;
+    if !task.get("options").is_some_and(Value::is_object) {
+        task["options"] = Value::Object(Default::default());
+    }
+    task["options"]["authentication"] = Value::from("skip");
+    let mut file = NamedTempFile::new()?;
+    file.write_all(serde_json::to_string_pretty(&raw)?.as_bytes())?;
+    Ok(file)
+}
+
+/// An end-to-end test of a task
+pub(crate) struct Test {
+    pub(crate) role: Option<String>,
+    pub(crate) task: String,
+    /// Images of the distributions the task is deployed on, tested in parallel
+    pub(crate) images: Vec<String>,
+    /// Actor of the role running the command, the first user actor by default
+    pub(crate) user: Option<String>,
+    /// Shell command preparing the container, such as installing packages
+    pub(crate) setup: Option<String>,
+    /// Directory of statically linked sr and gensr copied in the containers, the images
+    /// provide them otherwise
+    pub(crate) binaries: Option<PathBuf>,
+    pub(crate) keep: bool,
+}
+
+/// Result of the test on one image
+pub(crate) struct Outcome {
+    pub(crate) image: String,
+    /// Why the task failed, if it did
+    pub(crate) error: Option<String>,
+    /// Granted paths that do not exist on the image
+    pub(crate) missing_paths: Vec<String>,
+    /// Granted D-Bus destinations that no service of the image provides
+    pub(crate) missing_dbus: Vec<String>,
+}
+
+impl Outcome {
+    pub(crate) fn failed(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            Some(error) => write!(f, "FAIL {}: {}", self.image, error)?,
+            None => write!(f, "PASS {}", self.image)?,
+        }
+        for path in &self.missing_paths {
+            write!(f, "\n    missing path {}", path)?;
+        }
+        for destination in &self.missing_dbus {
+            write!(f, "\n    missing dbus name {}", destination)?;
+        }
+        Ok(())
+    }
+}
+
+/// Granted paths missing in the container, globs and placeholders aside
+fn missing_paths(container: &Container, policy: &Policy) -> anyhow::Result<Vec<String>> {
+    let mut script = vec![
+        "sh",
+        "-c",
+        r#"for p in "$@"; do [ -e "$p" ] || echo "$p"; done"#,
+        "sh",
+    ];
+    script.extend(
+        policy
+            .files
+            .keys()
+            .filter(|path| !path.contains(['*', '?', '[', '{']))
+            .map(String::as_str),
+    );
+    Ok(container
+        .exec(None, &script)?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Granted D-Bus destinations without a service file or bus policy in the container
+fn missing_dbus(container: &Container, policy: &Policy) -> anyhow::Result<Vec<String>> {
+    let destinations: BTreeSet<&str> = policy
+        .dbus
+        .iter()
+        .map(|rule| rule.destination.as_str())
+        .collect();
+    let mut script = vec![
+        "sh",
+        "-c",
+        r#"for d in "$@"; do
+            ls /usr/share/dbus-1/system-services/"$d".service \
+                /usr/share/dbus-1/system.d/"$d".conf /etc/dbus-1/system.d/"$d".conf \
+                >/dev/null 2>&1 || echo "$d"
+        done"#,
+        "sh",
+    ];
+    script.extend(destinations);
+    Ok(container
+        .exec(None, &script)?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+fn run_on(
+    config: &Path,
+    target: &Target,
+    test: &Test,
+    image: &str,
+    index: usize,
+) -> anyhow::Result<Outcome> {
+    let task = test.task.as_str();
+    let capabilities = SR_CAPABILITIES
+        .into_iter()
+        .chain(target.policy.capabilities.iter().map(String::as_str))
+        .collect();
+    let container = Container::start(image, index, test.keep, &capabilities)?;
+    if let Some(setup) = &test.setup {
+        container
+            .exec(None, &["sh", "-c", setup])
+            .context("The setup command failed")?;
+    }
+    match &test.binaries {
+        Some(binaries) => {
+            container.copy(&binaries.join("gensr"), "/usr/local/bin/gensr")?;
+            container.copy(&binaries.join("sr"), "/usr/bin/sr")?;
+            container
+                .exec(None, &["setcap", "=p", "/usr/bin/sr"])
+                .context("Cannot give sr its capabilities, the image needs setcap")?;
+        }
+        None => {
+            container
+                .exec(None, &["sh", "-c", "command -v sr && command -v gensr"])
+                .context(
+                    "The image provides no sr or gensr, install them with --setup or give static binaries with --binaries",
+                )?;
+        }
+    }
+    // The PAM service installed along with sr on the image prevails
+    if Path::new("/etc/pam.d/sr").exists()
+        && container
+            .exec(None, &["test", "-e", "/etc/pam.d/sr"])
+            .is_err()
+    {
+        container.copy(Path::new("/etc/pam.d/sr"), "/etc/pam.d/sr")?;
+    }
+    container.exec(None, &["mkdir", "-p", "/etc/security"])?;
+    container.copy(config, CONTAINER_CONFIG)?;
+    if container.exec(None, &["id", &target.actor]).is_err() {
+        container.exec(None, &["useradd", "-m", &target.actor])?;
+    }
+    let missing_paths = missing_paths(&container, &target.policy)?;
+    let missing_dbus = missing_dbus(&container, &target.policy)?;
+    let deployed = container
+        .exec(
+            None,
+            &["gensr", "deploy", "--config", CONTAINER_CONFIG, "--yes"],
+        )
+        .map_err(|e| format!("deployment failed: {}", e));
+    let mut sr_command = vec!["sr", "-r", &target.role, "-t", task, "--"];
+    sr_command.extend(target.command.iter().map(String::as_str));
+    let error = deployed
+        .and_then(|_| {
+            container
+                .exec(Some(&target.actor), &sr_command)
+                .map_err(|e| e.to_string())
+        })
+        .err();
+    Ok(Outcome {
+        image: image.to_string(),
+        error,
+        missing_paths,
+        missing_dbus,
+    })
+}
+
+/// Deploy the configuration in a container of each image, then run the command of the task
+/// through sr as an actor of its role, the images in parallel
+pub(crate) fn run(
+    config_path: &str,
+    config: &Rc<RefCell<SConfig>>,
+    test: &Test,
+) -> anyhow::Result<Vec<Outcome>> {
+    let task = test.task.as_str();
+    // Binaries of the host are linked against its libraries, not those of the images
+    if let Some(binaries) = &test.binaries {
+        for binary in ["sr", "gensr"] {
+            let path = binaries.join(binary);
+            let elf = fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))?;
+            if !is_static(&elf).with_context(|| format!("{}", path.display()))? {
+                bail!(
+                    "{} is dynamically linked, build it statically, e.g. for the musl target",
+                    path.display()
+                );
+            }
+        }
+    }
+    let target = target(config, test.role.as_deref(), task, test.user.as_deref())?;
+    let prepared = prepared_config(config_path, &target.role, task)?;
+    println!(
+        "Testing {}/{}: {}",
+        target.role,
+        task,
+        target.command.join(" ")
+    );
+    let target = &target;
+    let prepared = prepared.path();
+    Ok(thread::scope(|scope| {
+        let workers: Vec<_> = test
+            .images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| {
+                scope.spawn(move || {
+                    run_on(prepared, target, test, image, index).unwrap_or_else(|e| Outcome {
+                        image: image.clone(),
+                        error: Some(e.to_string()),
+                        missing_paths: Vec::new(),
+                        missing_dbus: Vec::new(),
+                    })
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .filter_map(|worker| worker.join().ok())
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64-bit little-endian ELF header followed by program headers of the given types
+    fn elf(types: &[u32]) -> Vec<u8> {
+        let mut elf = vec![0u8; 64];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&(types.len() as u16).to_le_bytes());
+        for program_type in types {
+            let mut header = vec![0u8; 56];
+            header[..4].copy_from_slice(&program_type.to_le_bytes());
+            elf.extend(header);
+        }
+        elf
+    }
+
+    #[test]
+    fn is_static_looks_for_an_interpreter() {
+        assert!(is_static(&elf(&[1, 1])).unwrap());
+        assert!(!is_static(&elf(&[6, 3, 1])).unwrap());
+    }
+
+    #[test]
+    fn is_static_rejects_other_binaries() {
+        assert!(is_static(b"#!/bin/sh\n").is_err());
+        let mut big_endian = elf(&[1]);
+        big_endian[5] = 2;
+        assert!(is_static(&big_endian).is_err());
+        let truncated = elf(&[1, 3]);
+        assert!(is_static(&truncated[..100]).is_err());
+    }
+}
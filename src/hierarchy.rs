@@ -0,0 +1,230 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
+
+use rootasrole_core::database::structs::{SConfig, SRole};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    library::{self, LIBRARY_ROLE},
+    policy::Access,
+};
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Grant {
+    File(String, String),
+    Capability(String),
+    Dbus(String),
+}
+
+/// Whether the task files hold every path of the base with at least its access, so that
+/// inheriting the base grants the task nothing it did not have
+fn holds_all(task_files: &Map<String, Value>, base: &BTreeMap<String, String>) -> bool {
+    base.iter().all(|(path, access)| {
+        let held = task_files
+            .get(path)
+            .and_then(Value::as_str)
+            .and_then(|held| held.parse::<Access>().ok());
+        match (held, access.parse::<Access>()) {
+            (Some(held), Ok(access)) => held.contains(access),
+            _ => false,
+        }
+    })
+}
+
+/// Roles sharing enough grants to inherit them from a common base role
+pub(crate) struct Cluster {
+    pub(crate) roles: Vec<String>,
+    /// Grants held by every role of the cluster
+    common: BTreeSet<Grant>,
+    /// Number of grants left to each role once the base is factored out
+    specific: BTreeMap<String, usize>,
+}
+
+impl std::fmt::Display for Cluster {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let files = self
+            .common
+            .iter()
+            .filter(|g| matches!(g, Grant::File(..)))
+            .count();
+        let capabilities = self
+            .common
+            .iter()
+            .filter(|g| matches!(g, Grant::Capability(_)))
+            .count();
+        writeln!(
+            f,
+            "base role with {} common grants ({} files, {} capabilities, {} D-Bus)",
+            self.common.len(),
+            files,
+            capabilities,
+            self.common.len() - files - capabilities
+        )?;
+        for role in &self.roles {
+            writeln!(f, "  └ {} (+{} specific)", role, self.specific[role])?;
+        }
+        Ok(())
+    }
+}
+
+fn role_grants(role: &SRole) -> BTreeSet<Grant> {
+    let mut grants = BTreeSet::new();
+    for task in &role.tasks {
+        let task = task.as_ref().borrow();
+        if let Some(files) = task
+            .cred
+            ._extra_fields
+            .get("files")
+            .and_then(|value| value.as_object())
+        {
+            grants.extend(files.iter().map(|(path, access)| {
+                Grant::File(path.clone(), access.as_str().unwrap_or("").to_string())
+            }));
+        }
+        if let Some(caps) = &task.cred.capabilities {
+            grants.extend(
+                caps.add
+                    .iter()
+                    .map(|cap| Grant::Capability(cap.to_string())),
+            );
+        }
+        if let Some(dbus) = task
+            .cred
+            ._extra_fields
+            .get("dbus")
+            .and_then(|value| value.as_array())
+        {
+            grants.extend(dbus.iter().map(|rule| Grant::Dbus(rule.to_string())));
+        }
+    }
+    grants
+}
+
+fn similarity(a: &BTreeSet<Grant>, b: &BTreeSet<Grant>) -> f64 {
+    let common = a.intersection(b).count();
+    if common == 0 {
+        return 0.0;
+    }
+    common as f64 / a.union(b).count() as f64
+}
+
+/// Cluster the roles by shared grants, merging the most similar clusters as long as
+/// the Jaccard similarity of their grants reaches the threshold
+pub(crate) fn analyze(config: &Rc<RefCell<SConfig>>, threshold: f64) -> Vec<Cluster> {
+    let mut grants = BTreeMap::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        if role.name == LIBRARY_ROLE {
+            continue;
+        }
+        let role_grants = role_grants(&role);
+        if !role_grants.is_empty() {
+            grants.insert(role.name.clone(), role_grants);
+        }
+    }
+    let mut clusters: Vec<(Vec<String>, BTreeSet<Grant>)> = grants
+        .iter()
+        .map(|(name, grants)| (vec![name.clone()], grants.clone()))
+        .collect();
+    loop {
+        let mut best = None;
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                let score = similarity(&clusters[i].1, &clusters[j].1);
+                if score >= threshold && !best.is_some_and(|(_, _, s)| s >= score) {
+                    best = Some((i, j, score));
+                }
+            }
+        }
+        let Some((i, j, _)) = best else {
+            break;
+        };
+        let (roles, merged) = clusters.remove(j);
+        clusters[i].0.extend(roles);
+        clusters[i].1 = clusters[i].1.intersection(&merged).cloned().collect();
+    }
+    clusters
+        .into_iter()
+        .filter(|(roles, _)| roles.len() > 1)
+        .map(|(roles, common)| Cluster {
+            specific: roles
+                .iter()
+                .map(|role| (role.clone(), grants[role].difference(&common).count()))
+                .collect(),
+            roles,
+            common,
+        })
+        .collect()
+}
+
+/// Move the common file grants of each cluster into a base library task referenced by
+/// the tasks of its roles, recording the base as their parent. Capabilities and D-Bus
+/// grants stay in the tasks
+pub(crate) fn apply(config: &Rc<RefCell<SConfig>>, clusters: &[Cluster]) -> Vec<String> {
+    let mut bases = Vec::new();
+    for cluster in clusters {
+        let files: BTreeMap<String, String> = cluster
+            .common
+            .iter()
+            .filter_map(|grant| match grant {
+                Grant::File(path, access) => Some((path.clone(), access.clone())),
+                _ => None,
+            })
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+        let base = library::add_library(config, "base", &files);
+        for name in &cluster.roles {
+            let Some(role) = config.as_ref().borrow().role(name).cloned() else {
+                continue;
+            };
+            let mut role = role.as_ref().borrow_mut();
+            for task in &role.tasks {
+                let mut task = task.as_ref().borrow_mut();
+                let holds_base = task
+                    .cred
+                    ._extra_fields
+                    .get("files")
+                    .and_then(|value| value.as_object())
+                    .is_some_and(|task_files| holds_all(task_files, &files));
+                if holds_base {
+                    library::use_library(&mut task, &base, &files);
+                }
+            }
+            role._extra_fields
+                .insert("parents".to_string(), json!([base]));
+        }
+        bases.push(base);
+    }
+    bases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_all_requires_every_base_access() {
+        let task_files = json!({ "/etc/app": "RW", "/var/log/app": "R" });
+        let task_files = task_files.as_object().unwrap();
+        let base = |files: &[(&str, &str)]| {
+            files
+                .iter()
+                .map(|(path, access)| (path.to_string(), access.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert!(holds_all(task_files, &base(&[])));
+        assert!(holds_all(task_files, &base(&[("/etc/app", "R")])));
+        assert!(holds_all(
+            task_files,
+            &base(&[("/etc/app", "RW"), ("/var/log/app", "R")])
+        ));
+        assert!(!holds_all(task_files, &base(&[("/var/log/app", "RW")])));
+        assert!(!holds_all(task_files, &base(&[("/srv", "R")])));
+        assert!(!holds_all(task_files, &base(&[("/etc/app", "?")])));
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+
+use anyhow::{bail, Context};
+use rootasrole_core::database::structs::{SRole, STask};
+
+/// Length of the hash kept in usernames qualified by a site
+const SHORT_HASH: usize = 8;
+
+/// Identifier of the site in generated usernames, `host` deriving it from the machine id
+pub(crate) fn site_id(site: &str) -> anyhow::Result<String> {
+    if site == "host" {
+        let machine_id = fs::read_to_string("/etc/machine-id")
+            .context("Cannot read /etc/machine-id to identify the host")?;
+        return Ok(machine_id.trim().chars().take(6).collect());
+    }
+    if site.is_empty()
+        || site.len() > 8
+        || !site
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    {
+        bail!(
+            "Invalid site {}, expected up to 8 lowercase letters and digits, or host",
+            site
+        );
+    }
+    Ok(site.to_string())
+}
+
+/// Synthetic username of the hashed inputs, `<prefix>_<site>_<hash8>` within a site
+pub(crate) fn username(prefix: &str, digest: &str, site: Option<&str>) -> String {
+    match site {
+        Some(site) => format!("{}_{}_{}", prefix, site, &digest[..SHORT_HASH]),
+        None => format!("{}_{}", prefix, digest),
+    }
+}
+
+fn task_identity(task: &STask) -> Option<&str> {
+    task._extra_fields
+        .get("provenance")
+        .and_then(|provenance| provenance.get("identity"))
+        .and_then(|identity| identity.as_str())
+}
+
+/// Fail when the role already holds tasks generated from other inputs, whose shortened
+/// username collides with the one of the task
+pub(crate) fn check_collision(role: &SRole, task: &STask) -> anyhow::Result<()> {
+    let Some(identity) = task_identity(task) else {
+        return Ok(());
+    };
+    for existing in &role.tasks {
+        let existing = existing.as_ref().borrow();
+        if task_identity(&existing).is_some_and(|other| other != identity) {
+            bail!(
+                "Username {} collides with the one of task {}, generated from other commands",
+                role.name,
+                existing.name
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use rootasrole_core::database::structs::{SConfig, STask};
+use serde_json::json;
+
+use crate::tracecache::sha256_file;
+
+/// A pinned binary whose content changed since it was traced
+pub(crate) struct Drift {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    pub(crate) path: String,
+    pub(crate) expected: String,
+    /// None when the binary no longer exists
+    pub(crate) actual: Option<String>,
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.actual {
+            Some(actual) => write!(
+                f,
+                "{}/{}: {} changed, pinned {} but found {}",
+                self.role, self.task, self.path, self.expected, actual
+            ),
+            None => write!(
+                f,
+                "{}/{}: {} is missing, pinned {}",
+                self.role, self.task, self.path, self.expected
+            ),
+        }
+    }
+}
+
+/// Record the content hashes the task's binaries must keep
+pub(crate) fn pin(task: &mut STask, binaries: &BTreeMap<String, String>) {
+    if !binaries.is_empty() {
+        task._extra_fields
+            .insert("pinned_binaries".to_string(), json!(binaries));
+    }
+}
+
+/// Compare the pinned binaries of every task with their content on disk
+pub(crate) fn verify(config: &Rc<RefCell<SConfig>>) -> (usize, Vec<Drift>) {
+    let mut checked = 0;
+    let mut drifts = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            let Some(pinned) = task
+                ._extra_fields
+                .get("pinned_binaries")
+                .and_then(|value| value.as_object())
+            else {
+                continue;
+            };
+            for (path, expected) in pinned {
+                let Some(expected) = expected.as_str() else {
+                    continue;
+                };
+                checked += 1;
+                let actual = sha256_file(path.as_ref());
+                if actual.as_deref() != Some(expected) {
+                    drifts.push(Drift {
+                        role: role.name.clone(),
+                        task: task.name.to_string(),
+                        path: path.clone(),
+                        expected: expected.to_string(),
+                        actual,
+                    });
+                }
+            }
+        }
+    }
+    (checked, drifts)
+}
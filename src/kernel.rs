@@ -0,0 +1,159 @@
+use std::{cell::RefCell, collections::BTreeSet, fs, path::Path, rc::Rc};
+
+use anyhow::bail;
+use rootasrole_core::database::structs::SConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::policy::Policy;
+
+/// Fail when tasks of the configuration hold capabilities the running kernel does not know,
+/// as happens when traces collected on another architecture or kernel version are deployed.
+/// Names no kernel knows are already rejected when the configuration is loaded
+pub(crate) fn check_capabilities(path: &str, config: &Rc<RefCell<SConfig>>) -> anyhow::Result<()> {
+    let mut unsupported_capabilities = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            let Some(capabilities) = &task.cred.capabilities else {
+                continue;
+            };
+            for cap in (capabilities.add | capabilities.sub)
+                .iter()
+                .filter(|cap| !cap.is_supported())
+            {
+                unsupported_capabilities.push(format!(
+                    "  {}/{}: {} (not supported by the running kernel)",
+                    role.name, task.name, cap
+                ));
+            }
+        }
+    }
+    if !unsupported_capabilities.is_empty() {
+        bail!(
+            "{} grants capabilities this host cannot grant, was it generated on another architecture or kernel?\n{}",
+            path,
+            unsupported_capabilities.join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Kernel settings changing whether an unprivileged command can do what the trace observed
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Requirement {
+    /// The command creates user namespaces
+    UnprivilegedUserns,
+    /// The command loads BPF programs or maps without CAP_BPF
+    UnprivilegedBpf,
+    /// The command reads raw kernel or device memory, which lockdown denies even to root
+    NoLockdown,
+}
+
+/// Files lockdown denies access to
+const LOCKDOWN_PATHS: [&str; 5] = [
+    "/dev/mem",
+    "/dev/kmem",
+    "/dev/port",
+    "/proc/kcore",
+    "/sys/kernel/debug",
+];
+
+impl std::fmt::Display for Requirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Requirement::UnprivilegedUserns => write!(f, "unprivileged user namespaces"),
+            Requirement::UnprivilegedBpf => write!(f, "unprivileged BPF"),
+            Requirement::NoLockdown => write!(f, "kernel lockdown disabled"),
+        }
+    }
+}
+
+fn sysctl(name: &str) -> Option<String> {
+    fs::read_to_string(Path::new("/proc/sys").join(name.replace('.', "/")))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+impl Requirement {
+    /// How the running kernel contradicts the requirement, if it does
+    pub(crate) fn mismatch(&self) -> Option<String> {
+        match self {
+            Requirement::UnprivilegedUserns => {
+                if sysctl("kernel.unprivileged_userns_clone").as_deref() == Some("0") {
+                    Some("kernel.unprivileged_userns_clone is 0".to_string())
+                } else if sysctl("user.max_user_namespaces").as_deref() == Some("0") {
+                    Some("user.max_user_namespaces is 0".to_string())
+                } else {
+                    None
+                }
+            }
+            Requirement::UnprivilegedBpf => sysctl("kernel.unprivileged_bpf_disabled")
+                .filter(|value| value != "0")
+                .map(|value| format!("kernel.unprivileged_bpf_disabled is {}", value)),
+            Requirement::NoLockdown => {
+                let lockdown = fs::read_to_string("/sys/kernel/security/lockdown").ok()?;
+                // The active mode is bracketed: `none [integrity] confidentiality`
+                let mode = lockdown
+                    .split_whitespace()
+                    .find(|mode| mode.starts_with('['))?
+                    .trim_matches(|c| c == '[' || c == ']');
+                (mode != "none").then(|| format!("kernel.lockdown is {}", mode))
+            }
+        }
+    }
+}
+
+/// Requirements of a traced policy, from what was observed on the traced processes and
+/// from its grants
+pub(crate) fn requirements(
+    policy: &Policy,
+    observed: &BTreeSet<Requirement>,
+) -> BTreeSet<Requirement> {
+    let has_capability = |name: &str| {
+        policy
+            .capabilities
+            .iter()
+            .any(|capability| capability.eq_ignore_ascii_case(name))
+    };
+    let mut requirements = observed.clone();
+    if has_capability("CAP_BPF") || has_capability("CAP_SYS_ADMIN") {
+        requirements.remove(&Requirement::UnprivilegedBpf);
+    }
+    if policy.files.keys().any(|path| {
+        LOCKDOWN_PATHS
+            .iter()
+            .any(|locked| Path::new(path).starts_with(locked))
+    }) {
+        requirements.insert(Requirement::NoLockdown);
+    }
+    requirements
+}
+
+/// Kernel requirements of the tasks the running kernel does not meet
+pub(crate) fn verify(config: &Rc<RefCell<SConfig>>) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            let Some(requirements) = task
+                ._extra_fields
+                .get("kernel_requirements")
+                .and_then(|value| serde_json::from_value::<Vec<Requirement>>(value.clone()).ok())
+            else {
+                continue;
+            };
+            for requirement in requirements {
+                if let Some(mismatch) = requirement.mismatch() {
+                    mismatches.push(format!(
+                        "{}/{}: requires {}, but {}",
+                        role.name, task.name, requirement, mismatch
+                    ));
+                }
+            }
+        }
+    }
+    mismatches
+}
@@ -0,0 +1,219 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+};
+
+use log::warn;
+use rootasrole_core::database::structs::{IdTask, SConfig, SRole, STask, SetBehavior};
+use serde_json::{Map, Value};
+use sha2::Digest;
+
+use crate::policy::Access;
+
+/// Role holding the shared privilege sets, never granted to any actor
+pub(crate) const LIBRARY_ROLE: &str = "gensr-library";
+
+/// A shared privilege set factored out of several tasks
+pub(crate) struct Library {
+    pub(crate) name: String,
+    pub(crate) files: usize,
+    pub(crate) tasks: Vec<String>,
+}
+
+/// Libraries referenced by the task, in a `uses` field only gensr understands: RootAsRole
+/// ignores it, the libraries are inlined by `expand` whenever gensr loads the configuration
+fn task_uses(task: &STask) -> Vec<String> {
+    task._extra_fields
+        .get("uses")
+        .and_then(|value| value.as_array())
+        .map(|uses| {
+            uses.iter()
+                .filter_map(|name| name.as_str().map(|name| name.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn library_name(prefix: &str, files: &BTreeMap<String, String>) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for (path, access) in files {
+        hasher.update(path.as_bytes());
+        hasher.update(access.as_bytes());
+    }
+    format!("{}-{}", prefix, &hex::encode(hasher.finalize())[..12])
+}
+
+fn library_role(config: &Rc<RefCell<SConfig>>) -> Rc<RefCell<SRole>> {
+    if let Some(role) = config.as_ref().borrow().role(LIBRARY_ROLE) {
+        return role.clone();
+    }
+    let role = Rc::new(RefCell::new(SRole::new(
+        LIBRARY_ROLE.to_string(),
+        Rc::downgrade(config),
+    )));
+    config.as_ref().borrow_mut().roles.push(role.clone());
+    role
+}
+
+/// Add a library task holding the file grants, returning its name
+pub(crate) fn add_library(
+    config: &Rc<RefCell<SConfig>>,
+    prefix: &str,
+    files: &BTreeMap<String, String>,
+) -> String {
+    let name = library_name(prefix, files);
+    let role = library_role(config);
+    let mut library = STask::new(IdTask::Name(name.clone()), Rc::downgrade(&role));
+    library.commands.default_behavior = Some(SetBehavior::None);
+    library.cred._extra_fields.insert(
+        "files".to_string(),
+        Value::Object(
+            files
+                .iter()
+                .map(|(path, access)| (path.clone(), Value::String(access.clone())))
+                .collect(),
+        ),
+    );
+    role.as_ref()
+        .borrow_mut()
+        .tasks
+        .push(Rc::new(RefCell::new(library)));
+    name
+}
+
+/// Remove the library grants from the task, and reference the library instead
+pub(crate) fn use_library(task: &mut STask, name: &str, files: &BTreeMap<String, String>) {
+    if let Some(task_files) = task
+        .cred
+        ._extra_fields
+        .get_mut("files")
+        .and_then(|value| value.as_object_mut())
+    {
+        task_files.retain(|path, access| files.get(path).map(String::as_str) != access.as_str());
+    }
+    let mut uses = task_uses(task);
+    uses.push(name.to_string());
+    task._extra_fields
+        .insert("uses".to_string(), serde_json::json!(uses));
+}
+
+/// Move the file grants shared by at least `min_tasks` tasks into library tasks,
+/// when a shared set holds at least `min_files` entries
+pub(crate) fn factor(
+    config: &Rc<RefCell<SConfig>>,
+    min_tasks: usize,
+    min_files: usize,
+) -> Vec<Library> {
+    let mut tasks: BTreeMap<String, Rc<RefCell<STask>>> = BTreeMap::new();
+    let mut holders: BTreeMap<(String, String), BTreeSet<String>> = BTreeMap::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        if role.name == LIBRARY_ROLE {
+            continue;
+        }
+        for task in &role.tasks {
+            let id = format!("{}/{}", role.name, task.as_ref().borrow().name);
+            if let Some(files) = task
+                .as_ref()
+                .borrow()
+                .cred
+                ._extra_fields
+                .get("files")
+                .and_then(|value| value.as_object())
+            {
+                for (path, access) in files {
+                    holders
+                        .entry((path.clone(), access.as_str().unwrap_or("").to_string()))
+                        .or_default()
+                        .insert(id.clone());
+                }
+            }
+            tasks.insert(id, task.clone());
+        }
+    }
+    // Grants held by exactly the same tasks form one shared set
+    let mut sets: BTreeMap<BTreeSet<String>, BTreeMap<String, String>> = BTreeMap::new();
+    for ((path, access), holders) in holders {
+        sets.entry(holders).or_default().insert(path, access);
+    }
+    let mut libraries = Vec::new();
+    for (holders, files) in sets {
+        if holders.len() < min_tasks.max(2) || files.len() < min_files {
+            continue;
+        }
+        let name = add_library(config, "lib", &files);
+        for id in &holders {
+            use_library(&mut tasks[id].as_ref().borrow_mut(), &name, &files);
+        }
+        libraries.push(Library {
+            name,
+            files: files.len(),
+            tasks: holders.into_iter().collect(),
+        });
+    }
+    libraries
+}
+
+/// Inline the library grants into the tasks referencing them, then drop the library role,
+/// so the loaded configuration can be deployed as if nothing was factored out
+pub(crate) fn expand(config: &Rc<RefCell<SConfig>>) {
+    let Some(role) = config.as_ref().borrow().role(LIBRARY_ROLE).cloned() else {
+        return;
+    };
+    let mut libraries: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+    for task in &role.as_ref().borrow().tasks {
+        let task = task.as_ref().borrow();
+        if let Some(files) = task
+            .cred
+            ._extra_fields
+            .get("files")
+            .and_then(|value| value.as_object())
+        {
+            libraries.insert(task.name.to_string(), files.clone());
+        }
+    }
+    config
+        .as_ref()
+        .borrow_mut()
+        .roles
+        .retain(|r| r.as_ref().borrow().name != LIBRARY_ROLE);
+    for r in &config.as_ref().borrow().roles {
+        for task in &r.as_ref().borrow().tasks {
+            let mut task = task.as_ref().borrow_mut();
+            let uses = task_uses(&task);
+            if uses.is_empty() {
+                continue;
+            }
+            let task_name = task.name.to_string();
+            let files = task
+                .cred
+                ._extra_fields
+                .entry("files")
+                .or_insert_with(|| Value::Object(Map::new()));
+            let Some(files) = files.as_object_mut() else {
+                continue;
+            };
+            for name in uses {
+                let Some(library) = libraries.get(&name) else {
+                    warn!("Task {} uses unknown library {}", task_name, name);
+                    continue;
+                };
+                for (path, access) in library {
+                    let access = match (files.get(path).and_then(|a| a.as_str()), access.as_str()) {
+                        (Some(current), Some(added)) => {
+                            match (current.parse::<Access>(), added.parse::<Access>()) {
+                                (Ok(current), Ok(added)) => {
+                                    Value::String((current | added).to_string())
+                                }
+                                _ => access.clone(),
+                            }
+                        }
+                        _ => access.clone(),
+                    };
+                    files.insert(path.clone(), access);
+                }
+            }
+        }
+    }
+}
@@ -1,33 +1,105 @@
 use std::{
     cell::RefCell,
-    io,
+    collections::{BTreeSet, HashMap},
+    env,
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
     rc::Rc,
+    time::Duration,
 };
 
 use clap::{Parser, Subcommand, ValueEnum};
-use log::{warn, LevelFilter};
-use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
-use policy::Policy;
+use compliance::ComplianceRules;
+use log::{debug, warn, LevelFilter};
+use nix::{
+    sys::signal::{signal, SigHandler, Signal},
+    unistd::{setgid, setgroups, setuid, Gid, Uid},
+};
+use policy::{NormalizeOptions, Policy};
+use progress::Progress;
 use rootasrole_core::{
     database::{
-        options::{EnvBehavior, EnvKey, Level, Opt, SAuthentication, SEnvOptions},
-        structs::{SConfig, SRole},
+        options::{EnvBehavior, EnvKey, Level, Opt, SAuthentication, SEnvOptions, STimeout},
+        structs::{SConfig, SRole, STask},
         versionning::Versioning,
     },
     rc_refcell,
 };
 use sha2::Digest;
 
+mod actors;
+mod alerts;
+mod artifacts;
+mod audit;
+mod auditlog;
+mod baseline;
+mod bench;
+mod bundle;
+mod canonical;
 mod capable;
+mod cgroup;
+mod compliance;
 mod deploy;
+mod doc;
+mod doctor;
+mod edit;
+mod ephemeral;
+mod exec;
+mod export;
+mod fanotify;
+mod fapolicyd;
+mod fileops;
+mod filter;
+mod fingerprint;
+mod fixture;
+mod harness;
+mod hierarchy;
+mod identity;
+mod integrity;
+mod kernel;
+mod library;
+mod metrics;
+mod migrate;
+mod mounts;
+mod notify;
+mod overlap;
+mod packages;
+mod pam;
+mod params;
 mod policy;
+mod polkitcache;
+mod procscan;
+mod progress;
+mod provenance;
+mod regenerate;
+mod rules;
+mod sandbox;
+mod schedule;
+mod script;
+mod search;
+mod secrets;
+mod selftest;
+mod selinux;
+mod setuid;
+mod stage;
+mod state;
+mod subid;
+mod telemetry;
+mod timer;
+mod tracecache;
+mod umask;
+mod whichrole;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Extra names of options and variables whose values are masked in the logs, the
+    /// trace cache, the audit log and the printed policies, such as SESSION
+    #[arg(long, global = true, value_delimiter = ',')]
+    redact: Vec<String>,
 }
 
 #[derive(Clone, ValueEnum)]
@@ -36,6 +108,103 @@ enum Mode {
     Manual,
 }
 
+/// Where and how a generated task is written
+struct Output {
+    mode: Mode,
+    format: String,
+    export: export::ExportOptions,
+    schedule: Option<schedule::Schedule>,
+    /// Whether sr authenticates the user before running the task
+    authentication: SAuthentication,
+    /// How long a successful authentication is remembered for the task
+    timeout: Option<STimeout>,
+    doc: Option<doc::Doc>,
+    env_values: Option<secrets::EnvValues>,
+    /// Granted paths whose use is reported by `gensr alerts`
+    alert_on: Vec<String>,
+    stage: Option<PathBuf>,
+    pin_binary: bool,
+    config: Option<String>,
+    skip_covered: bool,
+    baseline_threshold: Option<f64>,
+}
+
+#[derive(Subcommand)]
+enum BaselineAction {
+    /// Publish the tasks of the configuration as the approved baseline
+    Push {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Git repository or HTTP endpoint of the baselines
+        #[arg(short, long)]
+        remote: String,
+    },
+    /// Fetch the approved baseline
+    Pull {
+        /// Git repository or HTTP endpoint of the baselines
+        #[arg(short, long)]
+        remote: String,
+    },
+    /// Compare the tasks of the configuration with the pulled baseline
+    Diff {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Share of differing grants tolerated for a task
+        #[arg(long, default_value_t = 0.0)]
+        threshold: f64,
+    },
+}
+
+#[derive(Subcommand)]
+enum StateAction {
+    /// Write the users, ACL entries, dbus and polkit policies and configuration deployed
+    /// on this host into a portable bundle
+    Export {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// File of the bundle
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Rebuild the deployed state recorded in a bundle, on a replacement host
+    Import {
+        /// File of the bundle
+        bundle: PathBuf,
+
+        /// Where to write the configuration of the bundle
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Replace the state of a host where tasks are already deployed
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum Analysis {
+    /// Cluster the roles by shared grants and propose base roles holding the common ones
+    Hierarchy {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Minimum Jaccard similarity of the grants of roles sharing a base role
+        #[arg(long, default_value_t = 0.5)]
+        threshold: f64,
+
+        /// Rewrite the configuration to move the common file grants into the base roles
+        #[arg(long)]
+        apply: bool,
+    },
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Test if a user can perform an action
@@ -46,27 +215,157 @@ enum Commands {
         /// The action to perform
         #[arg(short, long)]
         action: String,
+
+        /// Namespace of the polkit policy, as deployed with --polkit-namespace
+        #[arg(long)]
+        namespace: Option<String>,
+
+        /// Cache the decisions for this many seconds, until the policy changes
+        #[arg(long)]
+        cache_ttl: Option<u64>,
     },
     /// Generate a policy for a task
     Generate {
         ///TODO: --mode auto|manual
         #[arg(short, long, default_value = "auto")]
         mode: Mode,
+        /// Write the RootAsRole task (json), or export the policy for another tool,
+        /// such as the observed network flows as firewall rules scoped to the role user
+        #[arg(long, default_value = export::JSON, value_parser = clap::builder::PossibleValuesParser::new(export::names()))]
+        format: String,
+        /// Write the exported policy to this file instead of the standard output
+        #[arg(long)]
+        export_output: Option<PathBuf>,
+        /// Template of the exported policy, whose {{rules}} and {{user}} placeholders are replaced
+        #[arg(long)]
+        template: Option<PathBuf>,
         /// capable path location
         #[arg(long)]
         capable: Option<PathBuf>,
+        /// Collector of the traced privileges: fanotify only records the files, where eBPF is locked down
+        #[arg(long, value_enum, default_value = "capable")]
+        backend: fanotify::Backend,
         /// Fail-then-add: Start with an empty privilege set, add privileges as the command fails, re-execute the command until it succeeds
         /// If not set, the command will be executed with the full privilege set directly, respecting the Replace-then-record approach
         #[arg(short, long, default_value = "false")]
         fail_then_add: bool,
 
+        /// Existing task of the configuration, as role/task, whose privileges are granted
+        /// from the first fail-then-add iteration, so that re-tracing an updated command converges quickly
+        #[arg(long, requires = "fail_then_add", requires = "config")]
+        seed_task: Option<String>,
+
+        /// Site identifier included in the generated username, as gsr_<site>_<hash8>, so that
+        /// configurations of several sites can be aggregated; host derives it from the machine id
+        #[arg(long)]
+        site: Option<String>,
+
+        /// Trace inside an unprivileged user namespace, without root privileges.
+        /// Capabilities observed there may differ from the ones needed on the host
+        #[arg(long, default_value = "false")]
+        rootless: bool,
+
+        /// Working directory of the traced command
+        #[arg(long)]
+        cwd: Option<PathBuf>,
+
+        /// Environment variable set for the traced command: KEY=VALUE
+        #[arg(long)]
+        env: Vec<String>,
+
+        /// File of KEY=VALUE lines to set in the traced command's environment
+        #[arg(long)]
+        env_file: Option<PathBuf>,
+
+        /// Start the traced command from an empty environment, as cron or systemd would
+        #[arg(long)]
+        clean_env: bool,
+
+        /// Maximum CPU time of the traced process, in seconds
+        #[arg(long)]
+        limit_cpu: Option<u64>,
+
+        /// Maximum address space of each traced process, e.g. 512M or 2G
+        #[arg(long)]
+        limit_mem: Option<String>,
+
+        /// Niceness of the traced process
+        #[arg(long, allow_hyphen_values = true)]
+        nice: Option<i32>,
+
+        /// Traces shorter than this many seconds are reported as possibly short-circuited
+        #[arg(long, default_value_t = 0.05)]
+        min_duration: f64,
+
+        /// Accept a policy generated from an empty trace of a successful command
+        #[arg(long)]
+        allow_empty: bool,
+
+        /// Reuse the result of a previous trace of the same command, binaries and options
+        #[arg(long)]
+        cache: bool,
+
+        /// Save what each trace saw in this directory, to compare runs with `gensr trace-diff`
+        #[arg(long)]
+        save_artifacts: Option<PathBuf>,
+
+        /// Directory of input files laid out as in the root filesystem, put in place during
+        /// the trace and removed afterwards
+        #[arg(long, conflicts_with = "join_unit")]
+        fixture: Option<PathBuf>,
+
+        /// Do not display the trace progress
+        #[arg(long)]
+        no_progress: bool,
+
+        /// Kill the traced process tree after this many seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+
+        /// Trace inside this network namespace, where mock services stand for the real endpoints,
+        /// or `new` for an empty namespace with only the loopback interface
+        #[arg(long, conflicts_with = "rootless")]
+        netns: Option<String>,
+
+        /// Run the traced command in the mount, network, IPC, UTS and cgroup namespaces of
+        /// this running service, to trace it with the view of the service
+        #[arg(long, conflicts_with_all = ["rootless", "netns", "no_side_effects"])]
+        join_unit: Option<String>,
+
+        /// Record the writes of the traced command without performing them: they report
+        /// success, or fail with erofs
+        #[arg(
+            long,
+            value_enum,
+            num_args = 0..=1,
+            default_missing_value = "success",
+            conflicts_with = "rootless"
+        )]
+        no_side_effects: Option<sandbox::WriteStrategy>,
+
         /// Loop until the command succeed
         #[arg(short, long, default_value = "false")]
         no_loop: bool,
 
+        /// Exit codes the traced command succeeds with, e.g. 0,1 for diff-like tools
+        #[arg(long, value_delimiter = ',', default_value = "0")]
+        success_exit_codes: Vec<i32>,
+
+        /// Run a failing command again up to this many times before taking the failure for
+        /// a missing privilege
+        #[arg(long, default_value_t = 0)]
+        retries: u32,
+
+        /// Seconds to wait before running a failed command again
+        #[arg(long, default_value_t = 5)]
+        retry_delay: u64,
+
         /// Path to the rootasrole configuration file
         #[arg(short, long)]
         config: Option<String>,
+        /// Write the task into this staging directory for review, instead of the configuration
+        #[arg(long)]
+        stage: Option<PathBuf>,
         /// Path to the ansible playbook
         #[arg(short, long)]
         playbook: Option<String>,
@@ -74,11 +373,130 @@ enum Commands {
         #[arg(short, long)]
         task: Option<String>,
 
+        /// Command rewriting the policy: it reads the policy as JSON on its standard input and
+        /// writes the transformed policy to its standard output, applied in the given order
+        #[arg(long)]
+        filter: Vec<String>,
+
+        /// Organization compliance rules (YAML) the generated policy must satisfy
+        #[arg(long)]
+        compliance: Option<PathBuf>,
+
+        /// Waive a compliance rule, recording the justification in the task: RULE=JUSTIFICATION
+        #[arg(long)]
+        waive: Vec<String>,
+
         /// Whether the password should be supplied.
         #[clap(default_value = "skip")]
         password_policy: String,
 
-        /// Additional ansible commands
+        /// Whether sr authenticates the user before running the generated task, the password
+        /// policy of the trace by default
+        #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(["skip", "perform"]))]
+        auth: Option<String>,
+
+        /// How long sr remembers an authentication for the generated task, such as 5m,
+        /// instead of the global RootAsRole default
+        #[arg(long)]
+        timeout_auth: Option<String>,
+
+        /// Business justification of the generated task, recorded in its doc block
+        #[arg(long)]
+        description: Option<String>,
+
+        /// Change or incident ticket the generated task was requested in
+        #[arg(long)]
+        ticket: Option<String>,
+
+        /// Also record the captured values of the environment variables for sr to set them
+        #[arg(long)]
+        env_values: bool,
+
+        /// How the values of variables looking like secrets (TOKEN, PASSWORD...) are stored
+        #[arg(long, value_enum, default_value_t, requires = "env_values")]
+        secret_storage: secrets::SecretStorage,
+
+        /// age recipient the secrets are encrypted for, with --secret-storage age
+        #[arg(long, required_if_eq("secret_storage", "age"))]
+        age_recipient: Vec<String>,
+
+        /// Prefix of the secret references, such as vault://secret/gensr,
+        /// with --secret-storage reference
+        #[arg(long, required_if_eq("secret_storage", "reference"))]
+        secret_backend: Option<String>,
+
+        /// Granted path whose use by the task is reported, such as /etc/shadow, watched by
+        /// the auditd rules of `deploy --with-audit`
+        #[arg(long)]
+        alert_on: Vec<String>,
+
+        /// Trace a script through the interpreter of its shebang line, arguments follow `--`
+        #[arg(long, conflicts_with = "playbook")]
+        script: Option<PathBuf>,
+
+        /// Also grant read access to the files the traced script imports or sources
+        #[arg(long)]
+        script_deps: bool,
+
+        /// Parameter of the traced command written {{NAME}}: NAME=TRACED_VALUE[,ALLOWED_VALUE...]
+        #[arg(long)]
+        param: Vec<String>,
+
+        /// Pin the SHA-256 of the traced binaries in the task, verified by `gensr audit`
+        #[arg(long)]
+        pin_binary: bool,
+
+        /// Restrict the generated task to the traced command instead of any command
+        #[arg(long)]
+        strict_commands: bool,
+
+        /// Replace the recorded arguments matching this glob by the glob, e.g. 'myapp-*'
+        #[arg(long, requires = "strict_commands")]
+        generalize_args: Vec<String>,
+
+        /// Replace the traced paths by their symlink-free target
+        #[arg(long)]
+        resolve_symlinks: bool,
+
+        /// Keep the trailing slash of traced directories
+        #[arg(long)]
+        keep_trailing_slash: bool,
+
+        /// Mount point of a case-insensitive filesystem, whose traced paths are lowercased
+        #[arg(long)]
+        case_insensitive: Vec<PathBuf>,
+
+        /// Collapse this many sibling files with the same access into a directory glob
+        #[arg(long)]
+        collapse_threshold: Option<usize>,
+
+        /// Do not add grants already covered by another task of the same role
+        #[arg(long)]
+        skip_covered: bool,
+
+        /// Fail when the task diverges from the pulled baseline by more than this share of its grants
+        #[arg(long)]
+        baseline_threshold: Option<f64>,
+
+        /// Hours the generated task may run, e.g. 22-6 for the night
+        #[arg(long)]
+        allowed_hours: Option<String>,
+
+        /// Days the generated task may run, e.g. mon-fri or sat,sun
+        #[arg(long)]
+        allowed_days: Option<String>,
+
+        /// Keep the privileges used by the setuid helpers the command executes (sudo, mount,
+        /// ping) in the task, or exclude them and only note the dependency. Asked when unset
+        #[arg(long, value_enum)]
+        setuid_helpers: Option<setuid::HelperMode>,
+
+        /// Generate one task per command separated by `---`, all run by the same role user
+        #[arg(long, conflicts_with = "script")]
+        per_command_tasks: bool,
+
+        /// Command to trace, `---` separates commands merged into the same task unless it is
+        /// the command of an ansible task
         #[arg(last = true)]
         command: Vec<String>,
     },
@@ -88,9 +506,117 @@ enum Commands {
         #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
         config: String,
 
-        /// Skip the confirmation prompt
+        /// Skip the confirmation prompt, also enabled by GENSR_ASSUME_YES=1
         #[arg(short, long)]
         yes: bool,
+
+        /// Install auditd rules watching the granted paths and executions of each role user
+        #[arg(long)]
+        with_audit: bool,
+
+        /// Install fapolicyd trust entries for the binaries each role is allowed to execute
+        #[arg(long)]
+        fapolicyd: bool,
+
+        /// SELinux user the role users are mapped to when SELinux is enforcing
+        #[arg(long, default_value = "user_u")]
+        selinux_user: String,
+
+        /// Organization compliance rules (YAML) every deployed task must satisfy
+        #[arg(long)]
+        compliance: Option<PathBuf>,
+
+        /// Deny interactive logins (ssh, console) of the role users through PAM
+        #[arg(long)]
+        deny_login: bool,
+
+        /// Do not create the role users, allocate them at each invocation with `gensr ephemeral`
+        #[arg(long)]
+        ephemeral_users: bool,
+
+        /// Create missing role users locally, or require them from SSSD/LDAP with nss-only
+        #[arg(long, value_enum, default_value = "local")]
+        user_backend: deploy::UserBackend,
+
+        /// Write the dbus policies in one file per task user, or in a single consolidated
+        /// rootasrole.conf rewritten atomically, faster for dbus to load with many users
+        #[arg(long, value_enum, default_value = "per-user")]
+        dbus_layout: deploy::DbusLayout,
+
+        /// Number of tasks deployed concurrently, the number of CPUs by default
+        #[arg(long)]
+        jobs: Option<usize>,
+
+        /// Redeploy every task, even the ones unchanged since the last deploy
+        #[arg(long)]
+        full: bool,
+
+        /// Grant the files to a rar-<role> group of the task users of each role, instead of each user
+        #[arg(long)]
+        role_groups: bool,
+
+        /// Also add the users granted each role to its group
+        #[arg(long, requires = "role_groups")]
+        role_group_actors: bool,
+
+        /// Fail when a task diverges from the pulled baseline by more than this share of its grants
+        #[arg(long)]
+        baseline_threshold: Option<f64>,
+
+        /// Record each execution of a role through sr in the audit log, for `gensr usage-report`
+        #[arg(long)]
+        usage_hook: bool,
+
+        /// Allocate the uids and gids of new task users from this range, as <first>-<last>,
+        /// so they match across the hosts of a fleet; recorded for later deploys
+        #[arg(long)]
+        id_range: Option<state::IdRange>,
+
+        /// Namespace of the polkit policy of the configuration, such as staging, so that
+        /// configurations deployed on the same host keep their own grants. Derived from the
+        /// configuration path by default, except for the default configuration
+        #[arg(long)]
+        polkit_namespace: Option<String>,
+
+        /// Have the polkit rule cache the decisions for this many seconds, so that bursts of
+        /// identical checks do not read the policy each time
+        #[arg(long)]
+        polkit_cache_ttl: Option<u64>,
+
+        /// Deploy the tasks monitor-only: create their users and grants, and audit the
+        /// operations they are denied, without restricting the existing access paths such as
+        /// sudo. Deploying again without it enforces them
+        #[arg(long, conflicts_with_all = ["fapolicyd", "deny_login"])]
+        shadow: bool,
+
+        /// Grant write on the directory of the files a task creates, deletes or renames,
+        /// letting it replace any file there; never granted on system directories
+        #[arg(long)]
+        allow_parent_write: bool,
+
+        /// age identity file decrypting the secrets stored with `--secret-storage age`, written
+        /// to the env file `gensr secret-exec` reads for the task user
+        #[arg(long)]
+        age_identity: Option<PathBuf>,
+
+        /// Command printing the value of a secret stored with `--secret-storage reference`,
+        /// given the reference as its last argument, such as `vault kv get -field=value`
+        #[arg(long)]
+        secret_resolver: Option<String>,
+
+        /// Install a systemd timer running `gensr audit` on the configuration daily, reporting
+        /// drift through the notification hooks
+        #[arg(long)]
+        install_timer: bool,
+    },
+    /// Merge a task staged by `generate --stage` into the configuration, after review
+    Approve {
+        /// The staged task file
+        staged: PathBuf,
+
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
     },
     /// Undeploy rootasrole from the system
     Undeploy {
@@ -98,180 +624,1853 @@ enum Commands {
         #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
         config: String,
 
-        /// Skip the confirmation prompt
+        /// Skip the confirmation prompt, also enabled by GENSR_ASSUME_YES=1
         #[arg(short, long)]
         yes: bool,
+
+        /// List the users, home directories, ACL entries and files to remove, without removing them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Confirm the removal of each role user
+        #[arg(short, long, conflicts_with = "yes")]
+        interactive: bool,
+
+        /// Namespace of the polkit policy the configuration was deployed with
+        #[arg(long)]
+        polkit_namespace: Option<String>,
     },
-}
+    /// Verify that the binaries pinned in the tasks still match their content on disk
+    Audit {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+    },
+    /// Queue for re-tracing the tasks whose binaries belong to packages upgraded since the last run
+    WatchPackages {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
 
-fn parse_sauthentication(auth: &str) -> anyhow::Result<SAuthentication> {
-    Ok(match auth {
-        "skip" => SAuthentication::Skip,
-        "perform" => SAuthentication::Perform,
-        _ => {
-            return Err(anyhow::anyhow!(
-                "Only 'skip' and 'perform' are allowed for password_policy"
-            ))
-        }
-    })
-}
+        /// Install a package manager hook running this command after each transaction
+        #[arg(long, value_enum)]
+        install_hook: Option<packages::Hook>,
+    },
+    /// Summarize the activity recorded by auditd for deployed roles
+    AuditReport {
+        /// Only report the activity of this role
+        #[arg(short, long)]
+        role: Option<String>,
 
-fn main() -> io::Result<()> {
-    #[cfg(debug_assertions)]
-    env_logger::builder()
-        .default_format()
-        .filter_level(LevelFilter::Debug)
-        .init();
-    #[cfg(not(debug_assertions))]
-    env_logger::builder()
-        .default_format()
-        .filter_level(LevelFilter::Info)
-        .init();
-    let args = Cli::parse();
-    match args.command {
-        Commands::Polkit { user, action } => deploy::check_polkit(&action, &user),
-        Commands::Generate {
-            mode,
-            config,
-            playbook,
-            task,
-            command,
-            fail_then_add,
-            capable,
-            no_loop,
-            password_policy,
-        } => {
-            // TODO: --mode auto|manual
-            let username = match (&playbook, &task) {
-                (Some(playbook), Some(task)) => get_username_ansible(playbook, task),
-                _ => get_username_gensr(&command),
-            };
-            let mut capable = capable::Capable::builder()
-                .fail_then_add(fail_then_add)
-                .command(command)
-                .maybe_path(capable)
-                .build()
-                .unwrap();
-            let mut policy = Policy::default();
-            policy.password_prompt = parse_sauthentication(&password_policy)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-            if fail_then_add && !no_loop {
-                fail_then_add_loop(playbook, &task, &username, capable, &mut policy).unwrap();
-            } else {
-                policy = capable.run().unwrap();
-            }
-            output_policy(mode, config, task, username, policy)
-        }
-        Commands::Deploy { yes, config } => {
-            prompt_for_confirmation(yes, &config)?;
-            let settings = rootasrole_core::get_settings(&config)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-            let config = &settings.as_ref().borrow().config;
-            deploy::setup_role_based_access(config)
-        }
-        Commands::Undeploy { yes, config } => {
-            prompt_for_confirmation(yes, &config)?;
-            let settings = rootasrole_core::get_settings(&config)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-            let config = &settings.as_ref().borrow().config;
-            deploy::remove_role_based_access(config)
-        }
-    }
-}
+        /// Start of the reported period, as accepted by ausearch --start
+        #[arg(short, long)]
+        since: Option<String>,
+    },
+    /// Compare the traces saved by `generate --save-artifacts` in two directories
+    TraceDiff {
+        /// Artifacts of the first run
+        a: PathBuf,
 
-fn output_policy(
-    mode: Mode,
-    config: Option<String>,
-    task: Option<String>,
-    username: String,
-    policy: Policy,
-) -> Result<(), io::Error> {
-    Ok(match mode {
-        Mode::Auto => {
-            let task = Rc::new(RefCell::new(policy.to_stask(&username, task.as_deref())));
-            let mut options = Opt::new(Level::Task);
-            options.authentication = Some(SAuthentication::Skip);
-            let mut envopt = SEnvOptions::new(EnvBehavior::Delete);
-            envopt.keep = policy
-                .env_vars
-                .keys()
-                .map(|k| EnvKey::new(k.clone()).unwrap())
-                .collect();
-            options.env = Some(envopt);
-            task.as_ref().borrow_mut().options = Some(rc_refcell!(options));
-            if let Some(config_path) = config {
-                let settings = rootasrole_core::get_settings(&config_path)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        /// Artifacts of the second run
+        b: PathBuf,
+    },
+    /// Report the uses of the grants marked alert on use since the last check, notifying the
+    /// webhooks, e.g. from a timer
+    Alerts {
+        /// Report from this start instead of the last check, as accepted by ausearch --start
+        #[arg(short, long)]
+        since: Option<String>,
+    },
+    /// Run a command as an ephemeral role user, allocated for the duration of the command
+    Ephemeral {
+        /// Name of the role user to allocate
+        #[arg(short, long)]
+        user: String,
+
+        /// The command to execute
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Execute a command with the user, groups and capabilities sr would give a task, to
+    /// debug a generated task without installing RootAsRole
+    Exec {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// The task whose credentials the command runs with
+        #[arg(short, long)]
+        task: String,
+
+        /// The role of the task, the first one holding it by default
+        #[arg(short, long)]
+        role: Option<String>,
+
+        /// The command to execute
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Execute a command with the secrets deploy resolved for the calling task user, which
+    /// the commands of a task storing secrets are run through
+    SecretExec {
+        /// The command to execute
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Record an execution of a role, called by pam_exec from the sr session
+    #[command(hide = true)]
+    UsageHook,
+    /// Run a command whose writes are intercepted by the trace listening on the socket,
+    /// called by `--no-side-effects`
+    #[command(hide = true)]
+    SandboxExec {
+        #[arg(long)]
+        socket: PathBuf,
+
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Summarize the executions of the roles recorded by the usage hook
+    UsageReport {
+        /// Period to look back, e.g. 30d, 12h or 2w
+        #[arg(short, long, default_value = "30d")]
+        since: String,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Run as a daemon exposing Prometheus metrics of every gensr operation
+    Serve {
+        /// Address the metrics endpoint listens on
+        #[arg(short, long, default_value = "127.0.0.1:9464")]
+        listen: String,
+    },
+    /// Report granted privileges that were never exercised by the role users
+    Usage {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Only report the privileges of this role
+        #[arg(short, long)]
+        role: Option<String>,
+
+        /// Period to look back, e.g. 30d, 12h or 2w
+        #[arg(short, long, default_value = "30d")]
+        since: String,
+
+        /// Print the unused grants as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Move file grants shared by several tasks into library tasks they reference
+    Factor {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Minimum number of tasks sharing the grants
+        #[arg(long, default_value_t = 2)]
+        min_tasks: usize,
+
+        /// Minimum number of shared grants worth a library task
+        #[arg(long, default_value_t = 10)]
+        min_files: usize,
+    },
+    /// Share generated tasks with a repository of approved baselines
+    Baseline {
+        #[command(subcommand)]
+        action: BaselineAction,
+    },
+    /// Analyze the configuration and suggest improvements
+    Analyze {
+        #[command(subcommand)]
+        analysis: Analysis,
+    },
+    /// Update the actors of the roles from a group membership source
+    SyncActors {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// YAML mapping of roles to users and groups, ldap://host/<base DN>, or nss for SSSD groups
+        #[arg(short, long)]
+        source: String,
+    },
+    /// Upgrade the generated tasks of a configuration to the format of this gensr
+    MigrateConfig {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+    },
+    /// Check the system integrations gensr relies on, with hints to fix them
+    Doctor,
+    /// Measure the overhead of running a command through its role instead of as root
+    Bench {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// The deployed task to run the command with
+        #[arg(short, long)]
+        task: String,
+
+        /// Number of measured runs of each variant
+        #[arg(short, long, default_value_t = 10)]
+        runs: usize,
+
+        /// The command to execute
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Check that the deployed dbus and polkit policies of a role take effect, by calling
+    /// the bus and polkit as its role users
+    Selftest {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// The deployed role to check
+        #[arg(short, long)]
+        role: String,
+    },
+    /// Deploy the configuration in a podman container and run the command of a task through
+    /// sr, testing the generated policy end to end without touching the host
+    Test {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// The task to test
+        #[arg(short, long)]
+        task: String,
+
+        /// The role of the task, the first one holding it by default
+        #[arg(short, long)]
+        role: Option<String>,
+
+        /// Image of the distribution to test on, such as fedora:41
+        #[arg(short, long, required_unless_present = "matrix")]
+        image: Option<String>,
+
+        /// Images to test on in parallel, such as debian:12,fedora:40,ubuntu:24.04
+        #[arg(long, value_delimiter = ',', conflicts_with = "image")]
+        matrix: Vec<String>,
+
+        /// Actor of the role running the command, the first user actor by default
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Shell command preparing the container, such as installing libcap or the packages
+        /// the command needs
+        #[arg(long)]
+        setup: Option<String>,
+
+        /// Directory of statically linked sr and gensr binaries to copy in the containers,
+        /// the images must provide them otherwise
+        #[arg(long)]
+        binaries: Option<PathBuf>,
+
+        /// Keep the container after the test for inspection
+        #[arg(long)]
+        keep: bool,
+    },
+    /// Trace the tasks of the configuration again and report how their grants drifted
+    Regenerate {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Tasks traced again, hand-written tasks are refused
+        #[arg(long, value_enum, default_value = "generated-only")]
+        filter: regenerate::Scope,
+
+        /// Number of tasks traced concurrently
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+
+        /// Update the grants of the drifted tasks, asking for each task how to resolve its drift
+        #[arg(long)]
+        apply: bool,
+
+        /// Resolve the drift of every task without asking
+        #[arg(long, value_enum, requires = "apply")]
+        auto: Option<regenerate::Decision>,
+
+        /// Only trace the tasks queued by watch-packages, dequeuing them once resolved
+        #[arg(long)]
+        queued: bool,
+    },
+    /// Move the deployed state between hosts
+    State {
+        #[command(subcommand)]
+        action: StateAction,
+    },
+    /// List the tasks granting a privilege, e.g. who can write to a path
+    Search {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Path granted by the tasks, directly, through a glob or below it
+        #[arg(short, long, required_unless_present_any = ["capability", "dbus"])]
+        file: Option<String>,
+
+        /// Access to the file the grants must include, such as W
+        #[arg(short, long, requires = "file")]
+        access: Option<policy::Access>,
+
+        /// Capability granted by the tasks
+        #[arg(long)]
+        capability: Option<String>,
+
+        /// D-Bus destination the tasks may talk to
+        #[arg(long)]
+        dbus: Option<String>,
+
+        /// Print the matching tasks as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rewrite the configuration in canonical form, for minimal diffs between generations
+    Fmt {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Only check that the configuration is in canonical form, failing otherwise
+        #[arg(long)]
+        check: bool,
+    },
+    /// Find the existing role the user could run a command with, or the one needing the
+    /// fewest additions
+    WhichRole {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// The user running the command
+        #[arg(short, long)]
+        user: String,
+
+        /// Tasks considered
+        #[arg(long, value_enum, default_value = "generated-only")]
+        filter: regenerate::Scope,
+
+        /// Trace the command to also compare the privileges it uses with the grants
+        #[arg(long)]
+        trace: bool,
+
+        /// Number of candidate tasks listed when none permits the command
+        #[arg(long, default_value_t = 3)]
+        limit: usize,
+
+        /// The command to run
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Add or remove a privilege across every task holding a matched privilege
+    Edit {
+        /// Path to the rootasrole configuration file
+        #[arg(short, long, default_value = "/etc/security/rootasrole.json")]
+        config: String,
+
+        /// Privilege selecting the tasks: capability:CAP_NET_RAW, file:/etc/shadow[:R]
+        /// or dbus:<destination>
+        #[arg(short, long = "match")]
+        matched: edit::Privilege,
+
+        /// Only edit the tasks of this role
+        #[arg(short, long)]
+        role: Option<String>,
+
+        /// Remove the matched privilege from the tasks
+        #[arg(long, required_unless_present = "add", conflicts_with = "add")]
+        remove: bool,
+
+        /// Add this privilege to the tasks
+        #[arg(long)]
+        add: Option<edit::Privilege>,
+
+        /// Skip the confirmation prompt, also enabled by GENSR_ASSUME_YES=1
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+/// Authentication timeout of a task, from a duration such as 5m
+fn auth_timeout(duration: &str) -> anyhow::Result<STimeout> {
+    let seconds = audit::parse_duration(duration)?.as_secs();
+    Ok(serde_json::from_value(serde_json::json!({
+        "duration": format!(
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }))?)
+}
+
+fn parse_sauthentication(auth: &str) -> anyhow::Result<SAuthentication> {
+    Ok(match auth {
+        "skip" => SAuthentication::Skip,
+        "perform" => SAuthentication::Perform,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Only 'skip' and 'perform' are allowed for password_policy"
+            ))
+        }
+    })
+}
+
+/// The default log line, with the secrets of the message masked
+fn log_format(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let style = buf.default_level_style(record.level());
+    writeln!(
+        buf,
+        "[{} {style}{}{style:#} {}] {}",
+        buf.timestamp(),
+        record.level(),
+        record.target(),
+        secrets::redact(&record.args().to_string())
+    )
+}
+
+fn main() -> io::Result<()> {
+    #[cfg(debug_assertions)]
+    env_logger::builder()
+        .format(log_format)
+        .filter_level(LevelFilter::Debug)
+        .init();
+    #[cfg(not(debug_assertions))]
+    env_logger::builder()
+        .format(log_format)
+        .filter_level(LevelFilter::Info)
+        .init();
+    // Stop quietly when the output is piped into a command that exits early, such as head,
+    // rather than panicking on the failed write
+    // SAFETY: no other thread runs yet and the default action installs no handler
+    unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) }.map_err(io::Error::from)?;
+    let args = Cli::parse();
+    secrets::configure(args.redact);
+    match args.command {
+        Commands::Polkit {
+            user,
+            action,
+            namespace,
+            cache_ttl,
+        } => deploy::check_polkit(&user, &action, namespace.as_deref(), cache_ttl),
+        Commands::Generate {
+            mode,
+            format,
+            export_output,
+            template,
+            config,
+            stage,
+            playbook,
+            task,
+            command,
+            fail_then_add,
+            seed_task,
+            site,
+            capable,
+            backend,
+            no_loop,
+            success_exit_codes,
+            retries,
+            retry_delay,
+            password_policy,
+            auth,
+            timeout_auth,
+            description,
+            ticket,
+            env_values,
+            secret_storage,
+            age_recipient,
+            secret_backend,
+            alert_on,
+            filter,
+            compliance,
+            waive,
+            rootless,
+            cwd,
+            env,
+            env_file,
+            clean_env,
+            limit_cpu,
+            limit_mem,
+            nice,
+            timeout,
+            netns,
+            join_unit,
+            no_side_effects,
+            no_progress,
+            cache,
+            save_artifacts,
+            fixture,
+            min_duration,
+            allow_empty,
+            script,
+            script_deps,
+            per_command_tasks,
+            param,
+            strict_commands,
+            pin_binary,
+            generalize_args,
+            skip_covered,
+            baseline_threshold,
+            resolve_symlinks,
+            keep_trailing_slash,
+            case_insensitive,
+            collapse_threshold,
+            allowed_hours,
+            allowed_days,
+            setuid_helpers,
+        } => {
+            if rootless && fail_then_add {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--rootless cannot be combined with --fail-then-add, which creates users",
+                ));
+            }
+            if backend == fanotify::Backend::Fanotify {
+                if fail_then_add {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "--backend fanotify cannot detect capabilities, which --fail-then-add requires",
+                    ));
+                }
+                warn!(
+                    "The fanotify backend only records files, capabilities and dbus are not traced"
+                );
+            }
+            let mut script = script
+                .map(|path| script::Script::resolve(&path, command.clone()))
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let commands: Vec<Vec<String>> = match &script {
+                Some(script) => vec![script.command()],
+                // The command of an ansible task is passed as is, `---` may be one of its arguments
+                None if playbook.is_some() && !command.is_empty() => vec![command.clone()],
+                None => command
+                    .split(|arg| arg == "---")
+                    .filter(|group| !group.is_empty())
+                    .map(|group| group.to_vec())
+                    .collect(),
+            };
+            // A script run by naming its interpreter, as `python script.py`
+            if let (None, [command]) = (&script, commands.as_slice()) {
+                script =
+                    script::Script::detect(&params::generalize_args(command, &generalize_args));
+            }
+            let params = param
+                .iter()
+                .map(|p| params::parse_param(p))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let schedule =
+                schedule::Schedule::parse(allowed_hours.as_deref(), allowed_days.as_deref())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            // TODO: --mode auto|manual
+            let (prefix, digest) = match (&playbook, &task) {
+                (Some(playbook), Some(task)) => ("rar", ansible_digest(playbook, task)),
+                _ => ("gsr", command_digest(&command)),
+            };
+            let site = site
+                .as_deref()
+                .map(identity::site_id)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let username = identity::username(prefix, &digest, site.as_deref());
+            // Recorded to detect collisions of the shortened usernames
+            let identity = site.is_some().then_some(digest);
+            let mut envs = match &env_file {
+                Some(path) => capable::read_env_file(path)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+                None => Vec::new(),
+            };
+            for var in &env {
+                envs.push(
+                    capable::parse_env_var(var)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?,
+                );
+            }
+            let limit_mem = limit_mem
+                .as_deref()
+                .map(capable::parse_size)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let min_duration = Duration::try_from_secs_f64(min_duration).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid --min-duration {}: {}", min_duration, e),
+                )
+            })?;
+            let command_label = commands
+                .iter()
+                .map(|command| command.join(" "))
+                .collect::<Vec<_>>()
+                .join(" --- ");
+            let seed = match (&seed_task, &config) {
+                (Some(seed_task), Some(config)) => Some(load_seed(config, seed_task)?),
+                _ => None,
+            };
+            let mut policy = Policy::default();
+            policy.password_prompt = parse_sauthentication(&password_policy)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let mut progress =
+                Progress::new(!no_progress, task.as_deref().unwrap_or(&command_label));
+            let normalize = NormalizeOptions {
+                resolve_symlinks,
+                strip_trailing_slash: !keep_trailing_slash,
+                case_insensitive,
+                collapse_threshold,
+            };
+            let mut stats = capable::TraceStats::default();
+            let mut failed = false;
+            let mut traced = Vec::new();
+            let mut binaries = std::collections::BTreeMap::new();
+            let trace_options = format!(
+                "{:?} {} {:?} {:?} {} {:?} {:?} {} {:?} {:?} {:?}",
+                backend,
+                fail_then_add && !no_loop,
+                success_exit_codes,
+                join_unit,
+                rootless,
+                cwd,
+                envs,
+                clean_env,
+                no_side_effects,
+                normalize,
+                fixture
+            );
+            let _fixture = fixture
+                .as_deref()
+                .map(fixture::Fixture::install)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            for command in if commands.is_empty() {
+                vec![Vec::new()]
+            } else {
+                commands.clone()
+            } {
+                let label = command.join(" ");
+                let traced_command = command.clone();
+                let substituted = params::substitute(&command, &params);
+                let hashes = tracecache::binary_hashes(&substituted);
+                let cache_key =
+                    cache.then(|| tracecache::key(&substituted, &hashes, &trace_options));
+                binaries.extend(hashes);
+                let (mut command_policy, command_stats, command_failed) = match cache_key
+                    .as_deref()
+                    .and_then(tracecache::load)
                 {
-                    let config =
-                        rootasrole_core::database::read_json_config(settings.clone(), &config_path)
+                    Some((cached, cached_stats)) => {
+                        debug!("Reusing the cached trace of '{}'", label);
+                        progress.discovered(&cached);
+                        (cached, cached_stats, false)
+                    }
+                    None => {
+                        let mut capable = capable::Capable::builder()
+                            .fail_then_add(fail_then_add)
+                            .command(substituted)
+                            .maybe_path(capable.clone())
+                            .rootless(rootless)
+                            .maybe_cwd(cwd.clone())
+                            .envs(envs.clone())
+                            .clear_env(clean_env)
+                            .maybe_limit_cpu(limit_cpu)
+                            .maybe_limit_mem(limit_mem)
+                            .maybe_nice(nice)
+                            .maybe_timeout(timeout.map(Duration::from_secs))
+                            .maybe_netns(netns.clone())
+                            .maybe_join_unit(join_unit.clone())
+                            .maybe_no_side_effects(no_side_effects)
+                            .backend(backend)
+                            .success_exit_codes(success_exit_codes.clone())
+                            .retries(retries)
+                            .retry_delay(Duration::from_secs(retry_delay))
+                            .build()
                             .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                    let mut conf = config.as_ref().borrow_mut();
-                    if let Some(role) = conf.role(&username) {
-                        if role
-                            .as_ref()
-                            .borrow_mut()
-                            .tasks
-                            .iter()
-                            .any(|t| t.as_ref().borrow().name == task.as_ref().borrow().name)
-                        {
-                            warn!(
-                                "Task '{}' already exists in role '{}'",
-                                task.as_ref().borrow().name,
-                                username
-                            );
+                        let mut command_policy = seed.clone().unwrap_or_default();
+                        if fail_then_add && !no_loop {
+                            fail_then_add_loop(
+                                playbook.clone(),
+                                &task,
+                                &username,
+                                &mut capable,
+                                &mut command_policy,
+                                &mut progress,
+                                &normalize,
+                            )?;
                         } else {
-                            task.as_ref().borrow_mut()._role = Some(Rc::downgrade(role));
-                            role.as_ref().borrow_mut().tasks.push(task.clone());
+                            progress.next_iteration();
+                            command_policy = capable
+                                .run()
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                            command_policy.normalize(&normalize);
+                            progress.discovered(&command_policy);
+                        }
+                        if let Some(dir) = &save_artifacts {
+                            let saved = artifacts::Artifacts {
+                                command: traced_command.clone(),
+                                processes: capable.processes().clone(),
+                                stats: capable.stats().clone(),
+                                policy: command_policy.clone(),
+                            };
+                            let raw = (backend == fanotify::Backend::Capable)
+                                .then(|| capable.raw_output());
+                            artifacts::save(dir, traced.len(), &saved, raw)
+                                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
                         }
-                    } else {
-                        let mut role = SRole::new(
-                            username.clone(),
-                            Rc::<RefCell<SConfig>>::downgrade(&config),
-                        );
-                        role.tasks.push(task.clone());
-                        conf.roles.push(Rc::new(RefCell::new(role)));
+                        if let Some(key) = cache_key.as_deref().filter(|_| !capable.is_failed()) {
+                            tracecache::store(key, &command_policy, capable.stats());
+                        }
+                        (command_policy, capable.stats().clone(), capable.is_failed())
                     }
+                };
+                let reasons = command_stats.shallow_reasons(min_duration, &success_exit_codes);
+                if !reasons.is_empty() {
+                    warn!(
+                        "The trace of '{}' may not have exercised its real code path: {}. \
+                        Re-run it with arguments and inputs matching its actual use",
+                        label,
+                        reasons.join(", ")
+                    );
+                }
+                stats.merge(&command_stats);
+                failed |= command_failed;
+                command_policy.password_prompt = policy.password_prompt;
+                params::generalize(&mut command_policy, &params);
+                if per_command_tasks {
+                    traced.push((traced_command, command_policy.clone(), command_stats));
+                }
+                policy |= command_policy;
+            }
+            progress.finish();
+            eprint!("{}", stats);
+            if stats.is_empty() && !failed && !allow_empty {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The trace is empty, refusing to generate an empty policy without --allow-empty",
+                ));
+            }
+            if let Some(script) = &mut script {
+                script.grant(&mut policy, script_deps);
+            }
+            let mut command_scripts = Vec::new();
+            for (command, command_policy, _) in &mut traced {
+                let mut script =
+                    script::Script::detect(&params::generalize_args(command, &generalize_args));
+                if let Some(script) = &mut script {
+                    script.grant(command_policy, script_deps);
                 }
-                // Create a file manually without save_settings
-                let file = std::fs::File::create(&config_path)
+                command_scripts.push(script);
+            }
+            let helper_mode = match setuid_helpers {
+                Some(mode) => mode,
+                None if policy.setuid_helpers.is_empty() => setuid::HelperMode::Include,
+                None => setuid::ask(&policy.setuid_helpers)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            };
+            let helpers = setuid::helpers(&mut policy, helper_mode);
+            if !filter.is_empty() {
+                policy = filter::apply(&policy, &filter, &username)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                serde_json::to_writer_pretty(&file, &Versioning::new(settings))
+                for (_, command_policy, _) in &mut traced {
+                    *command_policy = filter::apply(command_policy, &filter, &username)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                }
+            }
+            let waivers = compliance::parse_waivers(&waive)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            if let Some(rules) = compliance {
+                let rules = ComplianceRules::load(rules)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                file.sync_all()
+                compliance::enforce(
+                    &rules.check_policy(&policy),
+                    &waivers,
+                    "The generated policy",
+                )
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+            }
+            let authentication = match &auth {
+                Some(auth) => parse_sauthentication(auth)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?,
+                None => policy.password_prompt,
+            };
+            let timeout = timeout_auth
+                .as_deref()
+                .map(auth_timeout)
+                .transpose()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            if timeout.is_some() && authentication == SAuthentication::Skip {
+                warn!("--timeout-auth has no effect on a task skipping authentication");
+            }
+            let output = Output {
+                mode,
+                format,
+                export: export::ExportOptions {
+                    output: export_output,
+                    template,
+                },
+                schedule,
+                authentication,
+                timeout,
+                doc: doc::Doc::new(description, ticket),
+                env_values: env_values.then_some(secrets::EnvValues {
+                    storage: secret_storage,
+                    age_recipients: age_recipient,
+                    backend: secret_backend,
+                }),
+                alert_on,
+                stage,
+                pin_binary,
+                config,
+                skip_covered,
+                baseline_threshold,
+            };
+            if per_command_tasks {
+                let prefix = task.clone().unwrap_or_else(|| username.clone());
+                for (i, ((command, mut policy, stats), script)) in
+                    traced.into_iter().zip(command_scripts).enumerate()
+                {
+                    let binaries =
+                        tracecache::binary_hashes(&params::substitute(&command, &params));
+                    let provenance = provenance::Provenance {
+                        trace: Some(stats),
+                        script,
+                        commands: vec![params::generalize_args(&command, &generalize_args)],
+                        strict_commands: true,
+                        params: params.clone(),
+                        fingerprint: Some(fingerprint::Fingerprint::current(binaries.keys())),
+                        binaries,
+                        setuid_helpers: setuid::helpers(&mut policy, helper_mode),
+                        identity: identity.clone(),
+                        normalize: Some(normalize.clone()),
+                    };
+                    output_policy(
+                        &output,
+                        Some(format!("{}-{}", prefix, i + 1)),
+                        username.clone(),
+                        policy,
+                        &waivers,
+                        &provenance,
+                    )?;
+                }
+                return Ok(());
+            }
+            let provenance = provenance::Provenance {
+                trace: Some(stats),
+                script,
+                commands: commands
+                    .iter()
+                    .map(|command| params::generalize_args(command, &generalize_args))
+                    .collect(),
+                strict_commands,
+                params,
+                fingerprint: Some(fingerprint::Fingerprint::current(binaries.keys())),
+                binaries,
+                setuid_helpers: helpers,
+                identity,
+                normalize: Some(normalize),
+            };
+            output_policy(&output, task, username, policy, &waivers, &provenance)
+        }
+        Commands::Deploy {
+            yes,
+            config,
+            with_audit,
+            fapolicyd,
+            selinux_user,
+            deny_login,
+            ephemeral_users,
+            compliance,
+            user_backend,
+            dbus_layout,
+            jobs,
+            full,
+            role_groups,
+            role_group_actors,
+            baseline_threshold,
+            usage_hook,
+            id_range,
+            polkit_namespace,
+            polkit_cache_ttl,
+            shadow,
+            allow_parent_write,
+            age_identity,
+            secret_resolver,
+            install_timer,
+        } => {
+            check_config_exists(&config)?;
+            let polkit_namespace = deploy::polkit_namespace(&config, polkit_namespace)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            kernel::check_capabilities(&config, &settings.as_ref().borrow().config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let config_path = config;
+            let config = &settings.as_ref().borrow().config;
+            if let Some(threshold) = baseline_threshold {
+                baseline::snapshot(config)
+                    .and_then(|local| baseline::check(&local, threshold))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            }
+            for mismatch in fingerprint::verify(config) {
+                warn!("{}", mismatch);
+            }
+            library::expand(config);
+            let options = deploy::DeployOptions {
+                audit: with_audit || shadow,
+                fapolicyd,
+                // A confined SELinux user would restrict the role users
+                selinux_user: (!shadow && selinux::is_enforcing()).then_some(selinux_user),
+                deny_login,
+                ephemeral_users,
+                user_backend,
+                dbus_layout,
+                jobs: jobs.unwrap_or_else(|| {
+                    std::thread::available_parallelism().map_or(1, |jobs| jobs.get())
+                }),
+                full,
+                role_groups,
+                role_group_actors,
+                usage_hook,
+                id_range,
+                polkit_namespace,
+                polkit_cache_ttl,
+                shadow,
+                parent_write: allow_parent_write,
+                secret_sources: secrets::SecretSources {
+                    age_identity,
+                    resolver: secret_resolver,
+                },
+                compliance: compliance
+                    .map(ComplianceRules::load)
+                    .transpose()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+            };
+            let summary = deploy::deploy_summary(config, &options)?;
+            prompt_for_confirmation(yes, &summary)?;
+            deploy::setup_role_based_access(config, &options)?;
+            let risk = config
+                .as_ref()
+                .borrow()
+                .roles
+                .iter()
+                .flat_map(|role| role.as_ref().borrow().tasks.clone())
+                .map(|task| notify::risk_score(&task.as_ref().borrow()))
+                .max()
+                .unwrap_or_default();
+            notify::notify("deploy", &config_path, &summary.headline(), risk);
+            if install_timer {
+                let units = timer::install(&config_path, options.polkit_namespace.as_deref())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                for unit in units {
+                    println!("Installed {}", unit.display());
+                }
+            }
+            Ok(())
+        }
+        Commands::Approve { staged, config } => {
+            check_config_exists(&config)?;
+            let approved = stage::load_for_approval(&staged)
+                .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+            write_task(
+                &config,
+                &approved.role,
+                &Rc::new(RefCell::new(approved.task)),
+                false,
+                &format!("approved, staged by {},", approved.author),
+            )?;
+            std::fs::remove_file(&staged)
+        }
+        Commands::Undeploy {
+            yes,
+            config,
+            dry_run,
+            interactive,
+            polkit_namespace,
+        } => {
+            let polkit_namespace = deploy::polkit_namespace(&config, polkit_namespace)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let config = &settings.as_ref().borrow().config;
+            library::expand(config);
+            let summary = deploy::undeploy_summary(config)?;
+            if dry_run {
+                print!("{}", summary);
+                return Ok(());
+            }
+            if !interactive {
+                prompt_for_confirmation(yes, &summary)?;
+            }
+            deploy::remove_role_based_access(config, interactive, polkit_namespace.as_deref())?;
+            // Roles kept by an interactive undeploy are still worth auditing
+            if !interactive {
+                let units = timer::uninstall(polkit_namespace.as_deref())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                for unit in units {
+                    println!("Removed {}", unit.display());
+                }
+            }
+            Ok(())
+        }
+        Commands::Audit { config } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let sconfig = &settings.as_ref().borrow().config;
+            let (checked, drifts) = integrity::verify(sconfig);
+            let mut mismatches = kernel::verify(sconfig);
+            mismatches.extend(fingerprint::verify(sconfig));
+            for mismatch in &mismatches {
+                warn!("{}", mismatch);
+            }
+            for drift in &drifts {
+                println!("{}", drift);
+            }
+            println!(
+                "{} pinned binaries checked, {} drifted",
+                checked,
+                drifts.len()
+            );
+            if !drifts.is_empty() || !mismatches.is_empty() {
+                // The riskiest task running a drifted binary
+                let risk = sconfig
+                    .as_ref()
+                    .borrow()
+                    .roles
+                    .iter()
+                    .flat_map(|role| {
+                        let role = role.as_ref().borrow();
+                        role.tasks
+                            .iter()
+                            .filter(|task| {
+                                drifts.iter().any(|drift| {
+                                    drift.role == role.name
+                                        && drift.task == task.as_ref().borrow().name.to_string()
+                                })
+                            })
+                            .map(|task| notify::risk_score(&task.as_ref().borrow()))
+                            .collect::<Vec<_>>()
+                    })
+                    .max()
+                    .unwrap_or_default();
+                notify::notify(
+                    "drift",
+                    &config,
+                    &format!(
+                        "{} pinned binaries drifted, {} host mismatch(es)",
+                        drifts.len(),
+                        mismatches.len()
+                    ),
+                    risk,
+                );
+            }
+            if !drifts.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Pinned binaries changed since they were traced, regenerate or re-pin their tasks",
+                ));
+            }
+            Ok(())
+        }
+        Commands::WatchPackages {
+            config,
+            install_hook,
+        } => {
+            if let Some(hook) = install_hook {
+                let path = packages::install_hook(hook)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                println!("Installed {}", path.display());
+            }
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let queued = packages::watch(&settings.as_ref().borrow().config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            for retrace in &queued {
+                println!("{}", retrace);
+            }
+            println!("{} tasks queued for re-tracing", queued.len());
+            Ok(())
+        }
+        Commands::AuditReport { role, since } => {
+            audit::audit_report(role.as_deref(), since.as_deref())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        Commands::TraceDiff { a, b } => artifacts::trace_diff(&a, &b)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+        Commands::Alerts { since } => audit::alert_check(since.as_deref())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+        Commands::Ephemeral { user, command } => {
+            let code = ephemeral::run(&user, &command)?;
+            std::process::exit(code);
+        }
+        Commands::Exec {
+            config,
+            task,
+            role,
+            command,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let config = &settings.as_ref().borrow().config;
+            exec::exec(config, role.as_deref(), &task, &command)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        Commands::SecretExec { command } => {
+            secrets::exec(&command).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        Commands::UsageHook => {
+            telemetry::hook().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        Commands::SandboxExec { socket, command } => sandbox::exec(&socket, &command)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+        Commands::UsageReport { since, json } => telemetry::report(&since, json)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+        Commands::Serve { listen } => metrics::serve(&listen),
+        Commands::Usage {
+            config,
+            role,
+            since,
+            json,
+        } => {
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let config = &settings.as_ref().borrow().config;
+            library::expand(config);
+            audit::usage_report(config, role.as_deref(), &since, json)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        Commands::Factor {
+            config,
+            min_tasks,
+            min_files,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            migrate::check_writable(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let libraries =
+                library::factor(&settings.as_ref().borrow().config, min_tasks, min_files);
+            if libraries.is_empty() {
+                println!("No shared grants to factor out");
+                return Ok(());
+            }
+            for library in &libraries {
+                println!(
+                    "{}: {} file grant(s) shared by {}",
+                    library.name,
+                    library.files,
+                    library.tasks.join(", ")
+                );
+            }
+            write_config(&config, &Versioning::new(settings))?;
+            auditlog::record(
+                "config_write",
+                &config,
+                &format!("factored out {} library task(s)", libraries.len()),
+            );
+            Ok(())
+        }
+        Commands::Baseline { action } => match action {
+            BaselineAction::Push { config, remote } => {
+                check_config_exists(&config)?;
+                let settings = rootasrole_core::get_settings(&config)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                let pushed = baseline::push(
+                    &settings.as_ref().borrow().config,
+                    &baseline::Remote::parse(&remote),
+                )
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                println!("Pushed {} task(s) to {}", pushed, remote);
+                Ok(())
+            }
+            BaselineAction::Pull { remote } => {
+                let pulled = baseline::pull(&baseline::Remote::parse(&remote))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                println!("Pulled {} approved task(s) from {}", pulled, remote);
+                Ok(())
+            }
+            BaselineAction::Diff { config, threshold } => {
+                check_config_exists(&config)?;
+                let settings = rootasrole_core::get_settings(&config)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                let local = baseline::snapshot(&settings.as_ref().borrow().config)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                let approved = baseline::load()
+                    .map_err(|e| io::Error::new(io::ErrorKind::NotFound, e.to_string()))?;
+                let divergences = baseline::diff(&approved, &local);
+                for divergence in &divergences {
+                    println!("{}", divergence);
+                }
+                println!(
+                    "{} task(s) compared, {} diverging",
+                    local.len(),
+                    divergences.len()
+                );
+                baseline::check(&local, threshold)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            }
+        },
+        Commands::Analyze {
+            analysis:
+                Analysis::Hierarchy {
+                    config,
+                    threshold,
+                    apply,
+                },
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let clusters = hierarchy::analyze(&settings.as_ref().borrow().config, threshold);
+            if clusters.is_empty() {
+                println!("No roles share enough grants to form a hierarchy");
+                return Ok(());
+            }
+            for cluster in &clusters {
+                print!("{}", cluster);
+            }
+            if !apply {
+                return Ok(());
+            }
+            migrate::check_writable(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let bases = hierarchy::apply(&settings.as_ref().borrow().config, &clusters);
+            write_config(&config, &Versioning::new(settings))?;
+            auditlog::record(
+                "config_write",
+                &config,
+                &format!("introduced base role(s) {}", bases.join(", ")),
+            );
+            Ok(())
+        }
+        Commands::SyncActors { config, source } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            migrate::check_writable(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let updated = actors::sync(
+                &settings.as_ref().borrow().config,
+                &actors::Source::parse(&source),
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            if updated.is_empty() {
+                println!("Actors are up to date");
+                return Ok(());
+            }
+            println!("Updated the actors of {}", updated.join(", "));
+            write_config(&config, &Versioning::new(settings))?;
+            auditlog::record(
+                "config_write",
+                &config,
+                &format!(
+                    "synchronized actors of {} from {}",
+                    updated.join(", "),
+                    source
+                ),
+            );
+            Ok(())
+        }
+        Commands::MigrateConfig { config } => {
+            check_config_exists(&config)?;
+            let content = std::fs::read_to_string(&config)?;
+            let mut raw: serde_json::Value = serde_json::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let migrated = migrate::migrate(&mut raw)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let backup = format!("{}.bak", config);
+            std::fs::write(&backup, content)?;
+            // Load the migrated configuration through rootasrole_core, to stamp the format
+            // version it writes, from a staged copy: the configuration is replaced only once
+            let dir = Path::new(&config)
+                .parent()
+                .filter(|dir| dir != &Path::new(""))
+                .unwrap_or(Path::new("."));
+            let staged = tempfile::NamedTempFile::new_in(dir)?;
+            serde_json::to_writer_pretty(staged.as_file(), &raw)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let settings = rootasrole_core::get_settings(&staged.path().to_string_lossy())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            write_config(&config, &Versioning::new(settings))?;
+            auditlog::record(
+                "config_write",
+                &config,
+                &format!(
+                    "migrated {} task(s), previous version in {}",
+                    migrated, backup
+                ),
+            );
+            println!(
+                "Migrated {} task(s), previous version saved to {}",
+                migrated, backup
+            );
+            Ok(())
+        }
+        Commands::Doctor => doctor::doctor(),
+        Commands::Bench {
+            config,
+            task,
+            runs,
+            command,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let config = &settings.as_ref().borrow().config;
+            bench::bench(config, &task, &command, runs)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        }
+        Commands::Regenerate {
+            config,
+            filter,
+            jobs,
+            apply,
+            auto,
+            queued,
+        } => {
+            check_config_exists(&config)?;
+            if filter == regenerate::Scope::All {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Only the tasks generated by gensr are traced again, hand-written commands are patterns that cannot be run as is",
+                ));
+            }
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let only = queued.then(|| {
+                packages::queued()
+                    .into_iter()
+                    .map(|retrace| (retrace.role, retrace.task))
+                    .collect::<BTreeSet<_>>()
+            });
+            let regenerations =
+                regenerate::run(&settings.as_ref().borrow().config, jobs, only.as_ref());
+            for regeneration in &regenerations {
+                println!("{}", regeneration);
+            }
+            // A queued task is done once traced without drift, or its drift resolved
+            let dequeue = |drifted: bool| {
+                if !queued {
+                    return Ok(());
+                }
+                let traced = regenerations
+                    .iter()
+                    .filter(|r| r.skipped.is_none() && (drifted || !r.drifted()))
+                    .map(|r| (r.role.clone(), r.task.clone()))
+                    .collect();
+                packages::dequeue(&traced)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+            };
+            dequeue(false)?;
+            let drifted = regenerations.iter().filter(|r| r.drifted()).count();
+            println!(
+                "{} task(s) traced again, {} drifted",
+                regenerations.len(),
+                drifted
+            );
+            if drifted == 0 {
+                return Ok(());
+            }
+            if !apply {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The grants of some tasks drifted from their new trace, use --apply to refresh them",
+                ));
+            }
+            migrate::check_writable(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            let refreshed =
+                regenerate::resolve(&settings.as_ref().borrow().config, &regenerations, auto)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            write_config(&config, &Versioning::new(settings))?;
+            auditlog::record(
+                "config_write",
+                &config,
+                &format!("updated {} drifted task(s) from a new trace", refreshed),
+            );
+            dequeue(true)?;
+            println!("{} task(s) updated", refreshed);
+            Ok(())
+        }
+        Commands::Search {
+            config,
+            file,
+            access,
+            capability,
+            dbus,
+            json,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let query = search::Query {
+                file,
+                access: access.unwrap_or(policy::Access::empty()),
+                capability: capability.as_deref().map(policy::capability_name),
+                dbus,
+            };
+            let hits = search::run(&settings.as_ref().borrow().config, &query);
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&hits)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+                );
+            } else {
+                for hit in &hits {
+                    println!("{}", hit);
+                }
+                println!("{} task(s) found", hits.len());
+            }
+            Ok(())
+        }
+        Commands::Fmt { config, check } => {
+            check_config_exists(&config)?;
+            if !check {
+                migrate::check_writable(&config)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            }
+            let changed = canonical::run(&config, check)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            match (changed, check) {
+                (true, true) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not in canonical form, run gensr fmt", config),
+                )),
+                (true, false) => {
+                    auditlog::record("config_write", &config, "rewritten in canonical form");
+                    println!("{} formatted", config);
+                    Ok(())
+                }
+                (false, _) => Ok(()),
+            }
+        }
+        Commands::WhichRole {
+            config,
+            user,
+            filter,
+            trace,
+            limit,
+            command,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let needed = if trace {
+                Some(
+                    regenerate::trace(std::slice::from_ref(&command), &NormalizeOptions::default())
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+                )
+            } else {
+                None
+            };
+            let candidates = whichrole::candidates(
+                &settings.as_ref().borrow().config,
+                &user,
+                &command,
+                needed.as_ref(),
+                filter,
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let permitting: Vec<_> = candidates.iter().filter(|c| c.missing.is_empty()).collect();
+            if permitting.is_empty() {
+                println!("No task permits the command, closest tasks:");
+                for candidate in candidates.iter().take(limit) {
+                    println!("{}", candidate);
+                }
+            } else {
+                for candidate in permitting {
+                    println!("{}", candidate);
+                }
+            }
+            Ok(())
+        }
+        Commands::Edit {
+            config,
+            matched,
+            role,
+            remove: _,
+            add,
+            yes,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let action = match add {
+                Some(privilege) => edit::Action::Add(privilege),
+                None => edit::Action::Remove,
+            };
+            let edits = edit::plan(
+                &settings.as_ref().borrow().config,
+                &matched,
+                role.as_deref(),
+                &action,
+            );
+            if edits.is_empty() {
+                println!("No task to change");
+                return Ok(());
+            }
+            for edit in &edits {
+                print!("{}", edit);
+            }
+            if !confirm_edit(yes, edits.len())? {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "User cancelled the edit",
+                ));
+            }
+            migrate::check_writable(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            // Every task is changed in memory first, the configuration is written once
+            edit::apply(&settings.as_ref().borrow().config, &edits);
+            write_config(&config, &Versioning::new(settings))?;
+            auditlog::record(
+                "config_write",
+                &config,
+                &format!("bulk edit of {} task(s)", edits.len()),
+            );
+            println!("{} task(s) updated", edits.len());
+            Ok(())
+        }
+        Commands::State { action } => match action {
+            StateAction::Export { config, output } => {
+                check_config_exists(&config)?;
+                bundle::export(&config, &output)
                     .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                //println!("{}", serde_json::to_string_pretty(&settings).unwrap());
+                println!("Deployed state exported to {}", output.display());
+                Ok(())
+            }
+            StateAction::Import {
+                bundle,
+                config,
+                force,
+            } => {
+                bundle::import(&bundle, &config, force)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                println!("Deployed state imported from {}", bundle.display());
+                Ok(())
+            }
+        },
+        Commands::Test {
+            config,
+            task,
+            role,
+            image,
+            matrix,
+            user,
+            setup,
+            binaries,
+            keep,
+        } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let test = harness::Test {
+                role,
+                task,
+                images: image.into_iter().chain(matrix).collect(),
+                user,
+                setup,
+                binaries,
+                keep,
+            };
+            let outcomes = harness::run(&config, &settings.as_ref().borrow().config, &test)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            for outcome in &outcomes {
+                println!("{}", outcome);
+            }
+            let failed = outcomes.iter().filter(|outcome| outcome.failed()).count();
+            println!("{} image(s), {} failed", outcomes.len(), failed);
+            if failed > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "The task does not let its command run",
+                ));
+            }
+            Ok(())
+        }
+        Commands::Selftest { config, role } => {
+            check_config_exists(&config)?;
+            let settings = rootasrole_core::get_settings(&config)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            let config = &settings.as_ref().borrow().config;
+            library::expand(config);
+            let checks = selftest::run(config, &role)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            for check in &checks {
+                println!("{}", check);
+            }
+            let failed = checks.iter().filter(|check| check.failed()).count();
+            println!("{} check(s), {} failed", checks.len(), failed);
+            if failed > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Deployed policies of the role do not take effect",
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn output_policy(
+    output: &Output,
+    task: Option<String>,
+    username: String,
+    policy: Policy,
+    waivers: &HashMap<String, String>,
+    provenance: &provenance::Provenance,
+) -> Result<(), io::Error> {
+    let task = Rc::new(RefCell::new(policy.to_stask(&username, task.as_deref())));
+    if !waivers.is_empty() {
+        task.as_ref()
+            .borrow_mut()
+            ._extra_fields
+            .insert("waivers".to_string(), compliance::waivers_to_value(waivers));
+    }
+    provenance
+        .apply(&mut task.as_ref().borrow_mut())
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    provenance.restrict_commands(&mut task.as_ref().borrow_mut());
+    if let Some(doc) = &output.doc {
+        doc.apply(&mut task.as_ref().borrow_mut())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    if output.pin_binary {
+        integrity::pin(&mut task.as_ref().borrow_mut(), &provenance.binaries);
+    }
+    alerts::mark(&mut task.as_ref().borrow_mut(), &policy, &output.alert_on);
+    let mut options = Opt::new(Level::Task);
+    options.authentication = Some(output.authentication);
+    options.timeout = output.timeout.clone();
+    let mut envopt = SEnvOptions::new(EnvBehavior::Delete);
+    envopt.keep = policy
+        .env_vars
+        .keys()
+        .map(|k| EnvKey::new(k.clone()).unwrap())
+        .collect();
+    if let Some(env_values) = &output.env_values {
+        let name = task.as_ref().borrow().name.to_string();
+        let (set, blocks) = env_values
+            .store(&policy.env_vars, &name)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        envopt.set = set;
+        if !blocks.is_empty() {
+            secrets::wrap_commands(&mut task.as_ref().borrow_mut())?;
+        }
+        envopt._extra_fields.extend(blocks);
+    }
+    options.env = Some(envopt);
+    if let Some(schedule) = &output.schedule {
+        warn!("RootAsRole does not enforce the schedule of the task, it is only advisory");
+        options._extra_fields.insert(
+            "schedule".to_string(),
+            serde_json::to_value(schedule)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?,
+        );
+    }
+    if let Some(creation) = umask::recommend(&policy) {
+        warn!(
+            "RootAsRole does not enforce the recommended {}, the command must apply them itself",
+            creation.keys().cloned().collect::<Vec<_>>().join(" and ")
+        );
+        options._extra_fields.extend(creation);
+    }
+    task.as_ref().borrow_mut().options = Some(rc_refcell!(options));
+    if export::export(
+        &output.format,
+        &policy,
+        &task.as_ref().borrow(),
+        &username,
+        &output.export,
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+    {
+        return Ok(());
+    }
+    Ok(match output.mode {
+        Mode::Auto => {
+            if let Some(threshold) = output.baseline_threshold {
+                let task = task.as_ref().borrow();
+                let local = serde_json::to_value(&*task)
+                    .map(|value| [(baseline::key(&username, &task), value)].into())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                baseline::check(&local, threshold)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            }
+            if let Some(stage) = &output.stage {
+                let path = stage::stage(stage, &username, &task.as_ref().borrow())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                println!("Task staged for review in {}", path.display());
+            } else if let Some(config_path) = &output.config {
+                write_task(
+                    config_path,
+                    &username,
+                    &task,
+                    output.skip_covered,
+                    "generated",
+                )?;
             }
         }
         Mode::Manual => {
-            println!("{}", serde_json::to_string_pretty(&policy).unwrap());
+            let mut shown = policy.clone();
+            shown.env_vars = secrets::redact_env(&shown.env_vars);
+            println!("{}", serde_json::to_string_pretty(&shown.pretty()).unwrap());
         }
     })
 }
 
+/// Add a task to the role of the configuration file, creating the role if needed
+fn write_task(
+    config_path: &str,
+    username: &str,
+    task: &Rc<RefCell<STask>>,
+    skip_covered: bool,
+    action: &str,
+) -> Result<(), io::Error> {
+    migrate::check_writable(config_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let settings = rootasrole_core::get_settings(config_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    {
+        let config = rootasrole_core::database::read_json_config(settings.clone(), config_path)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let mut conf = config.as_ref().borrow_mut();
+        let overlaps = overlap::find(&conf, username, &task.as_ref().borrow());
+        for overlap in &overlaps {
+            warn!("{}", overlap);
+        }
+        if skip_covered {
+            let skipped =
+                overlap::skip_covered(&mut task.as_ref().borrow_mut(), username, &overlaps);
+            if skipped > 0 {
+                warn!(
+                    "Skipped {} grant(s) already covered in role {}",
+                    skipped, username
+                );
+            }
+        }
+        if let Some(role) = conf.role(username) {
+            identity::check_collision(&role.as_ref().borrow(), &task.as_ref().borrow())
+                .map_err(|e| io::Error::new(io::ErrorKind::AlreadyExists, e.to_string()))?;
+            if role
+                .as_ref()
+                .borrow_mut()
+                .tasks
+                .iter()
+                .any(|t| t.as_ref().borrow().name == task.as_ref().borrow().name)
+            {
+                warn!(
+                    "Task '{}' already exists in role '{}'",
+                    task.as_ref().borrow().name,
+                    username
+                );
+            } else {
+                task.as_ref().borrow_mut()._role = Some(Rc::downgrade(role));
+                role.as_ref().borrow_mut().tasks.push(task.clone());
+            }
+        } else {
+            let mut role = SRole::new(
+                username.to_string(),
+                Rc::<RefCell<SConfig>>::downgrade(&config),
+            );
+            role.tasks.push(task.clone());
+            conf.roles.push(Rc::new(RefCell::new(role)));
+        }
+    }
+    // Write the file manually without save_settings
+    write_config(config_path, &Versioning::new(settings))?;
+    let summary = format!(
+        "task {} {} for role {}",
+        task.as_ref().borrow().name,
+        action,
+        username
+    );
+    auditlog::record("config_write", config_path, &summary);
+    notify::notify(
+        "generate",
+        config_path,
+        &summary,
+        notify::risk_score(&task.as_ref().borrow()),
+    );
+    Ok(())
+}
+
 fn fail_then_add_loop(
     playbook: Option<String>,
     task: &Option<String>,
     username: &String,
-    mut capable: capable::Capable,
+    capable: &mut capable::Capable,
     policy: &mut Policy,
+    progress: &mut Progress,
+    normalize: &NormalizeOptions,
 ) -> Result<(), io::Error> {
-    let mut first = true;
+    let mut applied = false;
+    let result = fail_then_add_iterations(
+        playbook,
+        task,
+        username,
+        capable,
+        policy,
+        progress,
+        normalize,
+        &mut applied,
+    );
+    // A timeout or an interrupt must not leave the temporary grants behind
+    if applied {
+        if let Err(e) = policy.remove(username) {
+            warn!(
+                "Failed to remove the temporary policy of {}: {}",
+                username, e
+            );
+        }
+    }
+    result
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fail_then_add_iterations(
+    playbook: Option<String>,
+    task: &Option<String>,
+    username: &String,
+    capable: &mut capable::Capable,
+    policy: &mut Policy,
+    progress: &mut Progress,
+    normalize: &NormalizeOptions,
+    applied: &mut bool,
+) -> Result<(), io::Error> {
+    let to_io = |e: anyhow::Error| io::Error::new(io::ErrorKind::Other, e.to_string());
+    // A seeded policy is granted from the first iteration, and removed like any previous one
+    if !policy.is_empty() {
+        policy.apply(username, capable).map_err(to_io)?;
+        *applied = true;
+    }
     let mut looping = 0;
     // TODO: Fail-then-add don't add additionnal requested privileges if commannd succeed
     while !capable.has_ran() || capable.is_failed() {
         if looping > 0 {
             //test as root
             eprintln!("Failed to get policy, trying as root");
-            setuid(Uid::from_raw(0)).unwrap(); //.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-            setgid(Gid::from_raw(0)).unwrap();
-            setgroups(&[Gid::from_raw(0)]).unwrap();
+            setuid(Uid::from_raw(0))?;
+            setgid(Gid::from_raw(0))?;
+            setgroups(&[Gid::from_raw(0)])?;
         }
-        let p = capable.run().unwrap(); //.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        progress.next_iteration();
+        let mut p = capable.run().map_err(to_io)?;
+        p.normalize(normalize);
         if looping > 0 && capable.is_failed() {
-            policy.remove(username).unwrap();
             print!("{}", capable.last_stdout);
-            eprint!("{}", capable.last_stderr);
             return Err(io::Error::new(
                 io::ErrorKind::Other,
                 format!(
@@ -288,35 +2487,111 @@ fn fail_then_add_loop(
         } else {
             looping = 0;
         }
-        if !first {
-            policy.remove(username).unwrap() //.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let added = p.clone() - policy.clone();
+        if !added.is_empty() {
+            debug!(
+                "Iteration added {} capabilities, {} files, {} dbus",
+                added.capabilities.len(),
+                added.files.len(),
+                added.dbus.len()
+            );
+        }
+        if *applied {
+            policy.remove(username).map_err(to_io)?;
+            *applied = false;
         }
         *policy = p;
+        progress.discovered(policy);
         if capable.is_failed() {
-            policy.apply(username, &mut capable).unwrap() //.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            policy.apply(username, capable).map_err(to_io)?;
+            *applied = true;
         }
-        first = false;
     }
     Ok(())
 }
 
-fn prompt_for_confirmation(yes: bool, config: &str) -> Result<(), io::Error> {
-    let path = Path::new(config);
-    if !path.exists() {
+/// Privileges of an existing role/task of the configuration
+fn load_seed(config: &str, seed: &str) -> Result<Policy, io::Error> {
+    check_config_exists(config)?;
+    let Some((role, task)) = seed.split_once('/') else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid seed task {}, expected role/task", seed),
+        ));
+    };
+    let settings = rootasrole_core::get_settings(config)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let settings = settings.as_ref().borrow();
+    let config = settings.config.as_ref().borrow();
+    let seed = config
+        .role(role)
+        .and_then(|role| {
+            role.as_ref()
+                .borrow()
+                .tasks
+                .iter()
+                .find(|t| t.as_ref().borrow().name.to_string() == task)
+                .map(|task| Policy::from_stask(&task.as_ref().borrow()))
+        })
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Task {} not found in the configuration", seed),
+            )
+        })?;
+    Ok(seed)
+}
+
+/// Replace the configuration through a synced temporary file of its directory renamed
+/// over it, so that an interrupted write never leaves it truncated
+fn write_config<T: serde::Serialize>(config_path: &str, contents: &T) -> Result<(), io::Error> {
+    let path = Path::new(config_path);
+    let dir = path
+        .parent()
+        .filter(|dir| dir != &Path::new(""))
+        .unwrap_or(Path::new("."));
+    let file = tempfile::NamedTempFile::new_in(dir)?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    serde_json::to_writer_pretty(file.as_file(), contents)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    std::fs::File::open(dir)?.sync_all()
+}
+
+fn check_config_exists(config: &str) -> Result<(), io::Error> {
+    if !Path::new(config).exists() {
         return Err(io::Error::new(
             io::ErrorKind::NotFound,
             format!("Config file not found: {}", config),
         ));
     }
-    let mut input = String::new();
+    Ok(())
+}
+
+fn prompt_for_confirmation(yes: bool, summary: &deploy::ChangeSummary) -> Result<(), io::Error> {
     // If the user has passed the --yes flag, we don't need to prompt for confirmation
-    if yes {
+    if yes || env::var("GENSR_ASSUME_YES").is_ok_and(|v| v == "1") {
         return Ok(());
     }
-    // Verify that user to continue, y or no input will continue the process and any other input will stop the process
-    println!("This will deploy rootasrole config ({}) to the system, are you sure you want to continue? [Y/n]", path.canonicalize().unwrap().to_str().unwrap());
+    // Orchestration tools would hang forever waiting for an answer
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Cannot prompt for confirmation: stdin is not a terminal, use --yes or GENSR_ASSUME_YES=1",
+        ));
+    }
+    print!("{}", summary);
+    // Typing the number of affected users makes sure the summary was actually read
+    println!(
+        "Type the number of affected users ({}) to continue:",
+        summary.affected()
+    );
+    let mut input = String::new();
     io::stdin().read_line(&mut input)?;
-    if input.trim().to_lowercase() != "y" || !input.trim().is_empty() {
+    if input.trim() != summary.affected().to_string() {
         return Err(io::Error::new(
             io::ErrorKind::Other,
             "User cancelled deployment",
@@ -325,21 +2600,37 @@ fn prompt_for_confirmation(yes: bool, config: &str) -> Result<(), io::Error> {
     Ok(())
 }
 
-fn get_username_ansible(playbook: &str, task: &str) -> String {
+/// Ask before a bulk edit of the tasks, which cannot run unattended without --yes
+fn confirm_edit(yes: bool, tasks: usize) -> Result<bool, io::Error> {
+    if yes || env::var("GENSR_ASSUME_YES").is_ok_and(|v| v == "1") {
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Cannot prompt for confirmation: stdin is not a terminal, use --yes or GENSR_ASSUME_YES=1",
+        ));
+    }
+    print!("Apply these changes to {} task(s)? [y/N] ", tasks);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    Ok(matches!(input.trim(), "y" | "Y" | "yes"))
+}
+
+fn ansible_digest(playbook: &str, task: &str) -> String {
     let mut hasher = sha2::Sha224::new();
     hasher.update(playbook.as_bytes());
     hasher.update(task.as_bytes());
     let hash = hasher.finalize();
-    // transform to string
-    format!("rar_{}", hex::encode(hash))
+    hex::encode(hash)
 }
 
-fn get_username_gensr(command: &Vec<String>) -> String {
+fn command_digest(command: &Vec<String>) -> String {
     let mut hasher = sha2::Sha224::new();
     for c in command {
         hasher.update(c.as_bytes());
     }
     let hash = hasher.finalize();
-    // transform to string
-    format!("gsr_{}", hex::encode(hash))
+    hex::encode(hash)
 }
@@ -0,0 +1,164 @@
+use std::{
+    env,
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+};
+
+use log::{debug, info, warn};
+use nix::fcntl::{Flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+/// Counters shared by every gensr invocation, persisted between runs
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Metrics {
+    pub(crate) traces_total: u64,
+    pub(crate) trace_failures_total: u64,
+    pub(crate) trace_duration_seconds_sum: f64,
+    pub(crate) policies_deployed_total: u64,
+    pub(crate) polkit_checks_total: u64,
+}
+
+fn metrics_file_path() -> PathBuf {
+    env::var("GENSR_METRICS_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/var/lib/gensr/metrics.json"))
+}
+
+impl Metrics {
+    pub(crate) fn load() -> io::Result<Self> {
+        let path = metrics_file_path();
+        if !path.exists() {
+            return Ok(Metrics::default());
+        }
+        Ok(serde_json::from_reader(File::open(path)?)?)
+    }
+
+    /// Replace the file through a rename, the endpoint never reads it half written
+    fn save(&self) -> io::Result<()> {
+        let path = metrics_file_path();
+        let parent = path.parent().unwrap_or(Path::new("."));
+        let mut file = NamedTempFile::new_in(parent)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.as_file().sync_all()?;
+        file.persist(&path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Update the persisted counters, metrics must never make an operation fail. Concurrent
+    /// invocations are serialized by a lock next to the file so that no update is lost
+    pub(crate) fn record(update: impl FnOnce(&mut Metrics)) {
+        let result = (|| {
+            let path = metrics_file_path();
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let lock = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path.with_extension("lock"))?;
+            let _lock = Flock::lock(lock, FlockArg::LockExclusive)
+                .map_err(|(_, errno)| io::Error::from(errno))?;
+            let mut metrics = Metrics::load()?;
+            update(&mut metrics);
+            metrics.save()
+        })();
+        if let Err(e) = result {
+            debug!("Failed to record metrics: {}", e);
+        }
+    }
+
+    /// Render the counters in the Prometheus text exposition format
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let metrics: [(&str, &str, &str, f64); 5] = [
+            (
+                "gensr_traces_total",
+                "counter",
+                "Traced command executions",
+                self.traces_total as f64,
+            ),
+            (
+                "gensr_trace_failures_total",
+                "counter",
+                "Traced command executions that failed",
+                self.trace_failures_total as f64,
+            ),
+            (
+                "gensr_trace_duration_seconds_sum",
+                "counter",
+                "Total time spent tracing commands",
+                self.trace_duration_seconds_sum,
+            ),
+            (
+                "gensr_policies_deployed_total",
+                "counter",
+                "Tasks deployed to the system",
+                self.policies_deployed_total as f64,
+            ),
+            (
+                "gensr_polkit_checks_total",
+                "counter",
+                "Polkit authorization checks served",
+                self.polkit_checks_total as f64,
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        let average = if self.traces_total > 0 {
+            self.trace_duration_seconds_sum / self.traces_total as f64
+        } else {
+            0.0
+        };
+        let _ = writeln!(
+            out,
+            "# HELP gensr_trace_duration_seconds_average Average trace duration"
+        );
+        let _ = writeln!(out, "# TYPE gensr_trace_duration_seconds_average gauge");
+        let _ = writeln!(out, "gensr_trace_duration_seconds_average {}", average);
+        out
+    }
+}
+
+fn handle(mut stream: TcpStream) -> io::Result<()> {
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", Metrics::load()?.render())
+    } else {
+        ("404 Not Found", "Not Found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serve the metrics on `/metrics` until the process is stopped
+pub(crate) fn serve(listen: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    info!("Serving metrics on http://{}/metrics", listen);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle(stream) {
+                    warn!("Failed to answer metrics request: {}", e);
+                }
+            }
+            Err(e) => warn!("Failed to accept connection: {}", e),
+        }
+    }
+    Ok(())
+}
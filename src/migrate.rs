@@ -0,0 +1,91 @@
+use std::fs;
+
+use anyhow::bail;
+use log::warn;
+use serde_json::Value;
+
+use crate::rules;
+
+/// Major version of the RootAsRole configuration format gensr reads and writes
+const SUPPORTED_MAJOR: u64 = 3;
+
+fn major(version: &str) -> Option<u64> {
+    version.split('.').next()?.parse().ok()
+}
+
+/// Tasks of the raw configuration, whose roles are either at the top level or under `config`
+fn tasks_mut(config: &mut Value) -> Vec<&mut Value> {
+    let roles = match config.get("roles") {
+        Some(_) => config.get_mut("roles"),
+        None => config.get_mut("config").and_then(|c| c.get_mut("roles")),
+    };
+    roles
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(|role| role.get_mut("tasks").and_then(Value::as_array_mut))
+        .flatten()
+        .collect()
+}
+
+/// D-Bus grants still written as bare destination names, before structured rules
+fn legacy_dbus(task: &Value) -> bool {
+    task.pointer("/cred/dbus")
+        .and_then(Value::as_array)
+        .is_some_and(|rules| rules.iter().any(Value::is_string))
+}
+
+/// Refuse to write a configuration whose format this build of gensr does not know,
+/// and warn about generated tasks using older conventions
+pub(crate) fn check_writable(path: &str) -> anyhow::Result<()> {
+    let mut config: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    if let Some(version) = config.get("version").and_then(Value::as_str) {
+        match major(version) {
+            Some(SUPPORTED_MAJOR) => {}
+            Some(major) if major > SUPPORTED_MAJOR => bail!(
+                "{} uses the RootAsRole {} format, newer than this gensr supports, upgrade gensr",
+                path,
+                version
+            ),
+            _ => bail!(
+                "{} uses the RootAsRole {} format, run `gensr migrate-config` first",
+                path,
+                version
+            ),
+        }
+    }
+    if tasks_mut(&mut config)
+        .into_iter()
+        .any(|task| legacy_dbus(task))
+    {
+        warn!(
+            "{} has D-Bus grants in an older format, run `gensr migrate-config` to upgrade them",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// Upgrade the gensr conventions of the generated tasks to the current format,
+/// returning the number of tasks changed
+pub(crate) fn migrate(config: &mut Value) -> anyhow::Result<usize> {
+    if let Some(version) = config.get("version").and_then(Value::as_str) {
+        if major(version).is_some_and(|major| major > SUPPORTED_MAJOR) {
+            bail!(
+                "The RootAsRole {} format is newer than this gensr supports",
+                version
+            );
+        }
+    }
+    let mut migrated = 0;
+    for task in tasks_mut(config) {
+        if !legacy_dbus(task) {
+            continue;
+        }
+        if let Some(dbus) = task.pointer_mut("/cred/dbus") {
+            *dbus = serde_json::to_value(rules::dbus_rules(dbus))?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
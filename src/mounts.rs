@@ -0,0 +1,96 @@
+use std::{fs, path::Path};
+
+/// A line of /proc/self/mountinfo
+struct Mount {
+    device: String,
+    /// Directory of the filesystem mounted here, not `/` for bind mounts of a subdirectory
+    root: String,
+    mount_point: String,
+}
+
+/// Mount info fields escape spaces, tabs, newlines and backslashes as octal
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let code = rest.get(index + 1..index + 4).unwrap_or("");
+        match u8::from_str_radix(code, 8) {
+            Ok(byte) if code.len() == 3 => {
+                unescaped.push(byte as char);
+                rest = &rest[index + 4..];
+            }
+            _ => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn mounts() -> Vec<Mount> {
+    fs::read_to_string("/proc/self/mountinfo")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            Some(Mount {
+                device: fields.get(2)?.to_string(),
+                root: unescape(fields.get(3)?),
+                mount_point: unescape(fields.get(4)?),
+            })
+        })
+        .collect()
+}
+
+/// Part of the path below the directory, `None` when the path is outside
+fn below<'a>(path: &'a str, dir: &str) -> Option<&'a str> {
+    if dir == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(dir)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// Where the granted path really lives: symlinks are resolved, and a path under a bind
+/// mount is mapped to the original location of the directory, so that tasks granting the
+/// same file through different paths share one ACL entry
+pub(crate) fn canonical(path: &str) -> String {
+    let Ok(resolved) = fs::canonicalize(path) else {
+        return path.to_string();
+    };
+    let resolved = resolved.to_string_lossy().to_string();
+    let mounts = mounts();
+    let Some((mount, rest)) = mounts
+        .iter()
+        .filter_map(|mount| Some((mount, below(&resolved, &mount.mount_point)?)))
+        .max_by_key(|(mount, _)| mount.mount_point.len())
+    else {
+        return resolved;
+    };
+    if mount.root == "/" {
+        return resolved;
+    }
+    // The file relative to the root of its filesystem
+    let inner = format!("{}{}", mount.root, rest);
+    mounts
+        .iter()
+        .filter(|other| other.device == mount.device && other.mount_point != mount.mount_point)
+        .filter_map(|other| Some((other, below(&inner, &other.root)?)))
+        .min_by_key(|(other, _)| other.root.len())
+        .map(|(other, rest)| {
+            let original = Path::new(&other.mount_point)
+                .join(rest.trim_start_matches('/'))
+                .to_string_lossy()
+                .trim_end_matches('/')
+                .to_string();
+            if original.is_empty() {
+                "/".to_string()
+            } else {
+                original
+            }
+        })
+        .unwrap_or(resolved)
+}
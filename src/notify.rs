@@ -0,0 +1,161 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use capctl::Cap;
+use log::{debug, warn};
+use rootasrole_core::database::structs::{STask, SetBehavior};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{auditlog, policy::Access};
+
+/// Capabilities equivalent to root
+const ROOT_CAPABILITIES: [&str; 6] = [
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_PTRACE",
+    "CAP_DAC_OVERRIDE",
+    "CAP_SETUID",
+    "CAP_SETGID",
+];
+
+/// Directories whose write access lets a user escalate to root
+const SENSITIVE_DIRS: [&str; 5] = ["/etc", "/boot", "/usr", "/bin", "/sbin"];
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Slack,
+    Teams,
+    Generic,
+}
+
+#[derive(Deserialize)]
+struct Webhook {
+    kind: Kind,
+    url: String,
+    /// Events notified to this hook, all of them when empty
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+fn webhooks_file_path() -> PathBuf {
+    env::var("GENSR_WEBHOOKS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/etc/gensr/webhooks.yaml"))
+}
+
+fn load() -> Vec<Webhook> {
+    let path = webhooks_file_path();
+    let Ok(content) = fs::read_to_string(&path) else {
+        return Vec::new();
+    };
+    serde_yaml::from_str(&content).unwrap_or_else(|e| {
+        warn!("Ignoring invalid webhooks file {}: {}", path.display(), e);
+        Vec::new()
+    })
+}
+
+/// Rough risk of a task from 0 to 100: root-equivalent capabilities weigh the most,
+/// then other capabilities and write access to system directories
+pub(crate) fn risk_score(task: &STask) -> u32 {
+    let mut score = 0;
+    if let Some(caps) = &task.cred.capabilities {
+        // A task granted every capability but a few is as risky as root
+        let granted: Vec<Cap> = if caps.default_behavior == SetBehavior::All {
+            Cap::iter()
+                .filter(|cap| cap.is_supported() && !caps.sub.has(*cap))
+                .collect()
+        } else {
+            caps.add.iter().filter(|cap| !caps.sub.has(*cap)).collect()
+        };
+        for cap in granted {
+            let name = cap.to_string();
+            score += if ROOT_CAPABILITIES
+                .iter()
+                .any(|root| root.eq_ignore_ascii_case(&name))
+            {
+                40
+            } else {
+                10
+            };
+        }
+    }
+    if let Some(files) = task
+        .cred
+        ._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+    {
+        for (path, access) in files {
+            let writable = access
+                .as_str()
+                .and_then(|a| a.parse::<Access>().ok())
+                .is_some_and(|a| a.contains(Access::W));
+            if writable && SENSITIVE_DIRS.iter().any(|dir| path.starts_with(dir)) {
+                score += 15;
+            } else if writable {
+                score += 2;
+            }
+        }
+    }
+    score.min(100)
+}
+
+fn payload(kind: Kind, event: &str, target: &str, summary: &str, risk: u32) -> Value {
+    let text = format!(
+        "gensr {} on {} by {} (risk {}/100): {}",
+        event,
+        target,
+        auditlog::operator(),
+        risk,
+        summary
+    );
+    match kind {
+        Kind::Slack | Kind::Teams => json!({ "text": text }),
+        Kind::Generic => json!({
+            "event": event,
+            "target": target,
+            "operator": auditlog::operator(),
+            "summary": summary,
+            "risk": risk,
+        }),
+    }
+}
+
+fn post(url: &str, body: &Value) -> anyhow::Result<()> {
+    let mut child = Command::new("curl")
+        .args(["-sSf", "-m", "10", "-X", "POST"])
+        .args(["-H", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(body.to_string().as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!("curl exited with {}", status);
+    }
+    Ok(())
+}
+
+/// Notify the configured webhooks of a policy change, never failing the change itself
+pub(crate) fn notify(event: &str, target: &str, summary: &str, risk: u32) {
+    for hook in load()
+        .iter()
+        .filter(|h| h.events.is_empty() || h.events.iter().any(|e| e == event))
+    {
+        debug!("Notifying {} of {}", hook.url, event);
+        if let Err(e) = post(&hook.url, &payload(hook.kind, event, target, summary, risk)) {
+            warn!("Failed to notify webhook {}: {}", hook.url, e);
+        }
+    }
+}
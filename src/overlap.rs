@@ -0,0 +1,131 @@
+use rootasrole_core::database::structs::{SConfig, STask};
+
+use crate::{params::glob_match, policy::Access};
+
+/// An existing grant already covering a privilege of a new task
+pub(crate) struct Overlap {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    /// The covered file, or None for the capabilities
+    pub(crate) path: Option<String>,
+    pub(crate) grant: String,
+}
+
+impl std::fmt::Display for Overlap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) => write!(
+                f,
+                "{} is already covered by {} in task {} of role {}",
+                path, self.grant, self.task, self.role
+            ),
+            None => write!(
+                f,
+                "capabilities are already covered by {} in task {} of role {}",
+                self.grant, self.task, self.role
+            ),
+        }
+    }
+}
+
+fn files(task: &STask) -> Vec<(String, Access)> {
+    task.cred
+        ._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+        .map(|files| {
+            files
+                .iter()
+                .filter_map(|(path, access)| {
+                    Some((path.clone(), access.as_str()?.parse::<Access>().ok()?))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether a granted path gives access to another path, as a glob or a parent directory glob
+fn covers(granted: &str, path: &str) -> bool {
+    granted == path
+        || glob_match(granted.as_bytes(), path.as_bytes())
+        || granted
+            .strip_suffix("/**")
+            .is_some_and(|dir| path.starts_with(&format!("{}/", dir)))
+}
+
+/// Existing grants of the configuration covering the privileges of a new task of the role
+pub(crate) fn find(config: &SConfig, role_name: &str, task: &STask) -> Vec<Overlap> {
+    let new_files = files(task);
+    let new_caps = task.cred.capabilities.as_ref().map(|caps| caps.add);
+    let mut overlaps = Vec::new();
+    for role in &config.roles {
+        let role = role.as_ref().borrow();
+        for existing in &role.tasks {
+            let existing = existing.as_ref().borrow();
+            // The task being replaced, tasks of other roles may share its name
+            if role.name == role_name && existing.name == task.name {
+                continue;
+            }
+            for (path, access) in &new_files {
+                if let Some((granted, granted_access)) =
+                    files(&existing)
+                        .into_iter()
+                        .find(|(granted, granted_access)| {
+                            granted_access.contains(*access) && covers(granted, path)
+                        })
+                {
+                    overlaps.push(Overlap {
+                        role: role.name.clone(),
+                        task: existing.name.to_string(),
+                        path: Some(path.clone()),
+                        grant: format!("{} {}", granted_access, granted),
+                    });
+                }
+            }
+            if let (Some(new_caps), Some(caps)) = (new_caps, &existing.cred.capabilities) {
+                if !new_caps.is_empty() && caps.add.issuperset(new_caps) {
+                    overlaps.push(Overlap {
+                        role: role.name.clone(),
+                        task: existing.name.to_string(),
+                        path: None,
+                        grant: caps
+                            .add
+                            .iter()
+                            .map(|c| c.to_string())
+                            .collect::<Vec<_>>()
+                            .join(","),
+                    });
+                }
+            }
+        }
+    }
+    overlaps
+}
+
+/// Drop from the task the privileges covered by another task of its own role, which the
+/// actors of the role can already run with these privileges
+pub(crate) fn skip_covered(task: &mut STask, role: &str, overlaps: &[Overlap]) -> usize {
+    let mut skipped = 0;
+    for overlap in overlaps.iter().filter(|o| o.role == role) {
+        match &overlap.path {
+            Some(path) => {
+                if let Some(files) = task
+                    .cred
+                    ._extra_fields
+                    .get_mut("files")
+                    .and_then(|value| value.as_object_mut())
+                {
+                    if files.remove(path).is_some() {
+                        skipped += 1;
+                    }
+                }
+            }
+            None => {
+                if task.cred.capabilities.take().is_some() {
+                    skipped += 1;
+                }
+            }
+        }
+    }
+    skipped
+}
@@ -0,0 +1,223 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::PathBuf,
+    process::Command,
+    rc::Rc,
+};
+
+use clap::ValueEnum;
+use log::debug;
+use rootasrole_core::database::structs::{SCommand, SConfig, STask};
+use serde::{Deserialize, Serialize};
+
+use crate::{auditlog, state::state_dir};
+
+/// Package manager whose transactions trigger `gensr watch-packages`
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum Hook {
+    Apt,
+    Dnf,
+}
+
+/// A task whose binaries were upgraded since the last check, waiting to be traced again
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Retrace {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    pub(crate) package: String,
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+impl std::fmt::Display for Retrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}: {} upgraded from {} to {}",
+            self.role, self.task, self.package, self.from, self.to
+        )
+    }
+}
+
+/// Executables a task refers to: pinned and traced binaries, scripts and allowed commands
+fn task_binaries(task: &STask) -> BTreeSet<String> {
+    let mut binaries = BTreeSet::new();
+    if let Some(pinned) = task
+        ._extra_fields
+        .get("pinned_binaries")
+        .and_then(|v| v.as_object())
+    {
+        binaries.extend(pinned.keys().cloned());
+    }
+    if let Some(provenance) = task._extra_fields.get("provenance") {
+        if let Some(traced) = provenance.get("binaries").and_then(|v| v.as_object()) {
+            binaries.extend(traced.keys().cloned());
+        }
+        if let Some(script) = provenance
+            .get("script")
+            .and_then(|script| script.get("path"))
+            .and_then(|path| path.as_str())
+        {
+            binaries.insert(script.to_string());
+        }
+    }
+    for command in &task.commands.add {
+        if let SCommand::Simple(command) = command {
+            if let Some(program) = command.split_whitespace().next() {
+                if program.starts_with('/') {
+                    binaries.insert(program.to_string());
+                }
+            }
+        }
+    }
+    binaries
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|output| !output.is_empty())
+}
+
+/// Package owning a file, with its installed version
+pub(crate) fn owner(path: &str) -> Option<(String, String)> {
+    if let Some(package) = run("dpkg", &["-S", path])
+        .and_then(|line| line.split(':').next().map(|p| p.trim().to_string()))
+    {
+        let version = run("dpkg-query", &["-W", "-f", "${Version}", &package])?;
+        return Some((package, version));
+    }
+    let package = run("rpm", &["-qf", "--qf", "%{NAME}", path])?;
+    let version = run("rpm", &["-q", "--qf", "%{VERSION}-%{RELEASE}", &package])?;
+    Some((package, version))
+}
+
+/// Installed version of a package
+pub(crate) fn version(package: &str) -> Option<String> {
+    run("dpkg-query", &["-W", "-f", "${Version}", package])
+        .or_else(|| run("rpm", &["-q", "--qf", "%{VERSION}-%{RELEASE}", package]))
+}
+
+fn queue_path() -> PathBuf {
+    state_dir().join("retrace-queue.json")
+}
+
+/// Tasks waiting to be traced again
+pub(crate) fn queued() -> Vec<Retrace> {
+    fs::read_to_string(queue_path())
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Remove the traced tasks, by role and task name, from the queue
+pub(crate) fn dequeue(traced: &BTreeSet<(String, String)>) -> anyhow::Result<()> {
+    let queue = queued();
+    let (done, left): (Vec<Retrace>, Vec<Retrace>) = queue
+        .into_iter()
+        .partition(|retrace| traced.contains(&(retrace.role.clone(), retrace.task.clone())));
+    if done.is_empty() {
+        return Ok(());
+    }
+    for retrace in &done {
+        auditlog::record(
+            "retrace_dequeue",
+            &format!("{}/{}", retrace.role, retrace.task),
+            &retrace.to_string(),
+        );
+    }
+    fs::write(queue_path(), serde_json::to_string_pretty(&left)?)?;
+    Ok(())
+}
+
+/// Queue for re-tracing the tasks whose binaries belong to packages upgraded since
+/// the last check, returning the newly queued tasks
+pub(crate) fn watch(config: &Rc<RefCell<SConfig>>) -> anyhow::Result<Vec<Retrace>> {
+    let dir = state_dir();
+    let versions_path = dir.join("packages.json");
+    let known: BTreeMap<String, String> = fs::read_to_string(&versions_path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    let mut queue = queued();
+    let mut versions = BTreeMap::new();
+    let mut owners: BTreeMap<String, Option<(String, String)>> = BTreeMap::new();
+    let mut queued = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            for binary in task_binaries(&task) {
+                let owner = owners
+                    .entry(binary.clone())
+                    .or_insert_with(|| owner(&binary));
+                let Some((package, version)) = owner.clone() else {
+                    debug!("{} is not owned by any package", binary);
+                    continue;
+                };
+                versions.insert(package.clone(), version.clone());
+                let Some(previous) = known.get(&package).filter(|v| **v != version) else {
+                    continue;
+                };
+                let retrace = Retrace {
+                    role: role.name.clone(),
+                    task: task.name.to_string(),
+                    package,
+                    from: previous.clone(),
+                    to: version,
+                };
+                if !queue.contains(&retrace) {
+                    queued.push(retrace);
+                }
+            }
+        }
+    }
+    fs::create_dir_all(&dir)?;
+    fs::write(&versions_path, serde_json::to_string_pretty(&versions)?)?;
+    if !queued.is_empty() {
+        for retrace in &queued {
+            auditlog::record(
+                "retrace_queue",
+                &format!("{}/{}", retrace.role, retrace.task),
+                &retrace.to_string(),
+            );
+        }
+        queue.extend(queued.iter().cloned());
+        fs::write(queue_path(), serde_json::to_string_pretty(&queue)?)?;
+    }
+    Ok(queued)
+}
+
+/// Install a package manager hook running `gensr watch-packages` after each transaction
+pub(crate) fn install_hook(hook: Hook) -> anyhow::Result<PathBuf> {
+    let gensr = env::current_exe()?;
+    let (path, content) = match hook {
+        Hook::Apt => (
+            PathBuf::from("/etc/apt/apt.conf.d/90gensr"),
+            format!(
+                "DPkg::Post-Invoke {{ \"{} watch-packages || true\"; }};\n",
+                gensr.display()
+            ),
+        ),
+        // Requires the post-transaction-actions dnf plugin
+        Hook::Dnf => (
+            PathBuf::from("/etc/dnf/plugins/post-transaction-actions.d/gensr.action"),
+            format!("*:any:{} watch-packages\n", gensr.display()),
+        ),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, content)?;
+    auditlog::record(
+        "package_hook_write",
+        &path.to_string_lossy(),
+        "installed the watch-packages hook",
+    );
+    Ok(path)
+}
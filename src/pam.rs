@@ -0,0 +1,212 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use log::debug;
+
+use crate::auditlog;
+
+const MARKER: &str = "# gensr: deny interactive login of role users";
+
+/// Services through which a role user could open an interactive session
+const LOGIN_SERVICES: [&str; 3] = ["sshd", "login", "su"];
+
+/// Denies interactive logins of generated users through pam_access and pam_listfile,
+/// RootAsRole's own `sr` service is left untouched.
+pub(crate) struct PamAccessWriter {
+    security_folder: PathBuf,
+    pam_folder: PathBuf,
+}
+
+impl PamAccessWriter {
+    pub(crate) fn new() -> Self {
+        let security_folder = env::var("PAM_SECURITY_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("/etc/security"));
+        let pam_folder = env::var("PAM_CONF_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("/etc/pam.d"));
+        PamAccessWriter {
+            security_folder,
+            pam_folder,
+        }
+    }
+
+    fn deny_file_path(&self) -> PathBuf {
+        self.security_folder.join("gensr.deny")
+    }
+
+    fn access_file_path(&self) -> PathBuf {
+        self.security_folder.join("access.d/gensr.conf")
+    }
+
+    fn denied_users(&self) -> io::Result<Vec<String>> {
+        if !self.deny_file_path().exists() {
+            return Ok(Vec::new());
+        }
+        Ok(fs::read_to_string(self.deny_file_path())?
+            .lines()
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect())
+    }
+
+    fn write_users(&self, users: &[String]) -> io::Result<()> {
+        let mut deny = File::create(self.deny_file_path())?;
+        for user in users {
+            writeln!(deny, "{}", user)?;
+        }
+        deny.flush()?;
+        fs::create_dir_all(self.access_file_path().parent().unwrap())?;
+        let mut access = File::create(self.access_file_path())?;
+        writeln!(access, "{}", MARKER)?;
+        for user in users {
+            writeln!(access, "-:{}:ALL", user)?;
+        }
+        access.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn deny_login(&self, username: &str) -> io::Result<()> {
+        let mut users = self.denied_users()?;
+        if !users.iter().any(|u| u == username) {
+            debug!("Denying interactive login for user {}", username);
+            users.push(username.to_string());
+            self.write_users(&users)?;
+            auditlog::record(
+                "pam_login_deny",
+                username,
+                &self.deny_file_path().to_string_lossy(),
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn allow_login(&self, username: &str) -> io::Result<()> {
+        let mut users = self.denied_users()?;
+        let len = users.len();
+        users.retain(|u| u != username);
+        if users.len() != len {
+            self.write_users(&users)?;
+            auditlog::record(
+                "pam_login_allow",
+                username,
+                &self.deny_file_path().to_string_lossy(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Account rules, checked whatever the authentication method, key-based ssh included
+    fn rules(&self) -> [String; 2] {
+        [
+            format!(
+                "account required pam_listfile.so onerr=succeed item=user sense=deny file={}",
+                self.deny_file_path().display()
+            ),
+            format!(
+                "account required pam_access.so accessfile={}",
+                self.access_file_path().display()
+            ),
+        ]
+    }
+
+    /// The service without the lines gensr added, including the auth rule of earlier versions
+    fn strip(&self, contents: &str) -> String {
+        let legacy = format!(
+            "auth required pam_listfile.so onerr=succeed item=user sense=deny file={}",
+            self.deny_file_path().display()
+        );
+        let rules = self.rules();
+        contents
+            .lines()
+            .filter(|l| *l != MARKER && *l != legacy && !rules.iter().any(|rule| l == rule))
+            .map(|l| format!("{}\n", l))
+            .collect()
+    }
+
+    /// Insert the pam_listfile and pam_access rules at the top of each login service
+    pub(crate) fn build(&self) -> io::Result<()> {
+        for service in LOGIN_SERVICES {
+            let path = self.pam_folder.join(service);
+            if !path.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(&path)?;
+            let rules = self.rules();
+            if rules.iter().all(|rule| contents.lines().any(|l| l == rule)) {
+                continue;
+            }
+            debug!("Adding pam_listfile and pam_access rules to {:?}", path);
+            let contents = self.strip(&contents);
+            let mut writer = File::create(&path)?;
+            writeln!(writer, "{}", MARKER)?;
+            for rule in rules {
+                writeln!(writer, "{}", rule)?;
+            }
+            writer.write_all(contents.as_bytes())?;
+            writer.flush()?;
+            auditlog::record(
+                "pam_service_modify",
+                &path.to_string_lossy(),
+                "added pam_listfile and pam_access rules",
+            );
+        }
+        Ok(())
+    }
+
+    /// Remove every rule installed by gensr once no role user remains denied
+    pub(crate) fn clean(&self) -> io::Result<()> {
+        if !self.denied_users()?.is_empty() {
+            return Ok(());
+        }
+        for service in LOGIN_SERVICES {
+            let path = self.pam_folder.join(service);
+            if !path.exists() {
+                continue;
+            }
+            let contents = fs::read_to_string(&path)?;
+            if !contents.contains(MARKER) {
+                continue;
+            }
+            fs::write(&path, self.strip(&contents))?;
+            auditlog::record(
+                "pam_service_modify",
+                &path.to_string_lossy(),
+                "removed pam_listfile and pam_access rules",
+            );
+        }
+        for file in [self.deny_file_path(), self.access_file_path()] {
+            if file.exists() {
+                fs::remove_file(file)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_removes_only_gensr_lines() {
+        let writer = PamAccessWriter {
+            security_folder: PathBuf::from("/etc/security"),
+            pam_folder: PathBuf::from("/etc/pam.d"),
+        };
+        let [listfile, access] = writer.rules();
+        let legacy = listfile.replace("account", "auth");
+        let service = format!(
+            "{}\n{}\n{}\n{}\n@include common-auth\naccount required pam_nologin.so\n",
+            MARKER, legacy, listfile, access
+        );
+        assert_eq!(
+            writer.strip(&service),
+            "@include common-auth\naccount required pam_nologin.so\n"
+        );
+    }
+}
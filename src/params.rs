@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+use rootasrole_core::database::structs::{SCredentials, STask};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::policy::{Access, Policy};
+
+/// A parameter of a traced command, written `{{NAME}}` in the command line
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Param {
+    #[serde(skip)]
+    pub(crate) name: String,
+    /// Value used while tracing
+    pub(crate) traced: String,
+    /// Values the generated task accepts in place of the placeholder
+    pub(crate) allowed: Vec<String>,
+}
+
+impl Param {
+    fn placeholder(&self) -> String {
+        format!("{{{{{}}}}}", self.name)
+    }
+}
+
+/// Parse `NAME=VALUE[,VALUE...]`, the first value being the traced one
+pub(crate) fn parse_param(param: &str) -> anyhow::Result<Param> {
+    let (name, values) = param
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid parameter '{}', expected NAME=VALUE", param))?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("Invalid parameter name '{}'", name);
+    }
+    let allowed: Vec<String> = values
+        .split(',')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_string())
+        .collect();
+    let Some(traced) = allowed.first().cloned() else {
+        bail!("Parameter {} has no value", name);
+    };
+    Ok(Param {
+        name: name.to_string(),
+        traced,
+        allowed,
+    })
+}
+
+/// Replace the placeholders of a command with the traced values
+pub(crate) fn substitute(command: &[String], params: &[Param]) -> Vec<String> {
+    command
+        .iter()
+        .map(|arg| {
+            params.iter().fold(arg.clone(), |arg, param| {
+                arg.replace(&param.placeholder(), &param.traced)
+            })
+        })
+        .collect()
+}
+
+/// Replace the occurrences of `value` spanning whole components of the path, so that
+/// `sdb` does not rewrite `/dev/sdb1`
+fn replace_components(path: &str, value: &str, replacement: &str) -> String {
+    let value = value.trim_matches('/');
+    if value.is_empty() {
+        return path.to_string();
+    }
+    let bytes = path.as_bytes();
+    let mut replaced = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        if (i == 0 || bytes[i - 1] == b'/')
+            && path[i..].starts_with(value)
+            && matches!(bytes.get(i + value.len()), None | Some(b'/'))
+        {
+            replaced.push_str(replacement);
+            i += value.len();
+        } else {
+            let c = path[i..].chars().next().unwrap();
+            replaced.push(c);
+            i += c.len_utf8();
+        }
+    }
+    replaced
+}
+
+/// Replace the traced values found in file grants with their placeholders
+pub(crate) fn generalize(policy: &mut Policy, params: &[Param]) {
+    for param in params {
+        let mut files: BTreeMap<String, Access> = BTreeMap::new();
+        for (path, access) in std::mem::take(&mut policy.files) {
+            *files
+                .entry(replace_components(
+                    &path,
+                    &param.traced,
+                    &param.placeholder(),
+                ))
+                .or_insert(Access::empty()) |= access;
+        }
+        policy.files = files;
+    }
+}
+
+/// First `{{...}}` placeholder left in the text
+fn leftover_placeholder(text: &str) -> Option<&str> {
+    let start = text.find("{{")?;
+    let end = text[start..].find("}}")?;
+    Some(&text[start..start + end + 2])
+}
+
+/// Every text the placeholders expand to, each replaced by each of its allowed values
+pub(crate) fn expand(text: &str, params: &[Param]) -> Vec<String> {
+    let mut texts = vec![text.to_string()];
+    for param in params {
+        let placeholder = param.placeholder();
+        texts = texts
+            .into_iter()
+            .flat_map(|text| {
+                if text.contains(&placeholder) {
+                    param
+                        .allowed
+                        .iter()
+                        .map(|value| text.replace(&placeholder, value))
+                        .collect()
+                } else {
+                    vec![text]
+                }
+            })
+            .collect();
+    }
+    texts
+}
+
+/// Credentials of the task with the placeholders of its file grants replaced by each of
+/// their allowed values, as deploy grants them. Fails on a placeholder the task does
+/// not declare, which would be granted verbatim
+pub(crate) fn expand_files(task: &STask) -> anyhow::Result<SCredentials> {
+    let mut cred = task.cred.clone();
+    let Some(files) = cred
+        ._extra_fields
+        .get_mut("files")
+        .and_then(|value| value.as_object_mut())
+    else {
+        return Ok(cred);
+    };
+    let mut params = Vec::new();
+    if let Some(block) = task
+        ._extra_fields
+        .get("parameters")
+        .and_then(|value| value.as_object())
+    {
+        for (name, value) in block {
+            let mut param: Param = serde_json::from_value(value.clone())?;
+            param.name = name.clone();
+            params.push(param);
+        }
+    }
+    let mut expanded: BTreeMap<String, Access> = BTreeMap::new();
+    // Invalid accesses are kept as they are, reported and skipped when granted
+    let mut invalid = Map::new();
+    for (path, value) in files.iter() {
+        let Some(access) = value.as_str().and_then(|a| a.parse::<Access>().ok()) else {
+            invalid.insert(path.clone(), value.clone());
+            continue;
+        };
+        for path in expand(path, &params) {
+            if let Some(placeholder) = leftover_placeholder(&path) {
+                bail!(
+                    "Task {} grants {} with the undeclared parameter {}",
+                    task.name,
+                    path,
+                    placeholder
+                );
+            }
+            *expanded.entry(path).or_insert(Access::empty()) |= access;
+        }
+    }
+    *files = expanded
+        .into_iter()
+        .map(|(path, access)| (path, Value::String(access.to_string())))
+        .collect();
+    files.extend(invalid);
+    Ok(cred)
+}
+
+/// The `parameters` block describing the placeholders of a task
+pub(crate) fn to_value(params: &[Param]) -> anyhow::Result<Value> {
+    let mut block = Map::new();
+    for param in params {
+        block.insert(param.name.clone(), serde_json::to_value(param)?);
+    }
+    Ok(Value::Object(block))
+}
+
+/// Match a shell-like glob where `*` and `?` never cross a `/`,
+/// keeping a generalized path within its directory
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Wildcards never match a `/`, so the components are matched one by one
+    let mut patterns = pattern.split(|c| *c == b'/');
+    let mut texts = text.split(|c| *c == b'/');
+    loop {
+        match (patterns.next(), texts.next()) {
+            (None, None) => return true,
+            (Some(pattern), Some(text)) if component_match(pattern, text) => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Iterative glob match of a single component, backtracking only to the last `*`
+fn component_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*`, and of the text it was matched from
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Replace the arguments matching one of the patterns by the pattern itself
+pub(crate) fn generalize_args(command: &[String], patterns: &[String]) -> Vec<String> {
+    command
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            // The program itself is never generalized
+            if i == 0 {
+                return arg.clone();
+            }
+            patterns
+                .iter()
+                .find(|pattern| glob_match(pattern.as_bytes(), arg.as_bytes()))
+                .cloned()
+                .unwrap_or_else(|| arg.clone())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Weak;
+
+    use rootasrole_core::database::structs::IdTask;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn glob_match_stays_within_components() {
+        assert!(glob_match(b"/dev/sd?", b"/dev/sdb"));
+        assert!(glob_match(b"/var/log/*.log", b"/var/log/syslog.log"));
+        assert!(glob_match(b"/var/*/*", b"/var/log/syslog"));
+        assert!(!glob_match(b"/var/*", b"/var/log/syslog"));
+        assert!(!glob_match(b"/dev/sd?", b"/dev/sd/"));
+        assert!(!glob_match(b"/dev/sd?", b"/dev/sdb1"));
+    }
+
+    #[test]
+    fn glob_match_backtracks_to_the_last_star() {
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(!glob_match(&[b'*'; 40], b"/"));
+    }
+
+    #[test]
+    fn replace_components_whole_components_only() {
+        assert_eq!(
+            replace_components("/dev/sdb", "sdb", "{{disk}}"),
+            "/dev/{{disk}}"
+        );
+        assert_eq!(
+            replace_components("/dev/sdb1", "sdb", "{{disk}}"),
+            "/dev/sdb1"
+        );
+        assert_eq!(
+            replace_components("/srv/www/site/www", "www", "{{dir}}"),
+            "/srv/{{dir}}/site/{{dir}}"
+        );
+        assert_eq!(replace_components("/srv/é/x", "é", "{{p}}"), "/srv/{{p}}/x");
+        assert_eq!(replace_components("/srv", "/", "{{p}}"), "/srv");
+    }
+
+    fn task(files: Value) -> STask {
+        let mut task = STask::new(IdTask::Name("t".to_string()), Weak::new());
+        task.cred._extra_fields.insert("files".to_string(), files);
+        task._extra_fields.insert(
+            "parameters".to_string(),
+            json!({ "disk": { "traced": "sdb", "allowed": ["sdb", "sdc"] } }),
+        );
+        task
+    }
+
+    #[test]
+    fn expand_files_grants_each_allowed_value() {
+        let cred = expand_files(&task(json!({ "/dev/{{disk}}": "R", "/etc/fstab": "R" }))).unwrap();
+        assert_eq!(
+            cred._extra_fields["files"],
+            json!({ "/dev/sdb": "R", "/dev/sdc": "R", "/etc/fstab": "R" })
+        );
+    }
+
+    #[test]
+    fn expand_files_rejects_undeclared_parameters() {
+        assert!(expand_files(&task(json!({ "/dev/{{part}}": "R" }))).is_err());
+    }
+
+    #[test]
+    fn expand_each_allowed_value() {
+        let params = [
+            Param {
+                name: "disk".to_string(),
+                traced: "sdb".to_string(),
+                allowed: vec!["sdb".to_string(), "sdc".to_string()],
+            },
+            Param {
+                name: "part".to_string(),
+                traced: "1".to_string(),
+                allowed: vec!["1".to_string(), "2".to_string()],
+            },
+        ];
+        assert_eq!(
+            expand("/dev/{{disk}}{{part}}", &params),
+            ["/dev/sdb1", "/dev/sdb2", "/dev/sdc1", "/dev/sdc2"]
+        );
+        assert_eq!(expand("/etc/fstab", &params), ["/etc/fstab"]);
+    }
+}
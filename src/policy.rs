@@ -1,11 +1,14 @@
 use std::{
-    collections::HashMap,
-    ops::{BitOr, BitOrAssign},
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    ops::{BitAnd, BitOr, BitOrAssign, Sub},
+    path::{Path, PathBuf},
     rc::Weak,
     str::FromStr,
 };
 
 use bitflags::bitflags;
+use capctl::Cap;
 use log::warn;
 use nix::unistd::{getgroups, getuid, Gid, Group, Uid, User};
 use rootasrole_core::{
@@ -15,12 +18,14 @@ use rootasrole_core::{
     },
     util::parse_capset_iter,
 };
-use serde::{ser::SerializeMap, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 use crate::{
     capable::Capable,
     deploy::{enforce_policy, remove_policy},
+    kernel::Requirement,
+    rules::{dbus_rules, DbusRule, NetworkRule},
 };
 
 bitflags! {
@@ -33,27 +38,45 @@ bitflags! {
         const RX  = 0b101;
         const WX  = 0b011;
         const RWX = 0b111;
+        /// Created by the traced command
+        const C   = 0b0000_1000;
+        /// Deleted by the traced command
+        const D   = 0b0001_0000;
+        /// Mode or owner changed by the traced command
+        const A   = 0b0010_0000;
+        /// Renamed by the traced command
+        const N   = 0b0100_0000;
     }
 }
 
+/// Letters of the access bits, the ones after X were added later so that older
+/// `RWX` strings keep their meaning
+const ACCESS_LETTERS: [(char, Access); 7] = [
+    ('R', Access::R),
+    ('W', Access::W),
+    ('X', Access::X),
+    ('C', Access::C),
+    ('D', Access::D),
+    ('A', Access::A),
+    ('N', Access::N),
+];
+
 impl std::fmt::Display for Access {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut access = String::new();
-        if self.contains(Access::R) {
-            access.push('R');
-        }
-        if self.contains(Access::W) {
-            access.push('W');
-        }
-        if self.contains(Access::X) {
-            access.push('X');
-        }
+        let access: String = ACCESS_LETTERS
+            .iter()
+            .filter(|(_, bit)| self.contains(*bit))
+            .map(|(letter, _)| letter)
+            .collect();
         write!(f, "{}", access)
     }
 }
 
+#[derive(Debug)]
 pub struct AccessParseError;
 
+impl std::error::Error for AccessParseError {}
+
 impl std::fmt::Display for AccessParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Invalid access string")
@@ -66,12 +89,11 @@ impl FromStr for Access {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let mut access = Access::empty();
         for c in s.chars() {
-            match c {
-                'R' => access |= Access::R,
-                'W' => access |= Access::W,
-                'X' => access |= Access::X,
-                _ => return Err(AccessParseError),
-            }
+            let (_, bit) = ACCESS_LETTERS
+                .iter()
+                .find(|(letter, _)| *letter == c)
+                .ok_or(AccessParseError)?;
+            access |= *bit;
         }
         Ok(access)
     }
@@ -96,63 +118,70 @@ impl<'de> Deserialize<'de> for Access {
     }
 }
 
-#[derive(Deserialize, PartialEq, Eq)]
+/// Capability name as granted by RootAsRole, `net_raw` being `CAP_NET_RAW`
+pub(crate) fn capability_name(name: &str) -> String {
+    let name = name.to_uppercase();
+    if name.starts_with("CAP_") {
+        name
+    } else {
+        format!("CAP_{}", name)
+    }
+}
+
+/// Privileges observed by a trace. Serialization is symmetric so a saved policy can be
+/// loaded back, `pretty` renders ids as names for humans
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub(crate) struct Policy {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) setuid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) setgid: Option<Vec<u32>>,
-    pub(crate) capabilities: Vec<String>,
-    pub(crate) files: HashMap<String, Access>,
-    pub(crate) dbus: Vec<String>,
-    pub(crate) env_vars: HashMap<String, String>,
+    /// A set, so merged traces neither duplicate nor reorder capabilities
+    #[serde(default)]
+    pub(crate) capabilities: BTreeSet<String>,
+    #[serde(default)]
+    pub(crate) files: BTreeMap<String, Access>,
+    #[serde(default)]
+    pub(crate) dbus: BTreeSet<DbusRule>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) network: BTreeSet<NetworkRule>,
+    #[serde(default)]
+    pub(crate) env_vars: BTreeMap<String, String>,
     #[serde(default)]
     pub(crate) password_prompt: SAuthentication,
-}
-
-impl Serialize for Policy {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        let mut map = serializer.serialize_map(Some(5))?;
-        if let Some(setuid) = self.setuid {
-            let uid = Uid::from_raw(setuid);
-            if let Ok(Some(user)) = User::from_uid(uid) {
-                map.serialize_entry("setuid", &user.name)?;
-            } else {
-                map.serialize_entry("setuid", &setuid)?;
-            }
-        }
-        if let Some(setgid) = &self.setgid {
-            let groups: Vec<SActorType> = setgid
-                .iter()
-                .map(|g| {
-                    let gid = Gid::from_raw(*g);
-                    if let Ok(Some(group)) = Group::from_gid(gid) {
-                        SActorType::Name(group.name)
-                    } else {
-                        SActorType::Id(*g)
-                    }
-                })
-                .collect();
-            map.serialize_entry("setgid", &groups)?;
-        }
-        map.serialize_entry("capabilities", &self.capabilities)?;
-        map.serialize_entry("files", &self.files)?;
-        map.serialize_entry("dbus", &self.dbus)?;
-        map.end()
-    }
+    /// The trace ran inside an unprivileged user namespace, capabilities may not match the host
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) userns_derived: bool,
+    /// Setuid and setgid programs executed by the traced command
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) setuid_helpers: BTreeSet<String>,
+    /// Kernel settings the traced command relies on
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) kernel_requirements: BTreeSet<Requirement>,
+    /// Umasks the traced processes were seen running with
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) umasks: BTreeSet<u32>,
+    /// Mode, file type included, of the files and directories the traced command created
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) created_modes: BTreeMap<String, u32>,
 }
 
 impl Default for Policy {
     fn default() -> Self {
         Policy {
-            capabilities: Vec::new(),
-            files: HashMap::new(),
-            dbus: Vec::new(),
+            capabilities: BTreeSet::new(),
+            files: BTreeMap::new(),
+            dbus: BTreeSet::new(),
+            network: BTreeSet::new(),
             setuid: None,
             setgid: None,
-            env_vars: HashMap::new(),
+            env_vars: BTreeMap::new(),
             password_prompt: SAuthentication::Perform,
+            userns_derived: false,
+            setuid_helpers: BTreeSet::new(),
+            kernel_requirements: BTreeSet::new(),
+            umasks: BTreeSet::new(),
+            created_modes: BTreeMap::new(),
         }
     }
 }
@@ -174,9 +203,19 @@ impl BitOr for Policy {
         files.extend(rhs.files);
         let mut dbus = self.dbus;
         dbus.extend(rhs.dbus);
+        let mut network = self.network;
+        network.extend(rhs.network);
 
         let mut env = self.env_vars;
         env.extend(rhs.env_vars);
+        let mut setuid_helpers = self.setuid_helpers;
+        setuid_helpers.extend(rhs.setuid_helpers);
+        let mut kernel_requirements = self.kernel_requirements;
+        kernel_requirements.extend(rhs.kernel_requirements);
+        let mut umasks = self.umasks;
+        umasks.extend(rhs.umasks);
+        let mut created_modes = self.created_modes;
+        created_modes.extend(rhs.created_modes);
 
         if self.password_prompt != rhs.password_prompt {
             warn!(
@@ -189,10 +228,16 @@ impl BitOr for Policy {
             capabilities,
             files,
             dbus,
+            network,
             setuid: self.setuid.or(rhs.setuid),
             setgid: self.setgid.or(rhs.setgid),
             env_vars: env,
             password_prompt: self.password_prompt,
+            userns_derived: self.userns_derived || rhs.userns_derived,
+            setuid_helpers,
+            kernel_requirements,
+            umasks,
+            created_modes,
         }
     }
 }
@@ -214,10 +259,254 @@ impl BitOrAssign for Policy {
 
         self.files.extend(rhs.files);
         self.dbus.extend(rhs.dbus);
+        self.network.extend(rhs.network);
+        self.env_vars.extend(rhs.env_vars);
+        self.setuid = self.setuid.or(rhs.setuid);
+        self.setgid = self.setgid.take().or(rhs.setgid);
+        self.userns_derived |= rhs.userns_derived;
+        self.setuid_helpers.extend(rhs.setuid_helpers);
+        self.kernel_requirements.extend(rhs.kernel_requirements);
+        self.umasks.extend(rhs.umasks);
+        self.created_modes.extend(rhs.created_modes);
+    }
+}
+
+/// Privileges granted by both policies. Files keep the access bits common to both,
+/// setuid and setgid are kept only when identical
+impl BitAnd for Policy {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        let files = self
+            .files
+            .into_iter()
+            .filter_map(|(path, access)| {
+                let access = access & *rhs.files.get(&path)?;
+                (!access.is_empty()).then_some((path, access))
+            })
+            .collect();
+        let env_vars = self
+            .env_vars
+            .into_iter()
+            .filter(|(key, value)| rhs.env_vars.get(key) == Some(value))
+            .collect();
+        Policy {
+            capabilities: &self.capabilities & &rhs.capabilities,
+            files,
+            dbus: &self.dbus & &rhs.dbus,
+            network: &self.network & &rhs.network,
+            setuid: self.setuid.filter(|uid| rhs.setuid == Some(*uid)),
+            setgid: self.setgid.filter(|gids| rhs.setgid.as_ref() == Some(gids)),
+            env_vars,
+            password_prompt: self.password_prompt,
+            userns_derived: self.userns_derived || rhs.userns_derived,
+            setuid_helpers: &self.setuid_helpers & &rhs.setuid_helpers,
+            kernel_requirements: &self.kernel_requirements & &rhs.kernel_requirements,
+            umasks: &self.umasks & &rhs.umasks,
+            created_modes: self
+                .created_modes
+                .into_iter()
+                .filter(|(path, mode)| rhs.created_modes.get(path) == Some(mode))
+                .collect(),
+        }
+    }
+}
+
+/// Privileges granted by the left policy only, every field against its counterpart. Files
+/// keep the access bits missing from the right policy, setuid, setgid and the created
+/// modes are kept only when they differ
+impl Sub for Policy {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        let files = self
+            .files
+            .into_iter()
+            .filter_map(|(path, access)| {
+                let access = access & !rhs.files.get(&path).copied().unwrap_or(Access::empty());
+                (!access.is_empty()).then_some((path, access))
+            })
+            .collect();
+        let env_vars = self
+            .env_vars
+            .into_iter()
+            .filter(|(key, value)| rhs.env_vars.get(key) != Some(value))
+            .collect();
+        Policy {
+            capabilities: &self.capabilities - &rhs.capabilities,
+            files,
+            dbus: &self.dbus - &rhs.dbus,
+            network: &self.network - &rhs.network,
+            setuid: self.setuid.filter(|uid| rhs.setuid != Some(*uid)),
+            setgid: self.setgid.filter(|gids| rhs.setgid.as_ref() != Some(gids)),
+            env_vars,
+            password_prompt: self.password_prompt,
+            userns_derived: self.userns_derived,
+            setuid_helpers: &self.setuid_helpers - &rhs.setuid_helpers,
+            kernel_requirements: &self.kernel_requirements - &rhs.kernel_requirements,
+            umasks: &self.umasks - &rhs.umasks,
+            created_modes: self
+                .created_modes
+                .into_iter()
+                .filter(|(path, mode)| rhs.created_modes.get(path) != Some(mode))
+                .collect(),
+        }
+    }
+}
+
+/// How file paths are canonicalized so two traces of the same command compare equal
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct NormalizeOptions {
+    /// Replace existing paths by their symlink-free target
+    pub(crate) resolve_symlinks: bool,
+    /// Drop the trailing slash of directories
+    pub(crate) strip_trailing_slash: bool,
+    /// Mount points of case-insensitive filesystems, whose paths are lowercased
+    pub(crate) case_insensitive: Vec<PathBuf>,
+    /// Collapse this many sibling files with the same access into a `dir/*` glob
+    pub(crate) collapse_threshold: Option<usize>,
+}
+
+impl Default for NormalizeOptions {
+    fn default() -> Self {
+        NormalizeOptions {
+            resolve_symlinks: false,
+            strip_trailing_slash: true,
+            case_insensitive: Vec::new(),
+            collapse_threshold: None,
+        }
+    }
+}
+
+/// Canonicalize a single path, globs are kept as they are
+pub(crate) fn normalize_path(path: &str, options: &NormalizeOptions) -> String {
+    let is_glob = path.contains(['*', '?', '[']);
+    let resolved = if options.resolve_symlinks && !is_glob {
+        fs::canonicalize(path)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.to_string())
+    } else {
+        path.to_string()
+    };
+    // Remove empty and `.` components, `..` is left to symlink resolution
+    let absolute = resolved.starts_with('/');
+    let trailing = resolved.ends_with('/');
+    let components: Vec<&str> = resolved
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    let mut normalized = format!(
+        "{}{}",
+        if absolute { "/" } else { "" },
+        components.join("/")
+    );
+    if trailing && !options.strip_trailing_slash && !components.is_empty() {
+        normalized.push('/');
     }
+    let lowercased = options.case_insensitive.iter().find_map(|mount| {
+        // Compared in the same form as the path, a mount given as `/mnt//data/` matches too
+        let mount = normalize_path(&mount.to_string_lossy(), &NormalizeOptions::default());
+        let rest = normalized.strip_prefix(mount.as_str())?;
+        (rest.is_empty() || rest.starts_with('/') || mount.ends_with('/'))
+            .then(|| format!("{}{}", mount, rest.to_lowercase()))
+    });
+    lowercased.unwrap_or(normalized)
 }
 
 impl Policy {
+    /// Human readable rendering, with user and group names instead of ids
+    pub(crate) fn pretty(&self) -> Value {
+        let mut map = Map::new();
+        if let Some(setuid) = self.setuid {
+            let uid = Uid::from_raw(setuid);
+            map.insert(
+                "setuid".to_string(),
+                match User::from_uid(uid) {
+                    Ok(Some(user)) => Value::String(user.name),
+                    _ => Value::from(setuid),
+                },
+            );
+        }
+        if let Some(setgid) = &self.setgid {
+            let groups = setgid
+                .iter()
+                .map(|g| match Group::from_gid(Gid::from_raw(*g)) {
+                    Ok(Some(group)) => Value::String(group.name),
+                    _ => Value::from(*g),
+                })
+                .collect();
+            map.insert("setgid".to_string(), Value::Array(groups));
+        }
+        map.insert(
+            "capabilities".to_string(),
+            serde_json::json!(self.capabilities),
+        );
+        map.insert("files".to_string(), self.to_sfiles());
+        map.insert("dbus".to_string(), self.to_sdbus());
+        if !self.network.is_empty() {
+            map.insert("network".to_string(), self.to_snetwork());
+        }
+        map.insert("env_vars".to_string(), serde_json::json!(self.env_vars));
+        if self.userns_derived {
+            map.insert("userns_derived".to_string(), Value::Bool(true));
+        }
+        if !self.setuid_helpers.is_empty() {
+            map.insert(
+                "setuid_helpers".to_string(),
+                serde_json::json!(self.setuid_helpers),
+            );
+        }
+        if !self.kernel_requirements.is_empty() {
+            map.insert(
+                "kernel_requirements".to_string(),
+                serde_json::json!(self.kernel_requirements),
+            );
+        }
+        Value::Object(map)
+    }
+
+    /// Whether the policy grants no capability, file, dbus or network access
+    pub(crate) fn is_empty(&self) -> bool {
+        self.capabilities.is_empty()
+            && self.files.is_empty()
+            && self.dbus.is_empty()
+            && self.network.is_empty()
+    }
+
+    /// Canonicalize the file grants, merging the accesses of paths that become equal
+    pub(crate) fn normalize(&mut self, options: &NormalizeOptions) {
+        let mut files: BTreeMap<String, Access> = BTreeMap::new();
+        for (path, access) in std::mem::take(&mut self.files) {
+            *files
+                .entry(normalize_path(&path, options))
+                .or_insert(Access::empty()) |= access;
+        }
+        if let Some(threshold) = options.collapse_threshold {
+            let mut siblings: BTreeMap<(String, u8), Vec<String>> = BTreeMap::new();
+            for (path, access) in &files {
+                if let Some(parent) = Path::new(path).parent().filter(|p| p != &Path::new("")) {
+                    siblings
+                        .entry((parent.to_string_lossy().to_string(), access.bits()))
+                        .or_default()
+                        .push(path.clone());
+                }
+            }
+            for ((parent, access), paths) in siblings {
+                if paths.len() < threshold.max(2) {
+                    continue;
+                }
+                for path in paths {
+                    files.remove(&path);
+                }
+                // Deploy expands the glob to the files of the directory, see fileops
+                let glob = format!("{}/*", parent.trim_end_matches('/'));
+                *files.entry(glob).or_insert(Access::empty()) |= Access::from_bits_truncate(access);
+            }
+        }
+        self.files = files;
+    }
+
     pub(crate) fn apply(&self, username: &str, capable: &mut Capable) -> anyhow::Result<()> {
         //TODO: apply the policy
 
@@ -232,6 +521,95 @@ impl Policy {
         remove_policy(&username, self)
     }
 
+    /// Privileges granted by an existing task: its capabilities, files and dbus rules
+    pub(crate) fn from_stask(task: &STask) -> Policy {
+        let mut policy = Policy::default();
+        if let Some(capabilities) = &task.cred.capabilities {
+            policy.capabilities = if capabilities.default_behavior == SetBehavior::All {
+                // Every capability but the subtracted ones
+                Cap::iter()
+                    .filter(|cap| !capabilities.sub.has(*cap))
+                    .map(|cap| cap.to_string())
+                    .collect()
+            } else {
+                capabilities.add.iter().map(|cap| cap.to_string()).collect()
+            };
+            // Earlier versions listed the capabilities only children used apart
+            if let Some(ambient) = capabilities._extra_fields.get("ambient") {
+                policy.capabilities.extend(
+                    serde_json::from_value::<BTreeSet<String>>(ambient.clone()).unwrap_or_default(),
+                );
+            }
+        }
+        if let Some(files) = task
+            .cred
+            ._extra_fields
+            .get("files")
+            .and_then(|value| value.as_object())
+        {
+            policy.files = files
+                .iter()
+                .filter_map(|(path, access)| {
+                    Some((path.clone(), access.as_str()?.parse::<Access>().ok()?))
+                })
+                .collect();
+        }
+        if let Some(dbus) = task.cred._extra_fields.get("dbus") {
+            policy.dbus = dbus_rules(dbus).into_iter().collect();
+        }
+        policy
+    }
+
+    /// Replace the capabilities, files and dbus grants of an existing task by the ones of
+    /// the policy, leaving the rest of the task untouched. Only the added capabilities are
+    /// replaced: a task granted every capability keeps its default behavior, and the
+    /// capabilities the policy lacks are subtracted from it
+    pub(crate) fn replace_grants(&self, task: &mut STask, username: &str) {
+        let generated = self.to_stask(username, Some(&task.name.to_string()));
+        match (task.cred.capabilities.as_mut(), generated.cred.capabilities) {
+            (Some(existing), generated) => {
+                let generated = generated.unwrap_or_default();
+                if existing.default_behavior == SetBehavior::All {
+                    let held = parse_capset_iter(self.capabilities.iter().map(|c| c.as_str()))
+                        .unwrap_or(generated.add);
+                    existing.sub = Cap::iter().filter(|cap| !held.has(*cap)).collect();
+                } else {
+                    existing.add = generated.add;
+                }
+                // Folded into the capabilities of the policy, which `add` now holds
+                existing._extra_fields.remove("ambient");
+            }
+            (None, generated) => task.cred.capabilities = generated,
+        }
+        for key in ["files", "dbus"] {
+            if let Some(value) = generated.cred._extra_fields.get(key) {
+                task.cred
+                    ._extra_fields
+                    .insert(key.to_string(), value.clone());
+            }
+        }
+    }
+
+    /// One line per grant, prefixed by the sign, e.g. `+CAP_NET_RAW`
+    pub(crate) fn describe(&self, sign: char) -> Vec<String> {
+        let mut changes: Vec<String> = self
+            .capabilities
+            .iter()
+            .map(|capability| format!("{}{}", sign, capability))
+            .collect();
+        changes.extend(
+            self.files
+                .iter()
+                .map(|(path, access)| format!("{}{} ({})", sign, path, access)),
+        );
+        changes.extend(
+            self.dbus
+                .iter()
+                .map(|rule| format!("{}dbus {}", sign, rule.destination)),
+        );
+        changes
+    }
+
     pub fn to_stask(&self, username: &str, task: Option<&str>) -> STask {
         let mut stask = STask::new(
             IdTask::Name(task.unwrap_or(username).to_string()),
@@ -248,6 +626,24 @@ impl Policy {
             .cred
             ._extra_fields
             .insert("dbus".to_string(), self.to_sdbus());
+        if !self.network.is_empty() {
+            stask
+                .cred
+                ._extra_fields
+                .insert("network".to_string(), self.to_snetwork());
+        }
+        if !self.kernel_requirements.is_empty() {
+            stask._extra_fields.insert(
+                "kernel_requirements".to_string(),
+                serde_json::json!(self.kernel_requirements),
+            );
+        }
+        if self.userns_derived {
+            stask._extra_fields.insert(
+                "provenance".to_string(),
+                serde_json::json!({ "userns_derived": true }),
+            );
+        }
         stask.commands.default_behavior = Some(SetBehavior::All);
         stask
     }
@@ -258,6 +654,7 @@ impl Policy {
         } else {
             let mut scapabilities = SCapabilities::default();
             scapabilities.default_behavior = SetBehavior::None;
+            // Children inherit the capabilities sr raises as ambient, which are the added ones
             let res = parse_capset_iter(self.capabilities.iter().map(|c| c.as_str()));
             if let Ok(capset) = res {
                 scapabilities.add = capset;
@@ -278,7 +675,11 @@ impl Policy {
     }
 
     fn to_sdbus(&self) -> Value {
-        Value::Array(self.dbus.iter().map(|d| Value::String(d.clone())).collect())
+        serde_json::to_value(&self.dbus).unwrap_or_default()
+    }
+
+    fn to_snetwork(&self) -> Value {
+        serde_json::to_value(&self.network).unwrap_or_default()
     }
 
     pub(crate) fn current_user_creds(&mut self) {
@@ -0,0 +1,128 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, DirBuilder},
+    os::unix::fs::{chown, DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use log::{debug, warn};
+use nix::unistd::User;
+use serde::{Deserialize, Serialize};
+
+/// Directory of the cache, cleared at boot along with the decisions it holds
+fn cache_dir() -> PathBuf {
+    env::var("GENSR_POLKIT_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/run/gensr"))
+}
+
+/// Create the cache directory for polkitd, which runs the checks, and nobody else: the
+/// cached decisions must not be readable or forged by other users
+pub(crate) fn prepare_dir() -> anyhow::Result<()> {
+    let dir = cache_dir();
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    match User::from_name("polkitd")? {
+        Some(polkitd) => chown(&dir, Some(polkitd.uid.as_raw()), Some(polkitd.gid.as_raw()))
+            .with_context(|| format!("Cannot give {} to polkitd", dir.display()))?,
+        None => warn!(
+            "No polkitd user, the polkit cache {} is only writable by root",
+            dir.display()
+        ),
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// Modification time of the policy file, any rewrite of it invalidates the cache
+fn stamp(policy_file: &Path) -> u64 {
+    fs::metadata(policy_file)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos() as u64)
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Entries {
+    stamp: u64,
+    /// Decision and the time it was made, by `<user> <action>`
+    decisions: BTreeMap<String, (bool, u64)>,
+}
+
+/// Recent decisions of the polkit check, so that bursts of identical checks do not read
+/// the policy each time
+pub(crate) struct PolkitCache {
+    path: PathBuf,
+    ttl: Duration,
+    entries: Entries,
+}
+
+fn key(user: &str, action: &str) -> String {
+    format!("{} {}", user, action)
+}
+
+impl PolkitCache {
+    /// The cache of the policy file, emptied if the policy changed since
+    pub(crate) fn load(policy_file: &Path, ttl: Duration) -> PolkitCache {
+        let name = policy_file
+            .file_stem()
+            .map_or("rootasrole".into(), |stem| stem.to_string_lossy());
+        let path = cache_dir().join(format!("{}-cache.json", name));
+        let stamp = stamp(policy_file);
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Entries>(&contents).ok())
+            .filter(|entries| entries.stamp == stamp)
+            .unwrap_or(Entries {
+                stamp,
+                decisions: BTreeMap::new(),
+            });
+        PolkitCache { path, ttl, entries }
+    }
+
+    pub(crate) fn get(&self, user: &str, action: &str) -> Option<bool> {
+        let (allowed, at) = self.entries.decisions.get(&key(user, action))?;
+        (now().saturating_sub(*at) < self.ttl.as_secs()).then_some(*allowed)
+    }
+
+    /// Record the decision, dropping the expired ones. The cache is best effort: failing
+    /// to write it only costs the next check a read of the policy
+    pub(crate) fn insert(&mut self, user: &str, action: &str, allowed: bool) {
+        let now = now();
+        let ttl = self.ttl.as_secs();
+        self.entries
+            .decisions
+            .retain(|_, (_, at)| now.saturating_sub(*at) < ttl);
+        self.entries
+            .decisions
+            .insert(key(user, action), (allowed, now));
+        if let Err(e) = self.save() {
+            debug!("Cannot write the polkit cache {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Replace the file through a rename, concurrent checks never read it half written
+    fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+        let tmp = self
+            .path
+            .with_extension(format!("tmp.{}", std::process::id()));
+        fs::write(&tmp, serde_json::to_string(&self.entries)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
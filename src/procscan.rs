@@ -0,0 +1,184 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use crate::{kernel::Requirement, policy::Access};
+
+/// Directories of shared libraries, whose mappings are loader noise rather than data accesses
+const LIBRARY_DIRS: [&str; 4] = ["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+/// Supplementary collector sampling the open files and file mappings of the traced processes,
+/// catching accesses that escape the tracer: files read through io_uring registered files,
+/// or through mappings of descriptors closed since
+#[derive(Default)]
+pub(crate) struct ProcCollector {
+    files: BTreeMap<String, Access>,
+    /// Programs the traced processes were seen running
+    executables: BTreeSet<String>,
+    /// Kernel features the traced processes were seen using
+    kernel_features: BTreeSet<Requirement>,
+    umasks: BTreeSet<u32>,
+    /// Inode of the open files, to recognize them once renamed
+    inodes: BTreeMap<String, u64>,
+}
+
+/// Octal or decimal value of a line of a process status
+fn status_field(status: &str, field: &str, radix: u32) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))
+        .and_then(|value| u32::from_str_radix(value.trim(), radix).ok())
+}
+
+/// Only keep regular paths, not sockets, pipes, anonymous inodes or pseudo filesystems
+fn is_recordable(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.ends_with(" (deleted)")
+        && !["/proc/", "/sys/", "/dev/"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+}
+
+fn fd_access(flags: u32) -> Access {
+    match flags & 0o3 {
+        0 => Access::R,
+        1 => Access::W,
+        _ => Access::RW,
+    }
+}
+
+/// Access mode of a file descriptor, from the octal `flags:` line of its fdinfo
+fn fdinfo_flags(fdinfo: &str) -> Option<u32> {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+}
+
+impl ProcCollector {
+    fn record(&mut self, path: &str, access: Access) {
+        if is_recordable(path) {
+            *self
+                .files
+                .entry(path.to_string())
+                .or_insert(Access::empty()) |= access;
+        }
+    }
+
+    /// Open descriptors, and the files registered to io_uring instances
+    fn sample_fds(&mut self, proc: &Path) {
+        let Ok(fds) = fs::read_dir(proc.join("fd")) else {
+            return;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let target = target.to_string_lossy();
+            let fdinfo =
+                fs::read_to_string(proc.join("fdinfo").join(fd.file_name())).unwrap_or_default();
+            if target.starts_with("anon_inode:bpf") {
+                self.kernel_features.insert(Requirement::UnprivilegedBpf);
+            }
+            if target == "anon_inode:[io_uring]" {
+                // Registered files are listed as `  <index>: <path>` after `UserFiles:`
+                for line in fdinfo
+                    .lines()
+                    .skip_while(|line| !line.starts_with("UserFiles:"))
+                    .skip(1)
+                    .take_while(|line| line.starts_with(' '))
+                {
+                    if let Some((_, path)) = line.split_once(": ") {
+                        self.record(path.trim(), Access::R);
+                    }
+                }
+            } else if let Some(flags) = fdinfo_flags(&fdinfo) {
+                self.record(&target, fd_access(flags));
+                if let Ok(metadata) = fs::metadata(fd.path()) {
+                    self.inodes.insert(target.to_string(), metadata.ino());
+                }
+            }
+        }
+    }
+
+    /// File-backed mappings, leaving out the executable and its shared libraries
+    fn sample_maps(&mut self, proc: &Path) {
+        let Ok(maps) = fs::read_to_string(proc.join("maps")) else {
+            return;
+        };
+        let exe = fs::read_link(proc.join("exe")).unwrap_or_default();
+        for line in maps.lines() {
+            let fields: Vec<&str> = line.splitn(6, char::is_whitespace).collect();
+            let (Some(perms), Some(path)) = (fields.get(1), fields.get(5)) else {
+                continue;
+            };
+            let path = path.trim();
+            if Path::new(path) == exe
+                || perms.contains('x')
+                || LIBRARY_DIRS
+                    .iter()
+                    .any(|dir| path.starts_with(&format!("{}/", dir)))
+            {
+                continue;
+            }
+            let mut access = Access::empty();
+            if perms.starts_with('r') {
+                access |= Access::R;
+            }
+            // Private writable mappings are copy-on-write, the file itself is never written
+            if perms.ends_with('s') && perms.contains('w') {
+                access |= Access::W;
+            }
+            if !access.is_empty() {
+                self.record(path, access);
+            }
+        }
+    }
+
+    /// Sample the traced processes, except the tracer itself
+    pub(crate) fn sample(&mut self, pids: &[u32], tracer: &Path) {
+        for pid in pids {
+            let proc = Path::new("/proc").join(pid.to_string());
+            let Ok(exe) = fs::read_link(proc.join("exe")) else {
+                continue;
+            };
+            if exe == tracer {
+                continue;
+            }
+            self.executables.insert(exe.to_string_lossy().to_string());
+            if fs::read_link(proc.join("ns/user")).ok() != fs::read_link("/proc/self/ns/user").ok()
+            {
+                self.kernel_features.insert(Requirement::UnprivilegedUserns);
+            }
+            let status = fs::read_to_string(proc.join("status")).unwrap_or_default();
+            if let Some(umask) = status_field(&status, "Umask:", 8) {
+                self.umasks.insert(umask);
+            }
+            self.sample_fds(&proc);
+            self.sample_maps(&proc);
+        }
+    }
+
+    pub(crate) fn files(&self) -> &BTreeMap<String, Access> {
+        &self.files
+    }
+
+    pub(crate) fn executables(&self) -> &BTreeSet<String> {
+        &self.executables
+    }
+
+    pub(crate) fn kernel_features(&self) -> &BTreeSet<Requirement> {
+        &self.kernel_features
+    }
+
+    pub(crate) fn umasks(&self) -> &BTreeSet<u32> {
+        &self.umasks
+    }
+
+    pub(crate) fn inodes(&self) -> &BTreeMap<String, u64> {
+        &self.inodes
+    }
+}
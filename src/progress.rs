@@ -0,0 +1,65 @@
+use std::{
+    io::{self, IsTerminal},
+    time::Duration,
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::policy::Policy;
+
+/// Spinner reporting the progress of a trace, disabled when stdout is not a terminal
+pub(crate) struct Progress {
+    bar: Option<ProgressBar>,
+    label: String,
+    iteration: usize,
+}
+
+impl Progress {
+    pub(crate) fn new(enabled: bool, label: &str) -> Self {
+        let bar = (enabled && io::stdout().is_terminal()).then(|| {
+            let bar = ProgressBar::new_spinner();
+            bar.set_style(
+                ProgressStyle::with_template("{spinner} [{elapsed_precise}] {msg}")
+                    .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+            );
+            bar.enable_steady_tick(Duration::from_millis(120));
+            bar
+        });
+        let progress = Progress {
+            bar,
+            label: label.to_string(),
+            iteration: 0,
+        };
+        progress.update("starting");
+        progress
+    }
+
+    fn update(&self, status: &str) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(format!(
+                "{} | iteration {} | {}",
+                self.label, self.iteration, status
+            ));
+        }
+    }
+
+    pub(crate) fn next_iteration(&mut self) {
+        self.iteration += 1;
+        self.update("tracing");
+    }
+
+    pub(crate) fn discovered(&self, policy: &Policy) {
+        self.update(&format!(
+            "{} capabilities, {} files, {} dbus",
+            policy.capabilities.len(),
+            policy.files.len(),
+            policy.dbus.len()
+        ));
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use rootasrole_core::database::structs::{SCommand, STask, SetBehavior};
+use serde_json::json;
+
+use crate::{
+    capable::TraceStats, fingerprint::Fingerprint, params, params::Param, policy::NormalizeOptions,
+    script::Script, setuid::Helpers,
+};
+
+/// How a generated task was obtained, recorded in its `provenance` block
+#[derive(Default)]
+pub(crate) struct Provenance {
+    pub(crate) trace: Option<TraceStats>,
+    pub(crate) script: Option<Script>,
+    /// Commands traced into the same task
+    pub(crate) commands: Vec<Vec<String>>,
+    /// Restrict the task to its traced commands even when there is a single one
+    pub(crate) strict_commands: bool,
+    /// Placeholders of the traced commands
+    pub(crate) params: Vec<Param>,
+    /// Content hashes of the traced executables, scripts and interpreters
+    pub(crate) binaries: BTreeMap<String, String>,
+    /// Setuid programs executed by the traced commands, and whether their privileges were kept
+    pub(crate) setuid_helpers: Option<Helpers>,
+    /// Full hash of the inputs of a username shortened by a site identifier
+    pub(crate) identity: Option<String>,
+    /// The system the commands were traced on
+    pub(crate) fingerprint: Option<Fingerprint>,
+    /// How the traced paths were normalized, for new traces to compare equal
+    pub(crate) normalize: Option<NormalizeOptions>,
+}
+
+impl Provenance {
+    /// Merge into the task's provenance block, keeping what the policy already recorded
+    pub(crate) fn apply(&self, task: &mut STask) -> anyhow::Result<()> {
+        if !self.params.is_empty() {
+            task._extra_fields
+                .insert("parameters".to_string(), params::to_value(&self.params)?);
+        }
+        let Some(block) = task
+            ._extra_fields
+            .entry("provenance")
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+        else {
+            return Ok(());
+        };
+        if let Some(trace) = &self.trace {
+            block.insert("trace".to_string(), serde_json::to_value(trace)?);
+        }
+        if let Some(script) = &self.script {
+            block.insert("script".to_string(), serde_json::to_value(script)?);
+        }
+        if !self.binaries.is_empty() {
+            block.insert("binaries".to_string(), json!(self.binaries));
+        }
+        if let Some(helpers) = &self.setuid_helpers {
+            block.insert("setuid_helpers".to_string(), serde_json::to_value(helpers)?);
+        }
+        if let Some(identity) = &self.identity {
+            block.insert("identity".to_string(), json!(identity));
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            block.insert(
+                "fingerprint".to_string(),
+                serde_json::to_value(fingerprint)?,
+            );
+        }
+        if let Some(normalize) = &self.normalize {
+            block.insert("normalize".to_string(), serde_json::to_value(normalize)?);
+        }
+        // Kept even for a single command, for `gensr regenerate` to trace it again
+        if !self.commands.is_empty() {
+            block.insert("commands".to_string(), json!(self.commands));
+        }
+        if block.is_empty() {
+            task._extra_fields.remove("provenance");
+        }
+        Ok(())
+    }
+
+    /// Restrict the task to the traced script or commands when they are known
+    pub(crate) fn restrict_commands(&self, task: &mut STask) {
+        if let Some(script) = &self.script {
+            script.restrict(task);
+        } else if self.commands.len() > 1
+            || ((self.strict_commands || !self.params.is_empty()) && !self.commands.is_empty())
+        {
+            task.commands.default_behavior = Some(SetBehavior::None);
+            // sr matches commands literally, each allowed value is a command of its own
+            task.commands.add = self
+                .commands
+                .iter()
+                .flat_map(|command| params::expand(&command.join(" "), &self.params))
+                .map(SCommand::Simple)
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Weak;
+
+    use rootasrole_core::database::structs::IdTask;
+
+    use super::*;
+
+    #[test]
+    fn restrict_commands_to_each_allowed_value() {
+        let provenance = Provenance {
+            commands: vec![vec![
+                "smartctl".to_string(),
+                "-a".to_string(),
+                "{{DEVICE}}".to_string(),
+            ]],
+            params: vec![Param {
+                name: "DEVICE".to_string(),
+                traced: "/dev/sda".to_string(),
+                allowed: vec!["/dev/sda".to_string(), "/dev/sdb".to_string()],
+            }],
+            ..Default::default()
+        };
+        let mut task = STask::new(IdTask::Name("smart".to_string()), Weak::new());
+        provenance.restrict_commands(&mut task);
+        assert_eq!(task.commands.default_behavior, Some(SetBehavior::None));
+        assert_eq!(
+            task.commands.add,
+            [
+                SCommand::Simple("smartctl -a /dev/sda".to_string()),
+                SCommand::Simple("smartctl -a /dev/sdb".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    io::{self, IsTerminal, Write},
+    rc::Rc,
+    sync::Mutex,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use rootasrole_core::database::structs::{SCommand, SConfig, STask};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    capable::Capable,
+    doc::Doc,
+    policy::{NormalizeOptions, Policy},
+};
+
+/// Which tasks of the configuration are traced again
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub(crate) enum Scope {
+    /// Tasks generated by gensr, with a provenance block
+    GeneratedOnly,
+    /// Also the hand-written tasks, traced from their allowed commands
+    All,
+}
+
+/// A task and the commands to trace it from
+struct Target {
+    role: String,
+    task: String,
+    doc: Option<Doc>,
+    commands: Vec<Vec<String>>,
+    normalize: NormalizeOptions,
+    current: Policy,
+}
+
+/// What a new trace of a task found, compared with its grants
+pub(crate) struct Regeneration {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    pub(crate) doc: Option<Doc>,
+    pub(crate) added: Policy,
+    pub(crate) removed: Policy,
+    /// The new trace, None when the task could not be traced
+    pub(crate) traced: Option<Policy>,
+    /// Why the task was not traced
+    pub(crate) skipped: Option<String>,
+}
+
+impl Regeneration {
+    pub(crate) fn drifted(&self) -> bool {
+        !self.added.is_empty() || !self.removed.is_empty()
+    }
+}
+
+impl std::fmt::Display for Regeneration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(reason) = &self.skipped {
+            return write!(f, "{}/{}: skipped, {}", self.role, self.task, reason);
+        }
+        if !self.drifted() {
+            return write!(f, "{}/{}: unchanged", self.role, self.task);
+        }
+        let mut changes = self.added.describe('+');
+        changes.extend(self.removed.describe('-'));
+        write!(f, "{}/{}: {}", self.role, self.task, changes.join(", "))?;
+        if let Some(doc) = &self.doc {
+            write!(f, "\n    justified by: {}", doc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Commands the task was traced from, recorded in its provenance
+fn recorded_commands(task: &STask) -> Option<Vec<Vec<String>>> {
+    task._extra_fields
+        .get("provenance")
+        .and_then(|provenance| provenance.get("commands"))
+        .and_then(|commands| serde_json::from_value(commands.clone()).ok())
+}
+
+/// Commands a task was traced from, or the commands it allows when it was written by hand
+pub(crate) fn task_commands(task: &STask) -> Vec<Vec<String>> {
+    recorded_commands(task).unwrap_or_else(|| {
+        task.commands
+            .add
+            .iter()
+            .filter_map(|command| match command {
+                SCommand::Simple(command) => Some(
+                    command
+                        .split_whitespace()
+                        .map(|arg| arg.to_string())
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+/// Tasks generated by gensr, the commands allowed by hand-written tasks are patterns that
+/// must not be run as root
+fn targets(
+    config: &Rc<RefCell<SConfig>>,
+    only: Option<&BTreeSet<(String, String)>>,
+) -> (Vec<Target>, Vec<Regeneration>) {
+    let mut targets = Vec::new();
+    let mut skipped = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            if only.is_some_and(|only| !only.contains(&(role.name.clone(), task.name.to_string())))
+            {
+                continue;
+            }
+            let Some(provenance) = task._extra_fields.get("provenance") else {
+                continue;
+            };
+            let commands = recorded_commands(&task).unwrap_or_default();
+            // Traces from before the options were recorded used the defaults
+            let normalize = provenance
+                .get("normalize")
+                .and_then(|normalize| serde_json::from_value(normalize.clone()).ok())
+                .unwrap_or_default();
+            let reason = if task._extra_fields.contains_key("parameters") {
+                Some("its commands take parameters")
+            } else if commands.is_empty() {
+                Some("no command to trace")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                skipped.push(Regeneration {
+                    role: role.name.clone(),
+                    task: task.name.to_string(),
+                    doc: Doc::of(&task),
+                    added: Policy::default(),
+                    removed: Policy::default(),
+                    traced: None,
+                    skipped: Some(reason.to_string()),
+                });
+                continue;
+            }
+            targets.push(Target {
+                role: role.name.clone(),
+                task: task.name.to_string(),
+                doc: Doc::of(&task),
+                commands,
+                normalize,
+                current: Policy::from_stask(&task),
+            });
+        }
+    }
+    (targets, skipped)
+}
+
+/// Privileges the commands use, traced one after another
+pub(crate) fn trace(
+    commands: &[Vec<String>],
+    normalize: &NormalizeOptions,
+) -> anyhow::Result<Policy> {
+    let mut policy = Policy::default();
+    for command in commands {
+        let mut capable = Capable::builder()
+            .command(command.clone())
+            .fail_then_add(false)
+            .build()?;
+        let mut traced = capable.run()?;
+        traced.normalize(normalize);
+        policy |= traced;
+    }
+    Ok(policy)
+}
+
+fn regenerate(target: &Target) -> Regeneration {
+    let mut regeneration = Regeneration {
+        role: target.role.clone(),
+        task: target.task.clone(),
+        doc: target.doc.clone(),
+        added: Policy::default(),
+        removed: Policy::default(),
+        traced: None,
+        skipped: None,
+    };
+    match trace(&target.commands, &target.normalize) {
+        Ok(traced) => {
+            regeneration.added = traced.clone() - target.current.clone();
+            regeneration.removed = target.current.clone() - traced.clone();
+            regeneration.traced = Some(traced);
+        }
+        Err(e) => regeneration.skipped = Some(format!("trace failed: {}", e)),
+    }
+    regeneration
+}
+
+/// Trace every generated task again, or only the given ones by role and task name, `jobs`
+/// at a time, comparing the traces with the grants of the tasks
+pub(crate) fn run(
+    config: &Rc<RefCell<SConfig>>,
+    jobs: usize,
+    only: Option<&BTreeSet<(String, String)>>,
+) -> Vec<Regeneration> {
+    let (targets, mut regenerations) = targets(config, only);
+    let queue = Mutex::new(targets.iter());
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, targets.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some(target) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let regeneration = regenerate(target);
+                results.lock().unwrap().push(regeneration);
+            });
+        }
+    });
+    regenerations.extend(results.into_inner().unwrap());
+    regenerations.sort_by(|a, b| (&a.role, &a.task).cmp(&(&b.role, &b.task)));
+    regenerations
+}
+
+/// Decision taken on the drift of a task, recorded in its provenance
+#[derive(Clone, Copy, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Decision {
+    /// Grant the new privileges and revoke the ones no longer used
+    AcceptAll,
+    /// Grant the new privileges, keeping the ones no longer used
+    AcceptAdditions,
+    /// Revoke the privileges no longer used, without granting the new ones
+    AcceptRemovals,
+    /// Leave the task unchanged for an administrator to review
+    Manual,
+}
+
+impl Decision {
+    /// Grants of the task once the decision is applied
+    fn apply(&self, current: &Policy, regeneration: &Regeneration) -> Policy {
+        match self {
+            Decision::AcceptAll => {
+                (current.clone() - regeneration.removed.clone()) | regeneration.added.clone()
+            }
+            Decision::AcceptAdditions => current.clone() | regeneration.added.clone(),
+            Decision::AcceptRemovals => current.clone() - regeneration.removed.clone(),
+            Decision::Manual => current.clone(),
+        }
+    }
+}
+
+/// Ask the operator what to do with the drift of the task, leaving it to a manual review
+/// when nobody can answer
+fn ask(regeneration: &Regeneration) -> anyhow::Result<Decision> {
+    if !io::stdin().is_terminal() {
+        warn!(
+            "{}/{} drifted, left for manual review, choose with --auto",
+            regeneration.role, regeneration.task
+        );
+        return Ok(Decision::Manual);
+    }
+    loop {
+        print!(
+            "{}\nApply [a]ll changes, only the [+] additions, only the [-] removals, or leave it for [m]anual review? ",
+            regeneration
+        );
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        match input.trim() {
+            "a" | "all" => return Ok(Decision::AcceptAll),
+            "+" | "additions" => return Ok(Decision::AcceptAdditions),
+            "-" | "removals" => return Ok(Decision::AcceptRemovals),
+            "m" | "manual" => return Ok(Decision::Manual),
+            _ => continue,
+        }
+    }
+}
+
+/// Resolve the drift of every drifted task, with the given decision or by asking for each
+/// task, and update its grants accordingly. Returns the number of changed tasks
+pub(crate) fn resolve(
+    config: &Rc<RefCell<SConfig>>,
+    regenerations: &[Regeneration],
+    auto: Option<Decision>,
+) -> anyhow::Result<usize> {
+    let mut changed = 0;
+    for regeneration in regenerations.iter().filter(|r| r.drifted()) {
+        let config = config.as_ref().borrow();
+        let Some(role) = config.role(&regeneration.role) else {
+            continue;
+        };
+        let role = role.as_ref().borrow();
+        let Some(task) = role
+            .tasks
+            .iter()
+            .find(|task| task.as_ref().borrow().name.to_string() == regeneration.task)
+        else {
+            continue;
+        };
+        let decision = match auto {
+            Some(decision) => decision,
+            None => ask(regeneration)?,
+        };
+        let mut task = task.as_ref().borrow_mut();
+        if decision != Decision::Manual {
+            let current = Policy::from_stask(&task);
+            decision
+                .apply(&current, regeneration)
+                .replace_grants(&mut task, &role.name);
+            changed += 1;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let record = json!({
+            "decision": decision,
+            "timestamp": timestamp,
+            "added": regeneration.added.describe('+'),
+            "removed": regeneration.removed.describe('-'),
+        });
+        // A provenance block marks the task as generated by gensr, a hand-written task
+        // keeps the decision beside its fields instead
+        match task
+            ._extra_fields
+            .get_mut("provenance")
+            .and_then(|provenance| provenance.as_object_mut())
+        {
+            Some(provenance) => {
+                provenance.insert("regeneration".to_string(), record);
+            }
+            None => {
+                task._extra_fields
+                    .insert("regeneration".to_string(), record);
+            }
+        }
+    }
+    Ok(changed)
+}
@@ -1,4 +1,10 @@
 polkit.addRule(function(action, subject) {
-    // read rootasrole.json
-    polkit.spawn(["{{BINARY_PATH}}", "polkit", action, subject]);
-});
\ No newline at end of file
+    // gensr exits successfully when the policy grants the action to the user
+    try {
+        polkit.spawn(["{{BINARY_PATH}}", "polkit"{{NAMESPACE_ARGS}}{{CACHE_ARGS}}, "--user", subject.user, "--action", action.id]);
+        return polkit.Result.YES;
+    } catch (error) {
+        // Not granted by the policy, left to the other rules
+        return polkit.Result.NOT_HANDLED;
+    }
+});
@@ -0,0 +1,231 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Longest bus name, interface or member the D-Bus specification allows
+const DBUS_NAME_MAX: usize = 255;
+
+/// Whether the dot-separated name follows the D-Bus rules of bus names (`-` allowed) or
+/// interfaces: at least two elements of `[A-Za-z0-9_]`, none starting with a digit
+fn is_dotted_name(name: &str, dash: bool) -> bool {
+    let elements: Vec<&str> = name.split('.').collect();
+    name.len() <= DBUS_NAME_MAX
+        && elements.len() >= 2
+        && elements.iter().all(|element| {
+            !element.is_empty()
+                && !element.starts_with(|c: char| c.is_ascii_digit())
+                && element
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || (dash && c == '-'))
+        })
+}
+
+/// Unique connection names, such as `:1.42`, whose elements may start with a digit
+fn is_unique_name(name: &str) -> bool {
+    name.strip_prefix(':').is_some_and(|name| {
+        name.len() < DBUS_NAME_MAX
+            && name.split('.').count() >= 2
+            && name.split('.').all(|element| {
+                !element.is_empty()
+                    && element
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            })
+    })
+}
+
+fn is_member(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= DBUS_NAME_MAX
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Escape the characters that end an XML attribute value or start markup
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DbusDirection {
+    #[default]
+    Send,
+    Receive,
+}
+
+/// A D-Bus message the role user is allowed to send or receive
+#[derive(Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct DbusRule {
+    pub(crate) destination: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) member: Option<String>,
+    pub(crate) direction: DbusDirection,
+}
+
+impl DbusRule {
+    pub(crate) fn destination(destination: &str) -> Self {
+        DbusRule {
+            destination: destination.to_string(),
+            interface: None,
+            member: None,
+            direction: DbusDirection::Send,
+        }
+    }
+
+    /// Check the names against the D-Bus specification, a single invalid attribute makes
+    /// dbus-daemon reject the whole policy file. `*` matches any name in a policy
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        let destination = &self.destination;
+        if destination != "*" && !is_dotted_name(destination, true) && !is_unique_name(destination)
+        {
+            bail!("Invalid D-Bus bus name {}", destination);
+        }
+        if let Some(interface) = self.interface.as_deref().filter(|i| *i != "*") {
+            if !is_dotted_name(interface, false) {
+                bail!("Invalid D-Bus interface {} of {}", interface, destination);
+            }
+        }
+        if let Some(member) = self.member.as_deref().filter(|m| *m != "*") {
+            if !is_member(member) {
+                bail!("Invalid D-Bus member {} of {}", member, destination);
+            }
+        }
+        Ok(())
+    }
+
+    /// Attributes of the `<allow>` element of a dbus-daemon policy
+    pub(crate) fn to_allow_attributes(&self) -> String {
+        let (peer, prefix) = match self.direction {
+            DbusDirection::Send => ("send_destination", "send"),
+            DbusDirection::Receive => ("receive_sender", "receive"),
+        };
+        let mut attributes = format!("{}=\"{}\"", peer, xml_escape(&self.destination));
+        if let Some(interface) = &self.interface {
+            attributes.push_str(&format!(
+                " {}_interface=\"{}\"",
+                prefix,
+                xml_escape(interface)
+            ));
+        }
+        if let Some(member) = &self.member {
+            attributes.push_str(&format!(" {}_member=\"{}\"", prefix, xml_escape(member)));
+        }
+        attributes
+    }
+}
+
+impl<'de> Deserialize<'de> for DbusRule {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Fields {
+            destination: String,
+            #[serde(default)]
+            interface: Option<String>,
+            #[serde(default)]
+            member: Option<String>,
+            #[serde(default)]
+            direction: DbusDirection,
+        }
+        /// capable reports bare destination names
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Destination(String),
+            Rule(Fields),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Destination(destination) => DbusRule::destination(&destination),
+            Repr::Rule(fields) => DbusRule {
+                destination: fields.destination,
+                interface: fields.interface,
+                member: fields.member,
+                direction: fields.direction,
+            },
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum NetworkDirection {
+    Inbound,
+    Outbound,
+}
+
+/// A network flow the role user needs, as observed during the trace
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct NetworkRule {
+    pub(crate) direction: NetworkDirection,
+    /// tcp or udp
+    pub(crate) protocol: String,
+    /// Remote address for outbound flows, local address for inbound ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) port: Option<u16>,
+}
+
+/// Read the dbus rules of a task, also accepting the former list of destination names
+pub(crate) fn dbus_rules(value: &Value) -> Vec<DbusRule> {
+    match value {
+        Value::Array(values) => values.iter().flat_map(dbus_rules).collect(),
+        value => serde_json::from_value(value.clone()).into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(destination: &str, interface: Option<&str>, member: Option<&str>) -> DbusRule {
+        DbusRule {
+            interface: interface.map(str::to_string),
+            member: member.map(str::to_string),
+            ..DbusRule::destination(destination)
+        }
+    }
+
+    #[test]
+    fn validate_accepts_specification_names() {
+        for rule in [
+            rule("org.freedesktop.NetworkManager", None, None),
+            rule(
+                "org.example.my-service",
+                Some("org.example.Iface_2"),
+                Some("Get"),
+            ),
+            rule(":1.42", None, None),
+            rule("*", Some("*"), Some("*")),
+        ] {
+            assert!(rule.validate().is_ok(), "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_invalid_names() {
+        for rule in [
+            rule("freedesktop", None, None),
+            rule("org.1example", None, None),
+            rule("org..example", None, None),
+            rule("org.example\"/><allow own=\"*", None, None),
+            rule(":1", None, None),
+            rule("org.example", Some("org.my-iface"), None),
+            rule("org.example", Some("org.example.Iface"), Some("2Get")),
+            rule("org.example", None, Some("Get.All")),
+            rule(&format!("org.{}", "a".repeat(DBUS_NAME_MAX)), None, None),
+        ] {
+            assert!(rule.validate().is_err(), "{:?}", rule);
+        }
+    }
+}
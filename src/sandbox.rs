@@ -0,0 +1,689 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::FileExt,
+            net::{UnixListener, UnixStream},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use capctl::prctl;
+use clap::ValueEnum;
+use log::{debug, warn};
+use nix::libc;
+use tempfile::TempDir;
+
+use crate::policy::Access;
+
+/// What the traced command gets when it writes to a file
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum WriteStrategy {
+    /// The write reports success without being performed, opened files write to /dev/null
+    Success,
+    /// The write fails with EROFS
+    Erofs,
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls of the x32 ABI, which share the architecture of x86_64
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Same number on every architecture, newer than some libc bindings
+const SYS_FCHMODAT2: libc::c_long = 452;
+
+/// Open flags asking for a file to write
+const WRITE_FLAGS: u32 = (libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC) as u32;
+
+/// A file an intercepted syscall writes: the path argument, relative to the directory fd
+/// argument, or the fd argument alone when there is no path
+struct Target {
+    dirfd: Option<usize>,
+    path: Option<usize>,
+    access: Access,
+}
+
+fn at(dirfd: usize, path: usize, access: Access) -> Target {
+    Target {
+        dirfd: Some(dirfd),
+        path: Some(path),
+        access,
+    }
+}
+
+fn path(path: usize, access: Access) -> Target {
+    Target {
+        dirfd: None,
+        path: Some(path),
+        access,
+    }
+}
+
+fn fd(fd: usize, access: Access) -> Target {
+    Target {
+        dirfd: Some(fd),
+        path: None,
+        access,
+    }
+}
+
+struct Syscall {
+    nr: libc::c_long,
+    /// Argument of the open flags, the syscall is only intercepted when they write
+    flags: Option<usize>,
+    /// Whether the syscall returns a new fd of the file
+    opens: bool,
+    targets: Vec<Target>,
+}
+
+fn syscall(nr: libc::c_long, targets: Vec<Target>) -> Syscall {
+    Syscall {
+        nr,
+        flags: None,
+        opens: false,
+        targets,
+    }
+}
+
+fn open(nr: libc::c_long, flags: Option<usize>, target: Target) -> Syscall {
+    Syscall {
+        nr,
+        flags,
+        opens: true,
+        targets: vec![target],
+    }
+}
+
+/// Syscalls creating, writing, deleting, renaming a file or changing its metadata
+fn intercepted() -> Vec<Syscall> {
+    let mut syscalls = vec![
+        open(libc::SYS_openat, Some(2), at(0, 1, Access::W)),
+        syscall(libc::SYS_truncate, vec![path(0, Access::W)]),
+        syscall(libc::SYS_ftruncate, vec![fd(0, Access::W)]),
+        syscall(libc::SYS_unlinkat, vec![at(0, 1, Access::D)]),
+        syscall(
+            libc::SYS_renameat2,
+            vec![at(0, 1, Access::N), at(2, 3, Access::N)],
+        ),
+        syscall(libc::SYS_mkdirat, vec![at(0, 1, Access::C)]),
+        syscall(libc::SYS_mknodat, vec![at(0, 1, Access::C)]),
+        syscall(libc::SYS_linkat, vec![at(2, 3, Access::C)]),
+        syscall(libc::SYS_symlinkat, vec![at(1, 2, Access::C)]),
+        syscall(libc::SYS_fchmod, vec![fd(0, Access::A)]),
+        syscall(libc::SYS_fchmodat, vec![at(0, 1, Access::A)]),
+        syscall(SYS_FCHMODAT2, vec![at(0, 1, Access::A)]),
+        syscall(libc::SYS_fchown, vec![fd(0, Access::A)]),
+        syscall(libc::SYS_fchownat, vec![at(0, 1, Access::A)]),
+        syscall(libc::SYS_setxattr, vec![path(0, Access::A)]),
+        syscall(libc::SYS_lsetxattr, vec![path(0, Access::A)]),
+        syscall(libc::SYS_fsetxattr, vec![fd(0, Access::A)]),
+        syscall(libc::SYS_removexattr, vec![path(0, Access::A)]),
+        syscall(libc::SYS_lremovexattr, vec![path(0, Access::A)]),
+        syscall(libc::SYS_fremovexattr, vec![fd(0, Access::A)]),
+        syscall(libc::SYS_utimensat, vec![at(0, 1, Access::W)]),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend([
+        open(libc::SYS_open, Some(1), path(0, Access::W)),
+        open(libc::SYS_creat, None, path(0, Access::W)),
+        syscall(libc::SYS_unlink, vec![path(0, Access::D)]),
+        syscall(libc::SYS_rmdir, vec![path(0, Access::D)]),
+        syscall(
+            libc::SYS_rename,
+            vec![path(0, Access::N), path(1, Access::N)],
+        ),
+        syscall(
+            libc::SYS_renameat,
+            vec![at(0, 1, Access::N), at(2, 3, Access::N)],
+        ),
+        syscall(libc::SYS_mkdir, vec![path(0, Access::C)]),
+        syscall(libc::SYS_mknod, vec![path(0, Access::C)]),
+        syscall(libc::SYS_link, vec![path(1, Access::C)]),
+        syscall(libc::SYS_symlink, vec![path(1, Access::C)]),
+        syscall(libc::SYS_chmod, vec![path(0, Access::A)]),
+        syscall(libc::SYS_chown, vec![path(0, Access::A)]),
+        syscall(libc::SYS_lchown, vec![path(0, Access::A)]),
+        syscall(libc::SYS_utime, vec![path(0, Access::W)]),
+        syscall(libc::SYS_utimes, vec![path(0, Access::W)]),
+        syscall(libc::SYS_futimesat, vec![at(0, 1, Access::W)]),
+    ]);
+    syscalls
+}
+
+/// Syscalls whose writes cannot be inspected, failed with ENOSYS for the command to fall
+/// back to the intercepted ones
+const UNSUPPORTED: [libc::c_long; 2] = [libc::SYS_openat2, libc::SYS_io_uring_setup];
+
+fn statement(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// Offset of the low 32 bits of a syscall argument in `seccomp_data`
+fn arg_low(index: usize) -> u32 {
+    let offset = mem::offset_of!(libc::seccomp_data, args) + index * 8;
+    if cfg!(target_endian = "little") {
+        offset as u32
+    } else {
+        offset as u32 + 4
+    }
+}
+
+/// BPF program notifying the supervisor of the intercepted syscalls
+fn filter(syscalls: &[Syscall]) -> Vec<libc::sock_filter> {
+    let load = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    let equals = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    let ret = libc::BPF_RET | libc::BPF_K;
+    let enosys = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+    let mut program = vec![
+        statement(load, mem::offset_of!(libc::seccomp_data, arch) as u32),
+        jump(equals, AUDIT_ARCH, 1, 0),
+        statement(ret, enosys),
+        statement(load, mem::offset_of!(libc::seccomp_data, nr) as u32),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        jump(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            X32_SYSCALL_BIT,
+            0,
+            1,
+        ),
+        statement(ret, enosys),
+    ]);
+    for nr in UNSUPPORTED {
+        program.extend([jump(equals, nr as u32, 0, 1), statement(ret, enosys)]);
+    }
+    for syscall in syscalls {
+        match syscall.flags {
+            // The accumulator is reloaded with the flags, every branch returns
+            Some(flags) => program.extend([
+                jump(equals, syscall.nr as u32, 0, 5),
+                statement(load, arg_low(flags)),
+                statement(libc::BPF_ALU | libc::BPF_AND | libc::BPF_K, WRITE_FLAGS),
+                jump(equals, 0, 0, 1),
+                statement(ret, libc::SECCOMP_RET_ALLOW),
+                statement(ret, libc::SECCOMP_RET_USER_NOTIF),
+            ]),
+            None => program.extend([
+                jump(equals, syscall.nr as u32, 0, 1),
+                statement(ret, libc::SECCOMP_RET_USER_NOTIF),
+            ]),
+        }
+    }
+    program.push(statement(ret, libc::SECCOMP_RET_ALLOW));
+    program
+}
+
+/// Install the filter on the current process, returning the fd its notifications are
+/// received from. Without CAP_SYS_ADMIN, the kernel requires no_new_privs
+fn install_filter() -> anyhow::Result<OwnedFd> {
+    let mut program = filter(&intercepted());
+    let fprog = libc::sock_fprog {
+        len: program.len() as u16,
+        filter: program.as_mut_ptr(),
+    };
+    let install = || {
+        // SAFETY: fprog points to the program, alive for the duration of the call
+        unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_NEW_LISTENER,
+                &fprog as *const libc::sock_fprog,
+            )
+        }
+    };
+    let mut fd = install();
+    if fd < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EACCES) {
+        warn!("The traced command lacks CAP_SYS_ADMIN, it runs with no_new_privs: setuid programs and file capabilities are ignored");
+        prctl::set_no_new_privs()?;
+        fd = install();
+    }
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Cannot install the seccomp filter");
+    }
+    // SAFETY: the kernel returned a new fd, owned by nobody else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Run the command with its writes intercepted by the gensr trace listening on `socket`.
+/// Only returns on failure
+pub(crate) fn exec(socket: &Path, command: &[String]) -> anyhow::Result<()> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow!("No command to execute"))?;
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("Cannot reach the trace on {}", socket.display()))?;
+    let listener = install_filter()?;
+    writeln!(stream, "{} {}", std::process::id(), listener.as_raw_fd())?;
+    // The supervisor has its own copy of the listener once it answers
+    let mut ack = [0u8; 1];
+    stream
+        .read_exact(&mut ack)
+        .context("The trace did not take the seccomp listener")?;
+    drop(listener);
+    drop(stream);
+    Err(Command::new(program).args(args).exec())
+        .with_context(|| format!("Cannot execute {}", program))
+}
+
+/// Copy the fd of another process
+fn take_fd(pid: i32, fd: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: plain syscalls, the returned fds are owned by nobody else
+    unsafe {
+        let pidfd = libc::syscall(libc::SYS_pidfd_open, pid, 0);
+        if pidfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pidfd = OwnedFd::from_raw_fd(pidfd as RawFd);
+        let fd = libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(OwnedFd::from_raw_fd(fd as RawFd))
+    }
+}
+
+/// The NUL-terminated string the process passed at `address`
+fn read_string(mem: &File, address: u64) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut chunk = [0u8; 256];
+    while bytes.len() < libc::PATH_MAX as usize {
+        let read = mem.read_at(&mut chunk, address + bytes.len() as u64).ok()?;
+        if read == 0 {
+            return None;
+        }
+        if let Some(end) = chunk[..read].iter().position(|b| *b == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return Some(String::from_utf8_lossy(&bytes).to_string());
+        }
+        bytes.extend_from_slice(&chunk[..read]);
+    }
+    None
+}
+
+/// Absolute path of a file named by the process, without resolving its symlinks
+fn resolve(pid: u32, dirfd: Option<i32>, path: Option<&str>) -> Option<PathBuf> {
+    let process = PathBuf::from(format!("/proc/{}", pid));
+    let fd_target = |fd: i32| fs::read_link(process.join("fd").join(fd.to_string())).ok();
+    match (dirfd, path) {
+        (_, Some(path)) if path.starts_with('/') => Some(PathBuf::from(path)),
+        (None | Some(libc::AT_FDCWD), Some(path)) => {
+            Some(fs::read_link(process.join("cwd")).ok()?.join(path))
+        }
+        (Some(fd), Some("")) | (Some(fd), None) => fd_target(fd),
+        (Some(fd), Some(path)) => Some(fd_target(fd)?.join(path)),
+        (None, None) => None,
+    }
+}
+
+/// Writes leaving nothing on the filesystem: to devices, pipes and sockets
+fn passthrough(pid: u32, path: &Path) -> bool {
+    // Pipes and sockets are named `pipe:[inode]`
+    if !path.is_absolute() {
+        return true;
+    }
+    let process = PathBuf::from(format!("/proc/{}", pid));
+    let path = match path.strip_prefix("/proc/self") {
+        Ok(rest) => process.join(rest),
+        Err(_) => path.to_path_buf(),
+    };
+    if path.starts_with(process.join("fd")) {
+        return fs::read_link(&path)
+            .is_ok_and(|target| !target.is_absolute() || is_device(&target));
+    }
+    is_device(&path)
+}
+
+fn is_device(path: &Path) -> bool {
+    path.starts_with("/dev") && !path.starts_with("/dev/shm") && !path.starts_with("/dev/mqueue")
+}
+
+fn response(id: u64, val: i64, error: i32, flags: u32) -> libc::seccomp_notif_resp {
+    libc::seccomp_notif_resp {
+        id,
+        val,
+        error,
+        flags,
+    }
+}
+
+fn id_valid(listener: RawFd, id: u64) -> bool {
+    // SAFETY: the ioctl reads the id
+    unsafe { libc::ioctl(listener, libc::SECCOMP_IOCTL_NOTIF_ID_VALID, &id) == 0 }
+}
+
+struct Supervision {
+    listener: OwnedFd,
+    syscalls: Vec<Syscall>,
+    strategy: WriteStrategy,
+    null: File,
+    recorded: BTreeMap<String, Access>,
+}
+
+impl Supervision {
+    /// Record the files the notified syscall writes and answer it, None when the answer
+    /// was already sent with the fd of an open. Every other path answers, a syscall left
+    /// unanswered would block the traced command forever
+    fn handle(&mut self, notif: &libc::seccomp_notif) -> Option<libc::seccomp_notif_resp> {
+        let erofs = response(notif.id, 0, -libc::EROFS, 0);
+        let Some(syscall) = self
+            .syscalls
+            .iter()
+            .find(|syscall| syscall.nr == notif.data.nr as libc::c_long)
+        else {
+            return Some(response(
+                notif.id,
+                0,
+                0,
+                libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32,
+            ));
+        };
+        let args = notif.data.args;
+        let Ok(mem) = File::open(format!("/proc/{}/mem", notif.pid)) else {
+            return Some(erofs);
+        };
+        let mut paths = Vec::new();
+        for target in &syscall.targets {
+            let name = match target.path.map(|arg| args[arg]) {
+                // A null path names the directory fd itself, as with futimens
+                Some(0) | None => None,
+                Some(address) => match read_string(&mem, address) {
+                    Some(name) => Some(name),
+                    None => return Some(response(notif.id, 0, -libc::EFAULT, 0)),
+                },
+            };
+            let dirfd = target.dirfd.map(|arg| args[arg] as i32);
+            let Some(path) = resolve(notif.pid, dirfd, name.as_deref()) else {
+                return Some(erofs);
+            };
+            paths.push((path, target.access));
+        }
+        // The memory read belongs to the notified syscall only if it is still pending,
+        // answering one that is gone is harmless
+        if !id_valid(self.listener.as_raw_fd(), notif.id) {
+            return Some(erofs);
+        }
+        if paths.iter().all(|(path, _)| passthrough(notif.pid, path)) {
+            return Some(response(
+                notif.id,
+                0,
+                0,
+                libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32,
+            ));
+        }
+        let flags = syscall.flags.map_or(
+            (libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC) as u32,
+            |arg| args[arg] as u32,
+        );
+        for (path, mut access) in paths {
+            if syscall.opens
+                && flags & libc::O_CREAT as u32 != 0
+                && fs::symlink_metadata(&path).is_err()
+            {
+                access |= Access::C;
+            }
+            debug!("Intercepted {} of {}", access, path.display());
+            *self
+                .recorded
+                .entry(path.to_string_lossy().to_string())
+                .or_insert(Access::empty()) |= access;
+        }
+        match self.strategy {
+            WriteStrategy::Erofs => Some(erofs),
+            WriteStrategy::Success if syscall.opens => {
+                let addfd = libc::seccomp_notif_addfd {
+                    id: notif.id,
+                    flags: libc::SECCOMP_ADDFD_FLAG_SEND as u32,
+                    srcfd: self.null.as_raw_fd() as u32,
+                    newfd: 0,
+                    newfd_flags: flags & libc::O_CLOEXEC as u32,
+                };
+                // SAFETY: the ioctl reads addfd, and answers the syscall with the new fd
+                let sent = unsafe {
+                    libc::ioctl(
+                        self.listener.as_raw_fd(),
+                        libc::SECCOMP_IOCTL_NOTIF_ADDFD,
+                        &addfd,
+                    )
+                };
+                // Kernels before 5.14 cannot answer with an fd
+                (sent < 0).then_some(erofs)
+            }
+            WriteStrategy::Success => Some(response(notif.id, 0, 0, 0)),
+        }
+    }
+
+    /// Answer the notifications until every filtered process exited, or the trace is over
+    fn run(mut self, stop: &AtomicBool) -> BTreeMap<String, Access> {
+        let fd = self.listener.as_raw_fd();
+        while !stop.load(Ordering::Relaxed) {
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: pollfd is a single valid entry
+            match unsafe { libc::poll(&mut pollfd, 1, 100) } {
+                0 => continue,
+                ready if ready < 0 => {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    break;
+                }
+                _ if pollfd.revents & libc::POLLIN == 0 => break,
+                _ => {}
+            }
+            // SAFETY: the kernel requires a zeroed notification, which it fills
+            let mut notif: libc::seccomp_notif = unsafe { mem::zeroed() };
+            if unsafe { libc::ioctl(fd, libc::SECCOMP_IOCTL_NOTIF_RECV, &mut notif) } < 0 {
+                // The process was killed before its notification was received
+                continue;
+            }
+            if let Some(response) = self.handle(&notif) {
+                // SAFETY: the ioctl reads the response
+                unsafe { libc::ioctl(fd, libc::SECCOMP_IOCTL_NOTIF_SEND, &response) };
+            }
+        }
+        self.recorded
+    }
+}
+
+/// Accept the traced command and take its seccomp listener
+fn attach(socket: &UnixListener, stop: &AtomicBool) -> anyhow::Result<Option<OwnedFd>> {
+    let mut stream = loop {
+        match socket.accept() {
+            Ok((stream, _)) => break stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if stop.load(Ordering::Relaxed) {
+                    return Ok(None);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let (pid, fd) = line
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("Invalid listener announce {:?}", line))?;
+    let listener = take_fd(pid.parse()?, fd.parse()?)?;
+    stream.write_all(b"1")?;
+    Ok(Some(listener))
+}
+
+/// Supervisor of the writes of the traced command: they are recorded by a seccomp
+/// user notification and answered without being performed
+pub(crate) struct Supervisor {
+    dir: TempDir,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<BTreeMap<String, Access>>>,
+}
+
+impl Supervisor {
+    pub(crate) fn start(strategy: WriteStrategy) -> anyhow::Result<Supervisor> {
+        let dir = TempDir::new()?;
+        let socket = UnixListener::bind(dir.path().join("seccomp.sock"))?;
+        socket.set_nonblocking(true)?;
+        let null = File::options().read(true).write(true).open("/dev/null")?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = stop.clone();
+            move || match attach(&socket, &stop) {
+                Ok(Some(listener)) => Supervision {
+                    listener,
+                    syscalls: intercepted(),
+                    strategy,
+                    null,
+                    recorded: BTreeMap::new(),
+                }
+                .run(&stop),
+                Ok(None) => BTreeMap::new(),
+                Err(e) => {
+                    warn!("Cannot supervise the writes of the traced command: {}", e);
+                    BTreeMap::new()
+                }
+            }
+        });
+        Ok(Supervisor {
+            dir,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Wrapper installing the filter right before the traced command, the tracer is not
+    /// filtered
+    pub(crate) fn wrapper(&self) -> anyhow::Result<Vec<String>> {
+        Ok(vec![
+            env::current_exe()?.to_string_lossy().to_string(),
+            "sandbox-exec".to_string(),
+            "--socket".to_string(),
+            self.dir
+                .path()
+                .join("seccomp.sock")
+                .to_string_lossy()
+                .to_string(),
+            "--".to_string(),
+        ])
+    }
+
+    /// The files the command tried to write, once it exited
+    pub(crate) fn finish(mut self) -> BTreeMap<String, Access> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(recorded)) => recorded,
+            _ => {
+                warn!("The supervisor of the writes of the traced command failed");
+                BTreeMap::new()
+            }
+        }
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_jumps_stay_within_the_program() {
+        let program = filter(&intercepted());
+        let jumps = program
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| instruction.code as u32 & 0x07 == libc::BPF_JMP);
+        for (pc, instruction) in jumps {
+            let target = pc + 1 + instruction.jt.max(instruction.jf) as usize;
+            assert!(target < program.len(), "jump out of the program at {}", pc);
+        }
+        let last = program.last().unwrap();
+        assert_eq!(last.code as u32, libc::BPF_RET | libc::BPF_K);
+        assert_eq!(last.k, libc::SECCOMP_RET_ALLOW);
+    }
+
+    fn supervision(target: Target) -> Supervision {
+        Supervision {
+            listener: File::open("/dev/null").unwrap().into(),
+            syscalls: vec![syscall(libc::SYS_unlinkat, vec![target])],
+            strategy: WriteStrategy::Erofs,
+            null: File::open("/dev/null").unwrap(),
+            recorded: BTreeMap::new(),
+        }
+    }
+
+    fn notification(args: [u64; 6]) -> libc::seccomp_notif {
+        // SAFETY: seccomp_notif is plain data
+        let mut notif: libc::seccomp_notif = unsafe { mem::zeroed() };
+        notif.id = 1;
+        notif.pid = std::process::id();
+        notif.data.nr = libc::SYS_unlinkat as i32;
+        notif.data.args = args;
+        notif
+    }
+
+    #[test]
+    fn handle_answers_unreadable_paths() {
+        let mut supervision = supervision(at(0, 1, Access::D));
+        let response = supervision
+            .handle(&notification([libc::AT_FDCWD as u64, 1, 0, 0, 0, 0]))
+            .expect("the syscall must be answered");
+        assert_eq!(response.error, -libc::EFAULT);
+        assert!(supervision.recorded.is_empty());
+    }
+
+    #[test]
+    fn handle_answers_unresolvable_paths() {
+        let name = c"file";
+        let mut supervision = supervision(at(0, 1, Access::D));
+        // No fd of this process is that high, the directory cannot be resolved
+        let response = supervision
+            .handle(&notification([999_999, name.as_ptr() as u64, 0, 0, 0, 0]))
+            .expect("the syscall must be answered");
+        assert_eq!(response.error, -libc::EROFS);
+        assert!(supervision.recorded.is_empty());
+    }
+}
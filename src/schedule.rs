@@ -0,0 +1,82 @@
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Execution window of a task, recorded in its options
+#[derive(Serialize, Clone, Default)]
+pub(crate) struct Schedule {
+    /// Start and end hours, the window wrapping around midnight when start > end
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) hours: Option<(u8, u8)>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) days: Vec<&'static str>,
+}
+
+fn parse_hour(hour: &str) -> anyhow::Result<u8> {
+    let hour: u8 = hour
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid hour '{}'", hour))?;
+    if hour > 24 {
+        bail!("Invalid hour '{}', expected 0 to 24", hour);
+    }
+    Ok(hour)
+}
+
+/// Parse `START-END`, e.g. `22-6` for the night
+fn parse_hours(hours: &str) -> anyhow::Result<(u8, u8)> {
+    let (start, end) = hours
+        .split_once('-')
+        .ok_or_else(|| anyhow!("Invalid hours '{}', expected START-END", hours))?;
+    let (start, end) = (parse_hour(start)?, parse_hour(end)?);
+    if start == end {
+        bail!("Empty hour window '{}'", hours);
+    }
+    Ok((start, end))
+}
+
+fn day_index(day: &str) -> anyhow::Result<usize> {
+    let day = day.trim().to_lowercase();
+    DAYS.iter()
+        .position(|d| day.starts_with(d))
+        .ok_or_else(|| anyhow!("Invalid day '{}'", day))
+}
+
+/// Parse a list of days and day ranges, e.g. `mon-fri` or `sat,sun`
+fn parse_days(days: &str) -> anyhow::Result<Vec<&'static str>> {
+    let mut allowed = [false; 7];
+    for part in days.split(',').filter(|p| !p.trim().is_empty()) {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (day_index(start)?, day_index(end)?);
+                let mut day = start;
+                loop {
+                    allowed[day] = true;
+                    if day == end {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => allowed[day_index(part)?] = true,
+        }
+    }
+    Ok(DAYS
+        .iter()
+        .zip(allowed)
+        .filter_map(|(day, allowed)| allowed.then_some(*day))
+        .collect())
+}
+
+impl Schedule {
+    pub(crate) fn parse(hours: Option<&str>, days: Option<&str>) -> anyhow::Result<Option<Self>> {
+        if hours.is_none() && days.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Schedule {
+            hours: hours.map(parse_hours).transpose()?,
+            days: days.map(parse_days).transpose()?.unwrap_or_default(),
+        }))
+    }
+}
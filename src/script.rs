@@ -0,0 +1,201 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail};
+use rootasrole_core::database::structs::{SCommand, STask, SetBehavior};
+use serde::Serialize;
+use sha2::Digest;
+
+use crate::{
+    policy::{Access, Policy},
+    tracecache::sha256_file,
+};
+
+/// Interpreter run by the kernel when a script has no shebang line
+const DEFAULT_INTERPRETER: &str = "/bin/sh";
+
+/// Programs whose first argument is the script they run, with the extensions of the
+/// files their scripts import or source
+const INTERPRETERS: [(&str, &[&str]); 8] = [
+    ("sh", &["sh"]),
+    ("bash", &["sh", "bash"]),
+    ("dash", &["sh"]),
+    ("zsh", &["sh", "zsh"]),
+    ("python", &["py", "pyc", "pth"]),
+    ("perl", &["pl", "pm"]),
+    ("ruby", &["rb"]),
+    ("node", &["js", "mjs", "cjs"]),
+];
+
+/// Options after which the interpreter runs inline code or a module instead of a script
+const INLINE_OPTIONS: [&str; 3] = ["-c", "-m", "-e"];
+
+/// Extensions of the sources of the interpreter, `python3.12` being `python`
+fn source_extensions(interpreter: &Path) -> Option<&'static [&'static str]> {
+    let name = interpreter.file_name()?.to_string_lossy();
+    let name = name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    INTERPRETERS
+        .iter()
+        .find(|(interpreter, _)| *interpreter == name)
+        .map(|(_, extensions)| *extensions)
+}
+
+pub(crate) fn is_interpreter(path: &Path) -> bool {
+    source_extensions(path).is_some()
+}
+
+/// A script traced in place of a command line
+#[derive(Serialize, Clone)]
+pub(crate) struct Script {
+    pub(crate) path: PathBuf,
+    pub(crate) sha256: String,
+    /// Interpreter and its arguments, as resolved from the shebang line
+    pub(crate) interpreter: Vec<String>,
+    pub(crate) args: Vec<String>,
+    /// The interpreter was named on the command line, rather than found in the shebang line
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) invoked: bool,
+    /// Imported and sourced files granted to the task, with their SHA-256
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) dependencies: BTreeMap<String, String>,
+}
+
+impl Script {
+    pub(crate) fn resolve(path: &Path, args: Vec<String>) -> anyhow::Result<Self> {
+        let path = fs::canonicalize(path)
+            .map_err(|e| anyhow!("Cannot resolve script {}: {}", path.display(), e))?;
+        let mut first_line = String::new();
+        BufReader::new(File::open(&path)?).read_line(&mut first_line)?;
+        let interpreter = match first_line.strip_prefix("#!") {
+            Some(shebang) => resolve_shebang(shebang)?,
+            None => vec![DEFAULT_INTERPRETER.to_string()],
+        };
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(fs::read(&path)?);
+        Ok(Script {
+            path,
+            sha256: hex::encode(hasher.finalize()),
+            interpreter,
+            args,
+            invoked: false,
+            dependencies: BTreeMap::new(),
+        })
+    }
+
+    /// The script run by a traced `python script.py` or `bash script.sh` command line
+    pub(crate) fn detect(command: &[String]) -> Option<Self> {
+        let program = command.first()?;
+        let program = which::which(program).ok()?.canonicalize().ok()?;
+        if !is_interpreter(&program) {
+            return None;
+        }
+        let position = command
+            .iter()
+            .skip(1)
+            .position(|arg| !arg.starts_with('-'))?
+            + 1;
+        let options = &command[1..position];
+        if options
+            .iter()
+            .any(|option| INLINE_OPTIONS.contains(&option.as_str()))
+        {
+            return None;
+        }
+        let path = fs::canonicalize(&command[position]).ok()?;
+        if !path.is_file() {
+            return None;
+        }
+        let mut interpreter = vec![program.to_string_lossy().to_string()];
+        interpreter.extend_from_slice(options);
+        Some(Script {
+            sha256: sha256_file(&path)?,
+            path,
+            interpreter,
+            args: command[position + 1..].to_vec(),
+            invoked: true,
+            dependencies: BTreeMap::new(),
+        })
+    }
+
+    /// Let the task read the script, and with `dependencies` the files it imports or
+    /// sources found in the trace: sources of its interpreter or files next to it
+    pub(crate) fn grant(&mut self, policy: &mut Policy, dependencies: bool) {
+        let path = self.path.to_string_lossy().to_string();
+        *policy.files.entry(path.clone()).or_insert(Access::empty()) |= Access::R;
+        if !dependencies {
+            return;
+        }
+        let extensions = self
+            .interpreter
+            .first()
+            .and_then(|interpreter| source_extensions(Path::new(interpreter)))
+            .unwrap_or_default();
+        let dir = self.path.parent().unwrap_or(Path::new("/"));
+        for (file, access) in policy.files.iter_mut() {
+            let file_path = Path::new(file);
+            let is_source = file_path
+                .extension()
+                .is_some_and(|ext| extensions.iter().any(|e| ext == *e));
+            if *file == path || !(is_source || file_path.starts_with(dir)) {
+                continue;
+            }
+            if let Some(hash) = sha256_file(file_path) {
+                *access |= Access::R;
+                self.dependencies.insert(file.clone(), hash);
+            }
+        }
+    }
+
+    /// Command line running the script through its interpreter
+    pub(crate) fn command(&self) -> Vec<String> {
+        let mut command = self.interpreter.clone();
+        command.push(self.path.to_string_lossy().to_string());
+        command.extend_from_slice(&self.args);
+        command
+    }
+
+    /// Restrict the task to the script instead of any command, through the interpreter
+    /// when it was invoked explicitly
+    pub(crate) fn restrict(&self, task: &mut STask) {
+        let command = if self.invoked {
+            self.command()
+        } else {
+            let mut command = vec![self.path.to_string_lossy().to_string()];
+            command.extend_from_slice(&self.args);
+            command
+        };
+        task.commands.default_behavior = Some(SetBehavior::None);
+        task.commands.add = vec![SCommand::Simple(command.join(" "))];
+    }
+}
+
+/// Resolve `#!/usr/bin/env python3 -u` style lines to the absolute interpreter path
+fn resolve_shebang(shebang: &str) -> anyhow::Result<Vec<String>> {
+    let mut words = shebang.split_whitespace().map(|w| w.to_string());
+    let Some(program) = words.next() else {
+        bail!("Empty shebang line");
+    };
+    let mut interpreter = Vec::new();
+    if Path::new(&program)
+        .file_name()
+        .is_some_and(|name| name == "env")
+    {
+        // Options of env itself, such as -S, come before the interpreter name
+        let mut words = words.skip_while(|w| w.starts_with('-'));
+        let Some(name) = words.next() else {
+            bail!("No interpreter given to env in the shebang line");
+        };
+        let resolved =
+            which::which(&name).map_err(|_| anyhow!("Interpreter {} not found in PATH", name))?;
+        interpreter.push(resolved.to_string_lossy().to_string());
+        interpreter.extend(words);
+    } else {
+        interpreter.push(program);
+        interpreter.extend(words);
+    }
+    Ok(interpreter)
+}
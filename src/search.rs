@@ -0,0 +1,95 @@
+use std::{cell::RefCell, path::Path, rc::Rc};
+
+use rootasrole_core::database::structs::SConfig;
+use serde::Serialize;
+
+use crate::{
+    params::glob_match,
+    policy::{Access, Policy},
+};
+
+/// Privileges looked for, a task matches when it grants every given one
+pub(crate) struct Query {
+    pub(crate) file: Option<String>,
+    /// Access bits of the file the grant must include
+    pub(crate) access: Access,
+    pub(crate) capability: Option<String>,
+    pub(crate) dbus: Option<String>,
+}
+
+/// A task granting the privileges looked for
+#[derive(Serialize)]
+pub(crate) struct Hit {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    /// The grants that matched
+    pub(crate) grants: Vec<String>,
+}
+
+impl std::fmt::Display for Hit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}: {}", self.role, self.task, self.grants.join(", "))
+    }
+}
+
+/// Whether a granted path covers the path looked for: the same path, a glob matching it,
+/// or a path below it when it is a directory
+fn covers(granted: &str, path: &str) -> bool {
+    granted == path
+        || glob_match(granted.as_bytes(), path.as_bytes())
+        || Path::new(granted).starts_with(path)
+}
+
+impl Query {
+    fn matches(&self, policy: &Policy) -> Option<Vec<String>> {
+        let mut grants = Vec::new();
+        if let Some(path) = &self.file {
+            let files: Vec<String> = policy
+                .files
+                .iter()
+                .filter(|(granted, access)| covers(granted, path) && access.contains(self.access))
+                .map(|(granted, access)| format!("{} ({})", granted, access))
+                .collect();
+            if files.is_empty() {
+                return None;
+            }
+            grants.extend(files);
+        }
+        if let Some(capability) = &self.capability {
+            if !policy.capabilities.contains(capability) {
+                return None;
+            }
+            grants.push(capability.clone());
+        }
+        if let Some(destination) = &self.dbus {
+            if !policy
+                .dbus
+                .iter()
+                .any(|rule| rule.destination == *destination)
+            {
+                return None;
+            }
+            grants.push(format!("dbus {}", destination));
+        }
+        Some(grants)
+    }
+}
+
+/// Every task of the configuration granting the privileges of the query
+pub(crate) fn run(config: &Rc<RefCell<SConfig>>, query: &Query) -> Vec<Hit> {
+    let mut hits = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            if let Some(grants) = query.matches(&Policy::from_stask(&task)) {
+                hits.push(Hit {
+                    role: role.name.clone(),
+                    task: task.name.to_string(),
+                    grants,
+                });
+            }
+        }
+    }
+    hits
+}
@@ -0,0 +1,159 @@
+use std::{cell::RefCell, os::unix::process::CommandExt, process::Command, rc::Rc};
+
+use anyhow::{anyhow, Context};
+use nix::unistd::User;
+use rootasrole_core::database::structs::SConfig;
+
+use crate::{
+    deploy,
+    rules::{self, DbusDirection},
+};
+
+pub(crate) enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+/// A policy exercised as the role user
+pub(crate) struct Check {
+    pub(crate) task: String,
+    /// `dbus` or `polkit`
+    pub(crate) kind: &'static str,
+    pub(crate) target: String,
+    pub(crate) outcome: Outcome,
+}
+
+impl Check {
+    pub(crate) fn failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(_))
+    }
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = format!("{} {} {}", self.task, self.kind, self.target);
+        match &self.outcome {
+            Outcome::Passed => write!(f, "PASS {}", prefix),
+            Outcome::Failed(reason) => write!(f, "FAIL {}: {}", prefix, reason),
+            Outcome::Skipped(reason) => write!(f, "SKIP {}: {}", prefix, reason),
+        }
+    }
+}
+
+/// Run the program with the identity of the user, returning its error output on failure
+fn run_as(user: &User, program: &str, args: &[&str]) -> anyhow::Result<Result<(), String>> {
+    let output = Command::new(program)
+        .args(args)
+        .uid(user.uid.as_raw())
+        .gid(user.gid.as_raw())
+        .env_clear()
+        .env("PATH", "/usr/sbin:/usr/bin:/sbin:/bin")
+        .output()
+        .with_context(|| format!("Cannot run {}", program))?;
+    if output.status.success() {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_string()))
+    }
+}
+
+/// Ping the destination on the system bus, which its dbus policy must let through
+fn check_dbus(user: &User, destination: &str) -> anyhow::Result<Outcome> {
+    let result = run_as(
+        user,
+        "busctl",
+        &[
+            "--system",
+            "call",
+            destination,
+            "/",
+            "org.freedesktop.DBus.Peer",
+            "Ping",
+        ],
+    )?;
+    Ok(match result {
+        Ok(()) => Outcome::Passed,
+        Err(error) => Outcome::Failed(error),
+    })
+}
+
+/// Ask polkit whether the role user's own process is authorized for the action
+fn check_polkit(user: &User, action: &str) -> anyhow::Result<Outcome> {
+    let result = run_as(
+        user,
+        "sh",
+        &[
+            "-c",
+            "exec pkcheck --action-id \"$1\" --process $$",
+            "gensr-selftest",
+            action,
+        ],
+    )?;
+    Ok(match result {
+        Ok(()) => Outcome::Passed,
+        Err(error) => Outcome::Failed(if error.is_empty() {
+            "not authorized".to_string()
+        } else {
+            error
+        }),
+    })
+}
+
+/// Exercise the deployed dbus and polkit policies of every task of the role as its user
+pub(crate) fn run(config: &Rc<RefCell<SConfig>>, role: &str) -> anyhow::Result<Vec<Check>> {
+    let config = config.as_ref().borrow();
+    let role = config
+        .role(role)
+        .ok_or_else(|| anyhow!("Role {} not found in the configuration", role))?
+        .as_ref()
+        .borrow();
+    let mut checks = Vec::new();
+    for task in &role.tasks {
+        let task = task.as_ref().borrow();
+        let Some(dbus) = task.cred._extra_fields.get("dbus") else {
+            continue;
+        };
+        let rules = rules::dbus_rules(dbus);
+        if rules.is_empty() {
+            continue;
+        }
+        // The user deploy granted the dbus and polkit policies to
+        let username = deploy::task_username(&role.name, &task);
+        let Some(user) = User::from_name(&username)? else {
+            checks.push(Check {
+                task: task.name.to_string(),
+                kind: "dbus",
+                target: username.clone(),
+                outcome: Outcome::Failed(format!("role user {} does not exist", username)),
+            });
+            continue;
+        };
+        for rule in &rules {
+            let outcome = if rule.direction == DbusDirection::Receive {
+                Outcome::Skipped("receive rules cannot be exercised".to_string())
+            } else if rule.interface.is_some() || rule.member.is_some() {
+                // Calling the granted method could change the system
+                Outcome::Skipped("restricted to a method, not called".to_string())
+            } else {
+                check_dbus(&user, &rule.destination)?
+            };
+            checks.push(Check {
+                task: task.name.to_string(),
+                kind: "dbus",
+                target: rule.destination.clone(),
+                outcome,
+            });
+            // The polkit policy grants the destinations as actions
+            checks.push(Check {
+                task: task.name.to_string(),
+                kind: "polkit",
+                target: rule.destination.clone(),
+                outcome: check_polkit(&user, &rule.destination)?,
+            });
+        }
+    }
+    Ok(checks)
+}
@@ -0,0 +1,91 @@
+use std::{fs, io, process::Command};
+
+use log::{debug, warn};
+use nix::unistd::Uid;
+
+use crate::auditlog;
+
+const ENFORCE_FILE: &str = "/sys/fs/selinux/enforce";
+
+/// Whether SELinux is loaded and in enforcing mode
+pub(crate) fn is_enforcing() -> bool {
+    fs::read_to_string(ENFORCE_FILE).is_ok_and(|mode| mode.trim() == "1")
+}
+
+fn semanage(args: &[&str]) -> io::Result<bool> {
+    debug!("Running semanage {:?}", args);
+    let output = Command::new("semanage").args(args).output()?;
+    if !output.status.success() {
+        debug!(
+            "semanage failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(output.status.success())
+}
+
+/// Map the role user to the given SELinux user, updating an already existing mapping
+pub(crate) fn map_login(username: &str, seuser: &str) -> io::Result<()> {
+    if semanage(&["login", "-a", "-s", seuser, username])?
+        || semanage(&["login", "-m", "-s", seuser, username])?
+    {
+        auditlog::record("selinux_login_map", username, seuser);
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Failed to map user {} to SELinux user {}", username, seuser),
+    ))
+}
+
+pub(crate) fn unmap_login(username: &str) -> io::Result<()> {
+    if semanage(&["login", "-d", username])? {
+        auditlog::record("selinux_login_unmap", username, "removed login mapping");
+    } else {
+        debug!("No SELinux login mapping for user {}", username);
+    }
+    Ok(())
+}
+
+/// Security context of a path, as reported by stat
+pub(crate) fn file_context(path: &str) -> Option<String> {
+    let output = Command::new("stat")
+        .arg("-c")
+        .arg("%C")
+        .arg(path)
+        .output()
+        .ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+/// Report the AVC denials recently recorded for a user, returning how many were found
+pub(crate) fn report_denials(username: &str, uid: &Uid) -> io::Result<usize> {
+    let output = match Command::new("ausearch")
+        .arg("-m")
+        .arg("AVC,USER_AVC")
+        .arg("-ts")
+        .arg("recent")
+        .arg("-ui")
+        .arg(uid.as_raw().to_string())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Unable to search AVC denials: {}", e);
+            return Ok(0);
+        }
+    };
+    let records = String::from_utf8_lossy(&output.stdout);
+    let denials: Vec<&str> = records
+        .lines()
+        .filter(|line| line.contains("avc:") && line.contains("denied"))
+        .collect();
+    for denial in &denials {
+        warn!("SELinux denial for {}: {}", username, denial);
+    }
+    Ok(denials.len())
+}
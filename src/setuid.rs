@@ -0,0 +1,173 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    io::{self, IsTerminal, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use serde::Serialize;
+
+use crate::policy::{Access, Policy};
+
+/// Capabilities common setuid helpers hold through their setuid bit, attributed to the
+/// traced command since the tracer cannot tell them apart
+const HELPER_CAPABILITIES: [(&str, &[&str]); 11] = [
+    ("ping", &["CAP_NET_RAW"]),
+    ("mount", &["CAP_SYS_ADMIN"]),
+    ("umount", &["CAP_SYS_ADMIN"]),
+    ("fusermount", &["CAP_SYS_ADMIN"]),
+    ("fusermount3", &["CAP_SYS_ADMIN"]),
+    ("sudo", &["CAP_SETUID", "CAP_SETGID", "CAP_AUDIT_WRITE"]),
+    ("su", &["CAP_SETUID", "CAP_SETGID", "CAP_AUDIT_WRITE"]),
+    ("pkexec", &["CAP_SETUID", "CAP_SETGID"]),
+    ("newuidmap", &["CAP_SETUID"]),
+    ("newgidmap", &["CAP_SETGID"]),
+    ("passwd", &["CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_FOWNER"]),
+];
+
+fn helpers_file_path() -> PathBuf {
+    env::var("GENSR_SETUID_HELPERS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/etc/gensr/setuid-helpers.yaml"))
+}
+
+/// Capabilities of each helper by program name: the built-in table, where the helpers of
+/// the file replace or add entries, e.g. `ping: [CAP_NET_RAW]`
+fn helper_capabilities() -> BTreeMap<String, BTreeSet<String>> {
+    let mut table: BTreeMap<String, BTreeSet<String>> = HELPER_CAPABILITIES
+        .iter()
+        .map(|(helper, capabilities)| {
+            (
+                helper.to_string(),
+                capabilities.iter().map(|c| c.to_string()).collect(),
+            )
+        })
+        .collect();
+    let path = helpers_file_path();
+    if let Ok(content) = fs::read_to_string(&path) {
+        match serde_yaml::from_str::<BTreeMap<String, BTreeSet<String>>>(&content) {
+            Ok(helpers) => table.extend(helpers),
+            Err(e) => warn!(
+                "Ignoring invalid setuid helpers file {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    table
+}
+
+/// What to do with the setuid helpers executed by the traced command
+#[derive(Clone, Copy, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HelperMode {
+    /// Keep the privileges used by the helpers in the task
+    Include,
+    /// Leave the helpers' privileges to their setuid bit, only noting the dependency
+    Exclude,
+}
+
+/// Setuid helpers of a task, recorded in its provenance
+#[derive(Serialize)]
+pub(crate) struct Helpers {
+    pub(crate) mode: HelperMode,
+    pub(crate) programs: BTreeSet<String>,
+}
+
+/// Whether the file runs with the privileges of its owner or group
+pub(crate) fn is_setuid(path: &Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o6000 != 0)
+}
+
+/// Setuid and setgid programs among the executables seen running and the executed files
+pub(crate) fn detect<'a>(
+    policy: &'a Policy,
+    executables: impl IntoIterator<Item = &'a String>,
+) -> BTreeSet<String> {
+    policy
+        .files
+        .iter()
+        .filter(|(_, access)| access.contains(Access::X))
+        .map(|(path, _)| path)
+        .chain(executables)
+        .filter(|path| is_setuid(Path::new(path)))
+        .cloned()
+        .collect()
+}
+
+/// Remove the helpers from the policy along with the capabilities they hold themselves,
+/// returning the removed capabilities
+fn exclude(policy: &mut Policy) -> BTreeSet<String> {
+    let mut removed = BTreeSet::new();
+    let table = helper_capabilities();
+    for helper in &policy.setuid_helpers {
+        policy.files.remove(helper);
+        let name = Path::new(helper)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(capabilities) = table.get(&name) {
+            policy.capabilities.retain(|capability| {
+                let held = capabilities
+                    .iter()
+                    .any(|held| held.eq_ignore_ascii_case(capability));
+                if held {
+                    removed.insert(capability.clone());
+                }
+                !held
+            });
+        }
+    }
+    removed
+}
+
+/// Ask the operator whether the privileges of the helpers belong to the task, keeping
+/// them when nobody can answer
+pub(crate) fn ask(helpers: &BTreeSet<String>) -> anyhow::Result<HelperMode> {
+    let helpers = helpers.iter().cloned().collect::<Vec<_>>().join(", ");
+    if !io::stdin().is_terminal() {
+        warn!(
+            "The traced command executed setuid helpers ({}), their privileges are included in the task, choose with --setuid-helpers",
+            helpers
+        );
+        return Ok(HelperMode::Include);
+    }
+    loop {
+        print!(
+            "The traced command executed setuid helpers ({}).\n\
+            Include their privileges in the task, or exclude them and rely on their setuid bit? [include/exclude] ",
+            helpers
+        );
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        match input.trim() {
+            "i" | "include" => return Ok(HelperMode::Include),
+            "e" | "exclude" => return Ok(HelperMode::Exclude),
+            _ => continue,
+        }
+    }
+}
+
+/// The helpers of the policy as recorded in the task provenance, once the mode is applied
+pub(crate) fn helpers(policy: &mut Policy, mode: HelperMode) -> Option<Helpers> {
+    if policy.setuid_helpers.is_empty() {
+        return None;
+    }
+    if mode == HelperMode::Exclude {
+        let removed = exclude(policy);
+        if !removed.is_empty() {
+            warn!(
+                "Leaving {} to the setuid helpers, the task relies on their setuid bit",
+                removed.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+    Some(Helpers {
+        mode,
+        programs: policy.setuid_helpers.clone(),
+    })
+}
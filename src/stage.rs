@@ -0,0 +1,75 @@
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::fs::{chown, DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use nix::unistd::{Uid, User};
+use rootasrole_core::database::structs::STask;
+use serde::{Deserialize, Serialize};
+
+use crate::auditlog;
+
+/// A generated task waiting for review before being merged into the configuration
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Staged {
+    pub(crate) role: String,
+    /// Operator who generated the task, informative only: the owner of the staged file
+    /// is its author
+    pub(crate) author: String,
+    pub(crate) task: STask,
+}
+
+/// Anyone able to write into the staging directory could stage a task as someone else
+fn check_not_world_writable(path: &Path) -> anyhow::Result<()> {
+    let mode = fs::metadata(path)
+        .with_context(|| format!("Cannot read {}", path.display()))?
+        .mode();
+    if mode & 0o002 != 0 {
+        bail!(
+            "{} is writable by every user, staged tasks cannot be trusted",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Write a candidate task into the staging directory, owned by the operator staging it
+pub(crate) fn stage(dir: &Path, role: &str, task: &STask) -> anyhow::Result<PathBuf> {
+    DirBuilder::new().recursive(true).mode(0o755).create(dir)?;
+    check_not_world_writable(dir)?;
+    let path = dir.join(format!("{}-{}.json", role, task.name));
+    let staged = serde_json::json!({
+        "role": role,
+        "author": auditlog::operator(),
+        "task": task,
+    });
+    fs::write(&path, serde_json::to_string_pretty(&staged)?)?;
+    chown(&path, Some(auditlog::operator_uid().as_raw()), None)
+        .with_context(|| format!("Cannot give {} to its author", path.display()))?;
+    auditlog::record(
+        "stage",
+        &path.display().to_string(),
+        &format!("task {} staged for role {}", task.name, role),
+    );
+    Ok(path)
+}
+
+/// Read a staged task, refusing its approval by its own author
+pub(crate) fn load_for_approval(path: &Path) -> anyhow::Result<Staged> {
+    if let Some(dir) = path.parent().filter(|dir| dir != &Path::new("")) {
+        check_not_world_writable(dir)?;
+    }
+    check_not_world_writable(path)?;
+    let owner = Uid::from_raw(fs::metadata(path)?.uid());
+    let staged: Staged = serde_json::from_str(&fs::read_to_string(path)?)?;
+    if owner == auditlog::operator_uid() {
+        bail!(
+            "Task {} was staged by {}, it must be approved by someone else",
+            staged.task.name,
+            User::from_uid(owner)?.map_or_else(|| owner.to_string(), |user| user.name)
+        );
+    }
+    Ok(staged)
+}
@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs, io,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+/// Directory of the state gensr keeps between runs
+pub(crate) fn state_dir() -> PathBuf {
+    env::var("GENSR_STATE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/var/lib/gensr"))
+}
+
+/// What the last deploy set up on the system
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct DeployState {
+    /// Fingerprint of the deployed grants of each task user
+    #[serde(default)]
+    pub(crate) tasks: BTreeMap<String, String>,
+    /// Role groups created by gensr, deleted when undeploying
+    #[serde(default)]
+    pub(crate) groups: BTreeSet<String>,
+    /// ACL bits added by gensr, by path and entry (`user:<uid>` or `group:<gid>`)
+    #[serde(default)]
+    pub(crate) acls: BTreeMap<String, BTreeMap<String, u32>>,
+    /// Task users relying on each of these ACL entries, by path and entry
+    #[serde(default)]
+    pub(crate) acl_holders: BTreeMap<String, BTreeMap<String, BTreeSet<String>>>,
+    /// Ids reserved for the task users, kept once given to deploy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id_range: Option<IdRange>,
+    /// Uid, and gid of its group, allocated to each task user from the range
+    #[serde(default)]
+    pub(crate) ids: BTreeMap<String, u32>,
+    /// Task users deployed with --shadow, monitor-only until deployed without it
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) shadow: BTreeSet<String>,
+    /// Task users deployed with --usage-hook, the hook is removed along with the last one
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) usage_hook: BTreeSet<String>,
+}
+
+/// Inclusive range of uids and gids, written `<first>-<last>`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub(crate) struct IdRange {
+    pub(crate) first: u32,
+    pub(crate) last: u32,
+}
+
+impl FromStr for IdRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid id range {}, expected <first>-<last>", s))?;
+        let range = IdRange {
+            first: first.parse()?,
+            last: last.parse()?,
+        };
+        if range.first == 0 || range.first > range.last {
+            bail!("Invalid id range {}", s);
+        }
+        Ok(range)
+    }
+}
+
+/// What is left to do once a task user no longer relies on an ACL entry
+pub(crate) enum AclRelease {
+    /// Other task users still rely on the entry, it stays as is
+    Shared,
+    /// Last one gone, strip the bits gensr added, or the whole entry when not recorded
+    Last(Option<u32>),
+}
+
+impl DeployState {
+    fn path() -> PathBuf {
+        state_dir().join("deploy-state.json")
+    }
+
+    /// The recorded state, empty when nothing was deployed yet. A state that cannot be read
+    /// is an error: deploying from an empty one would forget what gensr set up
+    pub(crate) fn load() -> io::Result<Self> {
+        let content = match fs::read_to_string(Self::path()) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cannot parse {}: {}", Self::path().display(), e),
+            )
+        })
+    }
+
+    /// Remember the ACL bits gensr added to an entry for a task user, even none when they
+    /// all predate it
+    pub(crate) fn record_acl(&mut self, path: &str, entry: &str, holder: &str, bits: u32) {
+        *self
+            .acls
+            .entry(path.to_string())
+            .or_default()
+            .entry(entry.to_string())
+            .or_default() |= bits;
+        self.acl_holders
+            .entry(path.to_string())
+            .or_default()
+            .entry(entry.to_string())
+            .or_default()
+            .insert(holder.to_string());
+    }
+
+    /// The task user no longer relies on the entry, forgetting the entry with its last user.
+    /// An entry held by others is never released for a user that is not one of its holders
+    pub(crate) fn release_acl(&mut self, path: &str, entry: &str, holder: &str) -> AclRelease {
+        if let Some(entries) = self.acl_holders.get_mut(path) {
+            if let Some(holders) = entries.get_mut(entry) {
+                if !holders.remove(holder) || !holders.is_empty() {
+                    return AclRelease::Shared;
+                }
+                entries.remove(entry);
+            }
+            if entries.is_empty() {
+                self.acl_holders.remove(path);
+            }
+        }
+        let Some(entries) = self.acls.get_mut(path) else {
+            return AclRelease::Last(None);
+        };
+        let bits = entries.remove(entry);
+        if entries.is_empty() {
+            self.acls.remove(path);
+        }
+        AclRelease::Last(bits)
+    }
+
+    /// ACL entries the task user relies on, by path and entry
+    pub(crate) fn held_acls(&self, holder: &str) -> Vec<(String, String)> {
+        self.acl_holders
+            .iter()
+            .flat_map(|(path, entries)| {
+                entries
+                    .iter()
+                    .filter(|(_, holders)| holders.contains(holder))
+                    .map(move |(entry, _)| (path.clone(), entry.clone()))
+            })
+            .collect()
+    }
+
+    /// The task user now only needs `bits` of the entry. Returns the bits gensr added
+    /// beyond them, forgotten here and to be stripped, when no other task user relies on
+    /// the entry
+    pub(crate) fn narrow_acl(&mut self, path: &str, entry: &str, holder: &str, bits: u32) -> u32 {
+        let sole = self
+            .acl_holders
+            .get(path)
+            .and_then(|entries| entries.get(entry))
+            .is_some_and(|holders| holders.len() == 1 && holders.contains(holder));
+        let Some(added) = self
+            .acls
+            .get_mut(path)
+            .and_then(|entries| entries.get_mut(entry))
+            .filter(|_| sole)
+        else {
+            return 0;
+        };
+        let excess = *added & !bits;
+        *added &= bits;
+        excess
+    }
+
+    /// Id of a new task user, derived from its name so that every host of a fleet picks
+    /// the same one, and probing the range from there when taken. None without a range
+    pub(crate) fn allocate_id(
+        &mut self,
+        username: &str,
+        taken: impl Fn(u32) -> bool,
+    ) -> anyhow::Result<Option<u32>> {
+        let Some(range) = self.id_range else {
+            return Ok(None);
+        };
+        if let Some(id) = self.ids.get(username) {
+            return Ok(Some(*id));
+        }
+        let size = (range.last - range.first) as u64 + 1;
+        let hash = sha2::Sha256::digest(username.as_bytes());
+        let start = u64::from_be_bytes(hash[..8].try_into()?) % size;
+        let allocated: BTreeSet<u32> = self.ids.values().copied().collect();
+        let id = (0..size)
+            .map(|offset| range.first + ((start + offset) % size) as u32)
+            .find(|id| !allocated.contains(id) && !taken(*id))
+            .ok_or_else(|| anyhow!("No id left in the range {}-{}", range.first, range.last))?;
+        self.ids.insert(username.to_string(), id);
+        Ok(Some(id))
+    }
+
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let dir = state_dir();
+        fs::create_dir_all(&dir)?;
+        let file = tempfile::NamedTempFile::new_in(&dir)?;
+        serde_json::to_writer_pretty(file.as_file(), self)?;
+        file.as_file().sync_all()?;
+        file.persist(Self::path()).map_err(|e| e.error)?;
+        fs::File::open(&dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_acls_of_each_holder() {
+        let mut state = DeployState::default();
+        state.record_acl("/srv/a", "user:1001", "alice", 0o4);
+        state.record_acl("/srv/a", "group:1001", "alice", 0o4);
+        state.record_acl("/srv/b", "user:1002", "bob", 0o6);
+        assert_eq!(
+            state.held_acls("alice"),
+            [
+                ("/srv/a".to_string(), "group:1001".to_string()),
+                ("/srv/a".to_string(), "user:1001".to_string()),
+            ]
+        );
+        assert!(state.held_acls("carol").is_empty());
+    }
+
+    #[test]
+    fn narrow_acl_only_for_the_sole_holder() {
+        let mut state = DeployState::default();
+        state.record_acl("/srv/a", "group:100", "alice", 0o6);
+        assert_eq!(state.narrow_acl("/srv/a", "group:100", "alice", 0o4), 0o2);
+        assert_eq!(state.acls["/srv/a"]["group:100"], 0o4);
+        state.record_acl("/srv/a", "group:100", "bob", 0o4);
+        assert_eq!(state.narrow_acl("/srv/a", "group:100", "alice", 0), 0);
+        assert_eq!(state.acls["/srv/a"]["group:100"], 0o4);
+        assert_eq!(state.narrow_acl("/srv/b", "group:100", "alice", 0), 0);
+    }
+
+    #[test]
+    fn load_fails_on_a_corrupt_state() {
+        let dir = tempfile::tempdir().unwrap();
+        env::set_var("GENSR_STATE_DIR", dir.path());
+        assert!(DeployState::load().unwrap().tasks.is_empty());
+        let mut state = DeployState::default();
+        state
+            .tasks
+            .insert("rar-backup".to_string(), "abc".to_string());
+        state.save().unwrap();
+        assert_eq!(DeployState::load().unwrap().tasks, state.tasks);
+        fs::write(DeployState::path(), "{\"tasks\": ").unwrap();
+        assert_eq!(
+            DeployState::load().err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn id_range_parse() {
+        let range: IdRange = "60000-60099".parse().unwrap();
+        assert_eq!((range.first, range.last), (60000, 60099));
+        for range in ["60000", "0-10", "10-5", "a-b"] {
+            assert!(range.parse::<IdRange>().is_err());
+        }
+    }
+
+    #[test]
+    fn allocate_id_stable_and_probing() {
+        let mut state = DeployState {
+            id_range: Some(IdRange {
+                first: 60000,
+                last: 60003,
+            }),
+            ..Default::default()
+        };
+        let id = state.allocate_id("alice", |_| false).unwrap().unwrap();
+        assert!((60000..=60003).contains(&id));
+        assert_eq!(state.allocate_id("alice", |_| true).unwrap(), Some(id));
+        let mut fresh = DeployState {
+            id_range: state.id_range,
+            ..Default::default()
+        };
+        let probed = fresh
+            .allocate_id("alice", |taken| taken == id)
+            .unwrap()
+            .unwrap();
+        assert_ne!(probed, id);
+        assert!(fresh.allocate_id("bob", |_| true).is_err());
+        assert_eq!(
+            DeployState::default()
+                .allocate_id("alice", |_| false)
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use rootasrole_core::database::structs::SCredentials;
+
+use crate::auditlog;
+
+const SUBID_MIN: u64 = 100000;
+const SUBID_COUNT: u64 = 65536;
+
+/// Paths revealing that a task relies on user namespaces
+const USERNS_PATHS: [&str; 5] = [
+    "/etc/subuid",
+    "/etc/subgid",
+    "/proc/self/uid_map",
+    "/proc/self/gid_map",
+    "/proc/self/setgroups",
+];
+
+/// Executables of the container runtimes and of the id mapping helpers they run
+const CONTAINER_EXECUTABLES: [&str; 5] = ["podman", "buildah", "skopeo", "newuidmap", "newgidmap"];
+
+/// Whether the granted path is a container runtime, a user namespace file, or under the
+/// storage of the runtimes, e.g. ~/.local/share/containers/storage
+fn is_container_path(path: &str) -> bool {
+    let path = Path::new(path);
+    let components: Vec<_> = path.components().collect();
+    USERNS_PATHS.iter().any(|hint| path == Path::new(hint))
+        || path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| CONTAINER_EXECUTABLES.contains(&name))
+        || components
+            .windows(2)
+            .any(|pair| pair[0].as_os_str() == "containers" && pair[1].as_os_str() == "storage")
+}
+
+/// Whether the task needs subordinate ids to run its containers
+pub(crate) fn needs_subids(cred: &SCredentials) -> bool {
+    cred._extra_fields
+        .get("files")
+        .and_then(|value| value.as_object())
+        .is_some_and(|files| files.keys().any(|path| is_container_path(path)))
+}
+
+/// Manages a subordinate id file such as /etc/subuid
+pub(crate) struct SubIdFile {
+    path: PathBuf,
+}
+
+impl SubIdFile {
+    pub(crate) fn subuid() -> Self {
+        SubIdFile {
+            path: env::var("SUBUID_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from("/etc/subuid")),
+        }
+    }
+
+    pub(crate) fn subgid() -> Self {
+        SubIdFile {
+            path: env::var("SUBGID_FILE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from("/etc/subgid")),
+        }
+    }
+
+    fn entries(&self) -> io::Result<Vec<(String, u64, u64)>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(fs::read_to_string(&self.path)?
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(':');
+                let name = fields.next()?.to_string();
+                let start = fields.next()?.parse().ok()?;
+                let count = fields.next()?.parse().ok()?;
+                Some((name, start, count))
+            })
+            .collect())
+    }
+
+    /// Allocate a range after every existing one, returning its start
+    pub(crate) fn allocate(&self, username: &str) -> io::Result<u64> {
+        let entries = self.entries()?;
+        if let Some((_, start, _)) = entries.iter().find(|(name, _, _)| name == username) {
+            return Ok(*start);
+        }
+        let start = entries
+            .iter()
+            .map(|(_, start, count)| start + count)
+            .max()
+            .unwrap_or(SUBID_MIN)
+            .max(SUBID_MIN);
+        debug!(
+            "Allocating {}:{}:{} in {:?}",
+            username, start, SUBID_COUNT, self.path
+        );
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}:{}:{}", username, start, SUBID_COUNT)?;
+        auditlog::record(
+            "subid_allocate",
+            &self.path.to_string_lossy(),
+            &format!("{}:{}:{}", username, start, SUBID_COUNT),
+        );
+        Ok(start)
+    }
+
+    pub(crate) fn release(&self, username: &str) -> io::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(&self.path)?;
+        let prefix = format!("{}:", username);
+        if !contents.lines().any(|line| line.starts_with(&prefix)) {
+            return Ok(());
+        }
+        let kept: String = contents
+            .lines()
+            .filter(|line| !line.starts_with(&prefix))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        fs::write(&self.path, kept)?;
+        auditlog::record(
+            "subid_release",
+            &self.path.to_string_lossy(),
+            &format!("released range of {}", username),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_container_path_hints() {
+        for path in [
+            "/usr/bin/podman",
+            "/usr/bin/newuidmap",
+            "/etc/subuid",
+            "/proc/self/uid_map",
+            "/home/svc/.local/share/containers/storage/overlay",
+            "/var/lib/containers/storage",
+        ] {
+            assert!(is_container_path(path), "{}", path);
+        }
+        for path in [
+            "/usr/bin/podman-compose.conf/x",
+            "/etc/subuid.bak",
+            "/srv/containers",
+            "/srv/storage/containers",
+            "/usr/bin/ls",
+        ] {
+            assert!(!is_container_path(path), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use nix::unistd::getppid;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{audit::parse_duration, auditlog, state::state_dir};
+
+const MARKER: &str = "# gensr: record role executions";
+
+/// Audit log action of an execution of a role
+const ACTION: &str = "role_exec";
+
+fn service_path() -> PathBuf {
+    env::var("PAM_CONF_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/etc/pam.d"))
+        .join("sr")
+}
+
+fn hook_rule() -> anyhow::Result<String> {
+    Ok(format!(
+        "session optional pam_exec.so quiet {} usage-hook",
+        env::current_exe()?.display()
+    ))
+}
+
+/// Run `gensr usage-hook` when RootAsRole's `sr` opens and closes a session
+pub(crate) fn install_hook() -> anyhow::Result<()> {
+    let path = service_path();
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Cannot read the sr PAM service {}", path.display()))?;
+    if contents.contains(MARKER) {
+        return Ok(());
+    }
+    fs::write(&path, format!("{}{}\n{}\n", contents, MARKER, hook_rule()?))?;
+    auditlog::record(
+        "pam_service_modify",
+        &path.to_string_lossy(),
+        "added usage hook",
+    );
+    Ok(())
+}
+
+pub(crate) fn remove_hook() -> anyhow::Result<()> {
+    let path = service_path();
+    let Ok(contents) = fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    if !contents.contains(MARKER) {
+        return Ok(());
+    }
+    let mut lines = contents.lines();
+    let mut cleaned = String::new();
+    while let Some(line) = lines.next() {
+        if line == MARKER {
+            // The rule follows its marker
+            lines.next();
+            continue;
+        }
+        cleaned.push_str(line);
+        cleaned.push('\n');
+    }
+    fs::write(&path, cleaned)?;
+    auditlog::record(
+        "pam_service_modify",
+        &path.to_string_lossy(),
+        "removed usage hook",
+    );
+    Ok(())
+}
+
+/// An execution of a role, as recorded in the audit log
+#[derive(Serialize, Deserialize, Default)]
+pub(crate) struct Execution {
+    /// Role asked with `sr -r`, unknown when sr picked it
+    pub(crate) role: Option<String>,
+    pub(crate) task: Option<String>,
+    pub(crate) command: String,
+    pub(crate) duration_secs: f64,
+    /// Only known when gensr runs the command itself, PAM does not see it
+    pub(crate) exit_status: Option<i32>,
+}
+
+impl Execution {
+    /// Role and task requested on the command line of `sr`, and the command it runs
+    fn from_sr_args(args: &[String]) -> Self {
+        let mut execution = Execution::default();
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-r" | "--role" => execution.role = args.next().cloned(),
+                "-t" | "--task" => execution.task = args.next().cloned(),
+                "--" => break,
+                option if option.starts_with('-') => {}
+                command => {
+                    execution.command = command.to_string();
+                    break;
+                }
+            }
+        }
+        for arg in args {
+            if !execution.command.is_empty() {
+                execution.command.push(' ');
+            }
+            execution.command.push_str(arg);
+        }
+        execution
+    }
+
+    pub(crate) fn record(&self) {
+        let target = self.role.as_deref().unwrap_or(&self.command);
+        let summary = match self.exit_status {
+            Some(status) => format!(
+                "ran {} for {:.3}s, exited with {}",
+                self.command, self.duration_secs, status
+            ),
+            None => format!("ran {} for {:.3}s", self.command, self.duration_secs),
+        };
+        auditlog::record_details(ACTION, target, &summary, json!(self));
+    }
+}
+
+fn session_file() -> PathBuf {
+    state_dir().join("sessions").join(getppid().to_string())
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Called by pam_exec from the `sr` process: the start of the session is kept until
+/// its end, when the execution is recorded
+pub(crate) fn hook() -> anyhow::Result<()> {
+    let path = session_file();
+    match env::var("PAM_TYPE").as_deref() {
+        Ok("open_session") => {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&path, now().as_secs_f64().to_string())?;
+        }
+        Ok("close_session") => {
+            let start: f64 = fs::read_to_string(&path)?.trim().parse()?;
+            fs::remove_file(&path)?;
+            let args: Vec<String> = fs::read(format!("/proc/{}/cmdline", getppid()))?
+                .split(|byte| *byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).to_string())
+                .collect();
+            let mut execution = Execution::from_sr_args(&args);
+            execution.duration_secs = now().as_secs_f64() - start;
+            execution.record();
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Executions of a role over the period
+#[derive(Serialize, Default)]
+pub(crate) struct RoleUsage {
+    pub(crate) executions: usize,
+    pub(crate) users: BTreeSet<String>,
+    pub(crate) last_used: u64,
+    pub(crate) mean_duration_secs: f64,
+    pub(crate) failures: usize,
+}
+
+/// Summarize the recorded executions of the roles, by role or by command when the role
+/// was left for sr to pick
+pub(crate) fn report(since: &str, json: bool) -> anyhow::Result<()> {
+    let after = now().saturating_sub(parse_duration(since)?).as_secs();
+    let mut report: BTreeMap<String, RoleUsage> = BTreeMap::new();
+    for entry in auditlog::entries()? {
+        if entry["action"] != ACTION || entry["timestamp"].as_u64().unwrap_or(0) < after {
+            continue;
+        }
+        let Ok(execution) = serde_json::from_value::<Execution>(entry["details"].clone()) else {
+            continue;
+        };
+        let usage = report
+            .entry(execution.role.unwrap_or(execution.command))
+            .or_default();
+        usage.mean_duration_secs = (usage.mean_duration_secs * usage.executions as f64
+            + execution.duration_secs)
+            / (usage.executions + 1) as f64;
+        usage.executions += 1;
+        usage.failures += execution.exit_status.is_some_and(|status| status != 0) as usize;
+        usage.last_used = usage
+            .last_used
+            .max(entry["timestamp"].as_u64().unwrap_or(0));
+        if let Some(user) = entry["operator"].as_str() {
+            usage.users.insert(user.to_string());
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    for (role, usage) in &report {
+        println!(
+            "{}: {} execution(s) by {}, {} failed, {:.3}s on average, last at {}",
+            role,
+            usage.executions,
+            usage.users.iter().cloned().collect::<Vec<_>>().join(", "),
+            usage.failures,
+            usage.mean_duration_secs,
+            usage.last_used
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+use log::warn;
+
+use crate::auditlog;
+
+fn units_dir() -> PathBuf {
+    env::var("GENSR_SYSTEMD_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/etc/systemd/system"))
+}
+
+/// `gensr-audit`, or `gensr-audit-<namespace>` for the other configurations of the host
+fn unit_name(namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("gensr-audit-{}", namespace),
+        None => "gensr-audit".to_string(),
+    }
+}
+
+/// Escape the specifiers systemd expands in the values of a unit
+fn escape(value: &Path) -> String {
+    value.to_string_lossy().replace('%', "%%")
+}
+
+/// Quote a path as a single argument of `ExecStart`, whatever characters it holds
+fn quote(value: &Path) -> String {
+    format!(
+        "\"{}\"",
+        escape(value)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "$$")
+            .replace('\n', "\\n")
+    )
+}
+
+fn systemctl(args: &[&str]) -> anyhow::Result<()> {
+    let status = Command::new("systemctl")
+        .args(args)
+        .status()
+        .context("Cannot run systemctl")?;
+    anyhow::ensure!(status.success(), "systemctl {} failed", args.join(" "));
+    Ok(())
+}
+
+/// Install and start a systemd timer auditing the configuration daily, the drift being
+/// reported through the notification hooks. Returns the written units
+pub(crate) fn install(config_path: &str, namespace: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+    let gensr = env::current_exe()?;
+    let config_path = fs::canonicalize(config_path)?;
+    let name = unit_name(namespace);
+    let dir = units_dir();
+    fs::create_dir_all(&dir)?;
+    let service = dir.join(format!("{}.service", name));
+    fs::write(
+        &service,
+        format!(
+            "[Unit]\n\
+             Description=Audit the RootAsRole configuration {config} for drift\n\
+             \n\
+             [Service]\n\
+             Type=oneshot\n\
+             ExecStart={gensr} audit --config {quoted}\n",
+            gensr = quote(&gensr),
+            config = escape(&config_path),
+            quoted = quote(&config_path)
+        ),
+    )?;
+    let timer = dir.join(format!("{}.timer", name));
+    fs::write(
+        &timer,
+        format!(
+            "[Unit]\n\
+             Description=Daily audit of the RootAsRole configuration {config}\n\
+             \n\
+             [Timer]\n\
+             OnCalendar=daily\n\
+             RandomizedDelaySec=1h\n\
+             Persistent=true\n\
+             \n\
+             [Install]\n\
+             WantedBy=timers.target\n",
+            config = escape(&config_path)
+        ),
+    )?;
+    auditlog::record(
+        "audit_timer_write",
+        &timer.to_string_lossy(),
+        &format!("daily audit of {}", config_path.display()),
+    );
+    if let Err(e) = systemctl(&["daemon-reload"])
+        .and_then(|_| systemctl(&["enable", "--now", &format!("{}.timer", name)]))
+    {
+        warn!("The audit timer is installed but not started: {}", e);
+    }
+    Ok(vec![service, timer])
+}
+
+/// Stop and remove the audit timer of the configuration, if it was installed. Returns the
+/// removed units
+pub(crate) fn uninstall(namespace: Option<&str>) -> anyhow::Result<Vec<PathBuf>> {
+    let name = unit_name(namespace);
+    let dir = units_dir();
+    let timer = dir.join(format!("{}.timer", name));
+    let service = dir.join(format!("{}.service", name));
+    if !timer.exists() && !service.exists() {
+        return Ok(Vec::new());
+    }
+    if let Err(e) = systemctl(&["disable", "--now", &format!("{}.timer", name)]) {
+        warn!("Cannot stop the audit timer: {}", e);
+    }
+    auditlog::record(
+        "audit_timer_remove",
+        &timer.to_string_lossy(),
+        "daily audit removed",
+    );
+    let mut removed = Vec::new();
+    for unit in [timer, service] {
+        match fs::remove_file(&unit) {
+            Ok(()) => removed.push(unit),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if let Err(e) = systemctl(&["daemon-reload"]) {
+        warn!(
+            "Cannot reload systemd after removing the audit timer: {}",
+            e
+        );
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_exec_start_argument() {
+        assert_eq!(
+            quote(Path::new("/etc/security/rootasrole.json")),
+            "\"/etc/security/rootasrole.json\""
+        );
+        assert_eq!(
+            quote(Path::new("/srv/my conf/100%\"$HOME\\.json")),
+            "\"/srv/my conf/100%%\\\"$$HOME\\\\.json\""
+        );
+    }
+
+    #[test]
+    fn unit_name_namespaced() {
+        assert_eq!(unit_name(None), "gensr-audit");
+        assert_eq!(unit_name(Some("staging")), "gensr-audit-staging");
+    }
+}
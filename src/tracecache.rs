@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+use crate::{
+    capable::TraceStats,
+    policy::Policy,
+    script::{is_interpreter, Script},
+    secrets,
+};
+
+/// A previous trace of the same command, binaries and options
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    policy: Policy,
+    stats: TraceStats,
+}
+
+fn cache_dir() -> PathBuf {
+    env::var("GENSR_CACHE_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/var/cache/gensr/traces"))
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Option<String> {
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(fs::read(path).ok()?);
+    Some(hex::encode(hasher.finalize()))
+}
+
+fn resolve(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        fs::canonicalize(program).ok()
+    } else {
+        which::which(program).ok()?.canonicalize().ok()
+    }
+}
+
+/// Content hashes of the executable of a command, and of the script and interpreter
+/// it involves, keyed by their resolved path
+pub(crate) fn binary_hashes(command: &[String]) -> BTreeMap<String, String> {
+    let mut hashes = BTreeMap::new();
+    let Some(program) = command.first().and_then(|program| resolve(program)) else {
+        return hashes;
+    };
+    let mut add = |path: &Path| {
+        if let Some(hash) = sha256_file(path) {
+            hashes.insert(path.to_string_lossy().to_string(), hash);
+        }
+    };
+    add(&program);
+    let has_shebang = fs::read(&program).is_ok_and(|content| content.starts_with(b"#!"));
+    if let Some(script) = has_shebang
+        .then(|| Script::resolve(&program, Vec::new()).ok())
+        .flatten()
+    {
+        // The executable is a script run through the interpreter of its shebang line
+        if let Some(interpreter) = script.interpreter.first().and_then(|i| resolve(i)) {
+            if interpreter != program {
+                add(&interpreter);
+            }
+        }
+    }
+    if is_interpreter(&program) {
+        if let Some(script) = command
+            .iter()
+            .skip(1)
+            .find(|arg| !arg.starts_with('-'))
+            .and_then(|arg| fs::canonicalize(arg).ok())
+            .filter(|path| path.is_file())
+        {
+            add(&script);
+        }
+    }
+    hashes
+}
+
+/// Cache key of a trace, changing whenever the command, a hashed binary or a trace option does
+pub(crate) fn key(command: &[String], hashes: &BTreeMap<String, String>, options: &str) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for arg in command {
+        hasher.update(arg.as_bytes());
+        hasher.update([0]);
+    }
+    for (path, hash) in hashes {
+        hasher.update(path.as_bytes());
+        hasher.update(hash.as_bytes());
+    }
+    hasher.update(options.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+pub(crate) fn load(key: &str) -> Option<(Policy, TraceStats)> {
+    let path = cache_dir().join(format!("{}.json", key));
+    let entry: Entry = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+    debug!("Trace cache hit {}", path.display());
+    Some((entry.policy, entry.stats))
+}
+
+pub(crate) fn store(key: &str, policy: &Policy, stats: &TraceStats) {
+    let dir = cache_dir();
+    let mut policy = policy.clone();
+    policy.env_vars = secrets::redact_env(&policy.env_vars);
+    let entry = Entry {
+        policy,
+        stats: stats.clone(),
+    };
+    let result = fs::create_dir_all(&dir).and_then(|_| {
+        fs::write(
+            dir.join(format!("{}.json", key)),
+            serde_json::to_string(&entry).unwrap_or_default(),
+        )
+    });
+    if let Err(e) = result {
+        warn!("Failed to cache the trace in {}: {}", dir.display(), e);
+    }
+}
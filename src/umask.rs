@@ -0,0 +1,58 @@
+use std::{collections::BTreeMap, fs, os::unix::fs::MetadataExt, time::SystemTime};
+
+use serde_json::{Map, Value};
+
+use crate::policy::{Access, Policy};
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+
+/// Written files born after the trace started, with their mode. A status change is no
+/// evidence of creation, files on filesystems without a birth time are never reported
+pub(crate) fn created(policy: &Policy, started: SystemTime) -> BTreeMap<String, u32> {
+    policy
+        .files
+        .iter()
+        .filter(|(_, access)| access.contains(Access::W))
+        .filter_map(|(path, _)| {
+            let metadata = fs::symlink_metadata(path).ok()?;
+            (metadata.created().ok()? >= started).then(|| (path.clone(), metadata.mode()))
+        })
+        .collect()
+}
+
+fn is_dir(mode: u32) -> bool {
+    mode & S_IFMT == S_IFDIR
+}
+
+/// Umask the command runs with, and mode of the directories it creates, for the files created
+/// under the role user to get the permissions they got during the trace
+pub(crate) fn recommend(policy: &Policy) -> Option<Map<String, Value>> {
+    let mut options = Map::new();
+    let umask = match policy.umasks.iter().collect::<Vec<_>>().as_slice() {
+        [umask] => Some(**umask),
+        // The bits no created file or directory has, assuming default creation modes
+        _ => policy
+            .created_modes
+            .values()
+            .map(|mode| {
+                let default = if is_dir(*mode) { 0o777 } else { 0o666 };
+                default & !(mode & 0o777)
+            })
+            .reduce(|a, b| a & b),
+    };
+    if let Some(umask) = umask {
+        options.insert("umask".to_string(), Value::from(format!("{:04o}", umask)));
+    }
+    let mut directory_modes: BTreeMap<u32, usize> = BTreeMap::new();
+    for mode in policy.created_modes.values().filter(|mode| is_dir(**mode)) {
+        *directory_modes.entry(mode & 0o7777).or_default() += 1;
+    }
+    if let Some((mode, _)) = directory_modes.iter().max_by_key(|(_, count)| **count) {
+        options.insert(
+            "directory_mode".to_string(),
+            Value::from(format!("{:04o}", mode)),
+        );
+    }
+    (!options.is_empty()).then_some(options)
+}
@@ -0,0 +1,148 @@
+use std::{cell::RefCell, ffi::CString, rc::Rc};
+
+use anyhow::anyhow;
+use nix::unistd::{getgrouplist, Group, User};
+use rootasrole_core::database::structs::{
+    SActor, SActorType, SCommand, SConfig, SGroups, SRole, STask, SetBehavior,
+};
+
+use crate::{params::glob_match, policy::Policy, regenerate::Scope};
+
+/// A task the user could run the command with, and what it lacks for that
+pub(crate) struct Candidate {
+    pub(crate) role: String,
+    pub(crate) task: String,
+    pub(crate) missing: Vec<String>,
+}
+
+impl std::fmt::Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.missing.is_empty() {
+            return write!(f, "{}/{}: permits the command", self.role, self.task);
+        }
+        write!(
+            f,
+            "{}/{}: would need {}",
+            self.role,
+            self.task,
+            self.missing.join(", ")
+        )
+    }
+}
+
+/// Names and ids of the user and of its groups
+struct Identity {
+    user: User,
+    groups: Vec<Group>,
+}
+
+impl Identity {
+    fn new(name: &str) -> anyhow::Result<Identity> {
+        let user = User::from_name(name)?.ok_or_else(|| anyhow!("Unknown user {}", name))?;
+        let groups = getgrouplist(&CString::new(name)?, user.gid)?
+            .into_iter()
+            .filter_map(|gid| Group::from_gid(gid).ok().flatten())
+            .collect();
+        Ok(Identity { user, groups })
+    }
+
+    fn is_user(&self, actor: &SActorType) -> bool {
+        match actor {
+            SActorType::Name(name) => *name == self.user.name,
+            SActorType::Id(uid) => *uid == self.user.uid.as_raw(),
+        }
+    }
+
+    fn in_group(&self, actor: &SActorType) -> bool {
+        self.groups.iter().any(|group| match actor {
+            SActorType::Name(name) => *name == group.name,
+            SActorType::Id(gid) => *gid == group.gid.as_raw(),
+        })
+    }
+
+    fn is_actor(&self, role: &SRole) -> bool {
+        role.actors.iter().any(|actor| match actor {
+            SActor::User { id: Some(id), .. } => self.is_user(id),
+            SActor::Group {
+                groups: Some(SGroups::Single(group)),
+                ..
+            } => self.in_group(group),
+            // Every group of the actor is required
+            SActor::Group {
+                groups: Some(SGroups::Multiple(groups)),
+                ..
+            } => groups.iter().all(|group| self.in_group(group)),
+            _ => false,
+        })
+    }
+}
+
+/// The command line as RootAsRole compares it, with the program resolved in the PATH
+fn command_line(command: &[String]) -> String {
+    let mut words = command.to_vec();
+    if let Some(program) = words.first_mut() {
+        if let Ok(path) = which::which(&*program) {
+            *program = path.to_string_lossy().to_string();
+        }
+    }
+    words.join(" ")
+}
+
+fn command_matches(commands: &[SCommand], line: &str) -> bool {
+    commands.iter().any(|command| match command {
+        SCommand::Simple(allowed) => {
+            allowed == line || glob_match(allowed.as_bytes(), line.as_bytes())
+        }
+        _ => false,
+    })
+}
+
+fn allows(task: &STask, line: &str) -> bool {
+    if command_matches(&task.commands.sub, line) {
+        return false;
+    }
+    task.commands.default_behavior == Some(SetBehavior::All)
+        || command_matches(&task.commands.add, line)
+}
+
+/// Tasks of the scope the user could run the command with, those permitting it first,
+/// then those lacking the fewest grants. `needed` are the privileges of a trace of the
+/// command, compared with the grants of each task
+pub(crate) fn candidates(
+    config: &Rc<RefCell<SConfig>>,
+    user: &str,
+    command: &[String],
+    needed: Option<&Policy>,
+    scope: Scope,
+) -> anyhow::Result<Vec<Candidate>> {
+    let identity = Identity::new(user)?;
+    let line = command_line(command);
+    let mut candidates = Vec::new();
+    for role in &config.as_ref().borrow().roles {
+        let role = role.as_ref().borrow();
+        let actor = identity.is_actor(&role);
+        for task in &role.tasks {
+            let task = task.as_ref().borrow();
+            if scope == Scope::GeneratedOnly && !task._extra_fields.contains_key("provenance") {
+                continue;
+            }
+            let mut missing = Vec::new();
+            if !actor {
+                missing.push(format!("{} as an actor", user));
+            }
+            if !allows(&task, &line) {
+                missing.push(format!("the command {}", line));
+            }
+            if let Some(needed) = needed {
+                missing.extend((needed.clone() - Policy::from_stask(&task)).describe('+'));
+            }
+            candidates.push(Candidate {
+                role: role.name.clone(),
+                task: task.name.to_string(),
+                missing,
+            });
+        }
+    }
+    candidates.sort_by_key(|candidate| candidate.missing.len());
+    Ok(candidates)
+}